@@ -0,0 +1,74 @@
+use ariadne_dns::shared::buffer::{BitsBuf, BytesReader, BytesWriter};
+use ariadne_dns::shared::dns::Message;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+const QUERY: &[u8] = include_bytes!("../assets/messages/query_packet_bin.txt");
+const RESPONSE: &[u8] = include_bytes!("../assets/messages/response_packet_bin.txt");
+
+// Compare the old bit-level buffer with the byte cursors on the same
+// workload: read (and write back) the whole response as 16 bits words.
+fn buffers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("buffer");
+    group.bench_function("bits_buf_read", |b| {
+        b.iter(|| {
+            let mut buf = BitsBuf::from_raw_bytes(black_box(RESPONSE));
+            let mut acc = 0_u16;
+            while let Some(n) = buf.read_u16() {
+                acc = acc.wrapping_add(n);
+            }
+            acc
+        })
+    });
+    group.bench_function("bytes_reader_read", |b| {
+        b.iter(|| {
+            let mut buf = BytesReader::new(black_box(RESPONSE));
+            let mut acc = 0_u16;
+            while let Some(n) = buf.read_u16() {
+                acc = acc.wrapping_add(n);
+            }
+            acc
+        })
+    });
+    group.bench_function("bits_buf_write", |b| {
+        b.iter(|| {
+            let mut buf = BitsBuf::new();
+            for chunk in black_box(RESPONSE).chunks_exact(2) {
+                buf.write_u16(u16::from_be_bytes([chunk[0], chunk[1]]));
+            }
+            buf.into_vec()
+        })
+    });
+    group.bench_function("bytes_writer_write", |b| {
+        b.iter(|| {
+            let mut buf = BytesWriter::new();
+            for chunk in black_box(RESPONSE).chunks_exact(2) {
+                buf.write_u16(u16::from_be_bytes([chunk[0], chunk[1]]));
+            }
+            buf.into_vec()
+        })
+    });
+    group.finish();
+}
+
+fn messages(c: &mut Criterion) {
+    let mut group = c.benchmark_group("message");
+    group.bench_function("decode_query", |b| {
+        b.iter(|| Message::decode_from_bytes(black_box(QUERY)))
+    });
+    group.bench_function("decode_response", |b| {
+        b.iter(|| Message::decode_from_bytes(black_box(RESPONSE)))
+    });
+
+    // Encoding needs header counts matching the records (unknown
+    // records are skipped when decoding), so fix them up first.
+    let mut response = Message::decode_from_bytes(RESPONSE).unwrap();
+    response.header.additionals_count = response.additionals.len() as u16;
+    group.bench_function("encode_response", |b| b.iter(|| black_box(&response).encode_to_bytes()));
+    group.bench_function("encode_response_trunc", |b| {
+        b.iter(|| black_box(&response).encode_to_bytes_trunc())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, buffers, messages);
+criterion_main!(benches);
@@ -0,0 +1,116 @@
+use ariadne_dns::shared::dns;
+use ariadne_dns::shared::net::*;
+use ariadne_dns::shared::thread_pool::QueueLimits;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::sync::{atomic, Arc};
+use std::{net, thread, time};
+
+const QUERY: &[u8] = include_bytes!("../assets/messages/query_packet_bin.txt");
+const CLIENTS: usize = 8;
+const IN_FLIGHT: usize = 16;
+
+// A handler answering immediately, so that the benchmark
+// measures the server receive and send paths only.
+struct EchoHandler;
+
+impl DnsHandler for EchoHandler {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        if let DnsReadResult::FullMessage(mut msg) = req.read() {
+            msg.header.query_resp = true;
+            resp.reply(msg).unwrap();
+        }
+    }
+}
+
+fn start(port: u16, sockets_per_listener: usize, batch_size: usize) -> ServerHandle {
+    let listeners = vec![ListenAddr {
+        address: net::SocketAddr::from(([127, 0, 0, 1], port)),
+        ipv6_only: false,
+    }];
+    let params = ServersParams {
+        udp: UdpParams {
+            listeners: listeners.clone(),
+            write_timeout: time::Duration::from_secs(2),
+            threads: 4,
+            sockets_per_listener,
+            batch_size,
+            queue: QueueLimits::UNBOUNDED,
+            overload_action: OverloadAction::Drop,
+        },
+        tcp: TcpParams {
+            listeners,
+            write_timeout: time::Duration::from_secs(2),
+            read_timeout: time::Duration::from_secs(2),
+            idle_timeout: time::Duration::from_secs(2),
+            max_connections: 1,
+            threads: 1,
+            queue: QueueLimits::UNBOUNDED,
+            proxy_trusted: vec![],
+        },
+        #[cfg(feature = "tls")]
+        tls: None,
+        #[cfg(feature = "https")]
+        https: None,
+        #[cfg(feature = "quic")]
+        quic: None,
+        drain_timeout: time::Duration::from_secs(1),
+    };
+    let handle = start_servers(Arc::new(EchoHandler), params);
+    thread::sleep(time::Duration::from_millis(300));
+    handle
+}
+
+// Send `total` queries from several clients, each one keeping a window of
+// queries in flight. Lost datagrams are replaced after a short timeout.
+fn load(port: u16, total: u64) -> time::Duration {
+    let remaining = Arc::new(atomic::AtomicI64::new(total as i64));
+    let start = time::Instant::now();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let remaining = Arc::clone(&remaining);
+            thread::spawn(move || {
+                let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+                socket.connect(("127.0.0.1", port)).unwrap();
+                socket.set_read_timeout(Some(time::Duration::from_millis(50))).unwrap();
+                let mut buf = [0; dns::MAX_UDP_LEN_BYTES];
+                let mut in_flight = 0;
+                loop {
+                    while in_flight < IN_FLIGHT && remaining.fetch_sub(1, atomic::Ordering::Relaxed) > 0 {
+                        socket.send(QUERY).unwrap();
+                        in_flight += 1;
+                    }
+                    if in_flight == 0 {
+                        return;
+                    }
+                    match socket.recv(&mut buf) {
+                        Ok(_) => in_flight -= 1,
+                        Err(_) => in_flight = 0,
+                    }
+                }
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+    start.elapsed()
+}
+
+// Compare the previous design (a single socket, one request per
+// system call) with sharded sockets and batched system calls.
+fn udp_load(c: &mut Criterion) {
+    let mut group = c.benchmark_group("udp_load");
+    group.throughput(Throughput::Elements(1));
+    group.sample_size(20);
+    let configs = [("single_socket", 1, 1), ("sharded_batched", 4, 32)];
+    for (i, (name, sockets, batch_size)) in configs.into_iter().enumerate() {
+        let port = 48253 + i as u16;
+        let handle = start(port, sockets, batch_size);
+        group.bench_function(name, |b| b.iter_custom(|iters| load(port, iters)));
+        handle.shutdown();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, udp_load);
+criterion_main!(benches);
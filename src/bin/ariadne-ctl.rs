@@ -0,0 +1,63 @@
+use ariadne_dns::shared::control::*;
+use colored::Colorize;
+use std::{env, path, process, time};
+
+// The seconds waited for the response of the server, commands
+// like reload-zone or dump-cache can take a while on large zones.
+const TIMEOUT: u64 = 30;
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let socket = match args.iter().position(|arg| arg == "-s") {
+        Some(i) if i + 1 < args.len() => {
+            let socket = args.remove(i + 1);
+            args.remove(i);
+            socket
+        }
+        _ => {
+            print_usage();
+            process::exit(1);
+        }
+    };
+    if args.is_empty() || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        print_usage();
+        process::exit(1);
+    }
+
+    let command = args.join(" ");
+    match send_command(path::Path::new(&socket), &command, time::Duration::from_secs(TIMEOUT)) {
+        Ok(Ok(output)) => print!("{}", output),
+        Ok(Err(err)) => {
+            eprintln!("{} {}", "Error:".bold().bright_red(), err);
+            process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("{} connecting to '{}': {}", "Error:".bold().bright_red(), socket, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_usage() {
+    eprintln!(
+        "Run a command on a running nameserver or resolver, through the control socket set in its configuration.
+
+Usage: {} -s {} {} [args...]
+
+Commands:
+  status                   print the uptime, the log level and the state of the zones or of the cache
+  reload                   reload the configuration file, as on SIGHUP
+  log-level [LEVEL]        print or set the log level (error, warn, info, debug, trace)
+Nameserver commands:
+  reload-zone              reload the zone files of the current configuration
+Resolver commands:
+  flush NAME [TYPE]        remove the cached records of the name (of all the types if missing)
+  flush-tree NAME          remove the cached records of the name and of all the names below it
+  dump-cache               print the cached records, with the seconds left before they expire
+  trace-on [verbose]       print the lookup traces
+  trace-off                stop printing the lookup traces",
+        "ariadne-ctl".bold(),
+        "path/to/control.sock".bold().bright_green(),
+        "command".bold()
+    )
+}
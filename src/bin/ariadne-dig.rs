@@ -0,0 +1,426 @@
+use ariadne_dns::resolver::*;
+use ariadne_dns::shared::client::*;
+use ariadne_dns::shared::dns;
+use ariadne_dns::shared::net::Transport;
+use colored::Colorize;
+use serde_json::{json, Value};
+use std::io::IsTerminal;
+use std::net::ToSocketAddrs;
+use std::sync::Arc;
+use std::{env, fs, io, process, time};
+
+// The exit code used by dig when no reply is received.
+const NO_REPLY_EXIT_CODE: i32 = 9;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let opts = match Options::parse(&args) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            print_usage();
+            return;
+        }
+        Err(err) => {
+            eprintln!("{} {}\n", "Error:".bold().bright_red(), err);
+            print_usage();
+            process::exit(1);
+        }
+    };
+
+    let code = if opts.trace { run_trace(&opts) } else { run_query(&opts) };
+    process::exit(code);
+}
+
+/// The command line options, see [print_usage].
+struct Options {
+    server: Option<String>,
+    port: u16,
+    name: dns::Name,
+    kind: dns::RecordType,
+    class: dns::Class,
+    tcp: bool,
+    recurse: bool,
+    edns: bool,
+    bufsize: u16,
+    trace: bool,
+    json: bool,
+    timeout: u64,
+    tries: usize,
+}
+
+impl Options {
+    // Parse the arguments as dig does: the first ones not recognized as options
+    // are the name, the type and the class. Returns None if help is requested.
+    fn parse(args: &[String]) -> Result<Option<Options>, String> {
+        let mut opts = Options {
+            server: None,
+            port: 53,
+            name: dns::Name::from_string(".").unwrap(),
+            kind: dns::RecordType::NS,
+            class: dns::Class::IN,
+            tcp: false,
+            recurse: true,
+            edns: true,
+            bufsize: dns::Edns::default().udp_payload_size,
+            trace: false,
+            json: false,
+            timeout: 5,
+            tries: 3,
+        };
+        let (mut name, mut kind) = (None, None);
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = |flag: &str| args.next().ok_or(format!("missing value of '{}'", flag));
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(None),
+                "-p" => opts.port = parse_num("port", value("-p")?)?,
+                "-t" => kind = Some(parse_type(value("-t")?)?),
+                "-c" => opts.class = parse_class(value("-c")?)?,
+                server if server.starts_with('@') => opts.server = Some(server[1..].to_string()),
+                option if option.starts_with('+') => opts.parse_option(&option[1..])?,
+                _ if name.is_none() => name = Some(parse_name(arg)?),
+                _ if kind.is_none() && dns::RecordType::from_str(&arg.to_uppercase()).is_ok() => {
+                    kind = Some(parse_type(arg)?)
+                }
+                _ if dns::Class::from_string(&arg.to_uppercase()).is_ok() => opts.class = parse_class(arg)?,
+                _ => return Err(format!("unexpected argument '{}'", arg)),
+            }
+        }
+
+        // Without a name, the root nameservers are queried.
+        if let Some(name) = name {
+            opts.name = name;
+            opts.kind = kind.unwrap_or(dns::RecordType::A);
+        } else if let Some(kind) = kind {
+            opts.kind = kind;
+        }
+        Ok(Some(opts))
+    }
+
+    fn parse_option(&mut self, option: &str) -> Result<(), String> {
+        let (option, value) = match option.split_once('=') {
+            Some((option, value)) => (option, Some(value)),
+            None => (option, None),
+        };
+        match (option, value) {
+            ("tcp" | "vc", None) => self.tcp = true,
+            ("notcp" | "novc", None) => self.tcp = false,
+            ("recurse", None) => self.recurse = true,
+            ("norecurse", None) => self.recurse = false,
+            ("edns", None) => self.edns = true,
+            ("noedns", None) => self.edns = false,
+            ("bufsize", Some(size)) => {
+                self.bufsize = parse_num("buffer size", size)?;
+                self.edns = true;
+            }
+            ("trace", None) => self.trace = true,
+            ("notrace", None) => self.trace = false,
+            ("json", None) => self.json = true,
+            ("nojson", None) => self.json = false,
+            ("timeout", Some(secs)) => self.timeout = parse_num("timeout", secs)?,
+            ("tries", Some(tries)) => self.tries = parse_num("tries", tries)?,
+            _ => return Err(format!("unknown option '+{}'", option)),
+        }
+        Ok(())
+    }
+}
+
+fn parse_num<T: std::str::FromStr>(what: &str, s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("invalid {} '{}'", what, s))
+}
+
+// Names are always absolute, the trailing dot is optional.
+fn parse_name(s: &str) -> Result<dns::Name, String> {
+    let name = if s.ends_with('.') {
+        s.to_string()
+    } else {
+        format!("{}.", s)
+    };
+    dns::Name::from_string(&name).map_err(|err| format!("invalid name '{}': {:?}", s, err))
+}
+
+fn parse_type(s: &str) -> Result<dns::RecordType, String> {
+    match dns::RecordType::from_str(&s.to_uppercase()) {
+        Ok(kind) if kind.is_supported_for_question() => Ok(kind),
+        Ok(_) => Err(format!("type '{}' is not supported", s)),
+        Err(_) => Err(format!("invalid type '{}'", s)),
+    }
+}
+
+fn parse_class(s: &str) -> Result<dns::Class, String> {
+    match dns::Class::from_string(&s.to_uppercase()) {
+        Ok(class) if class.is_supported() => Ok(class),
+        Ok(_) => Err(format!("class '{}' is not supported", s)),
+        Err(_) => Err(format!("invalid class '{}'", s)),
+    }
+}
+
+// Send the query to the server, the first nameserver of
+// the system configuration if not set in the options.
+fn run_query(opts: &Options) -> i32 {
+    let host = match &opts.server {
+        Some(server) => server.clone(),
+        None => system_nameserver().unwrap_or("127.0.0.1".to_string()),
+    };
+    let server = match (host.as_str(), opts.port)
+        .to_socket_addrs()
+        .map(|mut addrs| addrs.next())
+    {
+        Ok(Some(addr)) => addr,
+        Ok(None) | Err(_) => {
+            eprintln!(";; Cannot resolve server '{}'.", host);
+            return 1;
+        }
+    };
+
+    let client = Client::new(ClientParams {
+        server,
+        tcp_only: opts.tcp,
+        tcp_fallback: true,
+        attempts: opts.tries.max(1),
+        read_timeout: time::Duration::from_secs(opts.timeout),
+        write_timeout: time::Duration::from_secs(opts.timeout),
+        recursion_desired: opts.recurse,
+        edns: opts.edns.then(|| dns::Edns {
+            udp_payload_size: opts.bufsize,
+            ..Default::default()
+        }),
+    });
+    let mut request = client.build_query(&opts.name, opts.kind);
+    request.questions[0].class = opts.class;
+
+    let reply = match client.send(&request) {
+        Ok(reply) => reply,
+        Err(err) => {
+            match opts.json {
+                true => println!(
+                    "{}",
+                    json!({ "server": server.to_string(), "error": format!("{:?}", err) })
+                ),
+                false => println!(";; Communications error to {}: {:?}", server, err),
+            }
+            return NO_REPLY_EXIT_CODE;
+        }
+    };
+
+    if opts.json {
+        let output = json!({
+            "server": server.to_string(),
+            "transport": reply.transport.name(),
+            "query_time_ms": reply.elapsed.as_secs_f64() * 1000.0,
+            "size": reply.bytes.len(),
+            "message": message_json(&reply.message),
+        });
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    } else {
+        println!("; <<>> ariadne-dig <<>> {} {}", opts.name, opts.kind.to_str());
+        print_message(&reply.message);
+        println!(";; Query time: {} msec", reply.elapsed.as_millis());
+        let transport = match reply.transport {
+            Transport::Tcp => "TCP",
+            _ => "UDP",
+        };
+        println!(";; SERVER: {}#{}({}) ({})", server.ip(), server.port(), host, transport);
+        println!(";; MSG SIZE  rcvd: {}", reply.bytes.len());
+    }
+    0
+}
+
+// The first nameserver listed in the resolver configuration of the system.
+fn system_nameserver() -> Option<String> {
+    let resolv_conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+    resolv_conf.lines().find_map(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("nameserver"), Some(addr)) => Some(addr.to_string()),
+            _ => None,
+        }
+    })
+}
+
+// Resolve the name starting from the root nameservers, as the resolver does,
+// printing the trace of the lookup. The cache of the resolver starts empty.
+fn run_trace(opts: &Options) -> i32 {
+    let color = !opts.json && io::stdout().is_terminal();
+    colored::control::set_override(color);
+    let cache = Arc::new(RecordsCache::new(CacheConf::default()));
+    let resolver_params = ResolverParams {
+        max_upd_retries: opts.tries.max(1),
+        read_timeout: time::Duration::from_secs(opts.timeout),
+        write_timeout: time::Duration::from_secs(opts.timeout),
+        tcp_only: opts.tcp,
+        ..Default::default()
+    };
+    let trace_params = TraceParams {
+        verbose: false,
+        silent: false,
+        color,
+    };
+    let resolver = Resolver::new(&cache, resolver_params, trace_params);
+    let (result, trace) = resolver.new_lookup(&opts.name, opts.kind).perform();
+
+    let trace_lines = trace.to_string();
+    let (output, code) = match result {
+        Ok(LookupResponse(answers, authorities, additionals, no_domain)) => {
+            let status = if no_domain { "NXDOMAIN" } else { "NOERROR" };
+            if !opts.json {
+                print!("{}", trace_lines);
+                println!(";; status: {}", status);
+                print_section("ANSWER", &answers);
+                print_section("AUTHORITY", &authorities);
+                print_section("ADDITIONAL", &additionals);
+            }
+            let output = json!({
+                "status": status,
+                "answers": records_json(&answers),
+                "authorities": records_json(&authorities),
+                "additionals": records_json(&additionals),
+            });
+            (output, 0)
+        }
+        Err((_, err)) => {
+            if !opts.json {
+                print!("{}", trace_lines);
+                println!(";; Lookup failed: {:?}", err);
+            }
+            (json!({ "error": format!("{:?}", err) }), NO_REPLY_EXIT_CODE)
+        }
+    };
+    if opts.json {
+        let mut output = output;
+        output["trace"] = trace_lines.lines().collect::<Vec<_>>().into();
+        println!("{}", serde_json::to_string_pretty(&output).unwrap());
+    }
+    code
+}
+
+// Print the message with the layout of dig.
+fn print_message(message: &dns::Message) {
+    let header = &message.header;
+    println!(
+        ";; ->>HEADER<<- opcode: {:?}, status: {}, id: {}",
+        header.op_code,
+        rcode(header.resp_code),
+        header.id
+    );
+    println!(
+        ";; flags: {}; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+        flags(header).join(" "),
+        message.questions.len(),
+        message.answers.len(),
+        message.authorities.len(),
+        message.additionals.len() + message.edns.is_some() as usize,
+    );
+    if let Some(edns) = &message.edns {
+        println!("\n;; OPT PSEUDOSECTION:");
+        let flags = if edns.dnssec_ok { " do" } else { "" };
+        println!(
+            "; EDNS: version: {}, flags:{}; udp: {}",
+            edns.version, flags, edns.udp_payload_size
+        );
+    }
+    if !message.questions.is_empty() {
+        println!("\n;; QUESTION SECTION:");
+        for question in &message.questions {
+            let kind = question.record_type.to_str();
+            println!(";{}\t\t{:?}\t{}", question.node, question.class, kind);
+        }
+    }
+    print_section("ANSWER", &message.answers);
+    print_section("AUTHORITY", &message.authorities);
+    print_section("ADDITIONAL", &message.additionals);
+    println!();
+}
+
+fn print_section(section: &str, records: &[dns::Record]) {
+    if records.is_empty() {
+        return;
+    }
+    println!("\n;; {} SECTION:", section);
+    for record in records {
+        println!("{}", record);
+    }
+}
+
+fn message_json(message: &dns::Message) -> Value {
+    let header = &message.header;
+    let questions: Vec<Value> = message
+        .questions
+        .iter()
+        .map(|question| {
+            json!({
+                "name": question.node.to_string(),
+                "type": question.record_type.to_str(),
+                "class": format!("{:?}", question.class),
+            })
+        })
+        .collect();
+    let edns = message.edns.as_ref().map(|edns| {
+        json!({
+            "version": edns.version,
+            "udp_payload_size": edns.udp_payload_size,
+            "dnssec_ok": edns.dnssec_ok,
+        })
+    });
+    json!({
+        "id": header.id,
+        "opcode": format!("{:?}", header.op_code),
+        "status": rcode(header.resp_code),
+        "flags": flags(header),
+        "questions": questions,
+        "answers": records_json(&message.answers),
+        "authorities": records_json(&message.authorities),
+        "additionals": records_json(&message.additionals),
+        "edns": edns,
+    })
+}
+
+fn records_json(records: &[dns::Record]) -> Vec<Value> {
+    records
+        .iter()
+        .map(|record| {
+            json!({
+                "name": record.node().to_string(),
+                "ttl": record.ttl(),
+                "class": format!("{:?}", record.class()),
+                "type": record.record_type().to_str(),
+                "data": record.format_data(),
+            })
+        })
+        .collect()
+}
+
+fn rcode(resp_code: dns::RespCode) -> String {
+    format!("{:?}", resp_code).to_uppercase()
+}
+
+fn flags(header: &dns::Header) -> Vec<&'static str> {
+    let flags = [
+        ("qr", header.query_resp),
+        ("aa", header.auth_answer),
+        ("tc", header.truncated),
+        ("rd", header.recursion_desired),
+        ("ra", header.recursion_available),
+    ];
+    flags.iter().filter(|(_, set)| *set).map(|(flag, _)| *flag).collect()
+}
+
+fn print_usage() {
+    eprintln!(
+        "Query a dns server, using the codec and the client of the crate.
+
+Usage: {} [@server] [-p port] [-t type] [-c class] [name] [type] [class] [+option...]
+
+The server defaults to the first nameserver in /etc/resolv.conf, the name to the root zone (type NS).
+Options:
+  +tcp, +notcp             send the query over TCP only (UDP is retried over TCP when truncated)
+  +recurse, +norecurse     set the recursion desired flag (set by default)
+  +edns, +noedns           add the EDNS OPT record (added by default)
+  +bufsize=B               set the UDP payload size advertised with EDNS (1232 by default)
+  +timeout=T               wait T seconds for every response (5 by default)
+  +tries=N                 send the query up to N times (3 by default)
+  +trace                   resolve the name from the root nameservers, printing the lookup trace
+  +json                    print the response as JSON",
+        "ariadne-dig".bold()
+    )
+}
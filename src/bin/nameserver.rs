@@ -1,11 +1,20 @@
-use ariadne_dns::nameserver::conf::ZoneConf;
+use ariadne_dns::nameserver::conf::{PrivilegesConf, QueryLogConf, QueryLogOutputConf, ZoneConf};
 use ariadne_dns::nameserver::*;
+use ariadne_dns::shared::control::*;
 use ariadne_dns::shared::dns;
+use ariadne_dns::shared::dnstap::*;
+use ariadne_dns::shared::layers::*;
 use ariadne_dns::shared::logs;
-use ariadne_dns::shared::net::{start_servers, TcpParams, UdpParams};
+use ariadne_dns::shared::metrics::*;
+use ariadne_dns::shared::net::*;
+use ariadne_dns::shared::privileges::*;
+use ariadne_dns::shared::query_log::*;
 use colored::Colorize;
-use std::sync::Arc;
-use std::{env, process, time};
+use std::sync::{Arc, Mutex};
+use std::{env, path, process, time};
+
+// The seconds allowed to control clients to send their command.
+const CONTROL_READ_TIMEOUT: u64 = 5;
 
 fn main() {
     logs::init_log();
@@ -29,34 +38,420 @@ fn main() {
         }
     };
 
-    let parsing_params = process_zones_confs(&conf.zone);
-    let zones = match parse_zone_files(parsing_params) {
-        Ok(v) => v,
+    // Metrics are always recorded, but exposed only if configured.
+    let registry = Arc::new(Registry::default());
+    let metrics = NameserverMetrics::register(&registry);
+    let nameserver_handler = match build_handler(&conf, &RootDir::default(), &metrics, build_rrl(&conf)) {
+        Ok(v) => ReloadableHandler::new(v),
         Err(err) => {
-            log::error!("Parsing zone files: {:?}", err);
+            log::error!("Parsing zone files: {}", err);
             process::exit(1);
         }
     };
+    if let Some(metrics_conf) = &conf.metrics {
+        if let Err(err) = start_metrics_server(Arc::clone(&registry), metrics_conf.socket_addr()) {
+            log::error!("Starting metrics server: {}", err);
+            process::exit(1);
+        }
+    }
 
-    // Instantiate the nameserver handler and start the servers.
-    let nameserver_handler = NameserverHandler(zones);
-    let nameserver_handler_arc = Arc::new(nameserver_handler);
+    // The root directory once the privileges are dropped, if changed: the files
+    // reopened afterwards (rotated query logs, reloaded zones) are resolved in it.
+    let privileges_params = conf
+        .privileges
+        .as_ref()
+        .map(|privileges_conf| privileges_params(privileges_conf, &conf.zone.file));
+    let root_dir = RootDir::new(privileges_params.as_ref().and_then(|params| params.chroot.as_deref()));
+    if let Err(err) = check_root_dir(&root_dir, &args[1], &conf) {
+        log::error!("Changing root directory: {}", err);
+        process::exit(1);
+    }
 
-    let udp_params = UdpParams {
-        address: conf.udp_server.address,
-        port: conf.udp_server.port,
-        write_timeout: time::Duration::new(conf.udp_server.write_timeout, 0),
-        threads: conf.udp_server.threads,
+    // Start the query log writer, if configured.
+    let query_log = conf.query_log.as_ref().map(|query_log_conf| {
+        let params = query_log_params(query_log_conf, &root_dir).unwrap_or_else(|err| {
+            log::error!("Starting query log writer: {}", err);
+            process::exit(1);
+        });
+        match QueryLog::start(params) {
+            Ok(v) => Arc::new(v),
+            Err(err) => {
+                log::error!("Starting query log writer: {}", err);
+                process::exit(1);
+            }
+        }
+    });
+
+    // Start the dnstap writer, if configured.
+    let dnstap = conf
+        .dnstap
+        .as_ref()
+        .map(|dnstap_conf| match Dnstap::start(dnstap_conf.into()) {
+            Ok(v) => Arc::new(v),
+            Err(err) => {
+                log::error!("Starting dnstap writer: {}", err);
+                process::exit(1);
+            }
+        });
+
+    // Wrap the nameserver handler, which is replaced on reloads.
+    let reloader = Reloader {
+        conf_path: args[1].clone(),
+        root_dir: RootDir::default(),
+        started: conf.clone(),
+        applied: conf.clone(),
+        handler: nameserver_handler.clone(),
+        metrics,
     };
-    let tcp_params = TcpParams {
-        address: conf.tcp_server.address,
-        port: conf.tcp_server.port,
-        write_timeout: time::Duration::new(conf.tcp_server.write_timeout, 0),
-        read_timeout: time::Duration::new(conf.tcp_server.read_timeout, 0),
-        threads: conf.tcp_server.threads,
+    let reloader = Arc::new(Mutex::new(reloader));
+    let server_metrics = ServerMetrics::register(&registry);
+    let nameserver_handler = QueryLogHandler::new(nameserver_handler, query_log);
+    let nameserver_handler = DnstapHandler::new(
+        nameserver_handler,
+        dnstap,
+        MessageType::AuthQuery,
+        MessageType::AuthResponse,
+    );
+    let nameserver_handler_arc = Arc::new(MeteredHandler::new("nameserver", nameserver_handler, server_metrics));
+
+    let servers_params = ServersParams {
+        udp: (&conf.udp_server).into(),
+        tcp: (&conf.tcp_server).into(),
+        #[cfg(feature = "tls")]
+        tls: conf.tls_server.as_ref().map(Into::into),
+        #[cfg(feature = "https")]
+        https: conf.https_server.as_ref().map(Into::into),
+        #[cfg(feature = "quic")]
+        quic: conf.quic_server.as_ref().map(Into::into),
+        drain_timeout: time::Duration::new(conf.drain_timeout, 0),
+    };
+
+    // Bind the sockets (or take the ones passed by systemd) and the control
+    // socket, then drop the privileges before starting the servers, if configured.
+    bind_sockets(&servers_params);
+    if let Some(control_conf) = &conf.control {
+        let control_params = ControlParams {
+            path: control_conf.path.clone().into(),
+            read_timeout: time::Duration::new(CONTROL_READ_TIMEOUT, 0),
+        };
+        let control = NameserverControl {
+            reloader: Arc::clone(&reloader),
+            started: time::Instant::now(),
+        };
+        if let Err(err) = start_control_server(control_params, Arc::new(control)) {
+            log::error!("Starting control server: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(privileges_params) = &privileges_params {
+        reloader.lock().unwrap().root_dir = root_dir;
+        if let Err(err) = drop_privileges(privileges_params) {
+            log::error!("Dropping privileges: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = register_reload_signal(move || {
+        let _ = reloader.lock().unwrap().reload();
+    }) {
+        log::error!("Registering signal handlers: {}", err);
+        process::exit(1);
+    }
+
+    // Use the async servers only if explicitly configured.
+    #[cfg(feature = "async")]
+    if let Some(async_conf) = &conf.async_runtime {
+        let async_params = AsyncParams {
+            worker_threads: async_conf.worker_threads,
+            blocking_threads: async_conf.blocking_threads,
+            max_pending: async_conf.max_pending,
+            drain_timeout: time::Duration::new(conf.drain_timeout, 0),
+        };
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        if let Err(err) = register_stop_signals(&stop) {
+            log::error!("Registering signal handlers: {}", err);
+            process::exit(1);
+        }
+        start_async_servers(
+            nameserver_handler_arc,
+            servers_params.udp,
+            servers_params.tcp,
+            async_params,
+            &stop,
+        );
+        return;
+    }
+
+    let server_handle = start_servers(nameserver_handler_arc, servers_params);
+    register_queue_stats(&registry, server_handle.queue_stats());
+    if let Err(err) = server_handle.stop_on_signals() {
+        log::error!("Registering signal handlers: {}", err);
+        process::exit(1);
+    }
+    server_handle.wait();
+}
+
+/// The state needed to reload the configuration file on SIGHUP. The settings
+/// that need a restart are compared with the `started` configuration, while the
+/// rate limiter is kept if its settings are the same of the `applied` one.
+struct Reloader {
+    conf_path: String,
+    root_dir: RootDir,
+    started: conf::Conf,
+    applied: conf::Conf,
+    handler: ReloadableHandler<NameserverHandler>,
+    metrics: NameserverMetrics,
+}
+
+impl Reloader {
+    // Reload the configuration file and the zone files, replacing the handler and
+    // the log level. On errors, the previous configuration keeps being served.
+    fn reload(&mut self) -> Result<(), String> {
+        log::info!("Reloading configuration file '{}'.", self.conf_path);
+        let conf = match self
+            .root_dir
+            .resolve(&self.conf_path)
+            .and_then(|path| conf::Conf::from_file(&path))
+        {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Reloading configuration file: {}, keeping the previous one.", err);
+                return Err(err);
+            }
+        };
+        self.replace_handler(&conf)?;
+
+        logs::set_max_level(conf.log_level);
+        log::info!("Configuration reloaded: {:?}.", conf);
+        let restart_required = self.started.restart_required(&conf);
+        if !restart_required.is_empty() {
+            log::warn!("Changes not applied until restart: {}.", restart_required.join(", "));
+        }
+        self.applied = conf;
+        Ok(())
+    }
+
+    // Reload only the zone files of the applied configuration.
+    fn reload_zones(&mut self) -> Result<(), String> {
+        log::info!("Reloading zone files.");
+        let conf = self.applied.clone();
+        self.replace_handler(&conf)?;
+        log::info!("Zone files reloaded.");
+        Ok(())
+    }
+
+    fn replace_handler(&mut self, conf: &conf::Conf) -> Result<(), String> {
+        let rrl = match conf.rrl == self.applied.rrl {
+            true => self.handler.current().rrl.clone(),
+            false => build_rrl(conf),
+        };
+        match build_handler(conf, &self.root_dir, &self.metrics, rrl) {
+            Ok(handler) => {
+                self.handler.replace(handler);
+                Ok(())
+            }
+            Err(err) => {
+                log::error!("Reloading zone files: {}, keeping the previous ones.", err);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// The commands run through the control server (see `ariadne-ctl`).
+struct NameserverControl {
+    reloader: Arc<Mutex<Reloader>>,
+    started: time::Instant,
+}
+
+impl ControlHandler for NameserverControl {
+    fn handle_command(&self, command: &str, args: &[&str]) -> Result<String, String> {
+        match (command, args) {
+            ("status", []) => Ok(self.status()),
+            ("reload", []) => {
+                self.reloader.lock().unwrap().reload()?;
+                Ok("Configuration reloaded.".to_string())
+            }
+            ("reload-zone", []) => {
+                let mut reloader = self.reloader.lock().unwrap();
+                reloader.reload_zones()?;
+                Ok(zones_status(&reloader.handler.current().zones))
+            }
+            ("log-level", []) => Ok(log::max_level().to_string()),
+            ("log-level", [level]) => {
+                let level = level
+                    .parse::<logs::Level>()
+                    .map_err(|_| format!("invalid log level '{}'", level))?;
+                logs::set_max_level(level);
+                Ok(format!("Log level set to {}.", level))
+            }
+            _ => Err(format!(
+                "invalid command '{}', expected: status, reload, reload-zone, log-level [LEVEL]",
+                [&[command], args].concat().join(" ")
+            )),
+        }
+    }
+}
+
+impl NameserverControl {
+    fn status(&self) -> String {
+        let reloader = self.reloader.lock().unwrap();
+        let handler = reloader.handler.current();
+        format!(
+            "uptime: {}s\nconfiguration: {}\nlog level: {}\nrrl: {}\nlayers: {}\n{}",
+            self.started.elapsed().as_secs(),
+            reloader.conf_path,
+            log::max_level(),
+            if handler.rrl.is_some() { "on" } else { "off" },
+            handler.layers.status(),
+            zones_status(&handler.zones),
+        )
+    }
+}
+
+// Describe the zones served: the serial of the authoritative
+// zone and the number of records of every zone.
+fn zones_status(zones: &ManagedZone) -> String {
+    let auth_zone = &zones.auth_zone;
+    let serial = auth_zone
+        .get(&auth_zone.zone, dns::RecordType::SOA)
+        .and_then(|soas| match soas.first() {
+            Some(dns::Record::SOA { serial, .. }) => Some(*serial),
+            _ => None,
+        });
+    let mut status = format!(
+        "zone {}: serial {}, {} records\n",
+        auth_zone.zone,
+        serial.unwrap_or_default(),
+        auth_zone.len()
+    );
+    for sub_zone in &zones.sub_zones {
+        status.push_str(&format!("sub zone {}: {} records\n", sub_zone.zone, sub_zone.len()));
+    }
+    status
+}
+
+/// The root directory of the process, changed when dropping the privileges. Files
+/// configured with paths relative to the starting directory are reloaded from the
+/// new root directory, so they must be inside it.
+#[derive(Default)]
+struct RootDir {
+    cwd: path::PathBuf,
+    chroot: Option<path::PathBuf>,
+}
+
+impl RootDir {
+    // Must be called before changing the root directory.
+    fn new(chroot: Option<&path::Path>) -> Self {
+        let cwd = env::current_dir().unwrap_or_default();
+        let chroot = chroot.map(|chroot| cwd.join(chroot));
+        RootDir { cwd, chroot }
+    }
+
+    fn resolve(&self, file: &str) -> Result<String, String> {
+        let chroot = match &self.chroot {
+            Some(v) => v,
+            None => return Ok(file.to_string()),
+        };
+        match self.cwd.join(file).strip_prefix(chroot) {
+            Ok(relative) => Ok(path::Path::new("/").join(relative).to_string_lossy().into_owned()),
+            Err(_) => Err(format!(
+                "'{}' outside of the root directory '{}'",
+                file,
+                chroot.display()
+            )),
+        }
+    }
+}
+
+// Check that the files read again on reloads, the configuration file and the sub
+// zone files, are inside the root directory, so that reloads don't fail later.
+fn check_root_dir(root_dir: &RootDir, conf_path: &str, conf: &conf::Conf) -> Result<(), String> {
+    root_dir.resolve(conf_path)?;
+    for sub_zone_conf in &conf.zone.sub_zones {
+        root_dir.resolve(&sub_zone_conf.file)?;
+    }
+    Ok(())
+}
+
+// Build the nameserver handler from the settings that can be reloaded: the zones,
+// read from the root directory, the access control lists and the layers.
+fn build_handler(
+    conf: &conf::Conf,
+    root_dir: &RootDir,
+    metrics: &NameserverMetrics,
+    rrl: Option<Arc<ResponseLimiter>>,
+) -> Result<NameserverHandler, String> {
+    let mut parsing_params = process_zones_confs(&conf.zone);
+    parsing_params.file_path = root_dir.resolve(&parsing_params.file_path)?;
+    for sub_zone_params in &mut parsing_params.sub_zones {
+        sub_zone_params.file_path = root_dir.resolve(&sub_zone_params.file_path)?;
+    }
+    let zones = parse_zone_files(parsing_params).map_err(|err| format!("{:?}", err))?;
+
+    let acls = match &conf.acl {
+        None => NameserverAcls::default(),
+        Some(acls_conf) => NameserverAcls {
+            query: acls_conf.query.as_ref().map(Into::into),
+            transfer: acls_conf.transfer.as_ref().map(Into::into),
+            action: (&acls_conf.action).into(),
+        },
     };
+    Ok(NameserverHandler {
+        zones,
+        rrl,
+        acls,
+        layers: LayerStack::new(conf.layers.iter().map(Into::into).collect()),
+        metrics: metrics.clone(),
+    })
+}
 
-    start_servers(nameserver_handler_arc, udp_params, tcp_params);
+fn build_rrl(conf: &conf::Conf) -> Option<Arc<ResponseLimiter>> {
+    conf.rrl.as_ref().map(|rrl_conf| {
+        Arc::new(ResponseLimiter::new(RrlParams {
+            responses_per_second: rrl_conf.responses_per_second,
+            window: time::Duration::new(rrl_conf.window, 0),
+            slip: rrl_conf.slip,
+            ipv4_prefix_len: rrl_conf.ipv4_prefix_len,
+            ipv6_prefix_len: rrl_conf.ipv6_prefix_len,
+            dry_run: rrl_conf.dry_run,
+        }))
+    })
+}
+
+// Bind the sockets of all the servers, taking the ones passed by systemd
+// socket activation first. Exit the process on errors.
+fn bind_sockets(servers_params: &ServersParams) {
+    #[cfg(unix)]
+    match inherit_listen_fds() {
+        Ok(0) => {}
+        Ok(n) => log::info!("Inherited {} sockets from systemd.", n),
+        Err(err) => {
+            log::error!("Inheriting systemd sockets: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = bind_servers(servers_params) {
+        log::error!("Binding server sockets: {}", err);
+        process::exit(1);
+    }
+}
+
+// Convert the validated privileges configuration, the
+// chroot directory is the one containing the zone file.
+fn privileges_params(privileges_conf: &PrivilegesConf, zone_file: &str) -> PrivilegesParams {
+    let chroot = privileges_conf.chroot.then(|| {
+        let zone_dir = path::Path::new(zone_file).parent().unwrap_or(path::Path::new(""));
+        match zone_dir.as_os_str().is_empty() {
+            true => path::PathBuf::from("."),
+            false => zone_dir.to_path_buf(),
+        }
+    });
+    PrivilegesParams {
+        user: privileges_conf.user.clone(),
+        group: privileges_conf.group.clone(),
+        chroot,
+        seccomp: privileges_conf.seccomp,
+    }
 }
 
 fn process_zones_confs(zone_conf: &ZoneConf) -> ParsingParams {
@@ -79,6 +474,19 @@ fn process_zones_confs(zone_conf: &ZoneConf) -> ParsingParams {
     }
 }
 
+// Convert the validated query log configuration into the writer parameters.
+// Files are rotated after dropping the privileges, so they must be inside
+// the root directory, if changed.
+fn query_log_params(query_log_conf: &QueryLogConf, root_dir: &RootDir) -> Result<QueryLogParams, String> {
+    let mut params = QueryLogParams::from(query_log_conf);
+    if let (QueryLogOutput::File { chroot_path, .. }, Some(_)) = (&mut params.output, &root_dir.chroot) {
+        if let QueryLogOutputConf::File { path, .. } = &query_log_conf.output {
+            *chroot_path = Some(root_dir.resolve(path)?.into());
+        }
+    }
+    Ok(params)
+}
+
 fn print_usage() {
     log::error!(
         "One argument should be provided when starting the resolver: the path of the configuration file.
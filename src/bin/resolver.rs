@@ -1,10 +1,20 @@
 use ariadne_dns::resolver::*;
+use ariadne_dns::shared::control::*;
+use ariadne_dns::shared::dns;
+use ariadne_dns::shared::dnstap::*;
+use ariadne_dns::shared::layers::*;
 use ariadne_dns::shared::logs;
+use ariadne_dns::shared::metrics::*;
 use ariadne_dns::shared::net::*;
+use ariadne_dns::shared::privileges::*;
+use ariadne_dns::shared::query_log::*;
 use colored::Colorize;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::{env, process, time};
 
+// The seconds allowed to control clients to send their command.
+const CONTROL_READ_TIMEOUT: u64 = 5;
+
 fn main() {
     logs::init_log();
 
@@ -35,7 +45,292 @@ fn main() {
     let cache = Arc::new(Cache::new(cache_conf));
     cache.start_clean_routine();
 
-    // Instantiate the resolver collecting all necessary configuration values.
+    // Metrics are always recorded, but exposed only if configured.
+    let registry = Arc::new(Registry::default());
+    if let Some(metrics_conf) = &conf.metrics {
+        if let Err(err) = start_metrics_server(Arc::clone(&registry), metrics_conf.socket_addr()) {
+            log::error!("Starting metrics server: {}", err);
+            process::exit(1);
+        }
+    }
+
+    // Start the query log writer, if configured.
+    let query_log = conf
+        .query_log
+        .as_ref()
+        .map(|query_log_conf| match QueryLog::start(query_log_conf.into()) {
+            Ok(v) => Arc::new(v),
+            Err(err) => {
+                log::error!("Starting query log writer: {}", err);
+                process::exit(1);
+            }
+        });
+
+    // Start the dnstap writer, if configured.
+    let dnstap = conf
+        .dnstap
+        .as_ref()
+        .map(|dnstap_conf| match Dnstap::start(dnstap_conf.into()) {
+            Ok(v) => Arc::new(v),
+            Err(err) => {
+                log::error!("Starting dnstap writer: {}", err);
+                process::exit(1);
+            }
+        });
+
+    // Instantiate the resolver handler, which is replaced on reloads.
+    let resolver_metrics = Arc::new(ResolverMetrics::register(&registry, &cache));
+    let servers = Arc::new(ServerStats::default());
+    let resolver_handler = ReloadableHandler::new(build_handler(&conf, &cache, &servers, &resolver_metrics, &dnstap));
+    let reloader = Reloader {
+        conf_path: args[1].clone(),
+        started: conf.clone(),
+        applied: conf.clone(),
+        handler: resolver_handler.clone(),
+        cache: Arc::clone(&cache),
+        servers,
+        metrics: resolver_metrics,
+        dnstap: dnstap.clone(),
+    };
+    let reloader = Arc::new(Mutex::new(reloader));
+    let server_metrics = ServerMetrics::register(&registry);
+    let resolver_handler = QueryLogHandler::new(resolver_handler, query_log);
+    let resolver_handler = DnstapHandler::new(
+        resolver_handler,
+        dnstap,
+        MessageType::ClientQuery,
+        MessageType::ClientResponse,
+    );
+    let resolver_handler_ptr = Arc::new(MeteredHandler::new("resolver", resolver_handler, server_metrics));
+
+    // Start the servers.
+    let servers_params = ServersParams {
+        udp: (&conf.udp_server).into(),
+        tcp: (&conf.tcp_server).into(),
+        #[cfg(feature = "tls")]
+        tls: conf.tls_server.as_ref().map(Into::into),
+        #[cfg(feature = "https")]
+        https: conf.https_server.as_ref().map(Into::into),
+        #[cfg(feature = "quic")]
+        quic: conf.quic_server.as_ref().map(Into::into),
+        drain_timeout: time::Duration::new(conf.drain_timeout, 0),
+    };
+
+    // Bind the sockets (or take the ones passed by systemd) and the control
+    // socket, then drop the privileges before starting the servers, if configured.
+    bind_sockets(&servers_params);
+    if let Some(control_conf) = &conf.control {
+        let control_params = ControlParams {
+            path: control_conf.path.clone().into(),
+            read_timeout: time::Duration::new(CONTROL_READ_TIMEOUT, 0),
+        };
+        let control = ResolverControl {
+            reloader: Arc::clone(&reloader),
+            started: time::Instant::now(),
+        };
+        if let Err(err) = start_control_server(control_params, Arc::new(control)) {
+            log::error!("Starting control server: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(privileges_conf) = &conf.privileges {
+        let privileges_params = PrivilegesParams {
+            user: privileges_conf.user.clone(),
+            group: privileges_conf.group.clone(),
+            chroot: None,
+            seccomp: privileges_conf.seccomp,
+        };
+        if let Err(err) = drop_privileges(&privileges_params) {
+            log::error!("Dropping privileges: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = register_reload_signal(move || {
+        let _ = reloader.lock().unwrap().reload();
+    }) {
+        log::error!("Registering signal handlers: {}", err);
+        process::exit(1);
+    }
+
+    // Use the async servers only if explicitly configured.
+    #[cfg(feature = "async")]
+    if let Some(async_conf) = &conf.async_runtime {
+        let async_params = AsyncParams {
+            worker_threads: async_conf.worker_threads,
+            blocking_threads: async_conf.blocking_threads,
+            max_pending: async_conf.max_pending,
+            drain_timeout: time::Duration::new(conf.drain_timeout, 0),
+        };
+        let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        if let Err(err) = register_stop_signals(&stop) {
+            log::error!("Registering signal handlers: {}", err);
+            process::exit(1);
+        }
+        start_async_servers(
+            resolver_handler_ptr,
+            servers_params.udp,
+            servers_params.tcp,
+            async_params,
+            &stop,
+        );
+        return;
+    }
+
+    let server_handle = start_servers(resolver_handler_ptr, servers_params);
+    register_queue_stats(&registry, server_handle.queue_stats());
+    if let Err(err) = server_handle.stop_on_signals() {
+        log::error!("Registering signal handlers: {}", err);
+        process::exit(1);
+    }
+    server_handle.wait();
+}
+
+/// The state needed to reload the configuration file on SIGHUP. The settings
+/// that need a restart are compared with the `started` configuration, while the
+/// `applied` one is changed at runtime by the control commands.
+struct Reloader {
+    conf_path: String,
+    started: conf::Conf,
+    applied: conf::Conf,
+    handler: ReloadableHandler<ResolverHandler>,
+    cache: Arc<RecordsCache>,
+    servers: Arc<ServerStats>,
+    metrics: Arc<ResolverMetrics>,
+    dnstap: Option<Arc<Dnstap>>,
+}
+
+impl Reloader {
+    // Reload the configuration file, replacing the handler (sharing the same cache and
+    // nameservers round trip times) and the log level. On errors, the previous
+    // configuration keeps being served.
+    fn reload(&mut self) -> Result<(), String> {
+        log::info!("Reloading configuration file '{}'.", self.conf_path);
+        let conf = match conf::Conf::from_file(&self.conf_path) {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Reloading configuration file: {}, keeping the previous one.", err);
+                return Err(err);
+            }
+        };
+
+        logs::set_max_level(conf.log_level);
+        log::info!("Configuration reloaded: {:?}.", conf);
+        let restart_required = self.started.restart_required(&conf);
+        if !restart_required.is_empty() {
+            log::warn!("Changes not applied until restart: {}.", restart_required.join(", "));
+        }
+        self.apply(conf);
+        Ok(())
+    }
+
+    fn apply(&mut self, conf: conf::Conf) {
+        let handler = build_handler(&conf, &self.cache, &self.servers, &self.metrics, &self.dnstap);
+        self.handler.replace(handler);
+        self.applied = conf;
+    }
+}
+
+/// The commands run through the control server (see `ariadne-ctl`). Changes
+/// to the trace settings and to the log level last until the next reload.
+struct ResolverControl {
+    reloader: Arc<Mutex<Reloader>>,
+    started: time::Instant,
+}
+
+impl ControlHandler for ResolverControl {
+    fn handle_command(&self, command: &str, args: &[&str]) -> Result<String, String> {
+        let cache = Arc::clone(&self.reloader.lock().unwrap().cache);
+        match (command, args) {
+            ("status", []) => Ok(self.status()),
+            ("reload", []) => {
+                self.reloader.lock().unwrap().reload()?;
+                Ok("Configuration reloaded.".to_string())
+            }
+            ("flush", [node]) => Ok(format!("Flushed {} entries.", cache.flush(&parse_name(node)?, None))),
+            ("flush", [node, kind]) => {
+                let kind = dns::RecordType::from_str(&kind.to_uppercase())
+                    .map_err(|_| format!("invalid record type '{}'", kind))?;
+                Ok(format!(
+                    "Flushed {} entries.",
+                    cache.flush(&parse_name(node)?, Some(kind))
+                ))
+            }
+            ("flush-tree", [node]) => Ok(format!("Flushed {} entries.", cache.flush_tree(&parse_name(node)?))),
+            ("dump-cache", []) => Ok(cache.dump()),
+            ("trace-on", []) => Ok(self.set_trace(true, false)),
+            ("trace-on", ["verbose"]) => Ok(self.set_trace(true, true)),
+            ("trace-off", []) => Ok(self.set_trace(false, false)),
+            ("log-level", []) => Ok(log::max_level().to_string()),
+            ("log-level", [level]) => {
+                let level = level
+                    .parse::<logs::Level>()
+                    .map_err(|_| format!("invalid log level '{}'", level))?;
+                logs::set_max_level(level);
+                Ok(format!("Log level set to {}.", level))
+            }
+            _ => Err(format!(
+                "invalid command '{}', expected: status, reload, flush NAME [TYPE], flush-tree NAME, \
+                dump-cache, trace-on [verbose], trace-off, log-level [LEVEL]",
+                [&[command], args].concat().join(" ")
+            )),
+        }
+    }
+}
+
+impl ResolverControl {
+    fn status(&self) -> String {
+        let reloader = self.reloader.lock().unwrap();
+        let trace_conf = &reloader.applied.resolver.trace_conf;
+        let trace = match (trace_conf.silent, trace_conf.verbose) {
+            (true, _) => "off",
+            (false, false) => "on",
+            (false, true) => "verbose",
+        };
+        format!(
+            "uptime: {}s\nconfiguration: {}\nlog level: {}\ntrace: {}\nlayers: {}\ncache entries: {}\n",
+            self.started.elapsed().as_secs(),
+            reloader.conf_path,
+            log::max_level(),
+            trace,
+            reloader.handler.current().layers.status(),
+            reloader.cache.len(),
+        )
+    }
+
+    // Print the lookup traces (they are printed on the standard output, regardless
+    // of the log level), replacing the handler with the changed trace settings.
+    fn set_trace(&self, on: bool, verbose: bool) -> String {
+        let mut reloader = self.reloader.lock().unwrap();
+        let mut conf = reloader.applied.clone();
+        conf.resolver.trace_conf.silent = !on;
+        conf.resolver.trace_conf.verbose = verbose;
+        reloader.apply(conf);
+        match on {
+            true => "Lookup traces on.".to_string(),
+            false => "Lookup traces off.".to_string(),
+        }
+    }
+}
+
+// Names are always absolute, the trailing dot is optional.
+fn parse_name(name: &str) -> Result<dns::Name, String> {
+    let absolute = if name.ends_with('.') {
+        name.to_string()
+    } else {
+        format!("{}.", name)
+    };
+    dns::Name::from_string(&absolute).map_err(|err| format!("invalid name '{}': {:?}", name, err))
+}
+
+// Build the resolver handler from the settings that can be reloaded: the resolver
+// parameters, the trace settings, the access control lists and the layers.
+fn build_handler(
+    conf: &conf::Conf,
+    cache: &Arc<RecordsCache>,
+    servers: &Arc<ServerStats>,
+    metrics: &Arc<ResolverMetrics>,
+    dnstap: &Option<Arc<Dnstap>>,
+) -> ResolverHandler {
     let resolver_conf = ResolverParams {
         max_ns_queried: conf.resolver.max_ns_queried,
         max_upd_retries: conf.resolver.max_ns_retries,
@@ -43,6 +338,9 @@ fn main() {
         read_timeout: time::Duration::new(conf.resolver.read_timeout, 0),
         write_timeout: time::Duration::new(conf.resolver.write_timeout, 0),
         no_follow_cname: false,
+        tcp_only: conf.resolver.tcp_only,
+        upstream_port: 53,
+        race_nameservers: conf.resolver.race_nameservers,
     };
     let trace_conf = TraceParams {
         silent: conf.resolver.trace_conf.silent,
@@ -50,26 +348,43 @@ fn main() {
         color: conf.resolver.trace_conf.color,
     };
 
-    let resolver = Resolver::new(&cache, resolver_conf, trace_conf);
-    let resolver_handler = ResolverHandler(resolver);
-    let resolver_handler_ptr = Arc::new(resolver_handler);
-
-    // Start the servers.
-    let udp_params = UdpParams {
-        address: conf.udp_server.address,
-        port: conf.udp_server.port,
-        write_timeout: time::Duration::new(conf.udp_server.write_timeout, 0),
-        threads: conf.udp_server.threads,
-    };
-    let tcp_params = TcpParams {
-        address: conf.tcp_server.address,
-        port: conf.tcp_server.port,
-        write_timeout: time::Duration::new(conf.tcp_server.write_timeout, 0),
-        read_timeout: time::Duration::new(conf.tcp_server.read_timeout, 0),
-        threads: conf.tcp_server.threads,
+    let mut resolver = Resolver::new(cache, resolver_conf, trace_conf)
+        .with_metrics(Arc::clone(metrics))
+        .with_server_stats(Arc::clone(servers));
+    if let Some(dnstap) = dnstap {
+        resolver = resolver.with_dnstap(Arc::clone(dnstap));
+    }
+    let acls = match &conf.acl {
+        None => ResolverAcls::default(),
+        Some(acls_conf) => ResolverAcls {
+            query: acls_conf.query.as_ref().map(Into::into),
+            recursion: acls_conf.recursion.as_ref().map(Into::into),
+            action: (&acls_conf.action).into(),
+        },
     };
+    ResolverHandler {
+        resolver,
+        acls,
+        layers: LayerStack::new(conf.layers.iter().map(Into::into).collect()),
+    }
+}
 
-    start_servers(resolver_handler_ptr, udp_params, tcp_params);
+// Bind the sockets of all the servers, taking the ones passed by systemd
+// socket activation first. Exit the process on errors.
+fn bind_sockets(servers_params: &ServersParams) {
+    #[cfg(unix)]
+    match inherit_listen_fds() {
+        Ok(0) => {}
+        Ok(n) => log::info!("Inherited {} sockets from systemd.", n),
+        Err(err) => {
+            log::error!("Inheriting systemd sockets: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Err(err) = bind_servers(servers_params) {
+        log::error!("Binding server sockets: {}", err);
+        process::exit(1);
+    }
 }
 
 fn print_usage() {
@@ -1,35 +1,112 @@
+use crate::shared::conf;
+pub use crate::shared::conf::{
+    AclActionConf, AclConf, AsyncRuntimeConf, ControlConf, DnstapOutputConf, HttpsServerConf, ListenerConf,
+    MetricsConf, OverloadActionConf, ProxyProtocolConf, QueryLogConf, QueryLogOutputConf, QueueConf, QuicServerConf,
+    RespCodeConf, TcpServerConf, TlsServerConf, UdpServerConf,
+};
 use crate::shared::dns;
+use crate::shared::dnstap::MessageType;
+pub use crate::shared::layers::{BlockActionConf, LayerConf};
+use crate::shared::privileges;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
-use std::{fs, net};
+use std::fs;
 
 /// Configuration values obtained parsing the configuration file.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Conf {
     pub log_level: log::Level,
+    #[serde(default = "conf::default_drain_timeout")]
+    pub drain_timeout: u64,
     pub udp_server: UdpServerConf,
     pub tcp_server: TcpServerConf,
     pub zone: ZoneConf,
+    #[serde(default)]
+    pub tls_server: Option<TlsServerConf>,
+    #[serde(default)]
+    pub https_server: Option<HttpsServerConf>,
+    #[serde(default)]
+    pub quic_server: Option<QuicServerConf>,
+    #[serde(default)]
+    pub async_runtime: Option<AsyncRuntimeConf>,
+    #[serde(default)]
+    pub acl: Option<AclsConf>,
+    #[serde(default)]
+    pub layers: Vec<LayerConf>,
+    #[serde(default)]
+    pub rrl: Option<RrlConf>,
+    #[serde(default)]
+    pub metrics: Option<MetricsConf>,
+    #[serde(default)]
+    pub dnstap: Option<DnstapConf>,
+    #[serde(default)]
+    pub privileges: Option<PrivilegesConf>,
+    #[serde(default)]
+    pub query_log: Option<QueryLogConf>,
+    #[serde(default)]
+    pub control: Option<ControlConf>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct UdpServerConf {
-    pub address: String,
-    pub port: u16,
-    pub write_timeout: u64,
-    pub threads: usize,
+/// Response Rate Limiting of the responses sent over UDP. If missing,
+/// the responses are not limited. See [RrlParams](crate::nameserver::RrlParams).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RrlConf {
+    pub responses_per_second: u32,
+    pub window: u64,
+    pub slip: u32,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+    #[serde(default)]
+    pub dry_run: bool,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct TcpServerConf {
-    pub address: String,
-    pub port: u16,
-    pub read_timeout: u64,
-    pub write_timeout: u64,
-    pub threads: usize,
+/// Access control of the clients, by source address. Missing lists allow
+/// all the clients, while denied requests are refused or dropped.
+/// Zone transfer and update requests are checked against the `transfer` list.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclsConf {
+    #[serde(default)]
+    pub action: AclActionConf,
+    #[serde(default)]
+    pub query: Option<AclConf>,
+    #[serde(default)]
+    pub transfer: Option<AclConf>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The dnstap configuration, logging the messages of the [DnstapMessageConf] types.
+pub type DnstapConf = conf::DnstapConf<DnstapMessageConf>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DnstapMessageConf {
+    AuthQuery,
+    AuthResponse,
+}
+
+impl From<&DnstapMessageConf> for MessageType {
+    fn from(message_conf: &DnstapMessageConf) -> Self {
+        match message_conf {
+            DnstapMessageConf::AuthQuery => MessageType::AuthQuery,
+            DnstapMessageConf::AuthResponse => MessageType::AuthResponse,
+        }
+    }
+}
+
+/// The privileges kept after binding the sockets: the user and group to switch to
+/// (the user primary group if `group` is missing) and whether to restrict the
+/// system calls with a seccomp filter (Linux only). With `chroot`, the root directory
+/// is changed to the directory of the zone file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrivilegesConf {
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub chroot: bool,
+    #[serde(default)]
+    pub seccomp: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ZoneConf {
     pub starting_ttl: u32,
     pub zone: String,
@@ -37,7 +114,7 @@ pub struct ZoneConf {
     pub sub_zones: Vec<SubZoneConf>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SubZoneConf {
     pub starting_ttl: u32,
     pub min_ttl: u32,
@@ -63,28 +140,121 @@ impl Conf {
         }
     }
 
+    /// Returns the settings changed in the `new` configuration that are not applied
+    /// on reloads, so need a restart: the servers, the async runtime, the metrics
+    /// server, the dnstap and query log writers, the privileges and the control
+    /// server. The zones, the access control lists, the layers, the rate limiting
+    /// and the log level are reloaded.
+    pub fn restart_required(&self, new: &Conf) -> Vec<&'static str> {
+        let changes = [
+            ("drain_timeout", self.drain_timeout != new.drain_timeout),
+            ("udp_server", self.udp_server != new.udp_server),
+            ("tcp_server", self.tcp_server != new.tcp_server),
+            ("tls_server", self.tls_server != new.tls_server),
+            ("https_server", self.https_server != new.https_server),
+            ("quic_server", self.quic_server != new.quic_server),
+            ("async_runtime", self.async_runtime != new.async_runtime),
+            ("metrics", self.metrics != new.metrics),
+            ("dnstap", self.dnstap != new.dnstap),
+            ("privileges", self.privileges != new.privileges),
+            ("query_log", self.query_log != new.query_log),
+            ("control", self.control != new.control),
+        ];
+        changes
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
     /// Validate a configuration struct against some common errors.
     fn validate(&self) -> Result<(), String> {
-        // Udp server confs.
-        if let Err(err) = net::IpAddr::from_str(self.udp_server.address.as_ref()) {
-            return Err(format!("invalid udp address: {}", err));
+        // Servers confs.
+        self.udp_server.validate()?;
+        self.tcp_server.validate()?;
+        let queues = self.udp_server.queue.is_some() || self.tcp_server.queue.is_some();
+        if queues && self.async_runtime.is_some() {
+            return Err("udp/tcp queue limits not supported by the async runtime".to_string());
+        }
+        if self.tcp_server.proxy_protocol.is_some() && self.async_runtime.is_some() {
+            return Err("tcp proxy protocol not supported by the async runtime".to_string());
+        }
+        if let Some(tls_conf) = &self.tls_server {
+            tls_conf.validate()?;
+            if self.async_runtime.is_some() {
+                return Err("tls server not supported by the async runtime".to_string());
+            }
+        }
+        if let Some(https_conf) = &self.https_server {
+            https_conf.validate()?;
+            if self.async_runtime.is_some() {
+                return Err("https server not supported by the async runtime".to_string());
+            }
+        }
+        if let Some(quic_conf) = &self.quic_server {
+            quic_conf.validate()?;
+            if self.async_runtime.is_some() {
+                return Err("quic server not supported by the async runtime".to_string());
+            }
         }
-        if self.udp_server.write_timeout == 0 {
-            return Err("invalid udp write timeout: 0 seconds".to_string());
+        if let Some(async_conf) = &self.async_runtime {
+            async_conf.validate()?;
         }
-        if self.udp_server.threads == 0 {
-            return Err("invalid udp threads: 0".to_string());
+
+        // Rate limiting confs.
+        if let Some(rrl_conf) = &self.rrl {
+            if rrl_conf.responses_per_second == 0 {
+                return Err("invalid rrl responses per second: 0".to_string());
+            }
+            if rrl_conf.window == 0 || rrl_conf.window > 3600 {
+                return Err("invalid rrl window: must be between 1 and 3600 seconds".to_string());
+            }
+            if rrl_conf.ipv4_prefix_len > 32 || rrl_conf.ipv6_prefix_len > 128 {
+                return Err("invalid rrl prefix lengths: max 32 for IPv4 and 128 for IPv6".to_string());
+            }
         }
 
-        // Tcp server confs.
-        if let Err(err) = net::IpAddr::from_str(self.tcp_server.address.as_ref()) {
-            return Err(format!("invalid tcp address: {}", err));
+        // Operational confs.
+        if let Some(metrics_conf) = &self.metrics {
+            metrics_conf.validate()?;
         }
-        if self.tcp_server.write_timeout == 0 {
-            return Err("invalid tcp write timeout: cannot be 0 seconds".to_string());
+        if let Some(dnstap_conf) = &self.dnstap {
+            dnstap_conf.validate()?;
         }
-        if self.tcp_server.threads == 0 {
-            return Err("invalid tcp threads: 0".to_string());
+        if let Some(query_log_conf) = &self.query_log {
+            query_log_conf.validate()?;
+        }
+        if let Some(control_conf) = &self.control {
+            control_conf.validate()?;
+        }
+
+        // Privileges confs.
+        if let Some(privileges_conf) = &self.privileges {
+            if !cfg!(unix) {
+                return Err("dropping privileges not supported on this platform".to_string());
+            }
+            if privileges_conf.seccomp && !privileges::seccomp_supported() {
+                return Err("seccomp filter not supported on this platform".to_string());
+            }
+            let tls_servers = self.tls_server.is_some() || self.https_server.is_some() || self.quic_server.is_some();
+            if privileges_conf.chroot && tls_servers {
+                return Err("chroot not supported with the tls, https and quic servers".to_string());
+            }
+        }
+
+        // Access control confs.
+        if let Some(acls_conf) = &self.acl {
+            if let Some(acl_conf) = &acls_conf.query {
+                acl_conf.validate("query")?;
+            }
+            if let Some(acl_conf) = &acls_conf.transfer {
+                acl_conf.validate("transfer")?;
+            }
+        }
+
+        // Layers confs.
+        for layer_conf in &self.layers {
+            layer_conf.validate()?;
         }
 
         // Zone confs.
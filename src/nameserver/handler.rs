@@ -1,19 +1,93 @@
+use crate::nameserver::metrics::*;
+use crate::nameserver::rrl::*;
 use crate::nameserver::zones::*;
 use crate::shared::dns;
 use crate::shared::dns::Question;
+use crate::shared::layers::LayerStack;
 use crate::shared::net::*;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::sync::Arc;
 
 /// The nameserver handler able to serve dns requests via its [`DnsHandler`] implementation.
-pub struct NameserverHandler(pub ManagedZone);
+/// If the `rrl` limiter is present, the responses sent over UDP are rate limited. Requests
+/// are checked against the access control lists before going through the `layers` and
+/// being served, so the responses of the layers are rate limited too.
+pub struct NameserverHandler {
+    pub zones: ManagedZone,
+    pub rrl: Option<Arc<ResponseLimiter>>,
+    pub acls: NameserverAcls,
+    pub layers: LayerStack,
+    pub metrics: NameserverMetrics,
+}
+
+/// The access control lists of the nameserver, missing lists allow all the
+/// clients. The `transfer` list is used for zone transfers and updates.
+#[derive(Clone, Debug, Default)]
+pub struct NameserverAcls {
+    pub query: Option<Acl>,
+    pub transfer: Option<Acl>,
+    pub action: AclAction,
+}
 
 impl DnsHandler for NameserverHandler {
     fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
-        handle_dns_request(req, resp, &self.0);
+        self.serve(req, resp);
+    }
+}
+
+impl NameserverHandler {
+    // Only UDP responses are rate limited, since UDP source
+    // addresses can be spoofed and used for reflection attacks.
+    fn serve<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        let source = req.source();
+        match &self.rrl {
+            Some(limiter) if source.transport == Transport::Udp => {
+                let resp = RateLimitedWriter {
+                    inner: resp,
+                    limiter,
+                    client: source.addr.ip(),
+                };
+                handle_dns_request(req, resp, self);
+            }
+            _ => handle_dns_request(req, resp, self),
+        }
+    }
+}
+
+/// Serving requests from the managed zones is a CPU-only operation, so
+/// the request is handled directly in the async task of the server.
+#[cfg(feature = "async")]
+impl AsyncDnsHandler for NameserverHandler {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let handler = Arc::clone(self);
+        async move { handler.serve(req, resp) }
     }
 }
 
-fn handle_dns_request<R: DnsRead, W: DnsWrite>(req: R, resp: W, zones: &ManagedZone) {
-    let dns_request = match req.read() {
+fn handle_dns_request<R: DnsRead, W: DnsWrite>(req: R, resp: W, handler: &NameserverHandler) {
+    let source = req.source();
+    let client = source.addr.ip();
+    let request = req.read();
+    let (acl, acl_name) = match is_transfer_request(&request) {
+        true => (&handler.acls.transfer, "transfer"),
+        false => (&handler.acls.query, "query"),
+    };
+    if !acl.as_ref().is_none_or(|acl| acl.allows(client)) {
+        log::warn!("Request from {} denied by the {} acl.", client, acl_name);
+        handle_denied(resp, request, handler.acls.action);
+        return;
+    }
+    let (request, resp) = match handler.layers.run(source, request, resp) {
+        Some(v) => v,
+        None => return,
+    };
+
+    let dns_request = match request {
         DnsReadResult::FullMessage(req) => req,
         DnsReadResult::HeaderOnly(hdr, err) => {
             handle_decode_err(resp, hdr, err);
@@ -46,12 +120,27 @@ fn handle_dns_request<R: DnsRead, W: DnsWrite>(req: R, resp: W, zones: &ManagedZ
     );
 
     log::debug!("[{}] Complete request: {:?}", dns_request.id(), dns_request);
-    handle_query(dns_request, resp, zones);
+    handle_query(dns_request, resp, handler);
+}
+
+// Zone transfers (AXFR and IXFR) and updates are not supported yet, so transfer
+// requests are detected from the error returned decoding their question. Update
+// requests cannot be decoded at all and are always dropped.
+fn is_transfer_request(request: &DnsReadResult) -> bool {
+    match request {
+        DnsReadResult::HeaderOnly(_, err) => matches!(
+            err.inner_err(),
+            dns::ParsingErr::UnsupportedType(dns::RecordType::AXFR) | dns::ParsingErr::UnknownType(251)
+        ),
+        _ => false,
+    }
 }
 
 /// Resolve the dns query. First of all the records are checked to see if they are
 /// contained in the managed zone. If yes search in subzones, then in the auth data.    
-fn handle_query<W: DnsWrite>(request: dns::Message, resp: W, zones: &ManagedZone) {
+fn handle_query<W: DnsWrite>(request: dns::Message, resp: W, handler: &NameserverHandler) {
+    let zones = &handler.zones;
+    let zone_queries = &handler.metrics.zone_queries;
     let dns::Question { node, .. } = &request.questions[0];
     if !node.is_in_zone(&zones.auth_zone.zone) {
         log::warn!("[{}] Requested node not in zone: '{}'.", request.id(), node);
@@ -62,11 +151,13 @@ fn handle_query<W: DnsWrite>(request: dns::Message, resp: W, zones: &ManagedZone
     // Check if records are in subzone, if yes delegate to it.
     for subzone in &zones.sub_zones {
         if node.is_in_zone(&subzone.zone) {
+            zone_queries.with(&[subzone.zone.as_ref()]).inc();
             handle_subzone(resp, request, subzone, zones);
             return;
         }
     }
 
+    zone_queries.with(&[zones.auth_zone.zone.as_ref()]).inc();
     handle_auth_zone(resp, request, &zones.auth_zone)
 }
 
@@ -98,12 +189,14 @@ fn handle_auth_zone<W: DnsWrite>(resp: W, request: dns::Message, auth_zone: &Zon
     resp_header.answers_count = searched_records.len() as u16;
     resp_header.authorities_count = 0;
     resp_header.additionals_count = 0;
+    let edns = request.response_edns();
     let response = dns::Message {
         header: resp_header,
         questions: request.questions,
         answers: searched_records,
         authorities: vec![],
         additionals: vec![],
+        edns,
     };
 
     reply(resp, response);
@@ -113,7 +206,7 @@ fn handle_auth_zone<W: DnsWrite>(resp: W, request: dns::Message, auth_zone: &Zon
 /// records necessary to reply to the client (NS records and eventually glue records).
 fn handle_subzone<W: DnsWrite>(resp: W, request: dns::Message, sub_zone: &Zone, zones: &ManagedZone) {
     let ns_records = sub_zone.get(&sub_zone.zone, dns::RecordType::NS).unwrap();
-    assert!(ns_records.len() > 0);
+    assert!(!ns_records.is_empty());
 
     let mut authorities: Vec<dns::Record> = vec![];
     let mut additionals: Vec<dns::Record> = vec![];
@@ -130,12 +223,14 @@ fn handle_subzone<W: DnsWrite>(resp: W, request: dns::Message, sub_zone: &Zone,
     resp_header.answers_count = 0;
     resp_header.authorities_count = authorities.len() as u16;
     resp_header.additionals_count = additionals.len() as u16;
+    let edns = request.response_edns();
     let response = dns::Message {
         header: resp_header,
         questions: request.questions,
         answers: vec![],
         authorities,
         additionals,
+        edns,
     };
 
     reply(resp, response);
@@ -150,7 +245,19 @@ fn search_a_additionals_for_subzone_ns<'a>(
         .filter(|sub_zone| ns_name.is_in_zone(&sub_zone.zone))
         .filter_map(|sub_zone| sub_zone.get(ns_name, dns::RecordType::A))
         .flatten()
-        .map(|r| r.clone())
+        .cloned()
+}
+
+/// Handle requests of clients denied by the access control lists, refusing
+/// them or dropping them as configured. Undecodable requests are dropped.
+fn handle_denied<W: DnsWrite>(resp: W, request: DnsReadResult, action: AclAction) {
+    match (action, request) {
+        (AclAction::Refuse, DnsReadResult::FullMessage(req)) => handle_err(resp, &req, dns::RespCode::Refused),
+        (AclAction::Refuse, DnsReadResult::HeaderOnly(header, _)) => {
+            handle_header_err(resp, header, dns::RespCode::Refused)
+        }
+        _ => {}
+    }
 }
 
 /// Handle decoding errors, either malformed messages or unsupported features.
@@ -164,13 +271,24 @@ fn handle_decode_err<W: DnsWrite>(resp: W, req_header: dns::Header, msg_err: dns
         dns::ParsingErr::UnsupportedType(_) => dns::RespCode::NotImp,
         _ => dns::RespCode::FormErr,
     };
-    let resp_header = resp_header_from_req_header(&req_header, resp_code);
+    handle_header_err(resp, req_header, resp_code);
+}
+
+/// Reply to a client with a specific error code, when only the
+/// request header is available. No questions are included.
+fn handle_header_err<W: DnsWrite>(resp: W, req_header: dns::Header, resp_code: dns::RespCode) {
+    let mut resp_header = resp_header_from_req_header(&req_header, resp_code);
+    resp_header.questions_count = 0;
+    resp_header.answers_count = 0;
+    resp_header.authorities_count = 0;
+    resp_header.additionals_count = 0;
     let dns_response = dns::Message {
         header: resp_header,
         questions: vec![],
         answers: vec![],
         authorities: vec![],
         additionals: vec![],
+        edns: None,
     };
 
     reply(resp, dns_response);
@@ -192,6 +310,7 @@ fn handle_nx_err<W: DnsWrite>(resp: W, dns_req: &dns::Message, soa_record: dns::
         answers: vec![],
         authorities: vec![soa_record],
         additionals: vec![],
+        edns: dns_req.response_edns(),
     };
 
     reply(resp, response);
@@ -211,6 +330,7 @@ fn handle_err<W: DnsWrite>(resp: W, dns_req: &dns::Message, resp_code: dns::Resp
         answers: vec![],
         authorities: vec![],
         additionals: vec![],
+        edns: dns_req.response_edns(),
     };
 
     reply(resp, dns_resp);
@@ -243,7 +363,7 @@ fn resp_header_from_req_header(req_header: &dns::Header, resp_code: dns::RespCod
 // Validate a client dns request against some minimal requirements.
 fn validate_dns_request(dns_req: &dns::Message) -> Result<&Question, String> {
     if !dns_req.header.is_request() {
-        return Err(format!("resp flag set in query"));
+        return Err("resp flag set in query".to_string());
     }
     if dns_req.header.answers_count != 0 {
         return Err(format!("invalid # of answers: {:?}", dns_req.header.answers_count));
@@ -0,0 +1,35 @@
+use crate::shared::metrics::*;
+use std::sync::Arc;
+
+/// The metrics of the requests served by the [NameserverHandler](crate::nameserver::NameserverHandler):
+/// the queries answered from each of the managed zones (the authoritative one and the sub zones).
+/// The [Default] metrics are not registered anywhere.
+#[derive(Clone)]
+pub struct NameserverMetrics {
+    pub zone_queries: Arc<Family<Counter>>,
+}
+
+impl Default for NameserverMetrics {
+    fn default() -> Self {
+        NameserverMetrics {
+            zone_queries: Arc::new(zone_queries()),
+        }
+    }
+}
+
+impl NameserverMetrics {
+    /// Creates the metrics, registering them in the passed registry.
+    pub fn register(registry: &Registry) -> Self {
+        NameserverMetrics {
+            zone_queries: registry.register(
+                "ariadne_nameserver_zone_queries_total",
+                "Queries answered by the nameserver, by zone.",
+                zone_queries(),
+            ),
+        }
+    }
+}
+
+fn zone_queries() -> Family<Counter> {
+    Family::new(&["zone"], Counter::default)
+}
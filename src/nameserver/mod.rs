@@ -1,6 +1,10 @@
 pub mod conf;
 mod handler;
+mod metrics;
+mod rrl;
 mod zones;
 
-pub use handler::NameserverHandler;
+pub use handler::{NameserverAcls, NameserverHandler};
+pub use metrics::NameserverMetrics;
+pub use rrl::{ResponseKind, ResponseLimiter, RrlAction, RrlParams};
 pub use zones::*;
@@ -0,0 +1,325 @@
+use crate::shared::dns;
+use crate::shared::net::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::{io, net, time};
+
+/// Parameters of the [ResponseLimiter]. Clients are grouped by network prefix, with
+/// the passed prefix lengths. Each group can receive `responses_per_second` responses
+/// of each kind, unused credit is not accumulated, while excess responses are
+/// accounted for up to `window` seconds. One every `slip` limited responses is sent
+/// truncated instead of being dropped (0 disables slipping). In `dry_run` mode the
+/// limited responses are only logged.
+#[derive(Clone, Debug)]
+pub struct RrlParams {
+    pub responses_per_second: u32,
+    pub window: time::Duration,
+    pub slip: u32,
+    pub ipv4_prefix_len: u8,
+    pub ipv6_prefix_len: u8,
+    pub dry_run: bool,
+}
+
+/// The kinds of responses limited separately, so that a flood of
+/// e.g. NXDOMAIN responses doesn't limit the answers sent to a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ResponseKind {
+    Answer,
+    NxDomain,
+    Error,
+    Referral,
+}
+
+impl ResponseKind {
+    /// Classifies a response about to be sent to a client.
+    pub fn of(response: &dns::Message) -> Self {
+        match response.header.resp_code {
+            dns::RespCode::NoError => {
+                let header = &response.header;
+                if !header.auth_answer && response.answers.is_empty() && !response.authorities.is_empty() {
+                    ResponseKind::Referral
+                } else {
+                    ResponseKind::Answer
+                }
+            }
+            dns::RespCode::NxDomain => ResponseKind::NxDomain,
+            _ => ResponseKind::Error,
+        }
+    }
+}
+
+/// The decision of the [ResponseLimiter] about a response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RrlAction {
+    Send,
+    Slip,
+    Drop,
+}
+
+/// BIND-style Response Rate Limiting (RRL), used to mitigate reflection attacks.
+/// Responses are accounted in token buckets keyed by client prefix and response
+/// kind. Clients sending too many requests get their responses dropped, except
+/// for the slipped ones, truncated so that real clients can retry over TCP.
+pub struct ResponseLimiter {
+    params: RrlParams,
+    buckets: Mutex<Buckets>,
+}
+
+struct Buckets {
+    buckets: HashMap<(net::IpAddr, ResponseKind), Bucket>,
+    last_sweep: time::Instant,
+}
+
+struct Bucket {
+    balance: f64,
+    updated: time::Instant,
+    limited: u32,
+}
+
+impl ResponseLimiter {
+    pub fn new(params: RrlParams) -> Self {
+        let buckets = Buckets {
+            buckets: HashMap::new(),
+            last_sweep: time::Instant::now(),
+        };
+        Self {
+            params,
+            buckets: Mutex::new(buckets),
+        }
+    }
+
+    /// Accounts a response of the passed kind sent to the client and
+    /// returns what should be done with it. Dry-run mode is not applied.
+    pub fn check(&self, client: net::IpAddr, kind: ResponseKind) -> RrlAction {
+        self.check_at(client, kind, time::Instant::now())
+    }
+
+    fn check_at(&self, client: net::IpAddr, kind: ResponseKind, now: time::Instant) -> RrlAction {
+        let rate = self.params.responses_per_second as f64;
+        let max_debt = rate * self.params.window.as_secs_f64();
+        let key = (client_prefix(client, &self.params), kind);
+
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.sweep(now, self.params.window);
+        let bucket = buckets.buckets.entry(key).or_insert(Bucket {
+            balance: rate,
+            updated: now,
+            limited: 0,
+        });
+
+        // Credit the bucket for the elapsed time, then charge the response.
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.balance = (bucket.balance + elapsed * rate).min(rate) - 1.0;
+        bucket.balance = bucket.balance.max(-max_debt);
+        bucket.updated = now;
+        if bucket.balance >= 0.0 {
+            bucket.limited = 0;
+            return RrlAction::Send;
+        }
+
+        bucket.limited = bucket.limited.wrapping_add(1);
+        if self.params.slip > 0 && bucket.limited.is_multiple_of(self.params.slip) {
+            RrlAction::Slip
+        } else {
+            RrlAction::Drop
+        }
+    }
+}
+
+impl Buckets {
+    // Remove the buckets not updated for longer than the window, once per window.
+    // These are refilled anyway, so removing them doesn't change any decision.
+    fn sweep(&mut self, now: time::Instant, window: time::Duration) {
+        let max_idle = window + time::Duration::from_secs(1);
+        if now.saturating_duration_since(self.last_sweep) < window {
+            return;
+        }
+        self.buckets
+            .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < max_idle);
+        self.last_sweep = now;
+    }
+}
+
+// Mask the client address with the configured prefix length. IPv4 addresses
+// mapped to IPv6 (e.g. from dual-stack sockets) are masked as IPv4.
+fn client_prefix(client: net::IpAddr, params: &RrlParams) -> net::IpAddr {
+    let client = match client {
+        net::IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, net::IpAddr::V4),
+        v4 => v4,
+    };
+    match client {
+        net::IpAddr::V4(addr) => {
+            let mask = u32::MAX.checked_shl(32 - params.ipv4_prefix_len as u32).unwrap_or(0);
+            net::IpAddr::V4(net::Ipv4Addr::from(u32::from(addr) & mask))
+        }
+        net::IpAddr::V6(addr) => {
+            let mask = u128::MAX.checked_shl(128 - params.ipv6_prefix_len as u32).unwrap_or(0);
+            net::IpAddr::V6(net::Ipv6Addr::from(u128::from(addr) & mask))
+        }
+    }
+}
+
+/// A [DnsWrite] implementor applying the [ResponseLimiter] to the responses
+/// written with the wrapped [DnsWrite], sent to the passed client.
+pub(crate) struct RateLimitedWriter<'a, W: DnsWrite> {
+    pub inner: W,
+    pub limiter: &'a ResponseLimiter,
+    pub client: net::IpAddr,
+}
+
+impl<W: DnsWrite> DnsWrite for RateLimitedWriter<'_, W> {
+    fn reply(self, response: dns::Message) -> io::Result<()> {
+        let kind = ResponseKind::of(&response);
+        let action = self.limiter.check(self.client, kind);
+        if action == RrlAction::Send {
+            return self.inner.reply(response);
+        }
+
+        if self.limiter.params.dry_run {
+            log::info!(
+                "[{}] Rate limit (dry-run): would {:?} {:?} response to {}.",
+                response.id(),
+                action,
+                kind,
+                self.client
+            );
+            return self.inner.reply(response);
+        }
+        log::debug!(
+            "[{}] Rate limit: {:?} {:?} response to {}.",
+            response.id(),
+            action,
+            kind,
+            self.client
+        );
+        match action {
+            RrlAction::Slip => self.inner.reply(truncate(response)),
+            _ => Ok(()),
+        }
+    }
+
+    fn annotate(&mut self, resolution: Resolution) {
+        self.inner.annotate(resolution);
+    }
+}
+
+// Strip all the records from the response, setting the TC flag
+// so that clients retry over TCP, which is not rate limited.
+fn truncate(mut response: dns::Message) -> dns::Message {
+    response.header.truncated = true;
+    response.header.answers_count = 0;
+    response.header.authorities_count = 0;
+    response.header.additionals_count = 0;
+    response.answers.clear();
+    response.authorities.clear();
+    response.additionals.clear();
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(slip: u32) -> ResponseLimiter {
+        ResponseLimiter::new(RrlParams {
+            responses_per_second: 5,
+            window: time::Duration::from_secs(2),
+            slip,
+            ipv4_prefix_len: 24,
+            ipv6_prefix_len: 56,
+            dry_run: false,
+        })
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let limiter = limiter(0);
+        let now = time::Instant::now();
+        let client: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let neighbour: net::IpAddr = "10.0.0.200".parse().unwrap();
+        let other: net::IpAddr = "10.0.1.1".parse().unwrap();
+
+        for _ in 0..5 {
+            assert_eq!(limiter.check_at(client, ResponseKind::Answer, now), RrlAction::Send);
+        }
+        assert_eq!(limiter.check_at(client, ResponseKind::Answer, now), RrlAction::Drop);
+        // Clients in the same prefix share the bucket, other kinds and prefixes don't.
+        assert_eq!(limiter.check_at(neighbour, ResponseKind::Answer, now), RrlAction::Drop);
+        assert_eq!(limiter.check_at(client, ResponseKind::NxDomain, now), RrlAction::Send);
+        assert_eq!(limiter.check_at(other, ResponseKind::Answer, now), RrlAction::Send);
+
+        // After a second some credit is available again.
+        let later = now + time::Duration::from_millis(1500);
+        assert_eq!(limiter.check_at(client, ResponseKind::Answer, later), RrlAction::Send);
+    }
+
+    #[test]
+    fn test_rate_limit_mapped() {
+        let limiter = limiter(0);
+        let now = time::Instant::now();
+        let client: net::IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        let other: net::IpAddr = "::ffff:10.0.1.1".parse().unwrap();
+
+        // Mapped IPv4 clients are limited by their IPv4 prefix, not the IPv6 one.
+        for _ in 0..5 {
+            assert_eq!(limiter.check_at(client, ResponseKind::Answer, now), RrlAction::Send);
+        }
+        assert_eq!(limiter.check_at(client, ResponseKind::Answer, now), RrlAction::Drop);
+        assert_eq!(limiter.check_at(other, ResponseKind::Answer, now), RrlAction::Send);
+        let unmapped: net::IpAddr = "10.0.0.2".parse().unwrap();
+        assert_eq!(limiter.check_at(unmapped, ResponseKind::Answer, now), RrlAction::Drop);
+    }
+
+    #[test]
+    fn test_rate_limit_window() {
+        let limiter = limiter(0);
+        let now = time::Instant::now();
+        let client: net::IpAddr = "2001:db8::1".parse().unwrap();
+
+        // Excess responses are accounted for up to the window (10 responses).
+        for _ in 0..100 {
+            limiter.check_at(client, ResponseKind::Error, now);
+        }
+        let later = now + time::Duration::from_millis(2100);
+        assert_eq!(limiter.check_at(client, ResponseKind::Error, later), RrlAction::Drop);
+        let later = now + time::Duration::from_millis(3100);
+        assert_eq!(limiter.check_at(client, ResponseKind::Error, later), RrlAction::Send);
+    }
+
+    #[test]
+    fn test_rate_limit_slip() {
+        let limiter = limiter(2);
+        let now = time::Instant::now();
+        let client: net::IpAddr = "10.0.0.1".parse().unwrap();
+
+        for _ in 0..5 {
+            limiter.check_at(client, ResponseKind::Answer, now);
+        }
+        let actions: Vec<_> = (0..4)
+            .map(|_| limiter.check_at(client, ResponseKind::Answer, now))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![RrlAction::Drop, RrlAction::Slip, RrlAction::Drop, RrlAction::Slip]
+        );
+    }
+
+    #[test]
+    fn test_client_prefix() {
+        let params = limiter(0).params;
+        let prefix = |addr: &str| client_prefix(addr.parse().unwrap(), &params).to_string();
+        assert_eq!(prefix("192.168.7.42"), "192.168.7.0");
+        assert_eq!(prefix("2001:db8:aa:bbcc::1"), "2001:db8:aa:bb00::");
+        assert_eq!(prefix("::ffff:192.168.7.42"), "192.168.7.0");
+        let params = RrlParams {
+            ipv4_prefix_len: 0,
+            ipv6_prefix_len: 128,
+            ..params
+        };
+        assert_eq!(
+            client_prefix("192.168.7.42".parse().unwrap(), &params).to_string(),
+            "0.0.0.0"
+        );
+        assert_eq!(client_prefix("::1".parse().unwrap(), &params).to_string(), "::1");
+    }
+}
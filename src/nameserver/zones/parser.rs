@@ -204,6 +204,19 @@ impl Zone {
             .collect()
     }
 
+    /// Returns the number of records in the zone.
+    pub fn len(&self) -> usize {
+        self.records
+            .values()
+            .flat_map(|inner| inner.values())
+            .map(|records| records.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
     /// Merge another [`Zone`] into the current one.
     pub fn extend(&mut self, other: Self) {
         for (_, inner) in other.records {
@@ -96,6 +96,35 @@ impl<K: Eq + Hash, V> Cache<K, V> {
         }
     }
 
+    /// Returns the number of entries, including the expired ones not yet removed.
+    pub fn len(&self) -> usize {
+        self.data.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes the entries for which the predicate returns false, along with the
+    /// expired ones. Returns the number of removed entries that were not expired.
+    pub fn retain<F>(&self, mut keep: F) -> usize
+    where
+        F: FnMut(&K, &V) -> bool,
+    {
+        let mut cache_guard = self.data.lock().unwrap();
+        let cache_inner = cache_guard.deref_mut();
+        let mut removed = 0;
+        cache_inner.retain(|key, entry| match is_expired(&entry.0) {
+            true => false,
+            false if keep(key, &entry.1) => true,
+            false => {
+                removed += 1;
+                false
+            }
+        });
+        removed
+    }
+
     /// Manually cleans the cache from expired entries. Usually this method is
     /// not invoked since the [start_clean_routine] is more ergonomic to use.
     pub fn clean(&self) {
@@ -126,6 +155,18 @@ impl<K: Eq + Hash, V: Clone> Cache<K, V> {
     }
 }
 
+impl<K: Eq + Hash + Clone, V: Clone> Cache<K, V> {
+    /// Clone and return all the entries not expired, with their expiration. The
+    /// cache is locked while cloning, so the method should be used sparingly.
+    pub fn entries(&self) -> Vec<(K, time::Instant, V)> {
+        let cache_guard = self.data.lock().unwrap();
+        let entries = cache_guard.iter().filter(|(_, entry)| !is_expired(&entry.0));
+        entries
+            .map(|(key, entry)| (key.clone(), entry.0, entry.1.clone()))
+            .collect()
+    }
+}
+
 impl<K: Eq + Hash + Send + 'static, V: Send + 'static> Cache<K, V> {
     /// Spawns a thread which cleans the [Cache] entries at regular
     /// periods of time (dictated by the confs).
@@ -1,3 +1,4 @@
+use crate::shared::client::ClientErr;
 use crate::shared::dns;
 use std::io;
 
@@ -28,3 +29,13 @@ impl From<io::Error> for LookupErr {
         LookupErr::IO(io_err)
     }
 }
+
+impl From<ClientErr> for LookupErr {
+    fn from(client_err: ClientErr) -> Self {
+        match client_err {
+            ClientErr::IO(io_err) => LookupErr::IO(io_err),
+            ClientErr::UnexpectedId(id) => LookupErr::MalformedResp(format!("unexpected header id: {}", id)),
+            err => LookupErr::MalformedResp(format!("{:?}", err)),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use crate::resolver::back_end::recursive::RecordsCache;
+use crate::shared::metrics::*;
+use std::sync::Arc;
+
+/// The metrics of the lookups performed by a [Resolver](crate::resolver::Resolver):
+/// cache hits and misses and queries sent to external nameservers, with their
+/// timeouts, the ones answered over TCP and the ones racing a slow nameserver.
+/// The [Default] metrics are not registered anywhere.
+#[derive(Debug, Default)]
+pub struct ResolverMetrics {
+    pub cache_hits: Arc<Counter>,
+    pub cache_misses: Arc<Counter>,
+    pub upstream_queries: Arc<Counter>,
+    pub upstream_timeouts: Arc<Counter>,
+    pub upstream_tcp_queries: Arc<Counter>,
+    pub upstream_races: Arc<Counter>,
+}
+
+impl ResolverMetrics {
+    /// Creates the metrics, registering them in the passed registry
+    /// along with the size of the cache used by the resolver.
+    pub fn register(registry: &Registry, cache: &Arc<RecordsCache>) -> Self {
+        let cache = Arc::clone(cache);
+        registry.register(
+            "ariadne_resolver_cache_entries",
+            "Entries in the resolver cache, including expired ones not yet cleaned.",
+            Computed::gauge(&[], move || vec![(vec![], cache.len() as f64)]),
+        );
+        ResolverMetrics {
+            cache_hits: registry.register(
+                "ariadne_resolver_cache_hits_total",
+                "Resolver cache lookups finding records.",
+                Counter::default(),
+            ),
+            cache_misses: registry.register(
+                "ariadne_resolver_cache_misses_total",
+                "Resolver cache lookups not finding records.",
+                Counter::default(),
+            ),
+            upstream_queries: registry.register(
+                "ariadne_resolver_upstream_queries_total",
+                "Queries sent to external nameservers, including retries.",
+                Counter::default(),
+            ),
+            upstream_timeouts: registry.register(
+                "ariadne_resolver_upstream_timeouts_total",
+                "Queries sent to external nameservers and timed out.",
+                Counter::default(),
+            ),
+            upstream_tcp_queries: registry.register(
+                "ariadne_resolver_upstream_tcp_queries_total",
+                "Queries sent to external nameservers answered over TCP, truncated over UDP or with tcp_only.",
+                Counter::default(),
+            ),
+            upstream_races: registry.register(
+                "ariadne_resolver_upstream_races_total",
+                "Queries sent to a second nameserver, when the first one was slower than expected.",
+                Counter::default(),
+            ),
+        }
+    }
+
+    pub(crate) fn cache_lookup(&self, hit: bool) {
+        match hit {
+            true => self.cache_hits.inc(),
+            false => self.cache_misses.inc(),
+        }
+    }
+}
@@ -1,11 +1,15 @@
 mod cache;
 mod errors;
+mod metrics;
 mod recursive;
 mod requests;
+mod servers;
 mod trace;
 mod utils;
 
 pub use cache::*;
 pub use errors::*;
+pub use metrics::*;
 pub use recursive::*;
+pub use servers::*;
 pub use trace::*;
@@ -1,14 +1,22 @@
 use crate::resolver::back_end::cache::*;
 use crate::resolver::back_end::errors::*;
+use crate::resolver::back_end::metrics::*;
 use crate::resolver::back_end::requests::*;
+use crate::resolver::back_end::servers::*;
 use crate::resolver::back_end::trace::*;
 use crate::resolver::back_end::utils::*;
 use crate::shared::dns;
+use crate::shared::dnstap::Dnstap;
 use std::sync::Arc;
 use std::{mem, time};
 
 /// The resolver parameters passed to the [`Resolver`] constructor.
 /// A good default configuration is provided via the [`Default`] trait.
+/// External nameservers are queried on the `upstream_port` over UDP,
+/// retrying over TCP if the response is truncated, or only over TCP
+/// with `tcp_only` (e.g. for networks filtering UDP). The fastest
+/// nameservers are queried first and, with `race_nameservers`, a
+/// second one is queried when the first one is slower than usual.
 #[derive(Debug, Clone)]
 pub struct ResolverParams {
     pub max_ns_queried: usize,
@@ -17,6 +25,9 @@ pub struct ResolverParams {
     pub read_timeout: time::Duration,
     pub write_timeout: time::Duration,
     pub no_follow_cname: bool,
+    pub tcp_only: bool,
+    pub upstream_port: u16,
+    pub race_nameservers: bool,
 }
 
 impl Default for ResolverParams {
@@ -28,22 +39,62 @@ impl Default for ResolverParams {
             read_timeout: time::Duration::new(2, 0),
             write_timeout: time::Duration::new(2, 0),
             no_follow_cname: false,
+            tcp_only: false,
+            upstream_port: 53,
+            race_nameservers: false,
         }
     }
 }
 
 /// The Resolver is a builder for [`Lookup`]s objects. It contains several parameters
 /// to tune lookup and tracing and can access the cache. [`Lookup`]s objects generated
-/// inherit part of the configuration and the ability to access the same cache. To
-/// perform a new lookup use the [new_lookup], which generates a new  [`Lookup`] object.
+/// inherit part of the configuration and the ability to access the same cache and
+/// [`ServerStats`]. To perform a new lookup use the [new_lookup], which generates
+/// a new  [`Lookup`] object.
 pub struct Resolver {
     cache: Arc<RecordsCache>,
     rsv_conf: ResolverParams,
     trc_conf: TraceParams,
+    metrics: Arc<ResolverMetrics>,
+    dnstap: Option<Arc<Dnstap>>,
+    servers: Arc<ServerStats>,
 }
 
 pub type RecordsCache = Cache<(dns::Name, dns::RecordType), Vec<dns::Record>>;
 
+impl RecordsCache {
+    /// Removes the cached records of the node, of all the types if `kind` is
+    /// missing. Returns the number of removed entries (one for every type).
+    pub fn flush(&self, node: &dns::Name, kind: Option<dns::RecordType>) -> usize {
+        self.retain(|(cached_node, cached_kind), _| {
+            cached_node != node || kind.is_some_and(|kind| kind != *cached_kind)
+        })
+    }
+
+    /// Removes the cached records of the node and of all the nodes below it.
+    /// Returns the number of removed entries (one for every node and type).
+    pub fn flush_tree(&self, node: &dns::Name) -> usize {
+        self.retain(|(cached_node, _), _| !cached_node.is_in_zone(node))
+    }
+
+    /// Returns the cached records in the zone file format, one per line, sorted by
+    /// node and type. The TTLs are the seconds left before the records expire.
+    pub fn dump(&self) -> String {
+        let mut entries = self.entries();
+        entries.sort_by(|(a, ..), (b, ..)| (a.0.as_ref(), a.1.to_str()).cmp(&(b.0.as_ref(), b.1.to_str())));
+        let now = time::Instant::now();
+        let mut dump = String::new();
+        for (_, expiration, records) in entries {
+            let ttl = expiration.saturating_duration_since(now).as_secs() as u32;
+            for mut record in records {
+                record.set_ttl(ttl);
+                dump.push_str(&format!("{}\n", record));
+            }
+        }
+        dump
+    }
+}
+
 impl Resolver {
     /// Build and return a new [`Resolver`] with the provided config values.
     pub fn new(cache: &Arc<RecordsCache>, rsv_conf: ResolverParams, trc_conf: TraceParams) -> Self {
@@ -51,9 +102,39 @@ impl Resolver {
             cache: Arc::clone(cache),
             rsv_conf: rsv_conf,
             trc_conf: trc_conf,
+            metrics: Arc::default(),
+            dnstap: None,
+            servers: Arc::default(),
         }
     }
 
+    /// Sets the metrics updated by the lookups of the resolver.
+    pub fn with_metrics(mut self, metrics: Arc<ResolverMetrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Sets the round trip times of the nameservers, to share them with
+    /// other resolvers (e.g. the ones replacing this one on reloads).
+    pub fn with_server_stats(mut self, servers: Arc<ServerStats>) -> Self {
+        self.servers = servers;
+        self
+    }
+
+    /// Sets the dnstap logger of the queries sent to external nameservers.
+    pub fn with_dnstap(mut self, dnstap: Arc<Dnstap>) -> Self {
+        self.dnstap = Some(dnstap);
+        self
+    }
+
+    /// Returns the records of the given name and type found in cache, without
+    /// querying external nameservers. An empty vector is returned on cache misses.
+    pub fn cached_records(&self, node: &dns::Name, kind: dns::RecordType) -> Vec<dns::Record> {
+        let records = search_records_in_cache(&self.cache, node, kind);
+        self.metrics.cache_lookup(!records.is_empty());
+        records
+    }
+
     /// Generates a new [Lookup] object with a copy of the resolver and tracing
     /// params. The generated object can be consumed to perform the lookup.
     pub fn new_lookup(&self, node: &dns::Name, kind: dns::RecordType) -> Lookup {
@@ -66,7 +147,11 @@ impl Resolver {
             cache: &self.cache,
             next_nss: vec![],
             conf: self.rsv_conf.clone(),
+            metrics: &self.metrics,
+            dnstap: self.dnstap.as_ref(),
+            servers: &self.servers,
             trace,
+            upstream_queries: 0,
         }
     }
 }
@@ -83,6 +168,19 @@ pub struct Lookup<'a> {
     cache: &'a RecordsCache,
     trace: Trace,
     conf: ResolverParams,
+    metrics: &'a Arc<ResolverMetrics>,
+    dnstap: Option<&'a Arc<Dnstap>>,
+    servers: &'a Arc<ServerStats>,
+    upstream_queries: u32,
+}
+
+/// Statistics of a performed lookup: the queries sent to external nameservers
+/// (including the ones of the sub-lookups) and if the response was served from
+/// cache only, without querying external nameservers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LookupStats {
+    pub upstream_queries: u32,
+    pub cache_hit: bool,
 }
 
 /// The response returned when a lookup is performed. The last field
@@ -100,9 +198,19 @@ impl<'a> Lookup<'a> {
     /// consulted to speed up the lookup, and records found are cached for next lookups.
     /// Cnames found are included in the response. If tracing is disabled, the returned
     /// [`Trace`] is empty.
-    pub fn perform(mut self) -> (Result<LookupResponse, LookupErrCtx>, Trace) {
+    pub fn perform(self) -> (Result<LookupResponse, LookupErrCtx>, Trace) {
+        let (res, trace, _) = self.perform_with_stats();
+        (res, trace)
+    }
+
+    /// Like [Lookup::perform], also returning the [LookupStats] of the lookup.
+    pub fn perform_with_stats(mut self) -> (Result<LookupResponse, LookupErrCtx>, Trace, LookupStats) {
         let res = self.perform_inner();
-        (res, self.trace)
+        let stats = LookupStats {
+            upstream_queries: self.upstream_queries,
+            cache_hit: res.is_ok() && self.upstream_queries == 0,
+        };
+        (res, self.trace, stats)
     }
 
     /// Performs the lookup process (private interface). First search in cache for direct
@@ -185,11 +293,11 @@ impl<'a> Lookup<'a> {
         'next_zone: loop {
             assert!(self.next_nss.len() > 0);
             let mut next_nss = mem::take(&mut self.next_nss);
-            sort_nameservers(&mut next_nss);
-            let next_nss = next_nss.into_iter().take(self.conf.max_ns_queried);
+            self.servers.sort_nameservers(&mut next_nss);
+            next_nss.truncate(self.conf.max_ns_queried);
             let mut error: Option<LookupErrCtx> = None;
 
-            for mut next_ns in next_nss {
+            for (i, mut next_ns) in next_nss.iter().cloned().enumerate() {
                 // If no address is present start a separate lookup.
                 if next_ns.addrs().is_empty() {
                     match self.resolve_ns_subquery(next_ns.node(), next_ns.zone()) {
@@ -202,14 +310,24 @@ impl<'a> Lookup<'a> {
                     }
                 }
 
-                // Query an external nameserver.
+                // Query an external nameserver, racing the next one with an address.
+                let race = match self.conf.race_nameservers {
+                    true => next_nss[i + 1..].iter().find(|ns| !ns.addrs().is_empty()),
+                    false => None,
+                };
                 let ns_response = self.perform_request_with_trace(NsRequest {
                     searched_node: self.searched_node.clone(),
                     searched_type: self.searched_kind,
+                    race,
                     retries: self.conf.max_upd_retries,
                     r_timeout: self.conf.read_timeout,
                     w_timeout: self.conf.write_timeout,
+                    tcp_only: self.conf.tcp_only,
+                    port: self.conf.upstream_port,
                     nameserver: &next_ns,
+                    metrics: self.metrics,
+                    dnstap: self.dnstap,
+                    servers: self.servers,
                 });
                 let ns_response = match ns_response {
                     Ok(resp) => resp,
@@ -258,10 +376,15 @@ impl<'a> Lookup<'a> {
             trace: self.trace.clone_empty(),
             next_nss: vec![],
             conf,
+            metrics: self.metrics,
+            dnstap: self.dnstap,
+            servers: self.servers,
+            upstream_queries: 0,
         };
 
-        let (response, sub_trace) = resolver.perform();
+        let (response, sub_trace, sub_stats) = resolver.perform_with_stats();
         self.trace.add_sub_trace(sub_trace);
+        self.upstream_queries += sub_stats.upstream_queries;
         match response {
             Ok(mut v) => Ok(extract_records(&mut v.0, dns::RecordType::A, node)),
             Err(err) => return Err(err),
@@ -270,6 +393,7 @@ impl<'a> Lookup<'a> {
 
     // Perform the request to a nameserver and trace the result.
     fn perform_request_with_trace(&mut self, ns_req: NsRequest) -> Result<NsResponse, LookupErr> {
+        self.upstream_queries += 1;
         self.trace
             .t_ns_req(&ns_req.searched_node.as_ref(), ns_req.searched_type, &ns_req.nameserver);
         match perform_request(ns_req, &mut self.trace) {
@@ -287,6 +411,7 @@ impl<'a> Lookup<'a> {
     // Search records in cache and trace the outcome.
     fn search_records_in_cache_with_trace(&mut self, searched_kind: dns::RecordType) -> Vec<dns::Record> {
         let results = search_records_in_cache(&self.cache, &self.searched_node, searched_kind);
+        self.metrics.cache_lookup(!results.is_empty());
         if results.is_empty() {
             self.trace.t_cache_miss(&self.searched_node.as_ref(), searched_kind);
         } else {
@@ -309,6 +434,7 @@ impl<'a> Lookup<'a> {
             }
         });
 
+        self.metrics.cache_lookup(ns_records.is_some());
         let ns_records: Vec<dns::Record> = match ns_records {
             Some(v) => v,
             None => {
@@ -1,22 +1,73 @@
 use crate::resolver::back_end::errors::*;
+use crate::resolver::back_end::metrics::*;
+use crate::resolver::back_end::servers::*;
 use crate::resolver::back_end::trace::*;
 use crate::resolver::back_end::utils::*;
+use crate::shared::client::*;
 use crate::shared::dns;
 use crate::shared::dns::Name;
+use crate::shared::dnstap::*;
+use crate::shared::net::Transport;
+use crate::shared::thread_pool::*;
 use std::fmt::Debug;
 use std::net::IpAddr;
+use std::sync::{mpsc, Arc, OnceLock};
 use std::{io, mem, net, time};
 
+// Threads of the pool sending the queries raced with other nameservers.
+const RACING_THREADS: usize = 32;
+
 /// The request to be made to an external nameserver. Contains data and several
-/// parameters to control. The nameserver address is contained in a [NextNsData].
+/// parameters to control. The nameserver address is contained in a [NextNsData],
+/// the query is sent to its `port` over UDP (TCP if the response is truncated)
+/// or over TCP only. If a `race` nameserver is set, it is queried too when the
+/// first one doesn't answer within its expected round trip time, and the first
+/// response received is used.
 #[derive(Debug)]
 pub struct NsRequest<'a> {
     pub searched_node: Name,
     pub searched_type: dns::RecordType,
     pub nameserver: &'a NextSubzoneNs,
+    pub race: Option<&'a NextSubzoneNs>,
     pub retries: usize,
     pub r_timeout: time::Duration,
     pub w_timeout: time::Duration,
+    pub tcp_only: bool,
+    pub port: u16,
+    pub metrics: &'a Arc<ResolverMetrics>,
+    pub dnstap: Option<&'a Arc<Dnstap>>,
+    pub servers: &'a Arc<ServerStats>,
+}
+
+// A query sent to a nameserver address. Fields are owned,
+// so that racing queries can be sent by other threads.
+#[derive(Clone)]
+struct UpstreamQuery {
+    server: net::SocketAddr,
+    node: Name,
+    kind: dns::RecordType,
+    r_timeout: time::Duration,
+    w_timeout: time::Duration,
+    tcp_only: bool,
+    metrics: Arc<ResolverMetrics>,
+    dnstap: Option<Arc<Dnstap>>,
+    servers: Arc<ServerStats>,
+}
+
+impl UpstreamQuery {
+    fn new(ns_request: &NsRequest, nameserver: &NextSubzoneNs) -> Self {
+        UpstreamQuery {
+            server: net::SocketAddr::new(*nameserver.addrs().first().unwrap(), ns_request.port),
+            node: ns_request.searched_node.clone(),
+            kind: ns_request.searched_type,
+            r_timeout: ns_request.r_timeout,
+            w_timeout: ns_request.w_timeout,
+            tcp_only: ns_request.tcp_only,
+            metrics: Arc::clone(ns_request.metrics),
+            dnstap: ns_request.dnstap.cloned(),
+            servers: Arc::clone(ns_request.servers),
+        }
+    }
 }
 
 /// Parsed response from a nameserver. Different variants represent different
@@ -162,9 +213,9 @@ fn extract_next_nss_for_subzone(resp: &mut dns::Message, node: &Name, zone: &str
             }
         })
         .filter(|next_subzone_ns| {
-            // Bad servers or truncation of messages could lead to ns in subzones
-            // without glue records. Anyway, we cannot use those records (without
-            // re-issuing the query with TCP). TODO: check if it's ok.
+            // Truncated responses are retried over TCP, so glue records are
+            // missing only if the server didn't send them. Those ns records
+            // cannot be used, resolving their addresses would loop.
             !is_nameserver_in_subzone_without_glue(
                 next_subzone_ns.node(),
                 next_subzone_ns.zone(),
@@ -211,13 +262,21 @@ fn is_nameserver_in_subzone_without_glue(ns_node: &Name, ns_zone: &Name, no_addr
 /// Encode a [`NsRequest`] appropriately as a [`dns::Message`] and send it to the
 /// destination nameserver. Retries are performed until a configurable maximum.
 fn send_query_with_retries(next_ns_request: &NsRequest) -> Result<dns::Message, LookupErr> {
+    let query = UpstreamQuery::new(next_ns_request, next_ns_request.nameserver);
+    let race = next_ns_request
+        .race
+        .map(|race| UpstreamQuery::new(next_ns_request, race));
     let mut err = None;
     let mut i = 0;
     loop {
         if i >= next_ns_request.retries {
             return Err(err.unwrap());
         }
-        match send_query(next_ns_request) {
+        let result = match &race {
+            Some(race) => send_racing_queries(&query, race),
+            None => send_query(&query),
+        };
+        match result {
             Ok(resp) => return Ok(resp),
             Err(er) => err = Some(er),
         };
@@ -225,55 +284,111 @@ fn send_query_with_retries(next_ns_request: &NsRequest) -> Result<dns::Message,
     }
 }
 
-fn send_query(ns_request: &NsRequest) -> Result<dns::Message, LookupErr> {
-    let request = build_dns_request(ns_request);
-    let request_bytes = request.encode_to_bytes().unwrap();
-
-    let (response_bytes, n_recv) = send_udp_packet(ns_request, &request_bytes)?;
-    let response = dns::Message::decode_from_bytes(&response_bytes[..n_recv]);
-    let response = match response {
-        Ok(v) => v,
-        Err(err) => {
-            let err_msg = format!("decoding error: {:?}", err);
-            return Err(LookupErr::MalformedResp(err_msg));
-        }
+// Send the query from a thread of the racing pool, then the racing one from the
+// calling thread if no response is received within the expected round trip time
+// of the first server. The first query keeps running to measure its server. The
+// query is not raced, but sent from the calling thread, if the pool is busy.
+fn send_racing_queries(query: &UpstreamQuery, race: &UpstreamQuery) -> Result<dns::Message, LookupErr> {
+    let queue_slot = match racing_pool().try_reserve() {
+        Some(v) => v,
+        None => return send_query(query),
     };
+    let (sender, receiver) = mpsc::channel();
+    let first_query = query.clone();
+    queue_slot.execute(move || {
+        let _ = sender.send(send_query(&first_query));
+    });
 
-    if response.header.id != request.header.id {
-        return Err(LookupErr::MalformedResp(format!(
-            "expected header id: {}, got: {}",
-            request.id(),
-            response.id()
-        )));
+    let err = match receiver.recv_timeout(query.servers.race_delay(query.server.ip())) {
+        Ok(Ok(resp)) => return Ok(resp),
+        Ok(Err(err)) => Some(err),
+        Err(_) => None,
+    };
+    query.metrics.upstream_races.inc();
+    match (send_query(race), err) {
+        (Ok(resp), _) => Ok(resp),
+        (Err(_), Some(err)) => Err(err),
+        (Err(race_err), None) => receiver.recv().unwrap_or(Err(race_err)),
     }
+}
 
-    return Ok(response);
+// The pool sending the queries raced by the lookups of all the resolvers, so
+// that racing doesn't spawn threads. Its queue is as long as the threads.
+fn racing_pool() -> &'static ThreadPool {
+    static RACING_POOL: OnceLock<ThreadPool> = OnceLock::new();
+    RACING_POOL.get_or_init(|| {
+        let limits = QueueLimits {
+            max_depth: RACING_THREADS,
+            max_age: None,
+        };
+        ThreadPool::with_queue(RACING_THREADS, "racing", limits, Arc::default())
+    })
 }
 
-fn build_dns_request(ns_request: &NsRequest) -> dns::Message {
-    let mut header = dns::Header::default();
-    header.questions_count = 1;
-    let question = dns::Question {
-        node: ns_request.searched_node.clone(),
-        record_type: ns_request.searched_type,
-        class: dns::Class::IN,
+// Send the query to the server, recording its round trip time or failure.
+fn send_query(query: &UpstreamQuery) -> Result<dns::Message, LookupErr> {
+    let server = query.server;
+    let client = Client::new(ClientParams {
+        server,
+        tcp_only: query.tcp_only,
+        tcp_fallback: true,
+        attempts: 1,
+        read_timeout: query.r_timeout,
+        write_timeout: query.w_timeout,
+        recursion_desired: false,
+        edns: None,
+    });
+    let request = client.build_query(&query.node, query.kind);
+
+    query.metrics.upstream_queries.inc();
+    let tap_message = query.dnstap.as_ref().map(|dnstap| {
+        let message = TapMessage {
+            kind: MessageType::ResolverQuery,
+            transport: match query.tcp_only {
+                true => Transport::Tcp,
+                false => Transport::Udp,
+            },
+            query_address: None,
+            response_address: Some(server),
+            query_time: Some(time::SystemTime::now()),
+            query_message: None,
+            response_time: None,
+            response_message: None,
+        };
+        dnstap.log(&TapMessage {
+            query_message: Some(&request.encode_to_bytes().unwrap()),
+            ..message.clone()
+        });
+        (dnstap, message)
+    });
+    let sent = time::Instant::now();
+    let reply = match client.send(&request) {
+        Ok(v) => v,
+        Err(ClientErr::IO(err)) => {
+            if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) {
+                query.metrics.upstream_timeouts.inc();
+            }
+            query.servers.record_failure(server.ip());
+            return Err(err.into());
+        }
+        Err(err) => {
+            query.servers.record_failure(server.ip());
+            return Err(err.into());
+        }
     };
-    dns::Message {
-        header: header,
-        questions: vec![question],
-        answers: vec![],
-        authorities: vec![],
-        additionals: vec![],
+    query.servers.record_rtt(server.ip(), sent.elapsed());
+    if reply.transport == Transport::Tcp {
+        query.metrics.upstream_tcp_queries.inc();
+    }
+    if let Some((dnstap, message)) = tap_message {
+        dnstap.log(&TapMessage {
+            kind: MessageType::ResolverResponse,
+            transport: reply.transport,
+            response_time: Some(time::SystemTime::now()),
+            response_message: Some(&reply.bytes),
+            ..message
+        });
     }
-}
 
-fn send_udp_packet(request: &NsRequest, bytes: &[u8]) -> io::Result<([u8; dns::MAX_UDP_LEN_BYTES], usize)> {
-    let addr = *request.nameserver.addrs().first().unwrap();
-    let udp_socket = net::UdpSocket::bind("0.0.0.0:0")?;
-    udp_socket.set_write_timeout(Some(request.w_timeout))?;
-    udp_socket.set_read_timeout(Some(request.r_timeout))?;
-    udp_socket.send_to(&bytes, (addr, 53))?;
-    let mut buffer = [0_u8; dns::MAX_UDP_LEN_BYTES];
-    let (n_recv, _) = udp_socket.recv_from(&mut buffer)?;
-    Ok((buffer, n_recv))
+    Ok(reply.message)
 }
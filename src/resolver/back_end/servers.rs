@@ -0,0 +1,252 @@
+use crate::resolver::back_end::requests::NextSubzoneNs;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time;
+
+// Share of the selections querying a random nameserver instead of the
+// fastest one, to keep measuring the round trip time of the other ones.
+const EXPLORATION_RATE: f64 = 0.05;
+// Nameservers not queried for this long are forgotten, so that
+// the ones that were failing are eventually queried again.
+const STATS_EXPIRATION: time::Duration = time::Duration::from_secs(600);
+// Maximum number of nameservers tracked, expired ones are removed first.
+const MAX_SERVERS: usize = 10_000;
+// The expected round trip time of the nameservers that never answered.
+const UNKNOWN_RTT: time::Duration = time::Duration::from_millis(500);
+// The expected round trip time doubles with every consecutive failure, up to 2^5 times.
+const MAX_BACKOFF: u32 = 5;
+// Lower bound of the time waited for an answer before racing another nameserver.
+const MIN_RACE_DELAY: time::Duration = time::Duration::from_millis(10);
+
+/// The round trip times and the failures of the external nameservers, shared by
+/// the lookups of a [Resolver](crate::resolver::Resolver) to query the fastest
+/// nameservers first. Round trip times are smoothed as done by TCP (RFC 6298).
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    servers: Mutex<HashMap<IpAddr, ServerState>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct ServerState {
+    srtt: Option<time::Duration>,
+    rttvar: time::Duration,
+    failures: u32,
+    updated: time::Instant,
+}
+
+impl ServerState {
+    fn new() -> Self {
+        ServerState {
+            srtt: None,
+            rttvar: time::Duration::ZERO,
+            failures: 0,
+            updated: time::Instant::now(),
+        }
+    }
+
+    fn is_expired(&self, now: time::Instant) -> bool {
+        now.saturating_duration_since(self.updated) > STATS_EXPIRATION
+    }
+
+    // The round trip time expected, penalized by the consecutive failures.
+    fn score(&self) -> time::Duration {
+        self.srtt.unwrap_or(UNKNOWN_RTT) * (1 << self.failures.min(MAX_BACKOFF))
+    }
+}
+
+impl ServerStats {
+    /// Records the round trip time of a query answered by the nameserver.
+    pub fn record_rtt(&self, addr: IpAddr, rtt: time::Duration) {
+        self.update(addr, |state| {
+            state.failures = 0;
+            match state.srtt {
+                None => {
+                    state.srtt = Some(rtt);
+                    state.rttvar = rtt / 2;
+                }
+                Some(srtt) => {
+                    state.rttvar = (state.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                    state.srtt = Some((srtt * 7 + rtt) / 8);
+                }
+            }
+        });
+    }
+
+    /// Records a query not answered by the nameserver (e.g. timed out).
+    pub fn record_failure(&self, addr: IpAddr) {
+        self.update(addr, |state| state.failures += 1);
+    }
+
+    /// Returns the smoothed round trip time of the nameserver, if known.
+    pub fn srtt(&self, addr: IpAddr) -> Option<time::Duration> {
+        let now = time::Instant::now();
+        let servers = self.servers.lock().unwrap();
+        servers.get(&addr).filter(|s| !s.is_expired(now)).and_then(|s| s.srtt)
+    }
+
+    /// Returns the time to wait for an answer of the nameserver before querying
+    /// another one: its smoothed round trip time plus four times its variation.
+    /// Nameservers never answering are expected to answer in 500 milliseconds.
+    pub fn race_delay(&self, addr: IpAddr) -> time::Duration {
+        let now = time::Instant::now();
+        let servers = self.servers.lock().unwrap();
+        let state = servers.get(&addr).filter(|s| !s.is_expired(now));
+        let delay = state.and_then(|s| s.srtt.map(|srtt| srtt + s.rttvar * 4));
+        delay.unwrap_or(UNKNOWN_RTT).max(MIN_RACE_DELAY)
+    }
+
+    /// Sorts the nameservers by expected round trip time, placing the ones without
+    /// addresses last. Nameservers never queried come first, so that all of them
+    /// are measured, in random order. Once in a while a random nameserver is placed
+    /// first, to keep measuring the other ones. The addresses of every nameserver
+    /// are sorted the same way.
+    pub fn sort_nameservers(&self, nameservers: &mut [NextSubzoneNs]) {
+        let now = time::Instant::now();
+        let servers = self.servers.lock().unwrap();
+        let score = |addr: &IpAddr| {
+            let state = servers.get(addr).filter(|s| !s.is_expired(now));
+            state.map_or(time::Duration::ZERO, ServerState::score)
+        };
+
+        let mut rng = rand::thread_rng();
+        nameservers.shuffle(&mut rng);
+        for nameserver in nameservers.iter_mut() {
+            nameserver
+                .a_records
+                .sort_by_cached_key(|record| score(&IpAddr::from(*record.a_data())));
+        }
+        nameservers.sort_by_cached_key(|ns| ns.addrs().first().map_or(time::Duration::MAX, score));
+
+        let with_addrs = nameservers.iter().filter(|ns| !ns.addrs().is_empty()).count();
+        if with_addrs > 1 && rng.gen_bool(EXPLORATION_RATE) {
+            nameservers.swap(0, rng.gen_range(1..with_addrs));
+        }
+    }
+
+    // Update the state of the nameserver, removing the expired
+    // ones (or all of them) when too many nameservers are tracked.
+    fn update<F: FnOnce(&mut ServerState)>(&self, addr: IpAddr, update: F) {
+        let now = time::Instant::now();
+        let mut servers = self.servers.lock().unwrap();
+        if servers.len() >= MAX_SERVERS && !servers.contains_key(&addr) {
+            servers.retain(|_, state| !state.is_expired(now));
+            if servers.len() >= MAX_SERVERS {
+                servers.clear();
+            }
+        }
+
+        let state = servers.entry(addr).or_insert_with(ServerState::new);
+        if state.is_expired(now) {
+            *state = ServerState::new();
+        }
+        update(state);
+        state.updated = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dns;
+
+    fn nameserver(name: &str, addrs: &[[u8; 4]]) -> NextSubzoneNs {
+        let name = dns::Name::from_string(name).unwrap();
+        NextSubzoneNs {
+            ns_record: dns::Record::NS {
+                node: dns::Name::from_string("example.com.").unwrap(),
+                class: dns::Class::IN,
+                ttl: 3600,
+                data_len: 0,
+                name: name.clone(),
+            },
+            a_records: addrs
+                .iter()
+                .map(|address| dns::Record::A {
+                    node: name.clone(),
+                    class: dns::Class::IN,
+                    ttl: 3600,
+                    data_len: 4,
+                    address: *address,
+                })
+                .collect(),
+        }
+    }
+
+    fn ms(millis: u64) -> time::Duration {
+        time::Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_smoothed_rtt() {
+        let stats = ServerStats::default();
+        let addr = IpAddr::from([10, 0, 0, 1]);
+        assert_eq!(stats.srtt(addr), None);
+        assert_eq!(stats.race_delay(addr), UNKNOWN_RTT);
+
+        stats.record_rtt(addr, ms(80));
+        assert_eq!(stats.srtt(addr), Some(ms(80)));
+        assert_eq!(stats.race_delay(addr), ms(80 + 4 * 40));
+        stats.record_rtt(addr, ms(160));
+        assert_eq!(stats.srtt(addr), Some(ms(90)));
+        assert_eq!(stats.race_delay(addr), ms(90 + 4 * 50));
+
+        // Failures don't change the round trip time, only the order of the nameservers.
+        stats.record_failure(addr);
+        assert_eq!(stats.srtt(addr), Some(ms(90)));
+    }
+
+    #[test]
+    fn test_sort_nameservers() {
+        let stats = ServerStats::default();
+        stats.record_rtt(IpAddr::from([10, 0, 0, 1]), ms(200));
+        stats.record_rtt(IpAddr::from([10, 0, 0, 2]), ms(20));
+        stats.record_rtt(IpAddr::from([10, 0, 0, 3]), ms(50));
+        stats.record_rtt(IpAddr::from([10, 0, 0, 4]), ms(10));
+        stats.record_failure(IpAddr::from([10, 0, 0, 4]));
+        stats.record_failure(IpAddr::from([10, 0, 0, 4]));
+
+        // Sort many times, the random nameserver first shouldn't be the usual case.
+        let mut fastest_first = 0;
+        for _ in 0..100 {
+            let mut nameservers = vec![
+                nameserver("a.example.com.", &[[10, 0, 0, 1], [10, 0, 0, 3]]),
+                nameserver("b.example.com.", &[]),
+                nameserver("c.example.com.", &[[10, 0, 0, 4]]),
+                nameserver("d.example.com.", &[[10, 0, 0, 2]]),
+            ];
+            stats.sort_nameservers(&mut nameservers);
+            assert_eq!(nameservers[3].node().as_ref(), "b.example.com.");
+            if nameservers[0].node().as_ref() == "d.example.com." {
+                fastest_first += 1;
+                let order: Vec<&str> = nameservers.iter().map(|ns| ns.node().as_ref()).collect();
+                assert_eq!(
+                    order,
+                    ["d.example.com.", "c.example.com.", "a.example.com.", "b.example.com."]
+                );
+            }
+            let a = nameservers
+                .iter()
+                .find(|ns| ns.node().as_ref() == "a.example.com.")
+                .unwrap();
+            assert_eq!(a.addrs(), [IpAddr::from([10, 0, 0, 3]), IpAddr::from([10, 0, 0, 1])]);
+        }
+        assert!(fastest_first > 70);
+
+        // Nameservers never queried are measured first.
+        let mut nameservers = vec![
+            nameserver("d.example.com.", &[[10, 0, 0, 2]]),
+            nameserver("e.example.com.", &[[10, 0, 0, 5]]),
+        ];
+        let mut unknown_first = 0;
+        for _ in 0..100 {
+            stats.sort_nameservers(&mut nameservers);
+            if nameservers[0].node().as_ref() == "e.example.com." {
+                unknown_first += 1;
+            }
+        }
+        assert!(unknown_first > 70);
+    }
+}
@@ -2,7 +2,6 @@ use crate::resolver::back_end::errors::*;
 use crate::resolver::back_end::requests::*;
 use crate::shared::dns;
 use crate::shared::dns::*;
-use std::cmp;
 
 // The list of root nameservers of the domain name system.
 const ROOT_SERVERS: [(&str, &str, [u8; 4]); 13] = [
@@ -85,15 +84,6 @@ pub fn extract_record(records: &mut Vec<Record>, kind: RecordType, node: &Name)
     Some(records.swap_remove(record_index))
 }
 
-/// Sort nameservers placing the ones with at least one address in the first positions.
-pub fn sort_nameservers(nameservers: &mut Vec<NextSubzoneNs>) {
-    nameservers.sort_by(|a, b| match (!a.addrs().is_empty(), !b.addrs().is_empty()) {
-        (true, false) => cmp::Ordering::Less,
-        (false, true) => cmp::Ordering::Greater,
-        _ => cmp::Ordering::Equal,
-    });
-}
-
 /// Detect if the passed cname record points to one of the cnames already
 /// encountered. If yes, a loop is detected and a proper error is returned.
 pub fn detect_cname_loops(cname_record: &Record, previous_cnames: &Vec<Record>) -> Result<(), LookupErrCtx> {
@@ -1,52 +1,118 @@
+use crate::shared::conf;
+pub use crate::shared::conf::{
+    AclActionConf, AclConf, AsyncRuntimeConf, ControlConf, DnstapOutputConf, HttpsServerConf, ListenerConf,
+    MetricsConf, OverloadActionConf, ProxyProtocolConf, QueryLogConf, QueryLogOutputConf, QueueConf, QuicServerConf,
+    RespCodeConf, TcpServerConf, TlsServerConf, UdpServerConf,
+};
+use crate::shared::dnstap::MessageType;
+pub use crate::shared::layers::{BlockActionConf, LayerConf};
+use crate::shared::privileges;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::net;
-use std::str::FromStr;
 
 /// Configuration values obtained parsing the configuration file.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Conf {
     pub log_level: log::Level,
+    #[serde(default = "conf::default_drain_timeout")]
+    pub drain_timeout: u64,
     pub udp_server: UdpServerConf,
     pub tcp_server: TcpServerConf,
     pub resolver: ResolverConf,
+    #[serde(default)]
+    pub tls_server: Option<TlsServerConf>,
+    #[serde(default)]
+    pub https_server: Option<HttpsServerConf>,
+    #[serde(default)]
+    pub quic_server: Option<QuicServerConf>,
+    #[serde(default)]
+    pub async_runtime: Option<AsyncRuntimeConf>,
+    #[serde(default)]
+    pub acl: Option<AclsConf>,
+    #[serde(default)]
+    pub layers: Vec<LayerConf>,
+    #[serde(default)]
+    pub metrics: Option<MetricsConf>,
+    #[serde(default)]
+    pub dnstap: Option<DnstapConf>,
+    #[serde(default)]
+    pub privileges: Option<PrivilegesConf>,
+    #[serde(default)]
+    pub query_log: Option<QueryLogConf>,
+    #[serde(default)]
+    pub control: Option<ControlConf>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct UdpServerConf {
-    pub address: String,
-    pub port: u16,
-    pub write_timeout: u64,
-    pub threads: usize,
+/// Access control of the clients, by source address. Missing lists allow
+/// all the clients, while denied requests are refused or dropped.
+/// Clients allowed to query but not to recurse are answered from cache.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclsConf {
+    #[serde(default)]
+    pub action: AclActionConf,
+    #[serde(default)]
+    pub query: Option<AclConf>,
+    #[serde(default)]
+    pub recursion: Option<AclConf>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct TcpServerConf {
-    pub address: String,
-    pub port: u16,
-    pub read_timeout: u64,
-    pub write_timeout: u64,
-    pub threads: usize,
+/// The dnstap configuration, logging the messages of the [DnstapMessageConf] types.
+pub type DnstapConf = conf::DnstapConf<DnstapMessageConf>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DnstapMessageConf {
+    ClientQuery,
+    ClientResponse,
+    ResolverQuery,
+    ResolverResponse,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl From<&DnstapMessageConf> for MessageType {
+    fn from(message_conf: &DnstapMessageConf) -> Self {
+        match message_conf {
+            DnstapMessageConf::ClientQuery => MessageType::ClientQuery,
+            DnstapMessageConf::ClientResponse => MessageType::ClientResponse,
+            DnstapMessageConf::ResolverQuery => MessageType::ResolverQuery,
+            DnstapMessageConf::ResolverResponse => MessageType::ResolverResponse,
+        }
+    }
+}
+
+/// The privileges kept after binding the sockets: the user and group to switch to
+/// (the user primary group if `group` is missing) and whether to restrict the
+/// system calls with a seccomp filter (Linux only).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PrivilegesConf {
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub seccomp: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ResolverConf {
     pub max_ns_queried: usize,
     pub max_ns_retries: usize,
     pub max_cname_redir: usize,
     pub read_timeout: u64,
     pub write_timeout: u64,
+    #[serde(default)]
+    pub tcp_only: bool,
+    #[serde(default)]
+    pub race_nameservers: bool,
     pub cache_conf: CacheConf,
     pub trace_conf: TraceConf,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CacheConf {
     pub clean_period: u64,
     pub entries_cleaned: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct TraceConf {
     pub silent: bool,
     pub verbose: bool,
@@ -71,28 +137,108 @@ impl Conf {
         }
     }
 
+    /// Returns the settings changed in the `new` configuration that are not applied
+    /// on reloads, so need a restart: the servers, the async runtime, the cache, the
+    /// metrics server, the dnstap and query log writers, the privileges and the
+    /// control server. The resolver parameters, the trace settings, the access
+    /// control lists, the layers and the log level are reloaded, keeping the cache.
+    pub fn restart_required(&self, new: &Conf) -> Vec<&'static str> {
+        let changes = [
+            ("drain_timeout", self.drain_timeout != new.drain_timeout),
+            ("udp_server", self.udp_server != new.udp_server),
+            ("tcp_server", self.tcp_server != new.tcp_server),
+            ("tls_server", self.tls_server != new.tls_server),
+            ("https_server", self.https_server != new.https_server),
+            ("quic_server", self.quic_server != new.quic_server),
+            ("async_runtime", self.async_runtime != new.async_runtime),
+            (
+                "resolver.cache_conf",
+                self.resolver.cache_conf != new.resolver.cache_conf,
+            ),
+            ("metrics", self.metrics != new.metrics),
+            ("dnstap", self.dnstap != new.dnstap),
+            ("privileges", self.privileges != new.privileges),
+            ("query_log", self.query_log != new.query_log),
+            ("control", self.control != new.control),
+        ];
+        changes
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(name, _)| *name)
+            .collect()
+    }
+
     /// Validate a configuration struct against some common errors.
     fn validate(&self) -> Result<(), String> {
-        // Udp server confs.
-        if let Err(err) = net::IpAddr::from_str(self.udp_server.address.as_ref()) {
-            return Err(format!("invalid udp address: {}", err));
+        // Servers confs.
+        self.udp_server.validate()?;
+        self.tcp_server.validate()?;
+        let queues = self.udp_server.queue.is_some() || self.tcp_server.queue.is_some();
+        if queues && self.async_runtime.is_some() {
+            return Err("udp/tcp queue limits not supported by the async runtime".to_string());
+        }
+        if self.tcp_server.proxy_protocol.is_some() && self.async_runtime.is_some() {
+            return Err("tcp proxy protocol not supported by the async runtime".to_string());
+        }
+        if let Some(tls_conf) = &self.tls_server {
+            tls_conf.validate()?;
+            if self.async_runtime.is_some() {
+                return Err("tls server not supported by the async runtime".to_string());
+            }
         }
-        if self.udp_server.write_timeout == 0 {
-            return Err("invalid udp write timeout: 0 seconds".to_string());
+        if let Some(https_conf) = &self.https_server {
+            https_conf.validate()?;
+            if self.async_runtime.is_some() {
+                return Err("https server not supported by the async runtime".to_string());
+            }
         }
-        if self.udp_server.threads == 0 {
-            return Err("invalid udp threads: 0".to_string());
+        if let Some(quic_conf) = &self.quic_server {
+            quic_conf.validate()?;
+            if self.async_runtime.is_some() {
+                return Err("quic server not supported by the async runtime".to_string());
+            }
+        }
+        if let Some(async_conf) = &self.async_runtime {
+            async_conf.validate()?;
         }
 
-        // Tcp server confs.
-        if let Err(err) = net::IpAddr::from_str(self.tcp_server.address.as_ref()) {
-            return Err(format!("invalid tcp address: {}", err));
+        // Operational confs.
+        if let Some(metrics_conf) = &self.metrics {
+            metrics_conf.validate()?;
+        }
+        if let Some(dnstap_conf) = &self.dnstap {
+            dnstap_conf.validate()?;
+        }
+        if let Some(query_log_conf) = &self.query_log {
+            query_log_conf.validate()?;
         }
-        if self.tcp_server.write_timeout == 0 {
-            return Err("invalid tcp write timeout: cannot be 0 seconds".to_string());
+        if let Some(control_conf) = &self.control {
+            control_conf.validate()?;
         }
-        if self.tcp_server.threads == 0 {
-            return Err("invalid tcp threads: 0".to_string());
+
+        // Privileges confs.
+        if let Some(privileges_conf) = &self.privileges {
+            if !cfg!(unix) {
+                return Err("dropping privileges not supported on this platform".to_string());
+            }
+            if privileges_conf.seccomp && !privileges::seccomp_supported() {
+                return Err("seccomp filter not supported on this platform".to_string());
+            }
+        }
+
+        // Access control confs.
+        if let Some(acls_conf) = &self.acl {
+            if let Some(acl_conf) = &acls_conf.query {
+                acl_conf.validate("query")?;
+            }
+            if let Some(acl_conf) = &acls_conf.recursion {
+                acl_conf.validate("recursion")?;
+            }
+        }
+
+        // Layers confs.
+        for layer_conf in &self.layers {
+            layer_conf.validate()?;
         }
 
         // Resolver confs.
@@ -1,18 +1,72 @@
 use crate::resolver::*;
 use crate::shared::dns;
+use crate::shared::layers::LayerStack;
 use crate::shared::net::*;
+#[cfg(feature = "async")]
+use std::{future::Future, sync::Arc};
 
 /// The resolver handler able to serve dns requests via its [`DnsHandler`] implementation.
-pub struct ResolverHandler(pub Resolver);
+/// Requests are checked against the access control lists before going through the
+/// `layers` and being served.
+pub struct ResolverHandler {
+    pub resolver: Resolver,
+    pub acls: ResolverAcls,
+    pub layers: LayerStack,
+}
+
+/// The access control lists of the resolver, missing lists allow all the clients.
+/// Clients allowed to query but not to recurse are answered from cache only.
+#[derive(Clone, Debug, Default)]
+pub struct ResolverAcls {
+    pub query: Option<Acl>,
+    pub recursion: Option<Acl>,
+    pub action: AclAction,
+}
 
 impl DnsHandler for ResolverHandler {
     fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
-        handle_request(req, resp, &self.0);
+        handle_request(req, resp, self);
+    }
+}
+
+/// Lookups are not async: they block on the network while querying external
+/// nameservers, so the request is moved to the blocking threads of the runtime.
+/// In this way slow lookups don't stall the async tasks reading and writing the
+/// sockets, but every lookup still takes a blocking thread. The async servers
+/// bound the requests pending (see [AsyncParams](crate::shared::net::AsyncParams)),
+/// so the queue of lookups waiting for a free blocking thread is bounded too.
+#[cfg(feature = "async")]
+impl AsyncDnsHandler for ResolverHandler {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let handler = Arc::clone(self);
+        async move {
+            let task = tokio::task::spawn_blocking(move || handle_request(req, resp, &handler));
+            if let Err(err) = task.await {
+                log::error!("Resolver task failed: {}", err);
+            }
+        }
     }
 }
 
-fn handle_request<R: DnsRead, W: DnsWrite>(req: R, resp: W, resolver: &Resolver) {
-    let dns_request = match req.read() {
+fn handle_request<R: DnsRead, W: DnsWrite>(req: R, resp: W, handler: &ResolverHandler) {
+    let source = req.source();
+    let client = source.addr.ip();
+    let request = req.read();
+    if !handler.acls.query.as_ref().is_none_or(|acl| acl.allows(client)) {
+        log::warn!("Request from {} denied by the query acl.", client);
+        handle_denied(resp, request, handler.acls.action);
+        return;
+    }
+    let (request, resp) = match handler.layers.run(source, request, resp) {
+        Some(v) => v,
+        None => return,
+    };
+
+    let dns_request = match request {
         DnsReadResult::FullMessage(req) => req,
         DnsReadResult::HeaderOnly(header, err) => {
             handle_decode_err(resp, header, err);
@@ -37,19 +91,27 @@ fn handle_request<R: DnsRead, W: DnsWrite>(req: R, resp: W, resolver: &Resolver)
     let dns::Question { node, record_type: t, .. } = &dns_request.questions[0];
     log::info!("[{}] Start handling request: {}, type {:?}.", dns_request.id(), node, t);
     log::debug!("[{}] Complete request: {:?}", dns_request.id(), dns_request);
-    handle_query(dns_request, resp, resolver);
+    if handler.acls.recursion.as_ref().is_none_or(|acl| acl.allows(client)) {
+        handle_query(dns_request, resp, &handler.resolver);
+    } else {
+        handle_cached_query(dns_request, resp, handler);
+    }
 }
 
 /// Resolve the dns query fetching the records of the given name and type. The
 /// response can be found in cache or querying external nameservers. The function
 /// performs uses a new [Lookup] object and a lookup trace is optionally printed.
-fn handle_query<W: DnsWrite>(req: dns::Message, resp: W, resolver: &Resolver) {
+fn handle_query<W: DnsWrite>(req: dns::Message, mut resp: W, resolver: &Resolver) {
     let dns::Question { node, record_type, .. } = &req.questions[0];
     let lookup = resolver.new_lookup(node, *record_type);
-    let (lookup_result, lookup_trace) = lookup.perform();
+    let (lookup_result, lookup_trace, lookup_stats) = lookup.perform_with_stats();
     if !lookup_trace.is_empty() {
         log::info!("[{}] Lookup trace:\n{}", req.id(), lookup_trace);
     }
+    resp.annotate(Resolution {
+        cache_hit: lookup_stats.cache_hit,
+        upstream_queries: lookup_stats.upstream_queries,
+    });
 
     // If we have no records use 'nx_domain' else 'serv_fail' always.
     let LookupResponse(answers, authorities, additionals, _) = match lookup_result {
@@ -71,17 +133,64 @@ fn handle_query<W: DnsWrite>(req: dns::Message, resp: W, resolver: &Resolver) {
     resp_header.answers_count = answers.len() as u16;
     resp_header.authorities_count = authorities.len() as u16;
     resp_header.additionals_count = additionals.len() as u16;
+    let edns = req.response_edns();
     let dns_response = dns::Message {
         header: resp_header,
         questions: req.questions,
-        answers: answers,
-        authorities: authorities,
-        additionals: additionals,
+        answers,
+        authorities,
+        additionals,
+        edns,
     };
 
     reply(resp, dns_response);
 }
 
+/// Serve the dns query of a client not allowed to recurse, only with the records
+/// found in cache. If none is found the request is handled as a denied one.
+fn handle_cached_query<W: DnsWrite>(req: dns::Message, mut resp: W, handler: &ResolverHandler) {
+    let dns::Question { node, record_type, .. } = &req.questions[0];
+    let answers = handler.resolver.cached_records(node, *record_type);
+    if answers.is_empty() {
+        log::warn!("[{}] Recursion denied by the recursion acl.", req.id());
+        handle_denied(resp, DnsReadResult::FullMessage(req), handler.acls.action);
+        return;
+    }
+    resp.annotate(Resolution {
+        cache_hit: true,
+        upstream_queries: 0,
+    });
+
+    let mut resp_header = resp_header_from_req_header(&req.header, dns::RespCode::NoError);
+    resp_header.recursion_available = false;
+    resp_header.answers_count = answers.len() as u16;
+    resp_header.authorities_count = 0;
+    resp_header.additionals_count = 0;
+    let edns = req.response_edns();
+    let dns_response = dns::Message {
+        header: resp_header,
+        questions: req.questions,
+        answers,
+        authorities: vec![],
+        additionals: vec![],
+        edns,
+    };
+
+    reply(resp, dns_response);
+}
+
+/// Handle requests of clients denied by the access control lists, refusing
+/// them or dropping them as configured. Undecodable requests are dropped.
+fn handle_denied<W: DnsWrite>(resp: W, request: DnsReadResult, action: AclAction) {
+    match (action, request) {
+        (AclAction::Refuse, DnsReadResult::FullMessage(req)) => handle_err(resp, &req, dns::RespCode::Refused),
+        (AclAction::Refuse, DnsReadResult::HeaderOnly(header, _)) => {
+            handle_header_err(resp, header, dns::RespCode::Refused)
+        }
+        _ => {}
+    }
+}
+
 /// Handle decoding errors, either malformed messages or unsupported features.
 /// If we cannot decode the header we cannot compose a valid response header,
 /// so simply drop the request in these cases.
@@ -93,13 +202,24 @@ fn handle_decode_err<W: DnsWrite>(resp: W, req_header: dns::Header, msg_err: dns
         dns::ParsingErr::UnsupportedType(_) => dns::RespCode::NotImp,
         _ => dns::RespCode::FormErr,
     };
-    let resp_header = resp_header_from_req_header(&req_header, resp_code);
+    handle_header_err(resp, req_header, resp_code);
+}
+
+/// Reply to a client with a specific error code, when only the
+/// request header is available. No questions are included.
+fn handle_header_err<W: DnsWrite>(resp: W, req_header: dns::Header, resp_code: dns::RespCode) {
+    let mut resp_header = resp_header_from_req_header(&req_header, resp_code);
+    resp_header.questions_count = 0;
+    resp_header.answers_count = 0;
+    resp_header.authorities_count = 0;
+    resp_header.additionals_count = 0;
     let dns_response = dns::Message {
         header: resp_header,
         questions: vec![],
         answers: vec![],
         authorities: vec![],
         additionals: vec![],
+        edns: None,
     };
 
     reply(resp, dns_response);
@@ -118,6 +238,7 @@ fn handle_err<W: DnsWrite>(resp: W, dns_req: &dns::Message, resp_code: dns::Resp
         answers: vec![],
         authorities: vec![],
         additionals: vec![],
+        edns: dns_req.response_edns(),
     };
 
     reply(resp, dns_response);
@@ -150,7 +271,7 @@ fn resp_header_from_req_header(req_header: &dns::Header, resp_code: dns::RespCod
 /// Validate a client dns request against some minimal requirements.
 fn validate_dns_request(dns_req: &dns::Message) -> Result<(), String> {
     if !dns_req.header.is_request() {
-        return Err(format!("resp flag set in query"));
+        return Err("resp flag set in query".to_string());
     }
     if dns_req.header.questions_count != 1 {
         return Err(format!("invalid # of questions: {:?}", dns_req.header.questions_count));
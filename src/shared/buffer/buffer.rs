@@ -1,3 +1,6 @@
+/// A bit-oriented buffer, where read and write positions are expressed in
+/// bits. The dns codecs work with the byte-oriented [BytesReader](super::BytesReader)
+/// and [BytesWriter](super::BytesWriter), use this type only for bit-level access.
 #[derive(Debug)]
 pub struct BitsBuf {
     buf: Vec<u8>,
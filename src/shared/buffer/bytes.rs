@@ -0,0 +1,284 @@
+/// A byte-oriented read cursor over a borrowed bytes slice. All positions
+/// are expressed in bytes and every read is bounds-checked: reads past the
+/// end of the slice return `None` and leave the cursor untouched. Multi-bytes
+/// integers are decoded in network (big-endian) order.
+#[derive(Debug, Clone)]
+pub struct BytesReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BytesReader<'a> {
+    /// Builds a new [BytesReader] reading from the beginning of the slice.
+    pub fn new(bytes: &'a [u8]) -> Self {
+        BytesReader { buf: bytes, pos: 0 }
+    }
+
+    /// Returns the current reading position in the buffer (in bytes).
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// Sets the reading position in the buffer (in bytes). Returns `None`
+    /// if the position is beyond the end of the buffer, in this case the
+    /// reading position is left untouched.
+    pub fn set_pos(&mut self, pos: usize) -> Option<()> {
+        if pos > self.buf.len() {
+            return None;
+        }
+        self.pos = pos;
+        Some(())
+    }
+
+    /// Returns the number of bytes still available to be read.
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    /// Returns the next byte without advancing the reading position.
+    pub fn peek_u8(&self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+
+    /// Reads and returns an `u8`, advancing the reading position by 1.
+    pub fn read_u8(&mut self) -> Option<u8> {
+        let byte = self.peek_u8()?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    /// Reads and returns a big-endian `u16`, advancing the reading position by 2.
+    pub fn read_u16(&mut self) -> Option<u16> {
+        self.read_bytes::<2>().map(u16::from_be_bytes)
+    }
+
+    /// Reads and returns a big-endian `u32`, advancing the reading position by 4.
+    pub fn read_u32(&mut self) -> Option<u32> {
+        self.read_bytes::<4>().map(u32::from_be_bytes)
+    }
+
+    /// Reads and returns N bytes as an array. The function is generic
+    /// over the number of bytes. The reading position is advanced by N.
+    pub fn read_bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let slice = self.read_slice(N)?;
+        let mut buf = [0; N];
+        buf.copy_from_slice(slice);
+        Some(buf)
+    }
+
+    /// Reads and returns `n` bytes as a slice borrowed from the underlying
+    /// buffer, no copies are performed. The reading position is advanced by n.
+    pub fn read_slice(&mut self, n: usize) -> Option<&'a [u8]> {
+        let end = self.pos.checked_add(n)?;
+        let slice = self.buf.get(self.pos..end)?;
+        self.pos = end;
+        Some(slice)
+    }
+
+    /// Reads and returns `n` bytes copied into a [Vec].
+    /// The reading position is advanced by n.
+    pub fn read_bytes_vec(&mut self, n: usize) -> Option<Vec<u8>> {
+        self.read_slice(n).map(|s| s.to_vec())
+    }
+}
+
+/// A byte-oriented write cursor over an owned, growable buffer. Writes past
+/// the end of the buffer append data, while writes before it (after moving
+/// the cursor back with [BytesWriter::set_pos]) overwrite existing bytes.
+/// Multi-bytes integers are encoded in network (big-endian) order.
+#[derive(Debug, Default, Clone)]
+pub struct BytesWriter {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl BytesWriter {
+    /// Builds a new empty [BytesWriter].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Builds a new empty [BytesWriter] able to hold at least
+    /// `capacity` bytes without reallocating.
+    pub fn with_capacity(capacity: usize) -> Self {
+        BytesWriter {
+            buf: Vec::with_capacity(capacity),
+            pos: 0,
+        }
+    }
+
+    /// Returns the current write position in the buffer (in bytes).
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// Returns the length of the written data (in bytes).
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Reports if no data has been written yet.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Sets the write position in the buffer (in bytes).
+    ///
+    /// # Panics
+    /// Panics if the position is beyond the end of the written data.
+    pub fn set_pos(&mut self, pos: usize) {
+        assert!(pos <= self.buf.len(), "write pos > buffer len");
+        self.pos = pos;
+    }
+
+    /// Shortens the buffer, keeping the first `len` bytes and dropping the
+    /// rest. If `len` is >= than the buffer length, this has no effect.
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+        if self.pos > len {
+            self.pos = len;
+        }
+    }
+
+    /// Consumes the writer and returns the written bytes as a [Vec].
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+
+    /// Returns the written bytes as a slice.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Writes an `u8`, advancing the write position by 1.
+    pub fn write_u8(&mut self, n: u8) {
+        self.write_bytes(&[n]);
+    }
+
+    /// Writes an `u16` in big-endian order, advancing the write position by 2.
+    pub fn write_u16(&mut self, n: u16) {
+        self.write_bytes(&n.to_be_bytes());
+    }
+
+    /// Writes an `u32` in big-endian order, advancing the write position by 4.
+    pub fn write_u32(&mut self, n: u32) {
+        self.write_bytes(&n.to_be_bytes());
+    }
+
+    /// Writes the passed bytes, advancing the write position by `bytes.len()`.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.pos == self.buf.len() {
+            self.buf.extend_from_slice(bytes);
+        } else {
+            let overlap = usize::min(self.buf.len() - self.pos, bytes.len());
+            self.buf[self.pos..self.pos + overlap].copy_from_slice(&bytes[..overlap]);
+            self.buf.extend_from_slice(&bytes[overlap..]);
+        }
+        self.pos += bytes.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shared::buffer::{BytesReader, BytesWriter};
+
+    #[test]
+    fn test_read() {
+        let bytes = [0x0a, 0x81, 0x43, 0x22, 0x01, 0x02, 0x03];
+        let mut buf = BytesReader::new(&bytes);
+        assert_eq!(buf.remaining(), 7);
+
+        assert_eq!(buf.peek_u8(), Some(0x0a));
+        assert_eq!(buf.read_u8(), Some(0x0a));
+        assert_eq!(buf.read_u16(), Some(0x8143));
+        assert_eq!(buf.pos(), 3);
+        assert_eq!(buf.read_u32(), Some(0x22010203));
+        assert_eq!(buf.pos(), 7);
+        assert_eq!(buf.remaining(), 0);
+
+        assert_eq!(buf.peek_u8(), None);
+        assert_eq!(buf.read_u8(), None);
+        assert_eq!(buf.pos(), 7);
+    }
+
+    #[test]
+    fn test_read_bounds() {
+        let bytes = [0x0a, 0x81, 0x43];
+        let mut buf = BytesReader::new(&bytes);
+        assert_eq!(buf.read_u32(), None);
+        assert_eq!(buf.pos(), 0);
+        assert_eq!(buf.read_bytes::<4>(), None);
+        assert_eq!(buf.read_slice(usize::MAX), None);
+        assert_eq!(buf.read_bytes_vec(4), None);
+        assert_eq!(buf.pos(), 0);
+
+        assert_eq!(buf.read_u16(), Some(0x0a81));
+        assert_eq!(buf.read_u16(), None);
+        assert_eq!(buf.pos(), 2);
+        assert_eq!(buf.read_bytes::<1>(), Some([0x43]));
+    }
+
+    #[test]
+    fn test_read_bytes() {
+        let bytes = [0x0a, 0x81, 0x43, 0x22];
+        let mut buf = BytesReader::new(&bytes);
+        assert_eq!(buf.read_bytes::<3>(), Some([0x0a, 0x81, 0x43]));
+        assert_eq!(buf.read_bytes_vec(1), Some(vec![0x22]));
+        assert_eq!(buf.read_slice(0), Some(&[][..]));
+        assert_eq!(buf.read_slice(1), None);
+    }
+
+    #[test]
+    fn test_read_pos() {
+        let bytes = [0x0a, 0x81, 0x43, 0x22];
+        let mut buf = BytesReader::new(&bytes);
+        assert_eq!(buf.read_u16(), Some(0x0a81));
+        assert_eq!(buf.set_pos(1), Some(()));
+        assert_eq!(buf.read_u16(), Some(0x8143));
+        assert_eq!(buf.set_pos(4), Some(()));
+        assert_eq!(buf.read_u8(), None);
+        assert_eq!(buf.set_pos(5), None);
+        assert_eq!(buf.pos(), 4);
+    }
+
+    #[test]
+    fn test_write() {
+        let mut buf = BytesWriter::new();
+        assert!(buf.is_empty());
+        buf.write_u8(0x0a);
+        buf.write_u16(0x8143);
+        buf.write_u32(0x22010203);
+        buf.write_bytes(&[0xff, 0xee]);
+        assert_eq!(buf.len(), 9);
+        assert_eq!(buf.pos(), 9);
+        assert_eq!(buf.as_slice(), &[0x0a, 0x81, 0x43, 0x22, 0x01, 0x02, 0x03, 0xff, 0xee]);
+    }
+
+    #[test]
+    fn test_write_pos() {
+        let mut buf = BytesWriter::with_capacity(8);
+        buf.write_bytes(&[0; 4]);
+        buf.set_pos(1);
+        buf.write_u16(0xabcd);
+        assert_eq!(buf.as_slice(), &[0x00, 0xab, 0xcd, 0x00]);
+        buf.write_u16(0x1234);
+        assert_eq!(buf.as_slice(), &[0x00, 0xab, 0xcd, 0x12, 0x34]);
+        assert_eq!(buf.pos(), 5);
+
+        buf.set_pos(0);
+        buf.truncate(2);
+        assert_eq!(buf.as_slice(), &[0x00, 0xab]);
+        assert_eq!(buf.pos(), 0);
+        buf.truncate(10);
+        assert_eq!(buf.len(), 2);
+        assert_eq!(buf.into_vec(), vec![0x00, 0xab]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_write_pos_invalid() {
+        let mut buf = BytesWriter::new();
+        buf.write_u16(0xabcd);
+        buf.set_pos(3);
+    }
+}
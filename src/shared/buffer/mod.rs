@@ -1,2 +1,5 @@
 mod buffer;
+mod bytes;
+
 pub use buffer::*;
+pub use bytes::*;
@@ -0,0 +1,400 @@
+use crate::shared::dns;
+use crate::shared::net::Transport;
+use std::io::{Read, Write};
+use std::{io, net, time};
+
+/// The parameters of a [Client]. Queries are sent to the `server` over UDP and
+/// repeated over TCP if the response is truncated and `tcp_fallback` is set,
+/// or always sent over TCP with `tcp_only`. Each query is sent up to `attempts`
+/// times, waiting `read_timeout` for every response. Queries carry the OPT
+/// record if `edns` is set, its payload size is used as the UDP buffer size.
+#[derive(Clone, Debug)]
+pub struct ClientParams {
+    pub server: net::SocketAddr,
+    pub tcp_only: bool,
+    pub tcp_fallback: bool,
+    pub attempts: usize,
+    pub read_timeout: time::Duration,
+    pub write_timeout: time::Duration,
+    pub recursion_desired: bool,
+    pub edns: Option<dns::Edns>,
+}
+
+impl Default for ClientParams {
+    fn default() -> Self {
+        ClientParams {
+            server: net::SocketAddr::from(([127, 0, 0, 1], 53)),
+            tcp_only: false,
+            tcp_fallback: true,
+            attempts: 3,
+            read_timeout: time::Duration::new(2, 0),
+            write_timeout: time::Duration::new(2, 0),
+            recursion_desired: true,
+            edns: Some(dns::Edns::default()),
+        }
+    }
+}
+
+/// A response received by a [Client], along with its raw bytes, the transport
+/// used to receive it and the time elapsed since the (last) query was sent.
+#[derive(Clone, Debug)]
+pub struct Reply {
+    pub message: dns::Message,
+    pub bytes: Vec<u8>,
+    pub transport: Transport,
+    pub elapsed: time::Duration,
+}
+
+/// Errors returned by the [Client]. Responses are validated: they must have
+/// the response flag set, the id of the query and the same question.
+#[derive(Debug)]
+pub enum ClientErr {
+    IO(io::Error),
+    InvalidRequest(dns::MessageErr),
+    MalformedResp(dns::MessageErr),
+    NotAResponse,
+    UnexpectedId(u16),
+    UnexpectedQuestion,
+}
+
+impl From<io::Error> for ClientErr {
+    fn from(io_err: io::Error) -> Self {
+        ClientErr::IO(io_err)
+    }
+}
+
+/// A dns client sending queries to a single server, as configured by the
+/// [ClientParams]. The client is stateless: every query uses a new socket.
+#[derive(Clone, Debug)]
+pub struct Client {
+    params: ClientParams,
+}
+
+impl Client {
+    pub fn new(params: ClientParams) -> Self {
+        Client { params }
+    }
+
+    pub fn params(&self) -> &ClientParams {
+        &self.params
+    }
+
+    /// Builds the query for the records of the given name and type, with a
+    /// random id and the recursion desired flag and EDNS of the client.
+    pub fn build_query(&self, node: &dns::Name, kind: dns::RecordType) -> dns::Message {
+        let header = dns::Header {
+            recursion_desired: self.params.recursion_desired,
+            questions_count: 1,
+            ..Default::default()
+        };
+        let question = dns::Question {
+            node: node.clone(),
+            record_type: kind,
+            class: dns::Class::IN,
+        };
+        dns::Message {
+            header,
+            questions: vec![question],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            edns: self.params.edns.clone(),
+        }
+    }
+
+    /// Queries the server for the records of the given name and type.
+    pub fn query(&self, node: &dns::Name, kind: dns::RecordType) -> Result<Reply, ClientErr> {
+        self.send(&self.build_query(node, kind))
+    }
+
+    /// Sends the request to the server, retrying until a valid response is
+    /// received or the attempts are exhausted. The last error is returned.
+    pub fn send(&self, request: &dns::Message) -> Result<Reply, ClientErr> {
+        let bytes = request.encode_to_bytes().map_err(ClientErr::InvalidRequest)?;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.exchange(request, &bytes) {
+                Err(err) if attempt < self.params.attempts => {
+                    log::debug!("[{}] Query attempt {} failed: {:?}", request.id(), attempt, err);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Send the request once, over UDP and TCP if the UDP response is truncated.
+    fn exchange(&self, request: &dns::Message, bytes: &[u8]) -> Result<Reply, ClientErr> {
+        let start = time::Instant::now();
+        if !self.params.tcp_only {
+            let response_bytes = self.exchange_udp(request.id(), bytes)?;
+            let message = validate_response(request, &response_bytes)?;
+            if !message.header.truncated || !self.params.tcp_fallback {
+                return Ok(Reply {
+                    message,
+                    bytes: response_bytes,
+                    transport: Transport::Udp,
+                    elapsed: start.elapsed(),
+                });
+            }
+            log::debug!("[{}] Truncated response, retrying over TCP.", request.id());
+        }
+
+        let response_bytes = self.exchange_tcp(bytes)?;
+        let message = validate_response(request, &response_bytes)?;
+        Ok(Reply {
+            message,
+            bytes: response_bytes,
+            transport: Transport::Tcp,
+            elapsed: start.elapsed(),
+        })
+    }
+
+    // The socket is connected to the server, so that datagrams from other
+    // addresses are discarded. Responses with a different id are skipped
+    // (they could be late responses to previous attempts, or spoofed).
+    fn exchange_udp(&self, id: u16, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let local_addr = match self.params.server {
+            net::SocketAddr::V4(_) => net::SocketAddr::from(([0, 0, 0, 0], 0)),
+            net::SocketAddr::V6(_) => net::SocketAddr::from(([0_u16; 8], 0)),
+        };
+        let socket = net::UdpSocket::bind(local_addr)?;
+        socket.connect(self.params.server)?;
+        socket.set_write_timeout(Some(self.params.write_timeout))?;
+        socket.send(bytes)?;
+
+        let buf_len = self.params.edns.as_ref().map_or(dns::MAX_UDP_LEN_BYTES, |edns| {
+            (edns.udp_payload_size as usize).max(dns::MAX_UDP_LEN_BYTES)
+        });
+        let mut buf = vec![0; buf_len];
+        let deadline = time::Instant::now() + self.params.read_timeout;
+        loop {
+            let timeout = deadline.saturating_duration_since(time::Instant::now());
+            if timeout.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            socket.set_read_timeout(Some(timeout))?;
+            let n = socket.recv(&mut buf)?;
+            if n >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+        }
+    }
+
+    // Messages sent over TCP are prefixed by their length (two bytes).
+    fn exchange_tcp(&self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut stream = net::TcpStream::connect_timeout(&self.params.server, self.params.write_timeout)?;
+        stream.set_write_timeout(Some(self.params.write_timeout))?;
+        stream.set_read_timeout(Some(self.params.read_timeout))?;
+        stream.set_nodelay(true)?;
+        let mut buf = Vec::with_capacity(bytes.len() + 2);
+        buf.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        buf.extend_from_slice(bytes);
+        stream.write_all(&buf)?;
+
+        let mut len_buf = [0; 2];
+        stream.read_exact(&mut len_buf)?;
+        let mut buf = vec![0; u16::from_be_bytes(len_buf) as usize];
+        stream.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+// Decode the response and check it matches the request. Error responses
+// may omit the question (e.g. when the server cannot decode it).
+fn validate_response(request: &dns::Message, bytes: &[u8]) -> Result<dns::Message, ClientErr> {
+    let response = dns::Message::decode_from_bytes(bytes).map_err(ClientErr::MalformedResp)?;
+    if !response.header.query_resp {
+        return Err(ClientErr::NotAResponse);
+    }
+    if response.id() != request.id() {
+        return Err(ClientErr::UnexpectedId(response.id()));
+    }
+    let no_question = response.questions.is_empty() && response.header.resp_code != dns::RespCode::NoError;
+    if response.questions != request.questions && !no_question {
+        return Err(ClientErr::UnexpectedQuestion);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn name(name: &str) -> dns::Name {
+        dns::Name::from_string(name).unwrap()
+    }
+
+    // Build the response to the request, with as many A records as requested.
+    fn response(request: &dns::Message, answers: u8) -> dns::Message {
+        let mut response = request.clone();
+        response.header.query_resp = true;
+        response.header.answers_count = answers as u16;
+        response.answers = (0..answers)
+            .map(|i| dns::Record::A {
+                node: request.questions[0].node.clone(),
+                class: dns::Class::IN,
+                ttl: 60,
+                data_len: 4,
+                address: [10, 0, 0, i],
+            })
+            .collect();
+        response
+    }
+
+    // Bind UDP and TCP sockets on the same local port.
+    fn bind_server() -> (net::UdpSocket, net::TcpListener) {
+        loop {
+            let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+            if let Ok(socket) = net::UdpSocket::bind(listener.local_addr().unwrap()) {
+                return (socket, listener);
+            }
+        }
+    }
+
+    fn recv_udp(socket: &net::UdpSocket) -> (dns::Message, net::SocketAddr) {
+        let mut buf = [0; 4096];
+        let (n, addr) = socket.recv_from(&mut buf).unwrap();
+        (dns::Message::decode_from_bytes(&buf[..n]).unwrap(), addr)
+    }
+
+    fn send_udp(socket: &net::UdpSocket, response: &dns::Message, addr: net::SocketAddr) {
+        socket
+            .send_to(&response.encode_to_bytes_trunc().unwrap(), addr)
+            .unwrap();
+    }
+
+    fn serve_tcp(listener: &net::TcpListener, respond: impl Fn(&dns::Message) -> dns::Message) {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).unwrap();
+        let request = dns::Message::decode_from_bytes(&buf).unwrap();
+        let bytes = respond(&request).encode_to_bytes().unwrap();
+        stream.write_all(&(bytes.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&bytes).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_fallback() {
+        let (socket, listener) = bind_server();
+        let params = ClientParams {
+            server: socket.local_addr().unwrap(),
+            ..Default::default()
+        };
+        let server = thread::spawn(move || {
+            // A response with another id is skipped, then the response is truncated.
+            let (request, addr) = recv_udp(&socket);
+            assert_eq!(request.edns.as_ref().unwrap().udp_payload_size, 1232);
+            assert!(request.header.recursion_desired);
+            let mut other = response(&request, 1);
+            other.header.id = request.id().wrapping_add(1);
+            send_udp(&socket, &other, addr);
+            send_udp(&socket, &response(&request, 200), addr);
+            serve_tcp(&listener, |request| response(request, 200));
+            (socket, listener)
+        });
+
+        let client = Client::new(params.clone());
+        let reply = client.query(&name("example.com."), dns::RecordType::A).unwrap();
+        assert_eq!(reply.transport, Transport::Tcp);
+        assert!(!reply.message.header.truncated);
+        assert_eq!(reply.message.answers.len(), 200);
+        assert_eq!(
+            dns::Message::decode_from_bytes(&reply.bytes).unwrap().answers.len(),
+            200
+        );
+
+        // Truncated responses are returned without fallback.
+        let (socket, _listener) = server.join().unwrap();
+        let server = thread::spawn(move || {
+            let (request, addr) = recv_udp(&socket);
+            send_udp(&socket, &response(&request, 200), addr);
+        });
+        let client = Client::new(ClientParams {
+            tcp_fallback: false,
+            edns: None,
+            ..params
+        });
+        let reply = client.query(&name("example.com."), dns::RecordType::A).unwrap();
+        assert_eq!(reply.transport, Transport::Udp);
+        assert!(reply.message.header.truncated);
+        assert!(reply.bytes.len() <= dns::MAX_UDP_LEN_BYTES);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_retries() {
+        let (socket, _listener) = bind_server();
+        let params = ClientParams {
+            server: socket.local_addr().unwrap(),
+            attempts: 2,
+            read_timeout: time::Duration::from_millis(200),
+            ..Default::default()
+        };
+        let server = thread::spawn(move || {
+            // The first query is ignored, the second one answered.
+            recv_udp(&socket);
+            let (request, addr) = recv_udp(&socket);
+            send_udp(&socket, &response(&request, 1), addr);
+            socket
+        });
+        let reply = Client::new(params.clone()).query(&name("example.com."), dns::RecordType::A);
+        assert_eq!(reply.unwrap().message.answers.len(), 1);
+
+        // The socket is kept open, so that the query times out.
+        let _socket = server.join().unwrap();
+
+        let client = Client::new(ClientParams { attempts: 1, ..params });
+        match client.query(&name("example.com."), dns::RecordType::A) {
+            Err(ClientErr::IO(err)) => assert!(matches!(
+                err.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            )),
+            reply => panic!("unexpected reply: {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_validation() {
+        let (_socket, listener) = bind_server();
+        let client = Client::new(ClientParams {
+            server: listener.local_addr().unwrap(),
+            tcp_only: true,
+            attempts: 1,
+            ..Default::default()
+        });
+        let server = thread::spawn(move || {
+            serve_tcp(&listener, |request| {
+                let mut response = response(request, 1);
+                response.questions[0].node = name("example.org.");
+                response
+            });
+            serve_tcp(&listener, |request| {
+                let mut response = response(request, 1);
+                response.header.id = request.id().wrapping_add(1);
+                response
+            });
+            serve_tcp(&listener, |request| request.clone());
+            serve_tcp(&listener, |request| {
+                let mut response = response(request, 0);
+                response.header.resp_code = dns::RespCode::FormErr;
+                response.header.questions_count = 0;
+                response.questions.clear();
+                response
+            });
+        });
+
+        let query = || client.query(&name("example.com."), dns::RecordType::A);
+        assert!(matches!(query(), Err(ClientErr::UnexpectedQuestion)));
+        assert!(matches!(query(), Err(ClientErr::UnexpectedId(_))));
+        assert!(matches!(query(), Err(ClientErr::NotAResponse)));
+        let reply = query().unwrap();
+        assert_eq!(reply.transport, Transport::Tcp);
+        assert_eq!(reply.message.header.resp_code, dns::RespCode::FormErr);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,754 @@
+use crate::shared::dns;
+use crate::shared::dnstap::*;
+use crate::shared::net::*;
+use crate::shared::query_log::*;
+use crate::shared::thread_pool::QueueLimits;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::{net, time};
+
+/// Parameters of the UDP server. As for the other server and operational settings
+/// shared by the resolver and the nameserver, the configuration is checked with
+/// `validate` and then converted into the parameters of the server.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UdpServerConf {
+    pub listeners: Vec<ListenerConf>,
+    pub write_timeout: u64,
+    pub threads: usize,
+    #[serde(default = "default_one")]
+    pub sockets_per_listener: usize,
+    #[serde(default = "default_one")]
+    pub batch_size: usize,
+    #[serde(default)]
+    pub queue: Option<QueueConf>,
+    #[serde(default)]
+    pub overload_action: OverloadActionConf,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TcpServerConf {
+    pub listeners: Vec<ListenerConf>,
+    pub read_timeout: u64,
+    pub write_timeout: u64,
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    pub threads: usize,
+    #[serde(default)]
+    pub queue: Option<QueueConf>,
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolConf>,
+}
+
+/// An address a server listens on. IPv6 sockets also accept
+/// IPv4 clients (dual-stack) unless `ipv6_only` is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ListenerConf {
+    pub address: String,
+    pub port: u16,
+    #[serde(default)]
+    pub ipv6_only: bool,
+}
+
+/// Parameters of the DNS-over-TLS server, used only when the servers
+/// are built with the `tls` feature. If missing, the server is not started.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TlsServerConf {
+    pub listeners: Vec<ListenerConf>,
+    pub read_timeout: u64,
+    pub write_timeout: u64,
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    pub threads: usize,
+    #[serde(default)]
+    pub queue: Option<QueueConf>,
+    #[serde(default)]
+    pub proxy_protocol: Option<ProxyProtocolConf>,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// Limits of the queue of requests waiting for a thread of the pool. Requests
+/// queued for longer than `max_age_ms` milliseconds are discarded (0 disables it).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueueConf {
+    pub max_depth: usize,
+    #[serde(default)]
+    pub max_age_ms: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum OverloadActionConf {
+    #[default]
+    Drop,
+    ServFail,
+}
+
+/// Parameters of the DNS-over-HTTPS server, used only when the servers
+/// are built with the `https` feature. If missing, the server is not started.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct HttpsServerConf {
+    pub listeners: Vec<ListenerConf>,
+    pub path: String,
+    pub read_timeout: u64,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    pub threads: usize,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// Parameters of the DNS-over-QUIC server, used only when the servers
+/// are built with the `quic` feature. If missing, the server is not started.
+/// Queries in 0-RTT data are accepted only if `early_data` is set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QuicServerConf {
+    pub listeners: Vec<ListenerConf>,
+    pub read_timeout: u64,
+    pub idle_timeout: u64,
+    pub max_connections: usize,
+    pub max_streams: u32,
+    pub threads: usize,
+    #[serde(default)]
+    pub early_data: bool,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// Parameters of the async runtime, used only when the servers are built
+/// with the `async` feature. If missing, the sync servers are started.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AsyncRuntimeConf {
+    pub worker_threads: usize,
+    pub blocking_threads: usize,
+    #[serde(default = "default_max_pending")]
+    pub max_pending: usize,
+}
+
+/// A list of allowed and denied network prefixes (e.g. `10.0.0.0/8`).
+/// A missing `allow` list allows all the clients not denied.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AclConf {
+    #[serde(default)]
+    pub allow: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum AclActionConf {
+    #[default]
+    Refuse,
+    Drop,
+}
+
+/// The load balancers allowed to send PROXY protocol v2 headers, by source network
+/// prefix. Connections from them must start with the header, carrying the client address.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProxyProtocolConf {
+    pub trusted: Vec<String>,
+}
+
+/// The address of the HTTP server exposing the Prometheus metrics on
+/// `/metrics`. If missing, the metrics server is not started.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MetricsConf {
+    pub address: String,
+    pub port: u16,
+}
+
+/// The dnstap output, a file or the Unix socket of a collector, and the types of the
+/// logged messages. At most `queue_size` messages wait to be written, further ones are dropped.
+/// The message types `M` are the ones produced by the server, converted into [MessageType].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DnstapConf<M> {
+    pub output: DnstapOutputConf,
+    #[serde(default)]
+    pub identity: String,
+    pub message_types: Vec<M>,
+    pub queue_size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum DnstapOutputConf {
+    File(String),
+    Unix(String),
+}
+
+/// The query log output and the logged requests, sampled with the `sample_rate`
+/// probability and filtered by response code, query type and duration. At most
+/// `queue_size` lines wait to be written, further ones are dropped.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct QueryLogConf {
+    pub output: QueryLogOutputConf,
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    #[serde(default)]
+    pub rcodes: Vec<RespCodeConf>,
+    #[serde(default)]
+    pub qtypes: Vec<String>,
+    #[serde(default)]
+    pub min_duration_ms: u64,
+    pub queue_size: usize,
+}
+
+/// The standard output or a file, rotated when it grows over
+/// `max_size_mb` megabytes, keeping `max_files` rotated files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum QueryLogOutputConf {
+    Stdout,
+    File {
+        path: String,
+        max_size_mb: u64,
+        max_files: usize,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum RespCodeConf {
+    NoError,
+    FormErr,
+    ServFail,
+    NxDomain,
+    NotImp,
+    Refused,
+}
+
+/// The path of the Unix socket of the control server, used by `ariadne-ctl`
+/// to run commands at runtime. If missing, the control server is not started.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ControlConf {
+    pub path: String,
+}
+
+impl UdpServerConf {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_listeners("udp", &self.listeners)?;
+        if self.write_timeout == 0 {
+            return Err("invalid udp write timeout: 0 seconds".to_string());
+        }
+        if self.threads == 0 {
+            return Err("invalid udp threads: 0".to_string());
+        }
+        if self.sockets_per_listener == 0 {
+            return Err("invalid udp sockets per listener: 0".to_string());
+        }
+        if self.batch_size == 0 || self.batch_size > 1024 {
+            return Err(format!("invalid udp batch size: {}", self.batch_size));
+        }
+        validate_queue("udp", &self.queue)
+    }
+}
+
+impl TcpServerConf {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_listeners("tcp", &self.listeners)?;
+        if self.write_timeout == 0 {
+            return Err("invalid tcp write timeout: cannot be 0 seconds".to_string());
+        }
+        if self.idle_timeout == 0 {
+            return Err("invalid tcp idle timeout: cannot be 0 seconds".to_string());
+        }
+        if self.max_connections == 0 {
+            return Err("invalid tcp max connections: 0".to_string());
+        }
+        if self.threads == 0 {
+            return Err("invalid tcp threads: 0".to_string());
+        }
+        validate_queue("tcp", &self.queue)?;
+        validate_proxy_protocol("tcp", &self.proxy_protocol)
+    }
+}
+
+impl ListenerConf {
+    /// Returns the socket address to listen on, the
+    /// address is expected to be already validated.
+    pub fn socket_addr(&self) -> net::SocketAddr {
+        net::SocketAddr::new(self.address.parse().unwrap(), self.port)
+    }
+}
+
+impl TlsServerConf {
+    pub fn validate(&self) -> Result<(), String> {
+        if !cfg!(feature = "tls") {
+            return Err("tls server configured but the 'tls' feature is disabled".to_string());
+        }
+        validate_listeners("tls", &self.listeners)?;
+        if self.read_timeout == 0 || self.write_timeout == 0 || self.idle_timeout == 0 {
+            return Err("invalid tls timeouts: cannot be 0 seconds".to_string());
+        }
+        if self.max_connections == 0 || self.threads == 0 {
+            return Err("invalid tls max connections/threads: 0".to_string());
+        }
+        validate_queue("tls", &self.queue)?;
+        validate_proxy_protocol("tls", &self.proxy_protocol)?;
+        if self.cert_file.is_empty() || self.key_file.is_empty() {
+            return Err("invalid tls cert/key files: empty paths".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl HttpsServerConf {
+    pub fn validate(&self) -> Result<(), String> {
+        if !cfg!(feature = "https") {
+            return Err("https server configured but the 'https' feature is disabled".to_string());
+        }
+        validate_listeners("https", &self.listeners)?;
+        if !self.path.starts_with('/') {
+            return Err(format!("invalid https path '{}': must start with '/'", self.path));
+        }
+        if self.read_timeout == 0 {
+            return Err("invalid https read timeout: cannot be 0 seconds".to_string());
+        }
+        if self.max_connections == 0 || self.threads == 0 {
+            return Err("invalid https max connections/threads: 0".to_string());
+        }
+        if self.cert_file.is_empty() || self.key_file.is_empty() {
+            return Err("invalid https cert/key files: empty paths".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl QuicServerConf {
+    pub fn validate(&self) -> Result<(), String> {
+        if !cfg!(feature = "quic") {
+            return Err("quic server configured but the 'quic' feature is disabled".to_string());
+        }
+        validate_listeners("quic", &self.listeners)?;
+        if self.read_timeout == 0 || self.idle_timeout == 0 {
+            return Err("invalid quic timeouts: cannot be 0 seconds".to_string());
+        }
+        if self.max_connections == 0 || self.max_streams == 0 || self.threads == 0 {
+            return Err("invalid quic max connections/streams/threads: 0".to_string());
+        }
+        if self.cert_file.is_empty() || self.key_file.is_empty() {
+            return Err("invalid quic cert/key files: empty paths".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl AsyncRuntimeConf {
+    pub fn validate(&self) -> Result<(), String> {
+        if !cfg!(feature = "async") {
+            return Err("async runtime configured but the 'async' feature is disabled".to_string());
+        }
+        if self.worker_threads == 0 || self.blocking_threads == 0 {
+            return Err("invalid async runtime threads: cannot be 0".to_string());
+        }
+        if self.max_pending == 0 {
+            return Err("invalid async runtime max pending: 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl AclConf {
+    /// Checks that all the prefixes of the access control list are
+    /// valid, `name` identifies the list in the returned error.
+    pub fn validate(&self, name: &str) -> Result<(), String> {
+        for prefix in self.allow.iter().flatten().chain(&self.deny) {
+            if let Err(err) = IpPrefix::from_str(prefix) {
+                return Err(format!("invalid {} acl: {}", name, err));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MetricsConf {
+    pub fn validate(&self) -> Result<(), String> {
+        match net::IpAddr::from_str(&self.address) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(format!("invalid metrics address: {}", err)),
+        }
+    }
+
+    /// Returns the socket address to listen on, the
+    /// address is expected to be already validated.
+    pub fn socket_addr(&self) -> net::SocketAddr {
+        net::SocketAddr::new(self.address.parse().unwrap(), self.port)
+    }
+}
+
+impl<M> DnstapConf<M> {
+    pub fn validate(&self) -> Result<(), String> {
+        if let DnstapOutputConf::Unix(_) = self.output {
+            if !cfg!(unix) {
+                return Err("dnstap unix socket output not supported on this platform".to_string());
+            }
+        }
+        if self.message_types.is_empty() {
+            return Err("invalid dnstap message types: none configured".to_string());
+        }
+        if self.queue_size == 0 {
+            return Err("invalid dnstap queue size: 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl QueryLogConf {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.sample_rate > 0.0 && self.sample_rate <= 1.0) {
+            return Err("invalid query log sample rate: must be in (0, 1]".to_string());
+        }
+        for qtype in &self.qtypes {
+            if dns::RecordType::from_str(qtype).is_err() {
+                return Err(format!("invalid query log query type: {}", qtype));
+            }
+        }
+        if let QueryLogOutputConf::File { max_size_mb, max_files, .. } = self.output {
+            if max_size_mb == 0 || max_files == 0 {
+                return Err("invalid query log file rotation: size and files cannot be 0".to_string());
+            }
+        }
+        if self.queue_size == 0 {
+            return Err("invalid query log queue size: 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl ControlConf {
+    pub fn validate(&self) -> Result<(), String> {
+        if !cfg!(unix) {
+            return Err("control socket not supported on this platform".to_string());
+        }
+        if self.path.is_empty() {
+            return Err("invalid control socket path: empty path".to_string());
+        }
+        Ok(())
+    }
+}
+
+// Check that at least one listener is configured, that the addresses are valid
+// and not repeated, and that `ipv6_only` is set only on IPv6 addresses.
+fn validate_listeners(proto: &str, listeners: &[ListenerConf]) -> Result<(), String> {
+    if listeners.is_empty() {
+        return Err(format!("invalid {} listeners: none configured", proto));
+    }
+    for (i, listener) in listeners.iter().enumerate() {
+        let address = match net::IpAddr::from_str(listener.address.as_ref()) {
+            Ok(v) => v,
+            Err(err) => return Err(format!("invalid {} address: {}", proto, err)),
+        };
+        if listener.ipv6_only && address.is_ipv4() {
+            return Err(format!(
+                "invalid {} listener {}: 'ipv6_only' set on an IPv4 address",
+                proto, address
+            ));
+        }
+        if listeners[..i].iter().any(|l| l.socket_addr() == listener.socket_addr()) {
+            return Err(format!(
+                "invalid {} listener {}: repeated",
+                proto,
+                listener.socket_addr()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Check that the trusted prefixes are valid, at least one is required.
+fn validate_proxy_protocol(proto: &str, proxy_protocol: &Option<ProxyProtocolConf>) -> Result<(), String> {
+    let proxy_protocol = match proxy_protocol {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    if proxy_protocol.trusted.is_empty() {
+        return Err(format!("invalid {} proxy protocol: no trusted prefixes", proto));
+    }
+    for prefix in &proxy_protocol.trusted {
+        if let Err(err) = IpPrefix::from_str(prefix) {
+            return Err(format!("invalid {} proxy protocol: {}", proto, err));
+        }
+    }
+    Ok(())
+}
+
+fn validate_queue(proto: &str, queue: &Option<QueueConf>) -> Result<(), String> {
+    match queue {
+        Some(queue) if queue.max_depth == 0 => Err(format!("invalid {} queue max depth: 0", proto)),
+        _ => Ok(()),
+    }
+}
+
+impl From<&UdpServerConf> for UdpParams {
+    fn from(udp_conf: &UdpServerConf) -> Self {
+        UdpParams {
+            listeners: listen_addrs(&udp_conf.listeners),
+            write_timeout: time::Duration::new(udp_conf.write_timeout, 0),
+            threads: udp_conf.threads,
+            sockets_per_listener: udp_conf.sockets_per_listener,
+            batch_size: udp_conf.batch_size,
+            queue: queue_limits(&udp_conf.queue),
+            overload_action: match udp_conf.overload_action {
+                OverloadActionConf::Drop => OverloadAction::Drop,
+                OverloadActionConf::ServFail => OverloadAction::ServFail,
+            },
+        }
+    }
+}
+
+impl From<&TcpServerConf> for TcpParams {
+    fn from(tcp_conf: &TcpServerConf) -> Self {
+        TcpParams {
+            listeners: listen_addrs(&tcp_conf.listeners),
+            write_timeout: time::Duration::new(tcp_conf.write_timeout, 0),
+            read_timeout: time::Duration::new(tcp_conf.read_timeout, 0),
+            idle_timeout: time::Duration::new(tcp_conf.idle_timeout, 0),
+            max_connections: tcp_conf.max_connections,
+            threads: tcp_conf.threads,
+            queue: queue_limits(&tcp_conf.queue),
+            proxy_trusted: proxy_trusted(&tcp_conf.proxy_protocol),
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+impl From<&TlsServerConf> for TlsParams {
+    fn from(tls_conf: &TlsServerConf) -> Self {
+        TlsParams {
+            listeners: listen_addrs(&tls_conf.listeners),
+            write_timeout: time::Duration::new(tls_conf.write_timeout, 0),
+            read_timeout: time::Duration::new(tls_conf.read_timeout, 0),
+            idle_timeout: time::Duration::new(tls_conf.idle_timeout, 0),
+            max_connections: tls_conf.max_connections,
+            threads: tls_conf.threads,
+            queue: queue_limits(&tls_conf.queue),
+            proxy_trusted: proxy_trusted(&tls_conf.proxy_protocol),
+            cert_file: tls_conf.cert_file.clone(),
+            key_file: tls_conf.key_file.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "https")]
+impl From<&HttpsServerConf> for HttpsParams {
+    fn from(https_conf: &HttpsServerConf) -> Self {
+        HttpsParams {
+            listeners: listen_addrs(&https_conf.listeners),
+            path: https_conf.path.clone(),
+            read_timeout: time::Duration::new(https_conf.read_timeout, 0),
+            max_connections: https_conf.max_connections,
+            threads: https_conf.threads,
+            cert_file: https_conf.cert_file.clone(),
+            key_file: https_conf.key_file.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "quic")]
+impl From<&QuicServerConf> for QuicParams {
+    fn from(quic_conf: &QuicServerConf) -> Self {
+        QuicParams {
+            listeners: listen_addrs(&quic_conf.listeners),
+            read_timeout: time::Duration::new(quic_conf.read_timeout, 0),
+            idle_timeout: time::Duration::new(quic_conf.idle_timeout, 0),
+            max_connections: quic_conf.max_connections,
+            max_streams: quic_conf.max_streams,
+            threads: quic_conf.threads,
+            early_data: quic_conf.early_data,
+            cert_file: quic_conf.cert_file.clone(),
+            key_file: quic_conf.key_file.clone(),
+        }
+    }
+}
+
+impl From<&AclConf> for Acl {
+    fn from(acl_conf: &AclConf) -> Self {
+        let parse = |prefixes: &[String]| prefixes.iter().map(|p| p.parse().unwrap()).collect::<Vec<_>>();
+        Acl::new(acl_conf.allow.as_deref().map(parse), parse(&acl_conf.deny))
+    }
+}
+
+impl From<&AclActionConf> for AclAction {
+    fn from(action_conf: &AclActionConf) -> Self {
+        match action_conf {
+            AclActionConf::Refuse => AclAction::Refuse,
+            AclActionConf::Drop => AclAction::Drop,
+        }
+    }
+}
+
+impl<M> From<&DnstapConf<M>> for DnstapParams
+where
+    for<'a> &'a M: Into<MessageType>,
+{
+    fn from(dnstap_conf: &DnstapConf<M>) -> Self {
+        let output = match &dnstap_conf.output {
+            DnstapOutputConf::File(path) => DnstapOutput::File(path.into()),
+            #[cfg(unix)]
+            DnstapOutputConf::Unix(path) => DnstapOutput::Unix(path.into()),
+            #[cfg(not(unix))]
+            DnstapOutputConf::Unix(_) => unreachable!(),
+        };
+        DnstapParams {
+            output,
+            identity: dnstap_conf.identity.clone(),
+            message_types: dnstap_conf.message_types.iter().map(Into::into).collect(),
+            queue_size: dnstap_conf.queue_size,
+        }
+    }
+}
+
+/// Builds the writer parameters without a `chroot_path`, to be
+/// set if the root directory of the process is changed.
+impl From<&QueryLogConf> for QueryLogParams {
+    fn from(query_log_conf: &QueryLogConf) -> Self {
+        let output = match &query_log_conf.output {
+            QueryLogOutputConf::Stdout => QueryLogOutput::Stdout,
+            QueryLogOutputConf::File { path, max_size_mb, max_files } => QueryLogOutput::File {
+                path: path.into(),
+                chroot_path: None,
+                max_size: max_size_mb * 1024 * 1024,
+                max_files: *max_files,
+            },
+        };
+        let rcodes = query_log_conf.rcodes.iter().map(|rcode| match rcode {
+            RespCodeConf::NoError => dns::RespCode::NoError,
+            RespCodeConf::FormErr => dns::RespCode::FormErr,
+            RespCodeConf::ServFail => dns::RespCode::ServFail,
+            RespCodeConf::NxDomain => dns::RespCode::NxDomain,
+            RespCodeConf::NotImp => dns::RespCode::NotImp,
+            RespCodeConf::Refused => dns::RespCode::Refused,
+        });
+        let qtypes = query_log_conf
+            .qtypes
+            .iter()
+            .map(|qtype| dns::RecordType::from_str(qtype).unwrap());
+        QueryLogParams {
+            output,
+            sample_rate: query_log_conf.sample_rate,
+            filter: QueryFilter {
+                rcodes: rcodes.collect(),
+                qtypes: qtypes.collect(),
+                min_duration: time::Duration::from_millis(query_log_conf.min_duration_ms),
+            },
+            queue_size: query_log_conf.queue_size,
+        }
+    }
+}
+
+fn listen_addrs(listeners: &[ListenerConf]) -> Vec<ListenAddr> {
+    let to_listen_addr = |l: &ListenerConf| ListenAddr {
+        address: l.socket_addr(),
+        ipv6_only: l.ipv6_only,
+    };
+    listeners.iter().map(to_listen_addr).collect()
+}
+
+// Missing limits mean an unbounded queue.
+fn queue_limits(queue_conf: &Option<QueueConf>) -> QueueLimits {
+    match queue_conf {
+        None => QueueLimits::UNBOUNDED,
+        Some(queue_conf) => QueueLimits {
+            max_depth: queue_conf.max_depth,
+            max_age: match queue_conf.max_age_ms {
+                0 => None,
+                ms => Some(time::Duration::from_millis(ms)),
+            },
+        },
+    }
+}
+
+// A missing proxy protocol configuration means no trusted proxies.
+fn proxy_trusted(proxy_conf: &Option<ProxyProtocolConf>) -> Vec<IpPrefix> {
+    match proxy_conf {
+        None => vec![],
+        Some(proxy_conf) => proxy_conf.trusted.iter().map(|p| p.parse().unwrap()).collect(),
+    }
+}
+
+pub(crate) fn default_drain_timeout() -> u64 {
+    5
+}
+
+fn default_one() -> usize {
+    1
+}
+
+fn default_idle_timeout() -> u64 {
+    10
+}
+
+fn default_max_connections() -> usize {
+    256
+}
+
+fn default_max_pending() -> usize {
+    1024
+}
+
+fn default_sample_rate() -> f64 {
+    1.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_conf() {
+        let udp_conf: UdpServerConf = serde_json::from_str(
+            r#"{
+                "listeners": [{"address": "::", "port": 53}, {"address": "127.0.0.1", "port": 53}],
+                "write_timeout": 5,
+                "threads": 4,
+                "queue": {"max_depth": 100, "max_age_ms": 500},
+                "overload_action": "ServFail"
+            }"#,
+        )
+        .unwrap();
+        udp_conf.validate().unwrap();
+        let udp_params = UdpParams::from(&udp_conf);
+        assert_eq!(udp_params.listeners[0].address, "[::]:53".parse().unwrap());
+        assert!(!udp_params.listeners[0].ipv6_only);
+        assert_eq!((udp_params.sockets_per_listener, udp_params.batch_size), (1, 1));
+        assert_eq!(udp_params.queue.max_depth, 100);
+        assert_eq!(udp_params.queue.max_age, Some(time::Duration::from_millis(500)));
+        assert_eq!(udp_params.overload_action, OverloadAction::ServFail);
+
+        let mut invalid = udp_conf.clone();
+        invalid.listeners[1].ipv6_only = true;
+        assert!(invalid.validate().is_err());
+        let mut invalid = udp_conf.clone();
+        invalid.listeners.push(invalid.listeners[0].clone());
+        assert!(invalid.validate().is_err());
+        let mut invalid = udp_conf;
+        invalid.queue = Some(QueueConf { max_depth: 0, max_age_ms: 0 });
+        assert!(invalid.validate().is_err());
+
+        let tcp_conf: TcpServerConf = serde_json::from_str(
+            r#"{
+                "listeners": [{"address": "127.0.0.1", "port": 53}],
+                "read_timeout": 2,
+                "write_timeout": 2,
+                "threads": 4
+            }"#,
+        )
+        .unwrap();
+        tcp_conf.validate().unwrap();
+        assert_eq!((tcp_conf.idle_timeout, tcp_conf.max_connections), (10, 256));
+
+        let acl_conf = AclConf {
+            allow: None,
+            deny: vec!["10.0.0.0/8".to_string()],
+        };
+        acl_conf.validate("query").unwrap();
+        let acl = Acl::from(&acl_conf);
+        assert!(!acl.allows("10.1.1.1".parse().unwrap()));
+        assert!(acl.allows("192.168.1.1".parse().unwrap()));
+        let acl_conf = AclConf {
+            allow: Some(vec!["10.0.0.0/33".to_string()]),
+            deny: vec![],
+        };
+        assert!(acl_conf.validate("query").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+#[cfg(unix)]
+use std::io::{BufRead, Read, Write};
+#[cfg(unix)]
+use std::os::unix::{fs::FileTypeExt, fs::PermissionsExt, io::AsRawFd, net};
+use std::sync::Arc;
+use std::{io, path, thread, time};
+
+// The longest command line accepted by the control server.
+const MAX_COMMAND_LEN: u64 = 4096;
+
+/// The parameters of the control server started via [start_control_server]: the
+/// path of the Unix socket and the time allowed to clients to send their command.
+#[derive(Clone, Debug)]
+pub struct ControlParams {
+    pub path: path::PathBuf,
+    pub read_timeout: time::Duration,
+}
+
+/// The handler of the commands received by the control server. Commands are
+/// lines of words separated by spaces, the first one is the name of the command.
+/// The handler returns the output of the command or an error message.
+pub trait ControlHandler: Send + Sync + 'static {
+    fn handle_command(&self, command: &str, args: &[&str]) -> Result<String, String>;
+}
+
+/// Starts the control server on a new thread, listening on a Unix socket. A stale
+/// socket left at the path is replaced, while other files are never removed. The
+/// socket is accessible only by its owner, the user starting the process, and
+/// clients are authenticated with their credentials: only root and the user owning
+/// the socket (or running the process, after dropping privileges) are served.
+///
+/// The protocol is a single request and response per connection: the client sends
+/// the command line terminated by a newline, the server replies with `OK` followed
+/// by the output of the command on the next lines, or with `ERR` and the error
+/// message on the same line, then closes the connection. See [send_command].
+#[cfg(unix)]
+pub fn start_control_server<H: ControlHandler>(params: ControlParams, handler: Arc<H>) -> io::Result<()> {
+    match std::fs::symlink_metadata(&params.path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&params.path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "path exists and is not a socket",
+            ))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    let listener = net::UnixListener::bind(&params.path)?;
+    std::fs::set_permissions(&params.path, std::fs::Permissions::from_mode(0o600))?;
+    // Safety: the function has no preconditions and cannot fail.
+    let owner = unsafe { libc::geteuid() };
+    log::info!("Starting control server, socket: '{}'.", params.path.display());
+
+    thread::Builder::new().name("control".to_string()).spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(v) => v,
+                Err(err) => {
+                    log::warn!("Accepting control connection: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = handle_connection(stream, &params, owner, &*handler) {
+                log::warn!("Serving control connection: {}", err);
+            }
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn start_control_server<H: ControlHandler>(_params: ControlParams, _handler: Arc<H>) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(unix)]
+fn handle_connection<H: ControlHandler>(
+    mut stream: net::UnixStream,
+    params: &ControlParams,
+    owner: libc::uid_t,
+    handler: &H,
+) -> io::Result<()> {
+    let uid = peer_uid(&stream)?;
+    // Safety: the function has no preconditions and cannot fail.
+    if uid != 0 && uid != owner && uid != unsafe { libc::geteuid() } {
+        log::warn!("Control connection from user {} denied.", uid);
+        return stream.write_all(b"ERR permission denied\n");
+    }
+
+    stream.set_read_timeout(Some(params.read_timeout))?;
+    let mut line = String::new();
+    io::BufReader::new(Read::by_ref(&mut stream).take(MAX_COMMAND_LEN)).read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let response = match words.next() {
+        None => "ERR empty command\n".to_string(),
+        Some(command) => {
+            log::info!("Control command from user {}: '{}'.", uid, line.trim());
+            let args: Vec<&str> = words.collect();
+            match handler.handle_command(command, &args) {
+                Ok(output) if output.is_empty() || output.ends_with('\n') => format!("OK\n{}", output),
+                Ok(output) => format!("OK\n{}\n", output),
+                Err(err) => format!("ERR {}\n", err.replace('\n', " ")),
+            }
+        }
+    };
+    stream.write_all(response.as_bytes())
+}
+
+// Return the user id of the process connected to the socket.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &net::UnixStream) -> io::Result<libc::uid_t> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // Safety: the credentials struct outlives the call and its size is passed.
+    let code = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    match code {
+        0 => Ok(cred.uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+fn peer_uid(stream: &net::UnixStream) -> io::Result<libc::uid_t> {
+    let (mut uid, mut gid) = (0, 0);
+    // Safety: the ids outlive the call.
+    match unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } {
+        0 => Ok(uid),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Sends the command line to the control server listening at `path`, waiting at most
+/// `timeout` for the response. Returns the output of the command, or the error message
+/// sent by the server. See [start_control_server] for the protocol.
+#[cfg(unix)]
+pub fn send_command(path: &path::Path, command: &str, timeout: time::Duration) -> io::Result<Result<String, String>> {
+    let mut stream = net::UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(format!("{}\n", command.trim()).as_bytes())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    match response.split_once('\n') {
+        Some(("OK", output)) => Ok(Ok(output.to_string())),
+        Some((error, _)) if error.starts_with("ERR ") => Ok(Err(error[4..].to_string())),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "malformed control response")),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn send_command(
+    _path: &path::Path,
+    _command: &str,
+    _timeout: time::Duration,
+) -> io::Result<Result<String, String>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    struct EchoHandler;
+
+    impl ControlHandler for EchoHandler {
+        fn handle_command(&self, command: &str, args: &[&str]) -> Result<String, String> {
+            match command {
+                "echo" => Ok(args.join(" ")),
+                "lines" => Ok("first\nsecond\n".to_string()),
+                _ => Err(format!("unknown command '{}'", command)),
+            }
+        }
+    }
+
+    #[test]
+    fn test_control_server() {
+        let dir = env::temp_dir().join(format!("ariadne-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("control.sock");
+        let params = ControlParams {
+            path: path.clone(),
+            read_timeout: time::Duration::from_secs(1),
+        };
+        start_control_server(params.clone(), Arc::new(EchoHandler)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let timeout = time::Duration::from_secs(1);
+        assert_eq!(
+            send_command(&path, "echo a  b", timeout).unwrap(),
+            Ok("a b\n".to_string())
+        );
+        assert_eq!(
+            send_command(&path, "lines", timeout).unwrap(),
+            Ok("first\nsecond\n".to_string())
+        );
+        let err = send_command(&path, "flush", timeout).unwrap();
+        assert_eq!(err, Err("unknown command 'flush'".to_string()));
+        let err = send_command(&path, "", timeout).unwrap();
+        assert_eq!(err, Err("empty command".to_string()));
+
+        // Stale sockets are replaced, other files are kept.
+        start_control_server(params.clone(), Arc::new(EchoHandler)).unwrap();
+        assert_eq!(send_command(&path, "echo c", timeout).unwrap(), Ok("c\n".to_string()));
+        let file_params = ControlParams { path: dir.join("file"), ..params };
+        fs::write(&file_params.path, "data").unwrap();
+        assert!(start_control_server(file_params.clone(), Arc::new(EchoHandler)).is_err());
+        assert_eq!(fs::read_to_string(&file_params.path).unwrap(), "data");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,6 +1,6 @@
 /// Classes of the domain name system. Only the internet (IN) class
 /// is supported in the project since other ones are unused/obsolete.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Class {
     IN,
     CS,
@@ -0,0 +1,157 @@
+use crate::shared::buffer::*;
+use crate::shared::dns::errors::*;
+use crate::shared::dns::utils::*;
+
+/// The type number of the OPT pseudo-record carrying EDNS data.
+pub const OPT_RECORD_TYPE: u16 = 41;
+/// The code of the edns-tcp-keepalive option (RFC 7828).
+pub const TCP_KEEPALIVE_OPT_CODE: u16 = 11;
+
+/// EDNS data (RFC 6891) carried by the OPT pseudo-record in the additional
+/// section of a dns message. Note that the OPT record is not considered part
+/// of the [Record](crate::shared::dns::Record)s of the message and it is not
+/// counted in the `additionals_count` of the [Header](crate::shared::dns::Header).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub ext_resp_code: u8,
+    pub version: u8,
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+/// The options carried in the data of the OPT pseudo-record. Options
+/// not explicitly supported are kept as raw bytes along with their code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdnsOption {
+    /// The edns-tcp-keepalive option. The timeout is expressed in units
+    /// of 100 milliseconds and must be omitted in client queries.
+    TcpKeepalive(Option<u16>),
+    Unknown(u16, Vec<u8>),
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Edns {
+            udp_payload_size: 1232,
+            ext_resp_code: 0,
+            version: 0,
+            dnssec_ok: false,
+            options: vec![],
+        }
+    }
+}
+
+impl Edns {
+    const DNSSEC_OK_MASK: u16 = 0b1000_0000_0000_0000;
+
+    /// Reports if the next bytes of the buffer contain an OPT pseudo-record.
+    /// The reading position of the buffer is not modified.
+    pub fn is_next_in_buf(buffer: &BytesReader) -> bool {
+        let mut peek = buffer.clone();
+        peek.read_u8() == Some(0) && peek.read_u16() == Some(OPT_RECORD_TYPE)
+    }
+
+    /// Decode an OPT pseudo-record from the bytes read from the passed buffer.
+    /// The owner name must be the root one, and options must fill exactly
+    /// the record data. Unknown options are preserved.
+    pub fn decode_from_buf(buffer: &mut BytesReader) -> Result<Edns, ParsingErr> {
+        if check_end(buffer.read_u8())? != 0 || check_end(buffer.read_u16())? != OPT_RECORD_TYPE {
+            return Err(ParsingErr::EdnsErr("not an OPT record".to_string()));
+        }
+        let udp_payload_size = check_end(buffer.read_u16())?;
+        let ext_resp_code = check_end(buffer.read_u8())?;
+        let version = check_end(buffer.read_u8())?;
+        let flags = check_end(buffer.read_u16())?;
+        let data_len = check_end(buffer.read_u16())?;
+        let mut data = BytesReader::new(check_end(buffer.read_slice(data_len as usize))?);
+
+        let mut options = vec![];
+        while data.remaining() > 0 {
+            let code = check_end(data.read_u16())?;
+            let len = check_end(data.read_u16())?;
+            let opt_data = check_end(data.read_slice(len as usize)).map_err(|_| ParsingErr::DataLenMismatch)?;
+            let option = match (code, len) {
+                (TCP_KEEPALIVE_OPT_CODE, 0) => EdnsOption::TcpKeepalive(None),
+                (TCP_KEEPALIVE_OPT_CODE, 2) => {
+                    EdnsOption::TcpKeepalive(Some(u16::from_be_bytes([opt_data[0], opt_data[1]])))
+                }
+                (TCP_KEEPALIVE_OPT_CODE, _) => return Err(ParsingErr::EdnsErr("invalid keepalive len".to_string())),
+                _ => EdnsOption::Unknown(code, opt_data.to_vec()),
+            };
+            options.push(option);
+        }
+
+        Ok(Edns {
+            udp_payload_size,
+            ext_resp_code,
+            version,
+            dnssec_ok: flags & Self::DNSSEC_OK_MASK != 0,
+            options,
+        })
+    }
+
+    /// Encode the OPT pseudo-record to raw bytes, writing them into the buffer.
+    pub fn encode_to_buf(&self, buffer: &mut BytesWriter) {
+        buffer.write_u8(0);
+        buffer.write_u16(OPT_RECORD_TYPE);
+        buffer.write_u16(self.udp_payload_size);
+        buffer.write_u8(self.ext_resp_code);
+        buffer.write_u8(self.version);
+        buffer.write_u16(if self.dnssec_ok { Self::DNSSEC_OK_MASK } else { 0 });
+
+        let len_pos = buffer.pos();
+        buffer.write_u16(0);
+        for option in &self.options {
+            match option {
+                EdnsOption::TcpKeepalive(None) => {
+                    buffer.write_u16(TCP_KEEPALIVE_OPT_CODE);
+                    buffer.write_u16(0);
+                }
+                EdnsOption::TcpKeepalive(Some(timeout)) => {
+                    buffer.write_u16(TCP_KEEPALIVE_OPT_CODE);
+                    buffer.write_u16(2);
+                    buffer.write_u16(*timeout);
+                }
+                EdnsOption::Unknown(code, data) => {
+                    buffer.write_u16(*code);
+                    buffer.write_u16(data.len() as u16);
+                    buffer.write_bytes(data);
+                }
+            }
+        }
+
+        let end_pos = buffer.pos();
+        buffer.set_pos(len_pos);
+        buffer.write_u16((end_pos - len_pos - 2) as u16);
+        buffer.set_pos(end_pos);
+    }
+
+    /// Returns the length in bytes of the encoded OPT pseudo-record.
+    pub fn encoded_len(&self) -> usize {
+        let options_len: usize = self
+            .options
+            .iter()
+            .map(|opt| match opt {
+                EdnsOption::TcpKeepalive(None) => 4,
+                EdnsOption::TcpKeepalive(Some(_)) => 6,
+                EdnsOption::Unknown(_, data) => 4 + data.len(),
+            })
+            .sum();
+        11 + options_len
+    }
+
+    /// Returns true if the edns-tcp-keepalive option is present.
+    pub fn has_tcp_keepalive(&self) -> bool {
+        self.options
+            .iter()
+            .any(|opt| matches!(opt, EdnsOption::TcpKeepalive(_)))
+    }
+
+    /// Sets the edns-tcp-keepalive option with the passed timeout
+    /// (in units of 100 milliseconds), replacing the existing one.
+    pub fn set_tcp_keepalive(&mut self, timeout: Option<u16>) {
+        self.options.retain(|opt| !matches!(opt, EdnsOption::TcpKeepalive(_)));
+        self.options.push(EdnsOption::TcpKeepalive(timeout));
+    }
+}
@@ -44,6 +44,7 @@ pub enum ParsingErr {
 
     DomainNameErr(NameErr),
     StringCharErr(String),
+    EdnsErr(String),
 }
 
 impl From<NameErr> for ParsingErr {
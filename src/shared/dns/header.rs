@@ -44,32 +44,35 @@ impl Default for Header {
 }
 
 impl Header {
+    // Masks of the flags packed in the second 16 bits word of the header.
+    const QR_MASK: u16 = 0b1000_0000_0000_0000;
+    const OP_CODE_MASK: u16 = 0b0111_1000_0000_0000;
+    const AA_MASK: u16 = 0b0000_0100_0000_0000;
+    const TC_MASK: u16 = 0b0000_0010_0000_0000;
+    const RD_MASK: u16 = 0b0000_0001_0000_0000;
+    const RA_MASK: u16 = 0b0000_0000_1000_0000;
+    const Z_MASK: u16 = 0b0000_0000_0111_0000;
+    const RESP_CODE_MASK: u16 = 0b0000_0000_0000_1111;
+
     /// Decode a dns message [`Header`] from the bytes read from the provided buffer.
     /// Unsupported op/resp codes are detected and an appropriate error is returned.
-    pub fn decode_from_buf(buffer: &mut BitsBuf) -> Result<Header, ParsingErr> {
+    pub fn decode_from_buf(buffer: &mut BytesReader) -> Result<Header, ParsingErr> {
         let id = check_end(buffer.read_u16())?;
-        let query_resp = check_end(buffer.read_bits(1))? == 1;
-        let op_code = decode_op_code(check_end(buffer.read_bits(4))?, true)?;
-        let auth_answer = check_end(buffer.read_bits(1))? == 1;
-        let truncated = check_end(buffer.read_bits(1))? == 1;
-        let recursion_desired = check_end(buffer.read_bits(1))? == 1;
-        let recursion_available = check_end(buffer.read_bits(1))? == 1;
-        let z = check_end(buffer.read_bits(3))?;
-        let resp_code = decode_resp_code(check_end(buffer.read_bits(4))?)?;
+        let flags = check_end(buffer.read_u16())?;
         let questions_count = check_end(buffer.read_u16())?;
         let answers_count = check_end(buffer.read_u16())?;
         let authorities_count = check_end(buffer.read_u16())?;
         let additionals_count = check_end(buffer.read_u16())?;
         Ok(Header {
             id,
-            query_resp,
-            op_code,
-            auth_answer,
-            truncated,
-            recursion_desired,
-            recursion_available,
-            z,
-            resp_code,
+            query_resp: flags & Self::QR_MASK != 0,
+            op_code: decode_op_code(((flags & Self::OP_CODE_MASK) >> 11) as u8, true)?,
+            auth_answer: flags & Self::AA_MASK != 0,
+            truncated: flags & Self::TC_MASK != 0,
+            recursion_desired: flags & Self::RD_MASK != 0,
+            recursion_available: flags & Self::RA_MASK != 0,
+            z: ((flags & Self::Z_MASK) >> 4) as u8,
+            resp_code: decode_resp_code((flags & Self::RESP_CODE_MASK) as u8)?,
             questions_count,
             answers_count,
             authorities_count,
@@ -80,24 +83,26 @@ impl Header {
     /// Decode a dns message [`Header`] from the passed bytes slice. It is a
     /// wrapper around [Header::decode_from_buf] method which needs a buffer.
     pub fn decode_from_bytes(bytes: &[u8]) -> Result<Header, ParsingErr> {
-        let mut buffer = BitsBuf::from_raw_bytes(bytes);
+        let mut buffer = BytesReader::new(bytes);
         Header::decode_from_buf(&mut buffer)
     }
 
     /// Encode a dns [`Header`] to raw bytes, writing them into the provided
     /// buffer. The function panics if some unsupported op codes are provided
     /// (this helps maintaining invariants about supported features).
-    pub fn encode_to_buf(&self, buffer: &mut BitsBuf) {
+    pub fn encode_to_buf(&self, buffer: &mut BytesWriter) {
         assert!(self.op_code.is_supported());
+        let mut flags = 0_u16;
+        flags |= (self.query_resp as u16) << 15;
+        flags |= ((self.op_code.to_num() & 0b1111) as u16) << 11;
+        flags |= (self.auth_answer as u16) << 10;
+        flags |= (self.truncated as u16) << 9;
+        flags |= (self.recursion_desired as u16) << 8;
+        flags |= (self.recursion_available as u16) << 7;
+        flags |= ((self.z & 0b111) as u16) << 4;
+        flags |= (self.resp_code.to_num() & 0b1111) as u16;
         buffer.write_u16(self.id);
-        buffer.write_bits(self.query_resp as u8, 1);
-        buffer.write_bits(self.op_code.to_num(), 4);
-        buffer.write_bits(self.auth_answer as u8, 1);
-        buffer.write_bits(self.truncated as u8, 1);
-        buffer.write_bits(self.recursion_desired as u8, 1);
-        buffer.write_bits(self.recursion_available as u8, 1);
-        buffer.write_bits(self.z, 3);
-        buffer.write_bits(self.resp_code.to_num(), 4);
+        buffer.write_u16(flags);
         buffer.write_u16(self.questions_count);
         buffer.write_u16(self.answers_count);
         buffer.write_u16(self.authorities_count);
@@ -133,13 +138,13 @@ impl Header {
 
     /// Tells if a [`Header`] represents a request.
     pub fn is_request(&self) -> bool {
-        self.query_resp == false
+        !self.query_resp
     }
 }
 
 /// The response code is a code present in the [`Header`] and it's used
 /// to inform the client about the outcome of the query.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RespCode {
     NoError,
     FormErr,
@@ -162,7 +167,7 @@ impl RespCode {
         }
     }
 
-    fn to_num(&self) -> u8 {
+    fn to_num(self) -> u8 {
         match self {
             RespCode::NoError => 0,
             RespCode::FormErr => 1,
@@ -205,9 +210,6 @@ impl OpCode {
 
     /// Try to generate a [`OpCode`] from its raw string representation.
     fn is_supported(&self) -> bool {
-        match self {
-            OpCode::STD => true,
-            _ => false,
-        }
+        matches!(self, OpCode::STD)
     }
 }
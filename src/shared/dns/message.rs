@@ -1,5 +1,6 @@
 use crate::shared::buffer::*;
 use crate::shared::dns;
+use crate::shared::dns::edns::*;
 use crate::shared::dns::errors::*;
 use crate::shared::dns::header::*;
 use crate::shared::dns::questions::*;
@@ -9,14 +10,16 @@ pub const MAX_UDP_LEN_BYTES: usize = 512;
 
 /// Represents a complete dns message. Contains the [`Header`], which fields
 /// must be concordant with the [`Question`]s and [`Record`]s carried in the other
-/// message fields ().
-#[derive(Debug)]
+/// message fields. The EDNS OPT pseudo-record, if any, is kept apart in the `edns`
+/// field and it is not counted in the header `additionals_count`.
+#[derive(Debug, Clone)]
 pub struct Message {
     pub header: Header,
     pub questions: Vec<Question>,
     pub answers: Vec<Record>,
     pub authorities: Vec<Record>,
     pub additionals: Vec<Record>,
+    pub edns: Option<Edns>,
 }
 
 impl Message {
@@ -25,9 +28,9 @@ impl Message {
     /// types still cause its record/question bytes to be consumed. In general we
     /// want to make sure no unsupported features enters or exits the system.
     pub fn decode_from_bytes(bytes: &[u8]) -> Result<Message, MessageErr> {
-        let mut buffer = BitsBuf::from_raw_bytes(&bytes);
+        let mut buffer = BytesReader::new(bytes);
 
-        let header = match Header::decode_from_buf(&mut buffer) {
+        let mut header = match Header::decode_from_buf(&mut buffer) {
             Err(err) => return Err(MessageErr::HeaderErr(err)),
             Ok(header) => header,
         };
@@ -39,6 +42,7 @@ impl Message {
         let mut answers = Vec::with_capacity(header.answers_count as usize);
         let mut authorities = Vec::with_capacity(header.authorities_count as usize);
         let mut additionals = Vec::with_capacity(header.additionals_count as usize);
+        let mut edns = None;
 
         for i in 0..header.questions_count as usize {
             let decoded_question = Question::decode_from_buf(&mut buffer);
@@ -65,6 +69,17 @@ impl Message {
             };
         }
         for i in 0..header.additionals_count as usize {
+            if Edns::is_next_in_buf(&buffer) {
+                if edns.is_some() {
+                    let err = ParsingErr::EdnsErr("multiple OPT records".to_string());
+                    return Err(MessageErr::AdditionalErr(i, err));
+                }
+                match Edns::decode_from_buf(&mut buffer) {
+                    Err(err) => return Err(MessageErr::AdditionalErr(i, err)),
+                    Ok(v) => edns = Some(v),
+                };
+                continue;
+            }
             let decoded_additional = Record::decode_from_buf(&mut buffer);
             match decoded_additional {
                 Err(ParsingErr::UnknownType(_)) => continue,
@@ -73,12 +88,17 @@ impl Message {
             };
         }
 
+        if edns.is_some() {
+            header.additionals_count -= 1;
+        }
+
         Ok(Message {
             header,
             questions,
             answers,
             authorities,
             additionals,
+            edns,
         })
     }
 
@@ -86,8 +106,8 @@ impl Message {
     /// function panics if some unsupported class or types are provided (to
     /// maintain invariants about supported features).
     pub fn encode_to_bytes(&self) -> Result<Vec<u8>, MessageErr> {
-        let mut buffer = BitsBuf::new();
-        self.header.encode_to_buf(&mut buffer);
+        let mut buffer = BytesWriter::with_capacity(MAX_UDP_LEN_BYTES);
+        buffer.write_bytes(&[0; 12]);
 
         for i in 0..self.header.questions_count as usize {
             match self.questions[i].encode_to_buf(&mut buffer) {
@@ -114,14 +134,15 @@ impl Message {
             }
         }
 
-        Ok(buffer.into_vec())
+        Ok(self.finish_encoding(buffer, self.header.clone()))
     }
 
     /// Encode a dns [`Message`] to raw bytes, returning a bytes vector. The message
-    /// is truncated before reaching [`MAX_UDP_LEN`] bytes of length (512 bytes). In
-    /// this case the header is modified appropriately.
+    /// is truncated before reaching [`MAX_UDP_LEN_BYTES`] bytes of length (512 bytes). In
+    /// this case the header is modified appropriately. The OPT record is always kept.
     pub fn encode_to_bytes_trunc(&self) -> Result<Vec<u8>, MessageErr> {
-        let mut buffer = BitsBuf::new();
+        let max_len = MAX_UDP_LEN_BYTES - self.edns.as_ref().map_or(0, |e| e.encoded_len());
+        let mut buffer = BytesWriter::with_capacity(MAX_UDP_LEN_BYTES);
         buffer.write_bytes(&[0; 12]);
         let mut header = dns::Header {
             questions_count: 0,
@@ -132,76 +153,78 @@ impl Message {
         };
 
         for i in 0..self.header.questions_count as usize {
-            let w_pos = buffer.write_pos();
+            let w_pos = buffer.pos();
             match self.questions[i].encode_to_buf(&mut buffer) {
                 Err(err) => return Err(MessageErr::QuestionErr(i, err)),
                 Ok(v) => v,
             };
-            if buffer.write_pos() / 8 > MAX_UDP_LEN_BYTES {
-                buffer.set_write_pos(0);
-                header.truncated = true;
-                header.encode_to_buf(&mut buffer);
+            if buffer.pos() > max_len {
                 buffer.truncate(w_pos);
-                return Ok(buffer.into_vec());
+                header.truncated = true;
+                return Ok(self.finish_encoding(buffer, header));
             } else {
                 header.questions_count += 1;
             }
         }
 
         for i in 0..self.header.answers_count as usize {
-            let w_pos = buffer.write_pos();
+            let w_pos = buffer.pos();
             match self.answers[i].encode_to_buf(&mut buffer) {
                 Err(err) => return Err(MessageErr::AnswerErr(i, err)),
                 Ok(v) => v,
             };
-            if buffer.write_pos() / 8 > MAX_UDP_LEN_BYTES {
-                buffer.set_write_pos(0);
-                header.truncated = true;
-                header.encode_to_buf(&mut buffer);
+            if buffer.pos() > max_len {
                 buffer.truncate(w_pos);
-                return Ok(buffer.into_vec());
+                header.truncated = true;
+                return Ok(self.finish_encoding(buffer, header));
             } else {
                 header.answers_count += 1;
             }
         }
 
         for i in 0..self.header.authorities_count as usize {
-            let w_pos = buffer.write_pos();
+            let w_pos = buffer.pos();
             match self.authorities[i].encode_to_buf(&mut buffer) {
                 Err(err) => return Err(MessageErr::AuthorityErr(i, err)),
                 Ok(v) => v,
             }
-            if buffer.write_pos() / 8 > MAX_UDP_LEN_BYTES {
-                buffer.set_write_pos(0);
-                header.truncated = true;
-                header.encode_to_buf(&mut buffer);
+            if buffer.pos() > max_len {
                 buffer.truncate(w_pos);
-                return Ok(buffer.into_vec());
+                header.truncated = true;
+                return Ok(self.finish_encoding(buffer, header));
             } else {
                 header.authorities_count += 1;
             }
         }
 
         for i in 0..self.header.additionals_count as usize {
-            let w_pos = buffer.write_pos();
+            let w_pos = buffer.pos();
             match self.additionals[i].encode_to_buf(&mut buffer) {
                 Err(err) => return Err(MessageErr::AdditionalErr(i, err)),
                 Ok(v) => v,
             };
-            if buffer.write_pos() / 8 > MAX_UDP_LEN_BYTES {
-                buffer.set_write_pos(0);
-                header.truncated = true;
-                header.encode_to_buf(&mut buffer);
+            if buffer.pos() > max_len {
                 buffer.truncate(w_pos);
-                return Ok(buffer.into_vec());
+                header.truncated = true;
+                return Ok(self.finish_encoding(buffer, header));
             } else {
                 header.additionals_count += 1;
             }
         }
 
-        buffer.set_write_pos(0);
+        Ok(self.finish_encoding(buffer, header))
+    }
+
+    // Append the OPT record (if any) to the encoded sections, then write
+    // the passed header at the beginning of the buffer, adjusting counts.
+    fn finish_encoding(&self, mut buffer: BytesWriter, mut header: Header) -> Vec<u8> {
+        if let Some(edns) = &self.edns {
+            edns.encode_to_buf(&mut buffer);
+            header.additionals_count += 1;
+        }
+        buffer.set_pos(0);
         header.encode_to_buf(&mut buffer);
-        Ok(buffer.into_vec())
+        buffer.into_vec()
     }
 }
 
@@ -209,4 +232,157 @@ impl Message {
     pub fn id(&self) -> u16 {
         self.header.id
     }
+
+    /// Returns the EDNS data to be attached to the response to this
+    /// request. As per RFC 6891, an OPT record is included in responses
+    /// only if the request carried one.
+    pub fn response_edns(&self) -> Option<Edns> {
+        self.edns.as_ref().map(|_| Edns::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shared::dns::*;
+
+    const QUERY: &[u8] = include_bytes!("../../../assets/messages/query_packet_bin.txt");
+    const RESPONSE: &[u8] = include_bytes!("../../../assets/messages/response_packet_bin.txt");
+
+    #[test]
+    fn test_decode_encode_query() {
+        let query = Message::decode_from_bytes(QUERY).unwrap();
+        assert_eq!(query.id(), 0x39f4);
+        assert!(query.header.is_request());
+        assert!(query.header.recursion_desired);
+        assert_eq!(query.header.questions_count, 1);
+        assert_eq!(query.questions[0].node.as_ref(), "google.com.");
+        assert_eq!(query.questions[0].record_type, RecordType::A);
+        assert_eq!(query.encode_to_bytes().unwrap(), QUERY);
+        assert_eq!(query.encode_to_bytes_trunc().unwrap(), QUERY);
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let response = Message::decode_from_bytes(RESPONSE).unwrap();
+        assert_eq!(response.id(), 0x39f4);
+        assert!(!response.header.is_request());
+        assert_eq!(response.questions.len(), 1);
+        assert_eq!(response.answers.len(), 0);
+        assert_eq!(response.authorities.len(), 13);
+        assert!(response.authorities.iter().all(|r| r.record_type() == RecordType::NS));
+        assert_eq!(response.authorities[0].ns_data().as_ref(), "e.gtld-servers.net.");
+        assert_eq!(response.authorities[1].ns_data().as_ref(), "b.gtld-servers.net.");
+
+        // AAAA records are unknown and skipped, only A ones are kept.
+        assert_eq!(response.additionals.len(), 6);
+        assert_eq!(response.additionals[0].node().as_ref(), "e.gtld-servers.net.");
+        assert_eq!(response.additionals[0].a_data(), &[192, 12, 94, 30]);
+    }
+
+    #[test]
+    fn test_display_records() {
+        let response = Message::decode_from_bytes(RESPONSE).unwrap();
+        let ns = &response.authorities[0];
+        assert_eq!(ns.format_data(), "e.gtld-servers.net.");
+        assert_eq!(
+            ns.to_string(),
+            format!("com.\t{}\tIN\tNS\te.gtld-servers.net.", ns.ttl())
+        );
+        assert_eq!(response.additionals[0].format_data(), "192.12.94.30");
+
+        let txt = Record::TXT {
+            node: Name::from_string("example.com.").unwrap(),
+            class: Class::IN,
+            ttl: 60,
+            data_len: 0,
+            txts: vec!["a \"quoted\" text".to_string(), "b".to_string()],
+        };
+        assert_eq!(
+            txt.to_string(),
+            "example.com.\t60\tIN\tTXT\t\"a \\\"quoted\\\" text\" \"b\""
+        );
+    }
+
+    #[test]
+    fn test_decode_bad_pointer() {
+        let mut bytes = QUERY.to_vec();
+        bytes.truncate(12);
+        bytes.extend([0b1100_0000, 0xff, 0, 1, 0, 1]);
+        let err = Message::decode_from_bytes(&bytes).unwrap_err();
+        assert!(matches!(
+            err,
+            MessageErr::QuestionErr(0, ParsingErr::DomainNameErr(NameErr::PointerOutOfBonds))
+        ));
+    }
+
+    #[test]
+    fn test_encode_trunc() {
+        let record = Record::TXT {
+            node: Name::from_string("txt.example.com.").unwrap(),
+            class: Class::IN,
+            ttl: 100,
+            data_len: 0,
+            txts: vec!["a".repeat(100)],
+        };
+        let mut message = Message::decode_from_bytes(QUERY).unwrap();
+        message.header.query_resp = true;
+        message.header.answers_count = 10;
+        message.answers = vec![record; 10];
+
+        let bytes = message.encode_to_bytes_trunc().unwrap();
+        assert!(bytes.len() <= MAX_UDP_LEN_BYTES);
+        let truncated = Message::decode_from_bytes(&bytes).unwrap();
+        assert!(truncated.header.truncated);
+        assert_eq!(truncated.answers.len(), 3);
+        assert_eq!(truncated.header.answers_count, 3);
+        let bytes = message.encode_to_bytes().unwrap();
+        assert_eq!(Message::decode_from_bytes(&bytes).unwrap().answers.len(), 10);
+    }
+
+    #[test]
+    fn test_decode_encode_edns() {
+        let mut bytes = QUERY.to_vec();
+        bytes[11] = 1;
+        bytes.extend([0, 0, 41, 0x10, 0x00, 0, 0, 0x80, 0, 0, 8]);
+        bytes.extend([0, 11, 0, 0, 0, 12, 0, 0]);
+        let query = Message::decode_from_bytes(&bytes).unwrap();
+        assert_eq!(query.header.additionals_count, 0);
+        assert!(query.additionals.is_empty());
+
+        let edns = query.edns.as_ref().unwrap();
+        assert_eq!(edns.udp_payload_size, 4096);
+        assert!(edns.dnssec_ok);
+        assert!(edns.has_tcp_keepalive());
+        assert_eq!(edns.options[1], EdnsOption::Unknown(12, vec![]));
+        assert_eq!(query.encode_to_bytes().unwrap(), bytes);
+        assert_eq!(query.encode_to_bytes_trunc().unwrap(), bytes);
+
+        bytes[11] = 2;
+        bytes.extend_from_within(QUERY.len()..);
+        let err = Message::decode_from_bytes(&bytes).unwrap_err();
+        assert!(matches!(err, MessageErr::AdditionalErr(1, ParsingErr::EdnsErr(_))));
+    }
+
+    #[test]
+    fn test_encode_trunc_edns() {
+        let record = Record::TXT {
+            node: Name::from_string("txt.example.com.").unwrap(),
+            class: Class::IN,
+            ttl: 100,
+            data_len: 0,
+            txts: vec!["a".repeat(100)],
+        };
+        let mut message = Message::decode_from_bytes(QUERY).unwrap();
+        message.header.answers_count = 4;
+        message.answers = vec![record; 4];
+        message.edns = Some(Edns::default());
+        message.edns.as_mut().unwrap().set_tcp_keepalive(Some(100));
+
+        let bytes = message.encode_to_bytes_trunc().unwrap();
+        assert!(bytes.len() <= MAX_UDP_LEN_BYTES);
+        let truncated = Message::decode_from_bytes(&bytes).unwrap();
+        assert!(truncated.header.truncated);
+        assert_eq!(truncated.answers.len(), 3);
+        assert_eq!(truncated.edns, message.edns);
+    }
 }
@@ -1,4 +1,5 @@
 mod class;
+mod edns;
 mod errors;
 mod header;
 mod message;
@@ -9,6 +10,7 @@ mod types;
 mod utils;
 
 pub use class::*;
+pub use edns::*;
 pub use errors::*;
 pub use header::*;
 pub use message::*;
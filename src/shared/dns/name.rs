@@ -1,7 +1,6 @@
 use crate::shared::buffer::*;
 use serde::{Deserialize, Serialize};
 use std::fmt::{self, Display, Formatter};
-use std::str;
 
 /// A wrapper for domain names. The [`Name`] struct is used to hold valid
 /// absolute domain names. This is the invariant that must be guaranteed
@@ -36,8 +35,8 @@ impl Name {
 
     /// Creates a [`Name`] parsing its binary representation (a series of labels,
     /// divided by a length byte). There's a max number of jumps allowed (for
-    /// security reasons).
-    pub fn from_bytes(buffer: &mut BitsBuf) -> Result<Self, NameErr> {
+    /// security reasons) and pointers must refer to data inside the buffer.
+    pub fn from_bytes(buffer: &mut BytesReader) -> Result<Self, NameErr> {
         let mut name_bytes: Vec<u8> = Vec::with_capacity(100);
         let mut pos_after_jump: usize = 0;
         let mut n_jumps: u16 = 0;
@@ -50,13 +49,14 @@ impl Name {
                 0b11000000 => {
                     match n_jumps {
                         v if v > Self::MAX_REDIR => return Err(NameErr::MaxRedir),
-                        0 => pos_after_jump = buffer.read_pos() + 8,
+                        0 => pos_after_jump = buffer.pos() + 1,
                         _ => {}
                     }
                     let second_byte = check_end(buffer.read_u8())? as u16;
                     let jump_pos = (((len_byte as u16) << 8) | second_byte) & Self::POINTER_MASK;
-                    let jump_pos = jump_pos * 8;
-                    buffer.set_read_pos(jump_pos as usize);
+                    if buffer.set_pos(jump_pos as usize).is_none() {
+                        return Err(NameErr::PointerOutOfBonds);
+                    }
                     n_jumps += 1;
                 }
                 // Normal label type. Could be found either after
@@ -66,14 +66,14 @@ impl Name {
                         return Err(NameErr::LongLabel);
                     }
                     if len_byte == 0 {
-                        name_bytes.push('.' as u8);
+                        name_bytes.push(b'.');
                         break;
                     }
                     if !name_bytes.is_empty() {
-                        name_bytes.push('.' as u8);
+                        name_bytes.push(b'.');
                     }
-                    let label_bytes = check_end(buffer.read_bytes_vec(len_byte as usize))?;
-                    name_bytes.extend(label_bytes);
+                    let label_bytes = check_end(buffer.read_slice(len_byte as usize))?;
+                    name_bytes.extend_from_slice(label_bytes);
                     if name_bytes.len() > 255 {
                         return Err(NameErr::LongName);
                     }
@@ -86,14 +86,14 @@ impl Name {
 
         // Re-set the position if we followed a pointer.
         if pos_after_jump > 0 {
-            buffer.set_read_pos(pos_after_jump);
+            buffer.set_pos(pos_after_jump).unwrap();
         }
 
-        match str::from_utf8(&name_bytes) {
+        match String::from_utf8(name_bytes) {
             Err(_) => Err(NameErr::MalformedName("not UTF-8")),
             Ok(name) => {
                 validate_name(&name)?;
-                Ok(Self(name.to_string()))
+                Ok(Self(name))
             }
         }
     }
@@ -132,7 +132,7 @@ fn validate_name(name: &str) -> Result<(), NameErr> {
     }
     let name = &name[..name.len() - 1];
     for label in name.split('.') {
-        if label.len() == 0 {
+        if label.is_empty() {
             return Err(NameErr::MalformedLabel("empty label"));
         }
         validate_label(label)?;
@@ -143,7 +143,7 @@ fn validate_name(name: &str) -> Result<(), NameErr> {
 // Validate the label, checking both its length and the characters.
 // The label must already be non empty.
 fn validate_label(label: &str) -> Result<(), NameErr> {
-    if label.len() == 0 {
+    if label.is_empty() {
         return Err(NameErr::MalformedLabel("empty label"));
     }
     let first = label.chars().next().unwrap();
@@ -4,6 +4,8 @@ use crate::shared::dns::errors::*;
 use crate::shared::dns::name::*;
 use crate::shared::dns::types::*;
 use crate::shared::dns::utils::*;
+use std::fmt;
+use std::net::Ipv4Addr;
 
 /// Records present in the answer, authority and additional sections of dns
 /// messages. A dns record refers to a specific node of the name system,
@@ -92,7 +94,7 @@ impl Record {
     /// proper errors. Unknown records types still cause the bytes of that record
     /// to be consumed (and an error is returned as usual).
     #[rustfmt::skip]
-    pub fn decode_from_buf(buffer: &mut BitsBuf) -> Result<Record, ParsingErr> {
+    pub fn decode_from_buf(buffer: &mut BytesReader) -> Result<Record, ParsingErr> {
         let node = Name::from_bytes(buffer)?;
         let rec_type = decode_record_type(buffer)?;
         let class = decode_class(check_end(buffer.read_u16())?)?;
@@ -157,7 +159,7 @@ impl Record {
     /// Wrapper function that allows decoding the record from raw bytes,
     /// opposed to [Record::decode_from_buf] method which needs a buffer.
     pub fn decode_from_bytes(bytes: &[u8]) -> Result<Record, ParsingErr> {
-        let mut buf = BitsBuf::from_raw_bytes(bytes);
+        let mut buf = BytesReader::new(bytes);
         Record::decode_from_buf(&mut buf)
     }
 
@@ -165,7 +167,7 @@ impl Record {
     /// provided buffer. This function panics if some unsupported class or
     /// types are provided (to maintain invariants about supported features).
     #[rustfmt::skip]
-    pub fn encode_to_buf(&self, buffer: &mut BitsBuf) -> Result<(), ParsingErr> {
+    pub fn encode_to_buf(&self, buffer: &mut BytesWriter) -> Result<(), ParsingErr> {
         let node = self.node();
         let class = self.class();
         let ttl = *self.ttl();
@@ -184,13 +186,13 @@ impl Record {
             Record::NS { name, .. } => encode_ns_data(buffer, name)?,
             Record::CNAME { name, .. } => encode_cname_data(buffer, name)?,
             Record::WKS { address, protocol, ports, .. } => encode_wks_data(buffer, address, *protocol, ports),
-            Record::PTR { name, .. } => encode_ptr_data(buffer, &name)?,
+            Record::PTR { name, .. } => encode_ptr_data(buffer, name)?,
             Record::HINFO { cpu, os, .. } => encode_hinfo_data(buffer, cpu, os)?,
             Record::MX { priority, name, .. } => encode_mx_data(buffer, *priority, name)?,
             Record::TXT { txts, .. } => encode_txt_data(buffer, txts)?,
             Record::SOA { ns_name, ml_name, serial, refresh, retry, expire, minimum, .. } => {
                 encode_soa_data(buffer,
-                    (&ns_name, &ml_name, *serial,
+                    (ns_name, ml_name, *serial,
                      *refresh, *retry, *expire, *minimum),
                 )?;
             }
@@ -200,7 +202,7 @@ impl Record {
     }
 }
 
-fn decode_record_type(buffer: &mut BitsBuf) -> Result<RecordType, ParsingErr> {
+fn decode_record_type(buffer: &mut BytesReader) -> Result<RecordType, ParsingErr> {
     match RecordType::from_num(check_end(buffer.read_u16())?) {
         Ok(v) if !v.is_supported_for_records() => Err(ParsingErr::UnsupportedType(v)),
         Ok(v) => Ok(v),
@@ -208,7 +210,7 @@ fn decode_record_type(buffer: &mut BitsBuf) -> Result<RecordType, ParsingErr> {
             check_end(buffer.read_u16())?;
             check_end(buffer.read_u32())?;
             let data_len = check_end(buffer.read_u16())?;
-            check_end(buffer.read_bytes_vec(data_len as usize))?;
+            check_end(buffer.read_slice(data_len as usize))?;
             Err(ParsingErr::UnknownType(n))
         }
     }
@@ -269,6 +271,7 @@ impl Record {
     getter!(class, class, &Class);
     getter!(ttl, ttl, &u32);
     getter!(data_len, data_len, &u16);
+    setter!(node, set_node, Name);
     setter!(ttl, set_ttl, u32);
 
     /// Returns the [RecordType] variant corresponding with the [`Record`].
@@ -312,6 +315,50 @@ impl Record {
             _ => panic!("cname_data"),
         }
     }
+
+    /// Formats the data of the [`Record`] as written in zone files.
+    pub fn format_data(&self) -> String {
+        let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+        match self {
+            Record::A { address, .. } => Ipv4Addr::from(*address).to_string(),
+            Record::NS { name, .. } | Record::CNAME { name, .. } | Record::PTR { name, .. } => name.to_string(),
+            Record::SOA {
+                ns_name,
+                ml_name,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                ..
+            } => format!(
+                "{} {} {} {} {} {} {}",
+                ns_name, ml_name, serial, refresh, retry, expire, minimum
+            ),
+            Record::WKS { address, protocol, ports, .. } => {
+                let ports: Vec<String> = ports.iter().map(|port| port.to_string()).collect();
+                format!("{} {} {}", Ipv4Addr::from(*address), protocol, ports.join(" "))
+            }
+            Record::HINFO { cpu, os, .. } => format!("{} {}", quote(cpu), quote(os)),
+            Record::MX { priority, name, .. } => format!("{} {}", priority, name),
+            Record::TXT { txts, .. } => txts.iter().map(|txt| quote(txt)).collect::<Vec<_>>().join(" "),
+        }
+    }
+}
+
+/// Displays the [`Record`] as a line of a zone file: name, ttl, class, type and data.
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{:?}\t{}\t{}",
+            self.node(),
+            self.ttl(),
+            self.class(),
+            self.record_type().to_str(),
+            self.format_data()
+        )
+    }
 }
 
 // The following functions are all related to decoding/encoding the variable
@@ -320,32 +367,32 @@ impl Record {
 // data len before the data.
 
 // A records data encoding and decoding functions.
-fn decode_a_data(buffer: &mut BitsBuf, data_len: u16) -> Result<[u8; 4], ParsingErr> {
+fn decode_a_data(buffer: &mut BytesReader, data_len: u16) -> Result<[u8; 4], ParsingErr> {
     if data_len != 4 {
         Err(ParsingErr::DataLenMismatch)
     } else {
-        Ok(buffer.read_bytes().ok_or(ParsingErr::BytesEnd)?)
+        buffer.read_bytes().ok_or(ParsingErr::BytesEnd)
     }
 }
 
-fn encode_a_data(buffer: &mut BitsBuf, ip: &[u8; 4]) {
+fn encode_a_data(buffer: &mut BytesWriter, ip: &[u8; 4]) {
     buffer.write_u16(4);
     buffer.write_bytes(ip);
 }
 
 // NS records data encoding and decoding functions.
-fn decode_ns_data(buffer: &mut BitsBuf, data_len: u16) -> Result<Name, ParsingErr> {
-    let before = buffer.read_pos();
+fn decode_ns_data(buffer: &mut BytesReader, data_len: u16) -> Result<Name, ParsingErr> {
+    let before = buffer.pos();
     let nameserver = Name::from_bytes(buffer)?;
-    let after = buffer.read_pos();
-    if after - before != (data_len * 8) as usize {
+    let after = buffer.pos();
+    if after - before != data_len as usize {
         Err(ParsingErr::DataLenMismatch)
     } else {
         Ok(nameserver)
     }
 }
 
-fn encode_ns_data(buffer: &mut BitsBuf, name: &Name) -> Result<(), ParsingErr> {
+fn encode_ns_data(buffer: &mut BytesWriter, name: &Name) -> Result<(), ParsingErr> {
     let domain_name = name.to_bytes();
     buffer.write_u16(domain_name.len() as u16);
     buffer.write_bytes(&domain_name);
@@ -353,18 +400,18 @@ fn encode_ns_data(buffer: &mut BitsBuf, name: &Name) -> Result<(), ParsingErr> {
 }
 
 // CNAME records data encoding and decoding functions.
-fn decode_cname_data(buffer: &mut BitsBuf, data_len: u16) -> Result<Name, ParsingErr> {
-    let before = buffer.read_pos();
+fn decode_cname_data(buffer: &mut BytesReader, data_len: u16) -> Result<Name, ParsingErr> {
+    let before = buffer.pos();
     let alias = Name::from_bytes(buffer)?;
-    let after = buffer.read_pos();
-    if after - before != (data_len * 8) as usize {
+    let after = buffer.pos();
+    if after - before != data_len as usize {
         Err(ParsingErr::DataLenMismatch)
     } else {
         Ok(alias)
     }
 }
 
-fn encode_cname_data(buffer: &mut BitsBuf, name: &Name) -> Result<(), ParsingErr> {
+fn encode_cname_data(buffer: &mut BytesWriter, name: &Name) -> Result<(), ParsingErr> {
     let domain_name = name.to_bytes();
     buffer.write_u16(domain_name.len() as u16);
     buffer.write_bytes(&domain_name);
@@ -374,8 +421,8 @@ fn encode_cname_data(buffer: &mut BitsBuf, name: &Name) -> Result<(), ParsingErr
 // SOA records data encoding and decoding functions.
 type SoaData = (Name, Name, u32, u32, u32, u32, u32);
 
-fn decode_soa_data(buffer: &mut BitsBuf, data_len: u16) -> Result<SoaData, ParsingErr> {
-    let before = buffer.read_pos();
+fn decode_soa_data(buffer: &mut BytesReader, data_len: u16) -> Result<SoaData, ParsingErr> {
+    let before = buffer.pos();
     let mname = Name::from_bytes(buffer)?;
     let rname = Name::from_bytes(buffer)?;
     let serial = buffer.read_u32().ok_or(ParsingErr::BytesEnd)?;
@@ -383,20 +430,20 @@ fn decode_soa_data(buffer: &mut BitsBuf, data_len: u16) -> Result<SoaData, Parsi
     let retry = buffer.read_u32().ok_or(ParsingErr::BytesEnd)?;
     let expire = buffer.read_u32().ok_or(ParsingErr::BytesEnd)?;
     let minimum = buffer.read_u32().ok_or(ParsingErr::BytesEnd)?;
-    let after = buffer.read_pos();
-    if after - before != (data_len * 8) as usize {
+    let after = buffer.pos();
+    if after - before != data_len as usize {
         Err(ParsingErr::DataLenMismatch)
     } else {
         Ok((mname, rname, serial, refresh, retry, expire, minimum))
     }
 }
 
-fn encode_soa_data(buffer: &mut BitsBuf, data: (&Name, &Name, u32, u32, u32, u32, u32)) -> Result<(), ParsingErr> {
+fn encode_soa_data(buffer: &mut BytesWriter, data: (&Name, &Name, u32, u32, u32, u32, u32)) -> Result<(), ParsingErr> {
     let auth_ns_name = &data.0.to_bytes();
     let mail_name = &data.1.to_bytes();
     buffer.write_u16((auth_ns_name.len() + mail_name.len() + 20) as u16);
-    buffer.write_bytes(&auth_ns_name);
-    buffer.write_bytes(&mail_name);
+    buffer.write_bytes(auth_ns_name);
+    buffer.write_bytes(mail_name);
     buffer.write_u32(data.2);
     buffer.write_u32(data.3);
     buffer.write_u32(data.4);
@@ -408,12 +455,15 @@ fn encode_soa_data(buffer: &mut BitsBuf, data: (&Name, &Name, u32, u32, u32, u32
 // WKS records data encoding and decoding functions.
 type WksData = ([u8; 4], u8, Vec<u32>);
 
-fn decode_wks_data(buffer: &mut BitsBuf, data_len: u16) -> Result<WksData, ParsingErr> {
+fn decode_wks_data(buffer: &mut BytesReader, data_len: u16) -> Result<WksData, ParsingErr> {
+    if data_len < 5 {
+        return Err(ParsingErr::DataLenMismatch);
+    }
     let address = buffer.read_bytes().ok_or(ParsingErr::BytesEnd)?;
     let protocol = buffer.read_u8().ok_or(ParsingErr::BytesEnd)?;
     let ports = if data_len > 5 {
-        let ports_bytes = buffer.read_bytes_vec((data_len - 5) as usize).unwrap();
-        parse_wks_ports(&ports_bytes)
+        let ports_bytes = buffer.read_slice((data_len - 5) as usize).ok_or(ParsingErr::BytesEnd)?;
+        parse_wks_ports(ports_bytes)
     } else {
         vec![]
     };
@@ -421,7 +471,7 @@ fn decode_wks_data(buffer: &mut BitsBuf, data_len: u16) -> Result<WksData, Parsi
     Ok((address, protocol, ports))
 }
 
-fn encode_wks_data(buffer: &mut BitsBuf, address: &[u8; 4], protocol: u8, ports: &[u32]) {
+fn encode_wks_data(buffer: &mut BytesWriter, address: &[u8; 4], protocol: u8, ports: &[u32]) {
     buffer.write_u16((5 + ports.len()) as u16);
     buffer.write_bytes(address);
     buffer.write_u8(protocol);
@@ -436,9 +486,10 @@ fn parse_wks_ports(ports_bytes: &[u8]) -> Vec<u32> {
         if byte == 0 {
             continue;
         }
-        for j in 1.. {
-            if byte << j == 0 {
-                ports.push((i * 8 + j - 1) as u32);
+        // The most significant bit of the first byte is port 0.
+        for j in 0..8 {
+            if byte & (0b1000_0000 >> j) != 0 {
+                ports.push((i * 8 + j) as u32);
             };
         }
     }
@@ -446,18 +497,18 @@ fn parse_wks_ports(ports_bytes: &[u8]) -> Vec<u32> {
 }
 
 // PTR records data encoding and decoding functions.
-fn decode_ptr_data(buffer: &mut BitsBuf, data_len: u16) -> Result<Name, ParsingErr> {
-    let before = buffer.read_pos();
+fn decode_ptr_data(buffer: &mut BytesReader, data_len: u16) -> Result<Name, ParsingErr> {
+    let before = buffer.pos();
     let name = Name::from_bytes(buffer)?;
-    let after = buffer.read_pos();
-    if after - before != (data_len * 8) as usize {
+    let after = buffer.pos();
+    if after - before != data_len as usize {
         Err(ParsingErr::DataLenMismatch)
     } else {
         Ok(name)
     }
 }
 
-fn encode_ptr_data(buffer: &mut BitsBuf, name: &Name) -> Result<(), ParsingErr> {
+fn encode_ptr_data(buffer: &mut BytesWriter, name: &Name) -> Result<(), ParsingErr> {
     let domain_name = name.to_bytes();
     buffer.write_u16(domain_name.len() as u16);
     buffer.write_bytes(&domain_name);
@@ -465,19 +516,19 @@ fn encode_ptr_data(buffer: &mut BitsBuf, name: &Name) -> Result<(), ParsingErr>
 }
 
 // HINFO records data encoding and decoding functions.
-fn decode_hinfo_data(buffer: &mut BitsBuf, data_len: u16) -> Result<(String, String), ParsingErr> {
-    let before = buffer.read_pos();
+fn decode_hinfo_data(buffer: &mut BytesReader, data_len: u16) -> Result<(String, String), ParsingErr> {
+    let before = buffer.pos();
     let cpu = decode_character_string(buffer)?;
     let os = decode_character_string(buffer)?;
-    let after = buffer.read_pos();
-    if after - before != (data_len * 8) as usize {
+    let after = buffer.pos();
+    if after - before != data_len as usize {
         Err(ParsingErr::DataLenMismatch)
     } else {
         Ok((cpu, os))
     }
 }
 
-fn encode_hinfo_data(buffer: &mut BitsBuf, cpu: &str, os: &str) -> Result<(), ParsingErr> {
+fn encode_hinfo_data(buffer: &mut BytesWriter, cpu: &str, os: &str) -> Result<(), ParsingErr> {
     let cpu = encode_character_string(cpu)?;
     let os = encode_character_string(os)?;
     buffer.write_u16((cpu.len() + os.len()) as u16);
@@ -487,19 +538,19 @@ fn encode_hinfo_data(buffer: &mut BitsBuf, cpu: &str, os: &str) -> Result<(), Pa
 }
 
 // MX records data encoding and decoding functions.
-fn decode_mx_data(buffer: &mut BitsBuf, data_len: u16) -> Result<(u16, Name), ParsingErr> {
-    let before = buffer.read_pos();
+fn decode_mx_data(buffer: &mut BytesReader, data_len: u16) -> Result<(u16, Name), ParsingErr> {
+    let before = buffer.pos();
     let preference = buffer.read_u16().ok_or(ParsingErr::BytesEnd)?;
     let exchange = Name::from_bytes(buffer)?;
-    let after = buffer.read_pos();
-    if after - before != (data_len * 8) as usize {
+    let after = buffer.pos();
+    if after - before != data_len as usize {
         Err(ParsingErr::DataLenMismatch)
     } else {
         Ok((preference, exchange))
     }
 }
 
-fn encode_mx_data(buffer: &mut BitsBuf, priority: u16, name: &Name) -> Result<(), ParsingErr> {
+fn encode_mx_data(buffer: &mut BytesWriter, priority: u16, name: &Name) -> Result<(), ParsingErr> {
     let domain_name = name.to_bytes();
     buffer.write_u16(2 + domain_name.len() as u16);
     buffer.write_u16(priority);
@@ -508,13 +559,11 @@ fn encode_mx_data(buffer: &mut BitsBuf, priority: u16, name: &Name) -> Result<()
 }
 
 // TXT records data encoding and decoding functions.
-fn decode_txt_data(buffer: &mut BitsBuf, data_len: u16) -> Result<Vec<String>, ParsingErr> {
+fn decode_txt_data(buffer: &mut BytesReader, data_len: u16) -> Result<Vec<String>, ParsingErr> {
     let mut strings = vec![];
     let mut read: u16 = 0;
     loop {
-        let pos = buffer.read_pos();
-        let len = buffer.read_u8().ok_or(ParsingErr::BytesEnd)? as u16;
-        buffer.set_read_pos(pos - 1);
+        let len = buffer.peek_u8().ok_or(ParsingErr::BytesEnd)? as u16;
         if read + len + 1 > data_len {
             return Err(ParsingErr::DataLenMismatch);
         }
@@ -527,7 +576,7 @@ fn decode_txt_data(buffer: &mut BitsBuf, data_len: u16) -> Result<Vec<String>, P
     Ok(strings)
 }
 
-fn encode_txt_data(buffer: &mut BitsBuf, strings: &Vec<String>) -> Result<(), ParsingErr> {
+fn encode_txt_data(buffer: &mut BytesWriter, strings: &[String]) -> Result<(), ParsingErr> {
     let mut buf = vec![];
     let mut len = 0;
     for str in strings {
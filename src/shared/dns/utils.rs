@@ -4,13 +4,10 @@ use std::str;
 
 /// Decode a character string, reading the bytes from the provided buffer.
 /// Both the string length and non-UTF-8 values are checked.
-pub fn decode_character_string(buffer: &mut BitsBuf) -> Result<String, ParsingErr> {
+pub fn decode_character_string(buffer: &mut BytesReader) -> Result<String, ParsingErr> {
     let len_byte = check_end(buffer.read_u8())?;
-    let str_bytes = check_end(buffer.read_bytes_vec(len_byte as usize))?;
-    if str_bytes.len() > 255 {
-        return Err(ParsingErr::StringCharErr("string len > 255".to_string()));
-    }
-    match str::from_utf8(&str_bytes) {
+    let str_bytes = check_end(buffer.read_slice(len_byte as usize))?;
+    match str::from_utf8(str_bytes) {
         Err(_) => Err(ParsingErr::StringCharErr("not utf-8".to_string())),
         Ok(str) => Ok(str.to_string()),
     }
@@ -31,7 +28,7 @@ pub fn encode_character_string(string: &str) -> Result<Vec<u8>, ParsingErr> {
 
 pub fn is_valid_character_string(s: &str, quoted: bool) -> bool {
     if quoted {
-        s.chars().all(|ch| ch.is_ascii())
+        s.is_ascii()
     } else {
         s.chars().all(|ch| ch.is_ascii() && ch != ' ')
     }
@@ -0,0 +1,167 @@
+use std::io::{self, BufWriter, Read, Write};
+
+/// The content type of the dnstap frames.
+pub const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+// Frame Streams control frames types and fields.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+const MAX_CONTROL_LEN: usize = 512;
+
+/// Writes data frames to an output using the Frame Streams protocol. In the
+/// unidirectional mode (e.g. files) the stream is simply delimited by the
+/// start and stop frames. In the bidirectional mode (e.g. sockets) the receiver
+/// must accept the content type first and acknowledge the end of the stream.
+pub struct FrameWriter<W: Write> {
+    out: BufWriter<W>,
+    bidirectional: bool,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Starts an unidirectional stream, writing the start frame.
+    pub fn unidirectional(out: W) -> io::Result<Self> {
+        let mut out = BufWriter::new(out);
+        write_control(&mut out, CONTROL_START, true)?;
+        Ok(FrameWriter { out, bidirectional: false })
+    }
+
+    /// Writes a data frame. Frames are buffered until [FrameWriter::flush].
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.out.write_all(data)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<S: Read + Write> FrameWriter<S> {
+    /// Starts a bidirectional stream, waiting for the receiver to accept it.
+    pub fn bidirectional(stream: S) -> io::Result<Self> {
+        let mut out = BufWriter::new(stream);
+        write_control(&mut out, CONTROL_READY, true)?;
+        out.flush()?;
+        let control_type = read_control(out.get_mut())?;
+        if control_type != CONTROL_ACCEPT {
+            let err = format!("expected accept frame, got control type {}", control_type);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+        }
+        write_control(&mut out, CONTROL_START, true)?;
+        Ok(FrameWriter { out, bidirectional: true })
+    }
+
+    /// Ends the stream writing the stop frame. In the bidirectional
+    /// mode, the finish frame of the receiver is waited for.
+    pub fn finish(mut self) -> io::Result<()> {
+        write_control(&mut self.out, CONTROL_STOP, false)?;
+        self.out.flush()?;
+        if self.bidirectional && read_control(self.out.get_mut())? != CONTROL_FINISH {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "expected finish frame"));
+        }
+        Ok(())
+    }
+}
+
+// A control frame is escaped by a zero length, followed by its own length.
+fn write_control<W: Write>(out: &mut W, control_type: u32, content_type: bool) -> io::Result<()> {
+    let mut frame = control_type.to_be_bytes().to_vec();
+    if content_type {
+        frame.extend_from_slice(&FIELD_CONTENT_TYPE.to_be_bytes());
+        frame.extend_from_slice(&(CONTENT_TYPE.len() as u32).to_be_bytes());
+        frame.extend_from_slice(CONTENT_TYPE);
+    }
+    out.write_all(&0_u32.to_be_bytes())?;
+    out.write_all(&(frame.len() as u32).to_be_bytes())?;
+    out.write_all(&frame)
+}
+
+// Read a control frame and return its type. The fields are not inspected.
+fn read_control<R: Read>(input: &mut R) -> io::Result<u32> {
+    let mut word = [0; 4];
+    input.read_exact(&mut word)?;
+    if u32::from_be_bytes(word) != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "expected control frame"));
+    }
+    input.read_exact(&mut word)?;
+    let len = u32::from_be_bytes(word) as usize;
+    if !(4..=MAX_CONTROL_LEN).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid control frame length",
+        ));
+    }
+    let mut frame = vec![0; len];
+    input.read_exact(&mut frame)?;
+    Ok(u32::from_be_bytes([frame[0], frame[1], frame[2], frame[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_unidirectional_stream() {
+        let mut out = vec![];
+        let mut writer = FrameWriter::unidirectional(&mut out).unwrap();
+        writer.write(b"frame").unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let mut input = Cursor::new(out);
+        assert_eq!(read_control(&mut input).unwrap(), CONTROL_START);
+        let mut data = [0; 9];
+        input.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"\0\0\0\x05frame");
+    }
+
+    // A stream over which the receiver frames are already queued.
+    struct TestStream {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_bidirectional_stream() {
+        let mut input = vec![];
+        write_control(&mut input, CONTROL_ACCEPT, true).unwrap();
+        write_control(&mut input, CONTROL_FINISH, false).unwrap();
+        let mut stream = TestStream {
+            input: Cursor::new(input),
+            output: vec![],
+        };
+
+        let mut writer = FrameWriter::bidirectional(&mut stream).unwrap();
+        writer.write(b"frame").unwrap();
+        writer.finish().unwrap();
+
+        let mut output = Cursor::new(stream.output);
+        assert_eq!(read_control(&mut output).unwrap(), CONTROL_READY);
+        assert_eq!(read_control(&mut output).unwrap(), CONTROL_START);
+        let mut data = [0; 9];
+        output.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"\0\0\0\x05frame");
+        assert_eq!(read_control(&mut output).unwrap(), CONTROL_STOP);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::shared::dns;
+use crate::shared::dnstap::message::*;
+use crate::shared::dnstap::writer::Dnstap;
+use crate::shared::net::*;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::sync::Arc;
+use std::{io, time};
+
+/// A [DnsHandler] wrapping another one, logging the queries received and the
+/// responses sent to the clients with the passed message types (e.g. the auth
+/// or the client ones). Requests are passed through if `dnstap` is None.
+pub struct DnstapHandler<H> {
+    pub inner: Arc<H>,
+    pub dnstap: Option<Arc<Dnstap>>,
+    pub query_type: MessageType,
+    pub response_type: MessageType,
+}
+
+impl<H: DnsHandler> DnsHandler for DnstapHandler<H> {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        let (req, resp) = self.wrap(req, resp);
+        self.inner.handle_request(req, resp);
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H: AsyncDnsHandler> AsyncDnsHandler for DnstapHandler<H> {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let (req, resp) = self.wrap(req, resp);
+        let inner = Arc::clone(&self.inner);
+        async move { inner.handle_request_async(req, resp).await }
+    }
+}
+
+impl<H> DnstapHandler<H> {
+    pub fn new(inner: H, dnstap: Option<Arc<Dnstap>>, query_type: MessageType, response_type: MessageType) -> Self {
+        DnstapHandler {
+            inner: Arc::new(inner),
+            dnstap,
+            query_type,
+            response_type,
+        }
+    }
+
+    fn wrap<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) -> (TappedRead<R>, TappedWrite<W>) {
+        let source = req.source();
+        let query_time = time::SystemTime::now();
+        let dnstap = self.dnstap.as_ref();
+        let req = TappedRead {
+            inner: req,
+            dnstap: dnstap.filter(|d| d.enabled(self.query_type)).cloned(),
+            kind: self.query_type,
+            query_time,
+        };
+        let resp = TappedWrite {
+            inner: resp,
+            dnstap: dnstap.filter(|d| d.enabled(self.response_type)).cloned(),
+            kind: self.response_type,
+            source,
+            query_time,
+        };
+        (req, resp)
+    }
+}
+
+// Log the query when the request is read, if it can be decoded.
+struct TappedRead<R> {
+    inner: R,
+    dnstap: Option<Arc<Dnstap>>,
+    kind: MessageType,
+    query_time: time::SystemTime,
+}
+
+impl<R: DnsRead> DnsRead for TappedRead<R> {
+    fn read(self) -> DnsReadResult {
+        let source = self.inner.source();
+        let request = self.inner.read();
+        if let (Some(dnstap), DnsReadResult::FullMessage(msg)) = (&self.dnstap, &request) {
+            if let Ok(query_bytes) = msg.encode_to_bytes() {
+                dnstap.log(&TapMessage {
+                    kind: self.kind,
+                    transport: source.transport,
+                    query_address: Some(source.addr),
+                    response_address: None,
+                    query_time: Some(self.query_time),
+                    query_message: Some(&query_bytes),
+                    response_time: None,
+                    response_message: None,
+                });
+            }
+        }
+        request
+    }
+
+    fn source(&self) -> RequestSource {
+        self.inner.source()
+    }
+}
+
+// Log the response when it is sent to the client.
+struct TappedWrite<W> {
+    inner: W,
+    dnstap: Option<Arc<Dnstap>>,
+    kind: MessageType,
+    source: RequestSource,
+    query_time: time::SystemTime,
+}
+
+impl<W: DnsWrite> DnsWrite for TappedWrite<W> {
+    fn reply(self, response: dns::Message) -> io::Result<()> {
+        if let Some(dnstap) = &self.dnstap {
+            if let Ok(response_bytes) = response.encode_to_bytes() {
+                dnstap.log(&TapMessage {
+                    kind: self.kind,
+                    transport: self.source.transport,
+                    query_address: Some(self.source.addr),
+                    response_address: None,
+                    query_time: Some(self.query_time),
+                    query_message: None,
+                    response_time: Some(time::SystemTime::now()),
+                    response_message: Some(&response_bytes),
+                });
+            }
+        }
+        self.inner.reply(response)
+    }
+
+    fn annotate(&mut self, resolution: Resolution) {
+        self.inner.annotate(resolution);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::shared::net::Transport;
+use std::{net, time};
+
+/// The kinds of dnstap messages, with the values of the `Message.Type` enum of
+/// the dnstap schema. Only the kinds produced by the servers are listed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    AuthQuery = 1,
+    AuthResponse = 2,
+    ResolverQuery = 3,
+    ResolverResponse = 4,
+    ClientQuery = 5,
+    ClientResponse = 6,
+}
+
+/// A dns message observed by a server, to be logged as a dnstap message. The
+/// query address is the one of the initiator (e.g. the client), the response
+/// address the one of the responder (e.g. the upstream nameserver). The
+/// messages are the dns messages in wire format.
+#[derive(Clone, Debug)]
+pub struct TapMessage<'a> {
+    pub kind: MessageType,
+    pub transport: Transport,
+    pub query_address: Option<net::SocketAddr>,
+    pub response_address: Option<net::SocketAddr>,
+    pub query_time: Option<time::SystemTime>,
+    pub query_message: Option<&'a [u8]>,
+    pub response_time: Option<time::SystemTime>,
+    pub response_message: Option<&'a [u8]>,
+}
+
+// Protobuf wire types used by the dnstap schema.
+const VARINT: u8 = 0;
+const LEN: u8 = 2;
+const FIXED32: u8 = 5;
+
+// The values of the `Dnstap.Type` and `SocketFamily` enums.
+const DNSTAP_MESSAGE: u64 = 1;
+const INET: u64 = 1;
+const INET6: u64 = 2;
+
+impl TapMessage<'_> {
+    /// Encodes the message as a `Dnstap` protobuf message, with the passed
+    /// identity and version of the server (omitted if empty).
+    pub fn encode(&self, identity: &[u8], version: &[u8]) -> Vec<u8> {
+        let mut message = Vec::with_capacity(256);
+        put_uint(&mut message, 1, self.kind as u64);
+        let family = self.query_address.or(self.response_address).map(|addr| match addr {
+            net::SocketAddr::V4(_) => INET,
+            net::SocketAddr::V6(_) => INET6,
+        });
+        if let Some(family) = family {
+            put_uint(&mut message, 2, family);
+        }
+        put_uint(&mut message, 3, socket_protocol(self.transport));
+        if let Some(addr) = self.query_address {
+            put_bytes(&mut message, 4, &ip_bytes(addr.ip()));
+        }
+        if let Some(addr) = self.response_address {
+            put_bytes(&mut message, 5, &ip_bytes(addr.ip()));
+        }
+        if let Some(addr) = self.query_address {
+            put_uint(&mut message, 6, addr.port() as u64);
+        }
+        if let Some(addr) = self.response_address {
+            put_uint(&mut message, 7, addr.port() as u64);
+        }
+        if let Some(query_time) = self.query_time {
+            put_time(&mut message, 8, 9, query_time);
+        }
+        if let Some(query_message) = self.query_message {
+            put_bytes(&mut message, 10, query_message);
+        }
+        if let Some(response_time) = self.response_time {
+            put_time(&mut message, 12, 13, response_time);
+        }
+        if let Some(response_message) = self.response_message {
+            put_bytes(&mut message, 14, response_message);
+        }
+
+        let mut dnstap = Vec::with_capacity(message.len() + identity.len() + version.len() + 16);
+        if !identity.is_empty() {
+            put_bytes(&mut dnstap, 1, identity);
+        }
+        if !version.is_empty() {
+            put_bytes(&mut dnstap, 2, version);
+        }
+        put_bytes(&mut dnstap, 14, &message);
+        put_uint(&mut dnstap, 15, DNSTAP_MESSAGE);
+        dnstap
+    }
+}
+
+// The values of the `SocketProtocol` enum.
+fn socket_protocol(transport: Transport) -> u64 {
+    match transport {
+        Transport::Udp => 1,
+        Transport::Tcp => 2,
+        Transport::Tls => 3,
+        Transport::Https => 4,
+        Transport::Quic => 7,
+    }
+}
+
+fn ip_bytes(ip: net::IpAddr) -> Vec<u8> {
+    match ip {
+        net::IpAddr::V4(ip) => ip.octets().to_vec(),
+        net::IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(out, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn put_uint(out: &mut Vec<u8>, field: u32, v: u64) {
+    put_key(out, field, VARINT);
+    put_varint(out, v);
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(out, field, LEN);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+// Times are encoded as seconds (varint) and nanoseconds (fixed32) since the epoch.
+fn put_time(out: &mut Vec<u8>, sec_field: u32, nsec_field: u32, t: time::SystemTime) {
+    let since_epoch = t.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    put_uint(out, sec_field, since_epoch.as_secs());
+    put_key(out, nsec_field, FIXED32);
+    out.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let message = TapMessage {
+            kind: MessageType::ClientQuery,
+            transport: Transport::Udp,
+            query_address: Some("10.0.0.1:5300".parse().unwrap()),
+            response_address: None,
+            query_time: Some(time::UNIX_EPOCH + time::Duration::new(300, 5)),
+            query_message: Some(&[0xab, 0xcd]),
+            response_time: None,
+            response_message: None,
+        };
+        let expected = [
+            0x0a, 0x02, b'n', b's', // identity
+            0x72, 0x1b, // message, 27 bytes
+            0x08, 0x05, // type: CLIENT_QUERY
+            0x10, 0x01, // socket family: INET
+            0x18, 0x01, // socket protocol: UDP
+            0x22, 0x04, 10, 0, 0, 1, // query address
+            0x30, 0xb4, 0x29, // query port: 5300
+            0x40, 0xac, 0x02, // query time sec: 300
+            0x4d, 0x05, 0x00, 0x00, 0x00, // query time nsec: 5
+            0x52, 0x02, 0xab, 0xcd, // query message
+            0x78, 0x01, // type: MESSAGE
+        ];
+        assert_eq!(message.encode(b"ns", b""), expected);
+    }
+}
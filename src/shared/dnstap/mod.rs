@@ -0,0 +1,9 @@
+mod frames;
+mod handler;
+mod message;
+mod writer;
+
+pub use frames::*;
+pub use handler::*;
+pub use message::*;
+pub use writer::*;
@@ -0,0 +1,250 @@
+use crate::shared::dnstap::frames::FrameWriter;
+use crate::shared::dnstap::message::*;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::{fmt, fs, io, path, thread, time};
+
+const RECONNECT_DELAY: time::Duration = time::Duration::from_secs(5);
+const HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(2);
+
+/// Where the dnstap frames are written: a file (truncated when the writer
+/// starts) or a Unix socket of a collector, like `fstrm_capture`.
+#[derive(Clone, Debug)]
+pub enum DnstapOutput {
+    File(path::PathBuf),
+    #[cfg(unix)]
+    Unix(path::PathBuf),
+}
+
+/// The parameters passed to [Dnstap::start]. Only the messages of the listed
+/// types are logged. At most `queue_size` messages wait for the writer
+/// thread, further messages are dropped.
+#[derive(Clone, Debug)]
+pub struct DnstapParams {
+    pub output: DnstapOutput,
+    pub identity: String,
+    pub message_types: Vec<MessageType>,
+    pub queue_size: usize,
+}
+
+/// Logs dns messages in the dnstap format. Messages are encoded by the caller
+/// and written by a background thread, so logging never blocks: if the writer
+/// falls behind, or the collector is not reachable, messages are dropped. The
+/// writer thread ends the stream and terminates when the [Dnstap] is dropped.
+pub struct Dnstap {
+    identity: Vec<u8>,
+    message_types: Vec<MessageType>,
+    sender: Option<mpsc::SyncSender<Vec<u8>>>,
+    writer: Option<thread::JoinHandle<()>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl Dnstap {
+    /// Starts the writer thread. Files are opened before returning, so that
+    /// errors are reported immediately, while sockets are connected (and
+    /// re-connected on errors) by the writer thread.
+    pub fn start(params: DnstapParams) -> io::Result<Dnstap> {
+        let sink = match &params.output {
+            DnstapOutput::File(path) => Some(Sink::File(FrameWriter::unidirectional(fs::File::create(path)?)?)),
+            #[cfg(unix)]
+            DnstapOutput::Unix(_) => None,
+        };
+        let (sender, receiver) = mpsc::sync_channel(params.queue_size);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = Arc::clone(&dropped);
+        let output = params.output.clone();
+        let writer = thread::spawn(move || write_frames(receiver, output, sink, &writer_dropped));
+        Ok(Dnstap {
+            identity: params.identity.into_bytes(),
+            message_types: params.message_types,
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped,
+        })
+    }
+
+    /// Reports if messages of the passed type are logged, so that callers
+    /// can skip preparing the messages that would be discarded.
+    pub fn enabled(&self, kind: MessageType) -> bool {
+        self.message_types.contains(&kind)
+    }
+
+    /// Encodes the message and queues it for the writer thread.
+    pub fn log(&self, message: &TapMessage) {
+        if !self.enabled(message.kind) {
+            return;
+        }
+        let frame = message.encode(&self.identity, VERSION);
+        let sender = self.sender.as_ref().unwrap();
+        if sender.try_send(frame).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The messages dropped because the queue was full or the output failed.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+const VERSION: &[u8] = concat!("ariadne-dns ", env!("CARGO_PKG_VERSION")).as_bytes();
+
+impl fmt::Debug for Dnstap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dnstap")
+            .field("message_types", &self.message_types)
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl Drop for Dnstap {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum Sink {
+    File(FrameWriter<fs::File>),
+    #[cfg(unix)]
+    Unix(FrameWriter<UnixStream>),
+}
+
+impl Sink {
+    #[cfg(unix)]
+    fn connect(path: &path::Path) -> io::Result<Sink> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        Ok(Sink::Unix(FrameWriter::bidirectional(stream)?))
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::File(writer) => writer.write(data),
+            #[cfg(unix)]
+            Sink::Unix(writer) => writer.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::File(writer) => writer.flush(),
+            #[cfg(unix)]
+            Sink::Unix(writer) => writer.flush(),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Sink::File(writer) => writer.finish(),
+            #[cfg(unix)]
+            Sink::Unix(writer) => writer.finish(),
+        }
+    }
+}
+
+// Write the queued frames, flushing the output when the queue is empty. Sockets
+// are re-connected after a delay, dropping the messages logged in the meantime.
+// Files are not re-opened: a write error discards all the following messages.
+fn write_frames(receiver: mpsc::Receiver<Vec<u8>>, output: DnstapOutput, mut sink: Option<Sink>, dropped: &AtomicU64) {
+    let mut connect_at = time::Instant::now();
+    loop {
+        let frame = match receiver.try_recv() {
+            Ok(frame) => frame,
+            Err(mpsc::TryRecvError::Empty) => {
+                if let Some(Err(err)) = sink.as_mut().map(Sink::flush) {
+                    log::error!("Writing dnstap frames: {}", err);
+                    sink = None;
+                    connect_at = time::Instant::now() + RECONNECT_DELAY;
+                }
+                match receiver.recv() {
+                    Ok(frame) => frame,
+                    Err(_) => break,
+                }
+            }
+            Err(mpsc::TryRecvError::Disconnected) => break,
+        };
+
+        #[cfg(unix)]
+        if let (None, DnstapOutput::Unix(path)) = (&sink, &output) {
+            if time::Instant::now() >= connect_at {
+                match Sink::connect(path) {
+                    Ok(v) => sink = Some(v),
+                    Err(err) => {
+                        log::warn!("Connecting to dnstap socket {}: {}", path.display(), err);
+                        connect_at = time::Instant::now() + RECONNECT_DELAY;
+                    }
+                }
+            }
+        }
+
+        let written = match sink.as_mut() {
+            Some(sink) => sink.write(&frame),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        };
+        if let Err(err) = written {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            if sink.take().is_some() {
+                log::error!("Writing dnstap frames: {}", err);
+                connect_at = time::Instant::now() + RECONNECT_DELAY;
+            }
+        }
+    }
+
+    if let Some(Err(err)) = sink.map(Sink::finish) {
+        log::error!("Ending dnstap stream: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dnstap::frames::CONTENT_TYPE;
+    use crate::shared::net::Transport;
+
+    #[test]
+    fn test_write_file() {
+        let path = std::env::temp_dir().join(format!("ariadne-dnstap-{}.fstrm", std::process::id()));
+        let params = DnstapParams {
+            output: DnstapOutput::File(path.clone()),
+            identity: "test".to_string(),
+            message_types: vec![MessageType::ClientQuery],
+            queue_size: 16,
+        };
+        let dnstap = Dnstap::start(params).unwrap();
+        let mut message = TapMessage {
+            kind: MessageType::ClientQuery,
+            transport: Transport::Udp,
+            query_address: Some("127.0.0.1:5300".parse().unwrap()),
+            response_address: None,
+            query_time: Some(time::SystemTime::now()),
+            query_message: Some(&[1, 2, 3]),
+            response_time: None,
+            response_message: None,
+        };
+        dnstap.log(&message);
+        assert!(!dnstap.enabled(MessageType::ClientResponse));
+        message.kind = MessageType::ClientResponse;
+        dnstap.log(&message);
+        drop(dnstap);
+
+        // Start frame, one data frame and the stop frame.
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let start_len = 8 + 4 + 8 + CONTENT_TYPE.len();
+        let data_len = u32::from_be_bytes(bytes[start_len..start_len + 4].try_into().unwrap()) as usize;
+        let data = &bytes[start_len + 4..start_len + 4 + data_len];
+        message.kind = MessageType::ClientQuery;
+        assert_eq!(data, message.encode(b"test", VERSION));
+        assert_eq!(
+            &bytes[start_len + 4 + data_len..],
+            &[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]
+        );
+    }
+}
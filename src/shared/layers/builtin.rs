@@ -0,0 +1,220 @@
+use crate::shared::dns;
+use crate::shared::layers::handler::*;
+use crate::shared::net::RequestSource;
+
+/// A [Layer] blocking the queries of the listed names and of all the names below
+/// them, answered with NXDOMAIN, refused or dropped according to the `action`.
+#[derive(Clone, Debug)]
+pub struct BlockLayer {
+    pub names: Vec<dns::Name>,
+    pub action: BlockAction,
+}
+
+/// How the queries blocked by a [BlockLayer] are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlockAction {
+    #[default]
+    NxDomain,
+    Refuse,
+    Drop,
+}
+
+impl Layer for BlockLayer {
+    fn name(&self) -> &'static str {
+        "block"
+    }
+
+    fn on_request(&self, _source: &RequestSource, request: &mut dns::Message) -> LayerAction {
+        let blocked = request
+            .questions
+            .iter()
+            .any(|question| self.names.iter().any(|name| question.node.is_in_zone(name)));
+        if !blocked {
+            return LayerAction::Continue;
+        }
+
+        log::info!("[{}] Query blocked: '{}'.", request.id(), request.questions[0].node);
+        match self.action {
+            BlockAction::NxDomain => LayerAction::Reply(layer_response(request, dns::RespCode::NxDomain)),
+            BlockAction::Refuse => LayerAction::Reply(layer_response(request, dns::RespCode::Refused)),
+            BlockAction::Drop => LayerAction::Drop,
+        }
+    }
+}
+
+/// A [Layer] rewriting the queries of the names in the `from` zone to the same names
+/// in the `to` zone (e.g. `www.example.net.` to `www.example.com.` with the zones
+/// `example.net.` and `example.com.`). In the responses, the question and the owner
+/// of the records of the rewritten name are restored to the name queried.
+#[derive(Clone, Debug)]
+pub struct RewriteLayer {
+    pub from: dns::Name,
+    pub to: dns::Name,
+}
+
+impl Layer for RewriteLayer {
+    fn name(&self) -> &'static str {
+        "rewrite"
+    }
+
+    fn on_request(&self, _source: &RequestSource, request: &mut dns::Message) -> LayerAction {
+        for question in &mut request.questions {
+            if let Some(node) = replace_zone(&question.node, &self.from, &self.to) {
+                log::debug!(
+                    "[{}] Query rewritten: '{}' to '{}'.",
+                    request.header.id,
+                    question.node,
+                    node
+                );
+                question.node = node;
+            }
+        }
+        LayerAction::Continue
+    }
+
+    fn on_response(&self, _source: &RequestSource, request: &dns::Message, response: &mut dns::Message) {
+        for question in &request.questions {
+            let Some(rewritten) = replace_zone(&question.node, &self.from, &self.to) else {
+                continue;
+            };
+            let sections = [
+                &mut response.answers,
+                &mut response.authorities,
+                &mut response.additionals,
+            ];
+            for record in sections.into_iter().flatten() {
+                if *record.node() == rewritten {
+                    record.set_node(question.node.clone());
+                }
+            }
+        }
+        response.questions = request.questions.clone();
+    }
+}
+
+// Replace the `from` zone with the `to` one in the name, if the name is in
+// the `from` zone. Names too long once rewritten are left as they are.
+fn replace_zone(name: &dns::Name, from: &dns::Name, to: &dns::Name) -> Option<dns::Name> {
+    if !name.is_in_zone(from) {
+        return None;
+    }
+    let prefix = &name.as_ref()[..name.as_ref().len() - from.as_ref().len()];
+    dns::Name::from_string(&format!("{}{}", prefix, to)).ok()
+}
+
+/// A [Layer] clamping the TTL of the records of the responses
+/// between `min` and `max` seconds, to cap how long they are cached.
+#[derive(Clone, Debug)]
+pub struct TtlLayer {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Layer for TtlLayer {
+    fn name(&self) -> &'static str {
+        "ttl"
+    }
+
+    fn on_response(&self, _source: &RequestSource, _request: &dns::Message, response: &mut dns::Message) {
+        let sections = [
+            &mut response.answers,
+            &mut response.authorities,
+            &mut response.additionals,
+        ];
+        for record in sections.into_iter().flatten() {
+            let ttl = (*record.ttl()).clamp(self.min, self.max);
+            record.set_ttl(ttl);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::*;
+
+    // Answer the A queries with a record of the queried name, with a 300 seconds TTL.
+    struct RecordHandler;
+
+    impl DnsHandler for RecordHandler {
+        fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+            if let DnsReadResult::FullMessage(request) = req.read() {
+                let mut response = layer_response(&request, dns::RespCode::NoError);
+                response.answers.push(dns::Record::A {
+                    node: request.questions[0].node.clone(),
+                    class: dns::Class::IN,
+                    ttl: 300,
+                    data_len: 4,
+                    address: [10, 0, 0, 1],
+                });
+                response.header.answers_count = 1;
+                resp.reply(response).unwrap();
+            }
+        }
+    }
+
+    fn name(name: &str) -> dns::Name {
+        dns::Name::from_string(name).unwrap()
+    }
+
+    #[test]
+    fn test_block_layer() {
+        let client = TestClient::default();
+        let query = |action, node| {
+            let layer = BlockLayer {
+                names: vec![name("ads.example.com.")],
+                action,
+            };
+            let handler = LayeredHandler::new(RecordHandler, vec![Box::new(layer)]);
+            client.query(&handler, &name(node), dns::RecordType::A)
+        };
+
+        let response = query(BlockAction::NxDomain, "www.example.com.").unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+        assert_eq!(response.answers.len(), 1);
+        let response = query(BlockAction::NxDomain, "ads.example.com.").unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::NxDomain);
+        assert!(response.answers.is_empty());
+        let response = query(BlockAction::Refuse, "a.b.ads.example.com.").unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::Refused);
+        assert!(query(BlockAction::Drop, "a.ads.example.com.").is_none());
+    }
+
+    #[test]
+    fn test_rewrite_layer() {
+        let client = TestClient::default();
+        let layer = RewriteLayer {
+            from: name("example.net."),
+            to: name("example.com."),
+        };
+        let handler = LayeredHandler::new(RecordHandler, vec![Box::new(layer)]);
+
+        let response = client
+            .query(&handler, &name("www.example.net."), dns::RecordType::A)
+            .unwrap();
+        assert_eq!(response.questions[0].node.as_ref(), "www.example.net.");
+        assert_eq!(response.answers[0].node().as_ref(), "www.example.net.");
+        let response = client
+            .query(&handler, &name("www.example.org."), dns::RecordType::A)
+            .unwrap();
+        assert_eq!(response.questions[0].node.as_ref(), "www.example.org.");
+        assert_eq!(response.answers[0].node().as_ref(), "www.example.org.");
+        assert_eq!(
+            replace_zone(&name("a.example.net."), &name("example.net."), &name("b.")),
+            Some(name("a.b."))
+        );
+    }
+
+    #[test]
+    fn test_ttl_layer() {
+        let client = TestClient::default();
+        let ttl = |min, max| {
+            let handler = LayeredHandler::new(RecordHandler, vec![Box::new(TtlLayer { min, max })]);
+            let response = client.query(&handler, &name("example.com."), dns::RecordType::A);
+            *response.unwrap().answers[0].ttl()
+        };
+        assert_eq!(ttl(0, 60), 60);
+        assert_eq!(ttl(600, 3600), 600);
+        assert_eq!(ttl(60, 3600), 300);
+    }
+}
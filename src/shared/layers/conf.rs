@@ -0,0 +1,124 @@
+use crate::shared::dns;
+use crate::shared::layers::builtin::*;
+use crate::shared::layers::handler::*;
+use serde::{Deserialize, Serialize};
+
+/// A layer run around the handler, see [Layer]. Queries of the `Block` names (and
+/// of the names below them) are answered with NXDOMAIN, refused or dropped, queries
+/// of the names in the `Rewrite` zone `from` are rewritten to the zone `to`, and
+/// the TTLs of the responses are clamped between the `Ttl` limits.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LayerConf {
+    Block {
+        names: Vec<String>,
+        #[serde(default)]
+        action: BlockActionConf,
+    },
+    Rewrite {
+        from: String,
+        to: String,
+    },
+    Ttl {
+        min: u32,
+        max: u32,
+    },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum BlockActionConf {
+    #[default]
+    NxDomain,
+    Refuse,
+    Drop,
+}
+
+impl LayerConf {
+    /// Checks that the names of the layer are valid, that the rewritten
+    /// zones are not the root one and that the TTL limits are ordered.
+    pub fn validate(&self) -> Result<(), String> {
+        let parse_name = |layer: &str, name: &str| match dns::Name::from_string(name) {
+            Ok(v) => Ok(v),
+            Err(err) => Err(format!("invalid {} layer name {}: {:?}", layer, name, err)),
+        };
+        match self {
+            LayerConf::Block { names, .. } => {
+                if names.is_empty() {
+                    return Err("invalid block layer: no names".to_string());
+                }
+                for name in names {
+                    parse_name("block", name)?;
+                }
+            }
+            LayerConf::Rewrite { from, to } => {
+                for name in [from, to] {
+                    if parse_name("rewrite", name)?.as_ref() == "." {
+                        return Err("invalid rewrite layer: cannot rewrite the root zone".to_string());
+                    }
+                }
+            }
+            LayerConf::Ttl { min, max } if min > max => {
+                return Err(format!("invalid ttl layer: min {} greater than max {}", min, max));
+            }
+            LayerConf::Ttl { .. } => {}
+        }
+        Ok(())
+    }
+}
+
+/// Builds the layer, the configuration is expected to be
+/// already validated with [LayerConf::validate].
+impl From<&LayerConf> for Box<dyn Layer> {
+    fn from(layer_conf: &LayerConf) -> Self {
+        let name = |name: &String| dns::Name::from_string(name).unwrap();
+        match layer_conf {
+            LayerConf::Block { names, action } => Box::new(BlockLayer {
+                names: names.iter().map(name).collect(),
+                action: match action {
+                    BlockActionConf::NxDomain => BlockAction::NxDomain,
+                    BlockActionConf::Refuse => BlockAction::Refuse,
+                    BlockActionConf::Drop => BlockAction::Drop,
+                },
+            }),
+            LayerConf::Rewrite { from, to } => Box::new(RewriteLayer { from: name(from), to: name(to) }),
+            LayerConf::Ttl { min, max } => Box::new(TtlLayer { min: *min, max: *max }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_conf() {
+        let layers_conf: Vec<LayerConf> = serde_json::from_str(
+            r#"[
+                {"Block": {"names": ["ads.example.com."], "action": "Refuse"}},
+                {"Rewrite": {"from": "internal.", "to": "example.com."}},
+                {"Ttl": {"min": 60, "max": 3600}}
+            ]"#,
+        )
+        .unwrap();
+        for layer_conf in &layers_conf {
+            layer_conf.validate().unwrap();
+        }
+        let layers: Vec<Box<dyn Layer>> = layers_conf.iter().map(Into::into).collect();
+        let names: Vec<_> = layers.iter().map(|layer| layer.name()).collect();
+        assert_eq!(names, ["block", "rewrite", "ttl"]);
+
+        let invalid = [
+            LayerConf::Block {
+                names: vec![],
+                action: BlockActionConf::Drop,
+            },
+            LayerConf::Rewrite {
+                from: ".".to_string(),
+                to: "example.com.".to_string(),
+            },
+            LayerConf::Ttl { min: 3600, max: 60 },
+        ];
+        for layer_conf in &invalid {
+            assert!(layer_conf.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use crate::shared::dns;
+use crate::shared::net::*;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+
+/// A middleware run by a [LayeredHandler] around its inner handler: it can inspect
+/// or modify the requests, reply to them directly and post-process the responses.
+/// Layers see only the requests that can be decoded, the other ones are passed to
+/// the inner handler as they are and their responses are not post-processed.
+pub trait Layer: Send + Sync + 'static {
+    /// The name of the layer, used in the logs.
+    fn name(&self) -> &'static str;
+
+    /// Inspects the request, possibly modifying it, before it is passed to the next
+    /// layers and to the inner handler. The returned [LayerAction] tells whether to
+    /// pass the request on, to reply with the passed response or to drop it.
+    fn on_request(&self, _source: &RequestSource, _request: &mut dns::Message) -> LayerAction {
+        LayerAction::Continue
+    }
+
+    /// Inspects the response, possibly modifying it, before it is passed to the previous
+    /// layers and sent. The request is the one received by the first layer, before any
+    /// change. The counts of the response header are updated after the layers.
+    fn on_response(&self, _source: &RequestSource, _request: &dns::Message, _response: &mut dns::Message) {}
+}
+
+/// What to do with a request after a [Layer] has inspected it.
+#[derive(Debug)]
+pub enum LayerAction {
+    Continue,
+    Reply(dns::Message),
+    Drop,
+}
+
+/// A stack of [Layer]s run around a handler. Requests go through the layers in order
+/// before reaching the handler, while responses go through them in reverse order. When
+/// a layer replies directly, the response is seen only by the layers before it.
+/// Handlers checking the clients (e.g. against access control lists) run the stack
+/// with [LayerStack::run] after the checks, so that the layers see only the admitted
+/// requests and their replies are checked too. Otherwise see [LayeredHandler].
+#[derive(Clone, Default)]
+pub struct LayerStack {
+    layers: Arc<[Box<dyn Layer>]>,
+}
+
+impl LayerStack {
+    pub fn new(layers: Vec<Box<dyn Layer>>) -> Self {
+        LayerStack { layers: layers.into() }
+    }
+
+    /// The names of the layers in order, or `none`, as reported by the status commands.
+    pub fn status(&self) -> String {
+        match self.layers.is_empty() {
+            true => "none".to_string(),
+            false => self
+                .layers
+                .iter()
+                .map(|layer| layer.name())
+                .collect::<Vec<_>>()
+                .join(", "),
+        }
+    }
+
+    /// Runs the layers on the request already read from `source`. Returns the request,
+    /// possibly modified, and the writer running the layers on the response, or None if
+    /// a layer replied to the request or dropped it, so the handler must skip it.
+    pub fn run<W: DnsWrite>(
+        &self,
+        source: RequestSource,
+        mut request: DnsReadResult,
+        resp: W,
+    ) -> Option<(DnsReadResult, LayeredWrite<W>)> {
+        let mut resp = LayeredWrite {
+            inner: resp,
+            layers: Arc::clone(&self.layers),
+            depth: 0,
+            source,
+            request: None,
+        };
+
+        if let DnsReadResult::FullMessage(msg) = &mut request {
+            if !self.layers.is_empty() {
+                resp.request = Some(msg.clone());
+            }
+            for (i, layer) in self.layers.iter().enumerate() {
+                match layer.on_request(&source, msg) {
+                    LayerAction::Continue => {}
+                    LayerAction::Reply(response) => {
+                        log::debug!("[{}] Request answered by the {} layer.", msg.id(), layer.name());
+                        resp.depth = i;
+                        if let Err(err) = resp.reply(response) {
+                            log::error!("[{}] Error replying: {}", msg.id(), err);
+                        }
+                        return None;
+                    }
+                    LayerAction::Drop => {
+                        log::debug!("[{}] Request dropped by the {} layer.", msg.id(), layer.name());
+                        return None;
+                    }
+                }
+            }
+            resp.depth = self.layers.len();
+        }
+        Some((request, resp))
+    }
+}
+
+/// A [DnsHandler] wrapping another one with a [LayerStack], run
+/// before the inner handler sees the request and its checks.
+pub struct LayeredHandler<H> {
+    pub inner: Arc<H>,
+    pub layers: LayerStack,
+}
+
+impl<H: DnsHandler> DnsHandler for LayeredHandler<H> {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        if let Some((req, resp)) = self.wrap(req, resp) {
+            self.inner.handle_request(req, resp);
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H: AsyncDnsHandler> AsyncDnsHandler for LayeredHandler<H> {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let wrapped = self.wrap(req, resp);
+        let inner = Arc::clone(&self.inner);
+        async move {
+            if let Some((req, resp)) = wrapped {
+                inner.handle_request_async(req, resp).await;
+            }
+        }
+    }
+}
+
+impl<H> LayeredHandler<H> {
+    pub fn new(inner: H, layers: Vec<Box<dyn Layer>>) -> Self {
+        LayeredHandler {
+            inner: Arc::new(inner),
+            layers: LayerStack::new(layers),
+        }
+    }
+
+    // Read the request and run the layers on it. Returns None if
+    // a layer replied or dropped it, so the inner handler is skipped.
+    fn wrap<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) -> Option<(LayeredRead, LayeredWrite<W>)> {
+        let source = req.source();
+        let (request, resp) = self.layers.run(source, req.read(), resp)?;
+        Some((LayeredRead { request, source }, resp))
+    }
+}
+
+// Pass the request already read, possibly modified by the layers.
+struct LayeredRead {
+    request: DnsReadResult,
+    source: RequestSource,
+}
+
+impl DnsRead for LayeredRead {
+    fn read(self) -> DnsReadResult {
+        self.request
+    }
+
+    fn source(&self) -> RequestSource {
+        self.source
+    }
+}
+
+/// A [DnsWrite] implementor running the layers of a [LayerStack]
+/// that saw the request on the response, in reverse order, before sending it.
+pub struct LayeredWrite<W> {
+    inner: W,
+    layers: Arc<[Box<dyn Layer>]>,
+    depth: usize,
+    source: RequestSource,
+    request: Option<dns::Message>,
+}
+
+impl<W: DnsWrite> DnsWrite for LayeredWrite<W> {
+    fn reply(self, mut response: dns::Message) -> io::Result<()> {
+        if let Some(request) = &self.request {
+            for layer in self.layers[..self.depth].iter().rev() {
+                layer.on_response(&self.source, request, &mut response);
+            }
+            response.header.questions_count = response.questions.len() as u16;
+            response.header.answers_count = response.answers.len() as u16;
+            response.header.authorities_count = response.authorities.len() as u16;
+            response.header.additionals_count = response.additionals.len() as u16;
+        }
+        self.inner.reply(response)
+    }
+
+    fn annotate(&mut self, resolution: Resolution) {
+        self.inner.annotate(resolution);
+    }
+}
+
+/// Builds an empty response to the request with the passed response code, for
+/// the layers replying directly. The question and the EDNS data are included.
+pub fn layer_response(request: &dns::Message, resp_code: dns::RespCode) -> dns::Message {
+    let header = dns::Header {
+        query_resp: true,
+        auth_answer: false,
+        recursion_available: false,
+        z: 0,
+        resp_code,
+        questions_count: request.questions.len() as u16,
+        answers_count: 0,
+        authorities_count: 0,
+        additionals_count: 0,
+        ..request.header.clone()
+    };
+    dns::Message {
+        header,
+        questions: request.questions.clone(),
+        answers: vec![],
+        authorities: vec![],
+        additionals: vec![],
+        edns: request.response_edns(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::{EchoHandler, TestClient};
+    use std::sync::Mutex;
+
+    // Append the suffix to the queried names, refuse the names starting with `refuse`,
+    // drop the ones starting with `drop` and record the responses it sees.
+    struct TestLayer {
+        suffix: &'static str,
+        responses: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Layer for TestLayer {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        fn on_request(&self, _: &RequestSource, request: &mut dns::Message) -> LayerAction {
+            let node = request.questions[0].node.to_string();
+            if node.starts_with("refuse") {
+                return LayerAction::Reply(layer_response(request, dns::RespCode::Refused));
+            }
+            if node.starts_with("drop") {
+                return LayerAction::Drop;
+            }
+            let node = format!("{}{}.", node.trim_end_matches('.'), self.suffix);
+            request.questions[0].node = dns::Name::from_string(&node).unwrap();
+            LayerAction::Continue
+        }
+
+        fn on_response(&self, _: &RequestSource, request: &dns::Message, response: &mut dns::Message) {
+            let entry = format!("{} {}", self.suffix, request.questions[0].node);
+            self.responses.lock().unwrap().push(entry);
+            response.header.recursion_available = true;
+        }
+    }
+
+    #[test]
+    fn test_layers() {
+        let responses = Arc::new(Mutex::new(vec![]));
+        let layer = |suffix| -> Box<dyn Layer> {
+            let responses = Arc::clone(&responses);
+            Box::new(TestLayer { suffix, responses })
+        };
+        let handler = LayeredHandler::new(EchoHandler::default(), vec![layer(".one"), layer(".two")]);
+        let client = TestClient::default();
+        let query = |node| client.query(&handler, &dns::Name::from_string(node).unwrap(), dns::RecordType::A);
+
+        // The requests go through the layers in order, the responses in reverse order.
+        // The inner handler echoes the request it received, as changed by the layers.
+        let reply = query("example.").unwrap();
+        assert_eq!(reply.header.resp_code, dns::RespCode::NoError);
+        assert!(reply.header.recursion_available);
+        assert_eq!(reply.questions[0].node.as_ref(), "example.one.two.");
+        assert_eq!(*responses.lock().unwrap(), [".two example.", ".one example."]);
+
+        // Short-circuited responses are seen only by the previous layers,
+        // while the inner handler, which replies to all the requests, is skipped.
+        responses.lock().unwrap().clear();
+        let reply = query("refuse.").unwrap();
+        assert_eq!(reply.header.resp_code, dns::RespCode::Refused);
+        assert_eq!(*responses.lock().unwrap(), Vec::<String>::new());
+        assert!(query("drop.").is_none());
+        assert_eq!(*responses.lock().unwrap(), Vec::<String>::new());
+    }
+}
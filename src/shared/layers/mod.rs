@@ -0,0 +1,7 @@
+mod builtin;
+mod conf;
+mod handler;
+
+pub use builtin::*;
+pub use conf::*;
+pub use handler::*;
@@ -0,0 +1,191 @@
+use crate::shared::dns;
+use crate::shared::metrics::registry::*;
+use crate::shared::net::*;
+use crate::shared::thread_pool::QueueStats;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::sync::Arc;
+use std::{io, time};
+
+/// The metrics of the requests served by the dns servers, recorded by a
+/// [MeteredHandler]: the queries received by transport and type, the responses
+/// sent by transport and response code and the latency of the handlers.
+pub struct ServerMetrics {
+    queries: Arc<Family<Counter>>,
+    responses: Arc<Family<Counter>>,
+    duration: Arc<Family<Histogram>>,
+}
+
+impl ServerMetrics {
+    /// Creates the metrics, registering them in the passed registry.
+    pub fn register(registry: &Registry) -> Self {
+        ServerMetrics {
+            queries: registry.register(
+                "ariadne_queries_total",
+                "Queries received, by transport and query type.",
+                Family::new(&["transport", "type"], Counter::default),
+            ),
+            responses: registry.register(
+                "ariadne_responses_total",
+                "Responses sent, by transport and response code (DROPPED if not answered).",
+                Family::new(&["transport", "rcode"], Counter::default),
+            ),
+            duration: registry.register(
+                "ariadne_request_duration_seconds",
+                "Time spent serving requests, by handler.",
+                Family::new(&["handler"], || Histogram::new(LATENCY_BUCKETS)),
+            ),
+        }
+    }
+}
+
+/// A [DnsHandler] wrapping another one, recording the [ServerMetrics] of the
+/// requests served by it. The `name` identifies the handler in the metrics.
+pub struct MeteredHandler<H> {
+    pub inner: Arc<H>,
+    pub name: &'static str,
+    pub metrics: Arc<ServerMetrics>,
+}
+
+impl<H: DnsHandler> DnsHandler for MeteredHandler<H> {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        let (req, resp) = self.wrap(req, resp);
+        self.inner.handle_request(req, resp);
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H: AsyncDnsHandler> AsyncDnsHandler for MeteredHandler<H> {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let (req, resp) = self.wrap(req, resp);
+        let inner = Arc::clone(&self.inner);
+        async move { inner.handle_request_async(req, resp).await }
+    }
+}
+
+impl<H> MeteredHandler<H> {
+    pub fn new(name: &'static str, inner: H, metrics: ServerMetrics) -> Self {
+        MeteredHandler {
+            inner: Arc::new(inner),
+            name,
+            metrics: Arc::new(metrics),
+        }
+    }
+
+    fn wrap<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) -> (MeteredRead<R>, MeteredWrite<W>) {
+        let transport = req.source().transport.name();
+        let req = MeteredRead {
+            inner: req,
+            transport,
+            metrics: Arc::clone(&self.metrics),
+        };
+        let resp = MeteredWrite {
+            inner: Some(resp),
+            transport,
+            handler: self.name,
+            start: time::Instant::now(),
+            metrics: Arc::clone(&self.metrics),
+        };
+        (req, resp)
+    }
+}
+
+// Record the type of the query when the request is read.
+struct MeteredRead<R> {
+    inner: R,
+    transport: &'static str,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl<R: DnsRead> DnsRead for MeteredRead<R> {
+    fn read(self) -> DnsReadResult {
+        let request = self.inner.read();
+        let query_type = match &request {
+            DnsReadResult::FullMessage(msg) => match msg.questions.first() {
+                Some(question) => format!("{:?}", question.record_type),
+                None => "NONE".to_string(),
+            },
+            _ => "INVALID".to_string(),
+        };
+        self.metrics.queries.with(&[self.transport, &query_type]).inc();
+        request
+    }
+
+    fn source(&self) -> RequestSource {
+        self.inner.source()
+    }
+}
+
+// Record the response code and the latency when the response is sent. Requests
+// left unanswered by the handler are recorded when the writer is dropped.
+struct MeteredWrite<W> {
+    inner: Option<W>,
+    transport: &'static str,
+    handler: &'static str,
+    start: time::Instant,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl<W: DnsWrite> DnsWrite for MeteredWrite<W> {
+    fn reply(mut self, response: dns::Message) -> io::Result<()> {
+        let rcode = format!("{:?}", response.header.resp_code).to_uppercase();
+        self.record(&rcode);
+        self.inner.take().unwrap().reply(response)
+    }
+
+    fn annotate(&mut self, resolution: Resolution) {
+        if let Some(inner) = self.inner.as_mut() {
+            inner.annotate(resolution);
+        }
+    }
+}
+
+impl<W> MeteredWrite<W> {
+    fn record(&self, rcode: &str) {
+        self.metrics.responses.with(&[self.transport, rcode]).inc();
+        let elapsed = self.start.elapsed().as_secs_f64();
+        self.metrics.duration.with(&[self.handler]).observe(elapsed);
+    }
+}
+
+impl<W> Drop for MeteredWrite<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            self.record("DROPPED");
+        }
+    }
+}
+
+/// Registers the depth and the shed requests counters of the thread pools
+/// queues of the servers, as returned by [ServerHandle::queue_stats].
+pub fn register_queue_stats(registry: &Registry, queue_stats: &[(&'static str, Arc<QueueStats>)]) {
+    let stats = queue_stats.to_vec();
+    registry.register(
+        "ariadne_queue_depth",
+        "Requests queued waiting for a thread, by server.",
+        Computed::gauge(&["server"], move || {
+            let depth = |(server, stats): &(&str, Arc<QueueStats>)| (vec![server.to_string()], stats.depth() as f64);
+            stats.iter().map(depth).collect()
+        }),
+    );
+    let stats = queue_stats.to_vec();
+    registry.register(
+        "ariadne_queue_shed_total",
+        "Requests shed because the queue was full or they were queued for too long, by server.",
+        Computed::counter(&["server", "reason"], move || {
+            let mut samples = vec![];
+            for (server, stats) in &stats {
+                samples.push((vec![server.to_string(), "full".to_string()], stats.shed_full() as f64));
+                samples.push((
+                    vec![server.to_string(), "expired".to_string()],
+                    stats.shed_expired() as f64,
+                ));
+            }
+            samples
+        }),
+    );
+}
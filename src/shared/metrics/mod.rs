@@ -0,0 +1,7 @@
+mod handler;
+mod registry;
+mod server;
+
+pub use handler::*;
+pub use registry::*;
+pub use server::*;
@@ -0,0 +1,338 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The buckets (in seconds) used by the latency histograms.
+pub const LATENCY_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// A metric that can be registered in a [Registry] and exposed in the
+/// Prometheus text format. The `labels` passed to [Metric::encode] are
+/// already formatted (e.g. `a="x",b="y"`) and can be empty.
+pub trait Metric: Send + Sync {
+    fn kind(&self) -> &'static str;
+    fn encode(&self, name: &str, labels: &str, out: &mut String);
+}
+
+/// A monotonically increasing counter.
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    fn kind(&self) -> &'static str {
+        "counter"
+    }
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        write_sample(out, name, labels, self.get() as f64);
+    }
+}
+
+/// A value that can go up and down.
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn set(&self, v: i64) {
+        self.0.store(v, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Gauge {
+    fn kind(&self) -> &'static str {
+        "gauge"
+    }
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        write_sample(out, name, labels, self.get() as f64);
+    }
+}
+
+/// A histogram of observed values, with the passed upper bounds of the buckets.
+#[derive(Debug)]
+pub struct Histogram {
+    bounds: Vec<f64>,
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &[f64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            state: Mutex::new(HistogramState {
+                buckets: vec![0; bounds.len()],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, v: f64) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(i) = self.bounds.iter().position(|bound| v <= *bound) {
+            state.buckets[i] += 1;
+        }
+        state.sum += v;
+        state.count += 1;
+    }
+}
+
+impl Metric for Histogram {
+    fn kind(&self) -> &'static str {
+        "histogram"
+    }
+
+    // Buckets are cumulative in the Prometheus format.
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        let state = self.state.lock().unwrap();
+        let sep = if labels.is_empty() { "" } else { "," };
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, n) in self.bounds.iter().zip(&state.buckets) {
+            cumulative += n;
+            let labels = format!("{}{}le=\"{}\"", labels, sep, bound);
+            write_sample(out, &bucket_name, &labels, cumulative as f64);
+        }
+        let labels_inf = format!("{}{}le=\"+Inf\"", labels, sep);
+        write_sample(out, &bucket_name, &labels_inf, state.count as f64);
+        write_sample(out, &format!("{}_sum", name), labels, state.sum);
+        write_sample(out, &format!("{}_count", name), labels, state.count as f64);
+    }
+}
+
+/// A set of metrics of the same kind, one for every combination of values of
+/// the `labels`. Metrics are created with `new_metric` the first time used.
+pub struct Family<M> {
+    labels: Vec<&'static str>,
+    kind: &'static str,
+    new_metric: Box<dyn Fn() -> M + Send + Sync>,
+    metrics: Mutex<BTreeMap<Vec<String>, Arc<M>>>,
+}
+
+impl<M: Metric> Family<M> {
+    pub fn new<F>(labels: &[&'static str], new_metric: F) -> Self
+    where
+        F: Fn() -> M + Send + Sync + 'static,
+    {
+        Family {
+            labels: labels.to_vec(),
+            kind: new_metric().kind(),
+            new_metric: Box::new(new_metric),
+            metrics: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the metric with the passed label values, in the same order of the labels.
+    pub fn with(&self, values: &[&str]) -> Arc<M> {
+        assert_eq!(values.len(), self.labels.len());
+        let key: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+        let mut metrics = self.metrics.lock().unwrap();
+        let metric = metrics.entry(key).or_insert_with(|| Arc::new((self.new_metric)()));
+        Arc::clone(metric)
+    }
+}
+
+impl<M: Metric> Metric for Family<M> {
+    fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    fn encode(&self, name: &str, labels: &str, out: &mut String) {
+        for (values, metric) in self.metrics.lock().unwrap().iter() {
+            let family_labels = format_labels(&self.labels, values);
+            match labels.is_empty() {
+                true => metric.encode(name, &family_labels, out),
+                false => metric.encode(name, &format!("{},{}", labels, family_labels), out),
+            }
+        }
+    }
+}
+
+/// A metric whose samples are computed when the registry is encoded, used to
+/// expose values owned by other components (e.g. the size of a cache). The
+/// function returns the label values and the value of every sample.
+pub struct Computed {
+    kind: &'static str,
+    labels: Vec<&'static str>,
+    compute: Box<dyn Fn() -> Vec<(Vec<String>, f64)> + Send + Sync>,
+}
+
+impl Computed {
+    pub fn gauge<F>(labels: &[&'static str], compute: F) -> Self
+    where
+        F: Fn() -> Vec<(Vec<String>, f64)> + Send + Sync + 'static,
+    {
+        Computed {
+            kind: "gauge",
+            labels: labels.to_vec(),
+            compute: Box::new(compute),
+        }
+    }
+
+    pub fn counter<F>(labels: &[&'static str], compute: F) -> Self
+    where
+        F: Fn() -> Vec<(Vec<String>, f64)> + Send + Sync + 'static,
+    {
+        Computed {
+            kind: "counter",
+            ..Computed::gauge(labels, compute)
+        }
+    }
+}
+
+impl Metric for Computed {
+    fn kind(&self) -> &'static str {
+        self.kind
+    }
+
+    fn encode(&self, name: &str, _labels: &str, out: &mut String) {
+        for (values, v) in (self.compute)() {
+            write_sample(out, name, &format_labels(&self.labels, &values), v);
+        }
+    }
+}
+
+// A registered metric, with its name and description.
+type Entry = (String, String, Arc<dyn Metric>);
+
+/// The set of metrics exposed by a process. Metrics are registered once,
+/// with a unique name, and then updated through the returned handles.
+#[derive(Default)]
+pub struct Registry {
+    metrics: Mutex<Vec<Entry>>,
+}
+
+impl Registry {
+    /// Registers the metric with the passed name and description.
+    /// Panics if a metric with the same name is already registered.
+    pub fn register<M: Metric + 'static>(&self, name: &str, help: &str, metric: M) -> Arc<M> {
+        let metric = Arc::new(metric);
+        let mut metrics = self.metrics.lock().unwrap();
+        assert!(
+            metrics.iter().all(|(n, _, _)| n != name),
+            "metric '{}' already registered",
+            name
+        );
+        metrics.push((
+            name.to_string(),
+            help.to_string(),
+            Arc::clone(&metric) as Arc<dyn Metric>,
+        ));
+        metric
+    }
+
+    /// Encodes all the registered metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        for (name, help, metric) in self.metrics.lock().unwrap().iter() {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, metric.kind());
+            metric.encode(name, "", &mut out);
+        }
+        out
+    }
+}
+
+fn write_sample(out: &mut String, name: &str, labels: &str, v: f64) {
+    let _ = match labels.is_empty() {
+        true => writeln!(out, "{} {}", name, v),
+        false => writeln!(out, "{}{{{}}} {}", name, labels, v),
+    };
+}
+
+fn format_labels(names: &[&str], values: &[String]) -> String {
+    let labels: Vec<String> = names
+        .iter()
+        .zip(values)
+        .map(|(name, v)| format!("{}=\"{}\"", name, escape_label(v)))
+        .collect();
+    labels.join(",")
+}
+
+fn escape_label(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        let registry = Registry::default();
+        let counter = registry.register("requests_total", "Requests.", Counter::default());
+        let family = registry.register("answers_total", "Answers.", Family::new(&["rcode"], Counter::default));
+        let histogram = registry.register("latency_seconds", "Latency.", Histogram::new(&[0.1, 1.0]));
+        registry.register(
+            "size",
+            "Size.",
+            Computed::gauge(&["name"], || vec![(vec!["a\"b".into()], 3.0)]),
+        );
+
+        counter.inc_by(2);
+        family.with(&["NOERROR"]).inc();
+        family.with(&["SERVFAIL"]).inc();
+        family.with(&["NOERROR"]).inc();
+        histogram.observe(0.05);
+        histogram.observe(0.5);
+        histogram.observe(7.0);
+
+        let expected = r#"# HELP requests_total Requests.
+# TYPE requests_total counter
+requests_total 2
+# HELP answers_total Answers.
+# TYPE answers_total counter
+answers_total{rcode="NOERROR"} 2
+answers_total{rcode="SERVFAIL"} 1
+# HELP latency_seconds Latency.
+# TYPE latency_seconds histogram
+latency_seconds_bucket{le="0.1"} 1
+latency_seconds_bucket{le="1"} 2
+latency_seconds_bucket{le="+Inf"} 3
+latency_seconds_sum 7.55
+latency_seconds_count 3
+# HELP size Size.
+# TYPE size gauge
+size{name="a\"b"} 3
+"#;
+        assert_eq!(registry.encode(), expected);
+    }
+
+    #[test]
+    fn test_histogram_family() {
+        let family = Family::new(&["handler"], || Histogram::new(&[1.0]));
+        family.with(&["resolver"]).observe(0.5);
+        let mut out = String::new();
+        family.encode("duration", "", &mut out);
+        assert_eq!(family.kind(), "histogram");
+        assert!(out.contains("duration_bucket{handler=\"resolver\",le=\"1\"} 1\n"));
+        assert!(out.contains("duration_count{handler=\"resolver\"} 1\n"));
+    }
+}
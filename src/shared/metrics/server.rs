@@ -0,0 +1,103 @@
+use crate::shared::metrics::registry::Registry;
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::{io, net, thread, time};
+
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Starts serving the metrics of the registry over HTTP, in the Prometheus text
+/// format, on the `/metrics` path of the passed address. Scrapes are served one
+/// at a time by a background thread. Returns the address the server is bound to.
+pub fn start_metrics_server(registry: Arc<Registry>, address: net::SocketAddr) -> io::Result<net::SocketAddr> {
+    let listener = net::TcpListener::bind(address)?;
+    let local_addr = listener.local_addr()?;
+    log::info!("Starting metrics server, address: '{}'.", local_addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let served = stream.and_then(|mut stream| serve_scrape(&mut stream, &registry));
+            if let Err(err) = served {
+                log::warn!("Serving metrics scrape: {}", err);
+            }
+        }
+    });
+    Ok(local_addr)
+}
+
+// Read the request head and answer with the metrics. The connection
+// is closed after the response, since scrapes are infrequent.
+fn serve_scrape(stream: &mut net::TcpStream, registry: &Registry) -> io::Result<()> {
+    stream.set_read_timeout(Some(time::Duration::from_secs(2)))?;
+    stream.set_write_timeout(Some(time::Duration::from_secs(2)))?;
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_LEN {
+            return write_response(stream, "431 Request Header Fields Too Large", "");
+        }
+        match stream.read(&mut buf)? {
+            0 => return Ok(()),
+            n => head.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let mut request_line = head.lines().next().unwrap_or("").split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => write_response(stream, "200 OK", &registry.encode()),
+        (Some("GET"), _) => write_response(stream, "404 Not Found", ""),
+        _ => write_response(stream, "405 Method Not Allowed", ""),
+    }
+}
+
+fn write_response(stream: &mut net::TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dns;
+    use crate::shared::metrics::*;
+    use crate::shared::net::*;
+
+    const QUERY: &[u8] = include_bytes!("../../../assets/messages/query_packet_bin.txt");
+
+    fn scrape(addr: net::SocketAddr, path: &str) -> String {
+        let mut stream = net::TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_scrape_metrics() {
+        let registry = Arc::new(Registry::default());
+        let handler = MeteredHandler::new("echo", EchoHandler::default(), ServerMetrics::register(&registry));
+        let client = TestClient::new("127.0.0.1:5300".parse().unwrap(), Transport::Tcp);
+        assert!(client.send_bytes(&handler, QUERY.to_vec()).is_some());
+        assert!(client.send_bytes(&handler, vec![1, 2, 3]).is_none());
+
+        let addr = start_metrics_server(Arc::clone(&registry), "127.0.0.1:0".parse().unwrap()).unwrap();
+        let response = scrape(addr, "/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let query_type = format!(
+            "{:?}",
+            dns::Message::decode_from_bytes(QUERY).unwrap().questions[0].record_type
+        );
+        let queries = format!("ariadne_queries_total{{transport=\"tcp\",type=\"{}\"}} 1\n", query_type);
+        assert!(response.contains(&queries));
+        assert!(response.contains("ariadne_queries_total{transport=\"tcp\",type=\"INVALID\"} 1\n"));
+        assert!(response.contains("ariadne_responses_total{transport=\"tcp\",rcode=\"NOERROR\"} 1\n"));
+        assert!(response.contains("ariadne_responses_total{transport=\"tcp\",rcode=\"DROPPED\"} 1\n"));
+        assert!(response.contains("ariadne_request_duration_seconds_count{handler=\"echo\"} 2\n"));
+
+        assert!(scrape(addr, "/other").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
@@ -1,5 +1,13 @@
 pub mod buffer;
+pub mod client;
+pub mod conf;
+pub mod control;
 pub mod dns;
+pub mod dnstap;
+pub mod layers;
 pub mod logs;
+pub mod metrics;
 pub mod net;
+pub mod privileges;
+pub mod query_log;
 pub mod thread_pool;
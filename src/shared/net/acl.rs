@@ -0,0 +1,151 @@
+use std::str::FromStr;
+use std::{fmt, net};
+
+/// A network prefix in CIDR notation (e.g. `10.0.0.0/8` or `2001:db8::/32`).
+/// A bare address is parsed as a prefix matching only that address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpPrefix {
+    addr: net::IpAddr,
+    len: u8,
+}
+
+impl IpPrefix {
+    /// Reports if the address is contained in the prefix. IPv4 addresses
+    /// mapped to IPv6 (e.g. from dual-stack sockets) are matched as IPv4.
+    pub fn contains(&self, addr: net::IpAddr) -> bool {
+        let addr = match addr {
+            net::IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, net::IpAddr::V4),
+            v4 => v4,
+        };
+        match (self.addr, addr) {
+            (net::IpAddr::V4(prefix), net::IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.len as u32).unwrap_or(0);
+                u32::from(prefix) & mask == u32::from(addr) & mask
+            }
+            (net::IpAddr::V6(prefix), net::IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.len as u32).unwrap_or(0);
+                u128::from(prefix) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr = net::IpAddr::from_str(addr).map_err(|err| format!("prefix '{}': {}", s, err))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let len = match len {
+            None => max_len,
+            Some(len) => match len.parse::<u8>() {
+                Ok(v) if v <= max_len => v,
+                _ => return Err(format!("prefix '{}': invalid length", s)),
+            },
+        };
+        Ok(IpPrefix { addr, len })
+    }
+}
+
+impl fmt::Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// An access control list of client addresses. A client is allowed
+/// if its address is contained in one of the `allow` prefixes and
+/// in none of the `deny` ones. An empty `allow` list denies everyone,
+/// use [Acl::new] to allow every address not denied.
+#[derive(Clone, Debug, Default)]
+pub struct Acl {
+    pub allow: Vec<IpPrefix>,
+    pub deny: Vec<IpPrefix>,
+}
+
+impl Acl {
+    /// Builds the list from the allowed and denied prefixes,
+    /// a missing `allow` list allows every address not denied.
+    pub fn new(allow: Option<Vec<IpPrefix>>, deny: Vec<IpPrefix>) -> Self {
+        let any = |addr: net::IpAddr| IpPrefix { addr, len: 0 };
+        let allow = allow.unwrap_or_else(|| {
+            vec![
+                any(net::Ipv4Addr::UNSPECIFIED.into()),
+                any(net::Ipv6Addr::UNSPECIFIED.into()),
+            ]
+        });
+        Acl { allow, deny }
+    }
+
+    /// Reports if the client address is allowed by the list.
+    pub fn allows(&self, addr: net::IpAddr) -> bool {
+        self.allow.iter().any(|p| p.contains(addr)) && !self.deny.iter().any(|p| p.contains(addr))
+    }
+}
+
+/// How requests of clients denied by an [Acl] are handled: either
+/// answered with the REFUSED response code or silently dropped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AclAction {
+    #[default]
+    Refuse,
+    Drop,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_prefix() {
+        let prefix: IpPrefix = "10.1.0.0/16".parse().unwrap();
+        assert!(prefix.contains("10.1.200.3".parse().unwrap()));
+        assert!(prefix.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!prefix.contains("10.2.0.1".parse().unwrap()));
+        assert!(!prefix.contains("::1".parse().unwrap()));
+
+        let prefix: IpPrefix = "2001:db8::/32".parse().unwrap();
+        assert!(prefix.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!prefix.contains("2001:db9::1".parse().unwrap()));
+
+        let any: IpPrefix = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("192.168.1.1".parse().unwrap()));
+        let host: IpPrefix = "::1".parse().unwrap();
+        assert_eq!(host.to_string(), "::1/128");
+        assert!(host.contains("::1".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpPrefix>().is_err());
+        assert!("10.0.0/8".parse::<IpPrefix>().is_err());
+        assert!("::/x".parse::<IpPrefix>().is_err());
+    }
+
+    #[test]
+    fn test_acl() {
+        let acl = Acl {
+            allow: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+            deny: vec!["10.6.6.0/24".parse().unwrap()],
+        };
+        assert!(acl.allows("10.1.2.3".parse().unwrap()));
+        assert!(acl.allows("::1".parse().unwrap()));
+        assert!(!acl.allows("10.6.6.6".parse().unwrap()));
+        assert!(!acl.allows("192.168.0.1".parse().unwrap()));
+        assert!(!Acl::default().allows("10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_acl_deny_only() {
+        let acl = Acl::new(None, vec!["10.6.6.0/24".parse().unwrap()]);
+        assert!(acl.allows("10.1.2.3".parse().unwrap()));
+        assert!(acl.allows("2001:db8::1".parse().unwrap()));
+        assert!(!acl.allows("10.6.6.6".parse().unwrap()));
+        assert!(!acl.allows("::ffff:10.6.6.6".parse().unwrap()));
+
+        let acl = Acl::new(Some(vec![]), vec![]);
+        assert!(!acl.allows("10.1.2.3".parse().unwrap()));
+    }
+}
@@ -0,0 +1,210 @@
+use crate::shared::dns;
+use crate::shared::net::async_tcp_server::*;
+use crate::shared::net::async_udp_server::*;
+use crate::shared::net::tcp_server::TcpParams;
+use crate::shared::net::traits::*;
+use crate::shared::net::udp_server::UdpParams;
+use crate::shared::net::utils::wait_stop;
+use std::sync::{atomic, Arc};
+use std::{io, time};
+use tokio::runtime;
+use tokio::sync::{oneshot, Semaphore};
+
+/// Parameters of the Tokio runtime driving the async servers started with
+/// [start_async_servers]. The worker threads poll sockets and futures, while
+/// the blocking threads run handlers performing blocking operations (e.g.
+/// the resolver waiting for upstream nameservers). At most `max_pending`
+/// requests are served at the same time, the following ones are shed: UDP
+/// requests are handled as the `overload_action` of the UDP server, while
+/// TCP requests are answered with SERVFAIL. This bounds the jobs waiting for
+/// a free blocking thread, whose queue is otherwise unbounded.
+#[derive(Clone)]
+pub struct AsyncParams {
+    pub worker_threads: usize,
+    pub blocking_threads: usize,
+    pub max_pending: usize,
+    pub drain_timeout: time::Duration,
+}
+
+/// Setup and start async UDP and TCP dns servers on a new Tokio runtime. The
+/// function blocks the current thread. When one of the servers errors or exits,
+/// or the `stop` flag is set, the servers stop accepting requests and the tasks
+/// in flight are given up to the drain timeout to complete. Note that the
+/// `threads` parameters of the servers are not used, tasks are spawned instead.
+pub fn start_async_servers<H>(
+    handler: Arc<H>,
+    udp_params: UdpParams,
+    tcp_params: TcpParams,
+    params: AsyncParams,
+    stop: &atomic::AtomicBool,
+) where
+    H: AsyncDnsHandler,
+{
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(params.worker_threads)
+        .max_blocking_threads(params.blocking_threads)
+        .thread_name("async-worker")
+        .enable_all()
+        .build();
+    let runtime = match runtime {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Cannot build the async runtime: {}", err);
+            return;
+        }
+    };
+
+    let pending = Arc::new(Semaphore::new(params.max_pending));
+    runtime.block_on(async {
+        tokio::select! {
+            _ = start_async_udp_server(Arc::clone(&handler), udp_params, Arc::clone(&pending)) => log::warn!("UDP server shut down."),
+            _ = start_async_tcp_server(Arc::clone(&handler), tcp_params, Arc::clone(&pending)) => log::warn!("TCP server shut down."),
+            _ = wait_stop(stop) => log::warn!("Stopping servers, draining requests in flight."),
+        }
+
+        // The servers futures are dropped here, wait for the spawned tasks.
+        let deadline = tokio::time::Instant::now() + params.drain_timeout;
+        let metrics = runtime::Handle::current().metrics();
+        while metrics.num_alive_tasks() > 0 && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(time::Duration::from_millis(50)).await;
+        }
+    });
+    runtime.shutdown_timeout(time::Duration::from_secs(1));
+}
+
+/// The request read by the async servers. The request is read from the socket
+/// and decoded by the server task, the [DnsRead] implementation only returns it.
+pub struct AsyncRequest {
+    pub request: DnsReadResult,
+    pub source: RequestSource,
+}
+
+impl DnsRead for AsyncRequest {
+    fn read(self) -> DnsReadResult {
+        self.request
+    }
+
+    fn source(&self) -> RequestSource {
+        self.source
+    }
+}
+
+/// The response of the async servers. The [DnsWrite] implementation hands the
+/// response back to the server task owning the socket, which encodes and sends
+/// it to the client without blocking the handler.
+pub struct AsyncResponse(oneshot::Sender<dns::Message>);
+
+impl DnsWrite for AsyncResponse {
+    fn reply(self, response: dns::Message) -> io::Result<()> {
+        match self.0.send(response) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "server task gone")),
+        }
+    }
+}
+
+/// Run the handler over the passed request and wait for its response.
+/// `None` is returned if the handler decided to not reply at all.
+pub(crate) async fn dispatch_request<H: AsyncDnsHandler>(
+    handler: &Arc<H>,
+    request: DnsReadResult,
+    source: RequestSource,
+) -> Option<dns::Message> {
+    let (tx, rx) = oneshot::channel();
+    handler
+        .handle_request_async(AsyncRequest { request, source }, AsyncResponse(tx))
+        .await;
+    rx.await.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::listen::ListenAddr;
+    use crate::shared::net::udp_server::OverloadAction;
+    use crate::shared::net::SlowHandler;
+    use crate::shared::thread_pool::QueueLimits;
+    use std::io::{Read, Write};
+    use std::{net, thread};
+
+    const QUERY: &[u8] = include_bytes!("../../../assets/messages/query_packet_bin.txt");
+
+    #[test]
+    fn test_shed_pending_requests() {
+        let listeners = vec![ListenAddr {
+            address: "127.0.0.1:48063".parse().unwrap(),
+            ipv6_only: false,
+        }];
+        let udp_params = UdpParams {
+            listeners: listeners.clone(),
+            write_timeout: time::Duration::from_secs(2),
+            threads: 1,
+            sockets_per_listener: 1,
+            batch_size: 1,
+            queue: QueueLimits::UNBOUNDED,
+            overload_action: OverloadAction::ServFail,
+        };
+        let tcp_params = TcpParams {
+            listeners,
+            write_timeout: time::Duration::from_secs(2),
+            read_timeout: time::Duration::from_secs(2),
+            idle_timeout: time::Duration::from_secs(10),
+            max_connections: 4,
+            threads: 1,
+            queue: QueueLimits::UNBOUNDED,
+            proxy_trusted: vec![],
+        };
+        let params = AsyncParams {
+            worker_threads: 1,
+            blocking_threads: 1,
+            max_pending: 1,
+            drain_timeout: time::Duration::from_secs(2),
+        };
+        let stop = Arc::new(atomic::AtomicBool::new(false));
+        let stop_clone = Arc::clone(&stop);
+        let server = thread::spawn(move || {
+            start_async_servers(
+                Arc::new(SlowHandler {
+                    delay: time::Duration::from_millis(500),
+                }),
+                udp_params,
+                tcp_params,
+                params,
+                &stop_clone,
+            )
+        });
+        thread::sleep(time::Duration::from_millis(300));
+
+        // The first request takes the only pending slot, the
+        // following ones are answered right away with SERVFAIL.
+        let udp_socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp_socket.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        udp_socket.send_to(QUERY, "127.0.0.1:48063").unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+        udp_socket.send_to(QUERY, "127.0.0.1:48063").unwrap();
+        let mut tcp_stream = net::TcpStream::connect("127.0.0.1:48063").unwrap();
+        tcp_stream.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        tcp_stream.write_all(&(QUERY.len() as u16).to_be_bytes()).unwrap();
+        tcp_stream.write_all(QUERY).unwrap();
+
+        let mut buf = [0; 512];
+        let n = udp_socket.recv(&mut buf).unwrap();
+        let shed = dns::Message::decode_from_bytes(&buf[..n]).unwrap();
+        assert_eq!(shed.header.resp_code, dns::RespCode::ServFail);
+        let mut len = [0; 2];
+        tcp_stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        tcp_stream.read_exact(&mut buf).unwrap();
+        let shed = dns::Message::decode_from_bytes(&buf).unwrap();
+        assert_eq!(shed.header.resp_code, dns::RespCode::ServFail);
+
+        let mut buf = [0; 512];
+        let n = udp_socket.recv(&mut buf).unwrap();
+        let served = dns::Message::decode_from_bytes(&buf[..n]).unwrap();
+        assert_eq!(served.header.resp_code, dns::RespCode::NoError);
+        assert!(!served.header.is_request());
+
+        stop.store(true, atomic::Ordering::SeqCst);
+        server.join().unwrap();
+    }
+}
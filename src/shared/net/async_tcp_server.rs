@@ -0,0 +1,180 @@
+use crate::shared::dns;
+use crate::shared::net::async_setup::*;
+use crate::shared::net::listen::*;
+use crate::shared::net::tcp_server::{keepalive_timeout, set_keepalive, TcpParams};
+use crate::shared::net::traits::*;
+use crate::shared::net::utils::servfail_response;
+use std::sync::Arc;
+use std::{io, time};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task::JoinSet;
+
+/// Starts a new async TCP server generic over a request handler ([AsyncDnsHandler]).
+/// The function loops accepting new TCP connections on every listener and spawns a
+/// new task for each one of them. The task reads the requests sent over the connection (RFC 7766) and
+/// spawns a new task for each one, using the dns handler to serve it and writing back
+/// the response. The [TcpParams] is used to setup the server. Every request task holds
+/// a permit of `pending`, requests read when none is available are answered with SERVFAIL.
+pub async fn start_async_tcp_server<H>(handler: Arc<H>, params: TcpParams, pending: Arc<Semaphore>)
+where
+    H: AsyncDnsHandler,
+{
+    let listeners = match bind_tcp_listeners(&params.listeners, "async TCP") {
+        Some(v) => v,
+        None => return,
+    };
+
+    // Serve every listener in its own task, sharing the connections
+    // limit. The tasks are aborted when the server future is dropped.
+    let connections = Arc::new(Semaphore::new(params.max_connections));
+    let mut listeners_tasks = JoinSet::new();
+    for listener in listeners {
+        let tcp_socket = match listener
+            .set_nonblocking(true)
+            .and_then(|_| net::TcpListener::from_std(listener))
+        {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Cannot setup socket: {}", err);
+                return;
+            }
+        };
+        let (handler, connections, pending) = (Arc::clone(&handler), Arc::clone(&connections), Arc::clone(&pending));
+        listeners_tasks.spawn(serve_listener(
+            handler,
+            tcp_socket,
+            connections,
+            pending,
+            params.clone(),
+        ));
+    }
+    while listeners_tasks.join_next().await.is_some() {}
+}
+
+async fn serve_listener<H: AsyncDnsHandler>(
+    handler: Arc<H>,
+    tcp_socket: net::TcpListener,
+    connections: Arc<Semaphore>,
+    pending: Arc<Semaphore>,
+    params: TcpParams,
+) {
+    // Loop accepting TCP connections. When a new one is accepted, spawn
+    // a new task to serve it, unless too many connections are open.
+    loop {
+        let (tcp_stream, src_addr) = match tcp_socket.accept().await {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Accepting tcp connection: {}", err);
+                continue;
+            }
+        };
+        let permit = match Arc::clone(&connections).try_acquire_owned() {
+            Ok(v) => v,
+            Err(_) => {
+                log::warn!("Max tcp connections reached, dropping connection from {}.", src_addr);
+                continue;
+            }
+        };
+
+        let (handler, pending) = (Arc::clone(&handler), Arc::clone(&pending));
+        let params = params.clone();
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(handler, tcp_stream, pending, &params).await {
+                log::warn!("Serving tcp connection from {}: {}", src_addr, err);
+            }
+            drop(permit);
+        });
+    }
+}
+
+// Read the length-prefixed requests until the client closes the connection or
+// the idle timeout expires. Every request is served in its own task and the
+// length-prefixed responses are written back as soon as they are ready. When
+// too many requests are pending, the new ones are answered with SERVFAIL.
+async fn serve_connection<H: AsyncDnsHandler>(
+    handler: Arc<H>,
+    tcp_stream: net::TcpStream,
+    pending: Arc<Semaphore>,
+    params: &TcpParams,
+) -> io::Result<()> {
+    let source = RequestSource {
+        addr: tcp_stream.peer_addr()?,
+        transport: Transport::Tcp,
+    };
+    let (mut reader, writer) = tcp_stream.into_split();
+    let writer = Arc::new(Mutex::new(writer));
+
+    // The first request is awaited for the read timeout only,
+    // while the following ones for the idle timeout.
+    let mut wait_timeout = params.read_timeout;
+    loop {
+        let req_len = match tokio::time::timeout(wait_timeout, reader.read_u16()).await {
+            Ok(Ok(v)) => v,
+            Ok(Err(err)) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(err)) => return Err(err),
+            Err(_) => return Ok(()),
+        };
+        let req_bytes = with_timeout(params.read_timeout, async {
+            let mut buf = vec![0_u8; req_len as usize];
+            reader.read_exact(&mut buf).await?;
+            Ok(buf)
+        })
+        .await?;
+
+        let permit = match Arc::clone(&pending).try_acquire_owned() {
+            Ok(v) => v,
+            Err(_) => {
+                log::debug!("Too many pending requests, answering {} with SERVFAIL.", source.addr);
+                if let Some(response) = servfail_response(&req_bytes) {
+                    write_response(&writer, response, params.write_timeout).await?;
+                }
+                wait_timeout = params.idle_timeout;
+                continue;
+            }
+        };
+
+        let handler = Arc::clone(&handler);
+        let writer = Arc::clone(&writer);
+        let (idle_timeout, write_timeout) = (params.idle_timeout, params.write_timeout);
+        tokio::spawn(async move {
+            let _permit = permit;
+            let request = DnsReadResult::from_bytes(&req_bytes);
+            let keepalive = keepalive_timeout(&request, idle_timeout);
+            let mut response = match dispatch_request(&handler, request, source).await {
+                Some(v) => v,
+                None => return,
+            };
+            set_keepalive(&mut response, keepalive);
+            if let Err(err) = write_response(&writer, response, write_timeout).await {
+                log::warn!("Writing tcp response: {}", err);
+            }
+        });
+        wait_timeout = params.idle_timeout;
+    }
+}
+
+async fn write_response(
+    writer: &Mutex<OwnedWriteHalf>,
+    response: dns::Message,
+    timeout: time::Duration,
+) -> io::Result<()> {
+    let resp_bytes = response.encode_to_bytes().unwrap();
+    let mut buf = Vec::with_capacity(resp_bytes.len() + 2);
+    buf.extend_from_slice(&(resp_bytes.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&resp_bytes);
+    let mut writer = writer.lock().await;
+    with_timeout(timeout, writer.write_all(&buf)).await
+}
+
+async fn with_timeout<T>(
+    timeout: time::Duration,
+    f: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match tokio::time::timeout(timeout, f).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "tcp timeout")),
+    }
+}
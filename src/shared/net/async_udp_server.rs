@@ -0,0 +1,115 @@
+use crate::shared::dns;
+use crate::shared::net::async_setup::*;
+use crate::shared::net::listen::*;
+use crate::shared::net::traits::*;
+use crate::shared::net::udp_server::{OverloadAction, UdpParams};
+use crate::shared::net::utils::servfail_response;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::{net, time};
+
+/// Starts a new async UDP server generic over a request handler ([AsyncDnsHandler]).
+/// The function loops over new UDP messages and spawns a new task for each one of
+/// them, with a receiving loop for every listening socket. The task uses the dns
+/// handler to serve the request and sends back the response. The [UdpParams] is
+/// used to setup the server properly. Every task holds a permit of `pending`,
+/// requests received when none is available are handled as `overload_action`.
+pub async fn start_async_udp_server<H>(handler: Arc<H>, params: UdpParams, pending: Arc<Semaphore>)
+where
+    H: AsyncDnsHandler,
+{
+    let sockets = match bind_udp_sockets(&params.listeners, params.sockets_per_listener, "async UDP") {
+        Some(v) => v,
+        None => return,
+    };
+
+    // Serve every socket in its own task. The tasks
+    // are aborted when the server future is dropped.
+    let mut sockets_tasks = JoinSet::new();
+    for socket in sockets {
+        let socket = match socket
+            .set_nonblocking(true)
+            .and_then(|_| net::UdpSocket::from_std(socket))
+        {
+            Ok(v) => Arc::new(v),
+            Err(err) => {
+                log::error!("Cannot setup socket: {}", err);
+                return;
+            }
+        };
+        let (handler, pending) = (Arc::clone(&handler), Arc::clone(&pending));
+        sockets_tasks.spawn(serve_socket(handler, socket, pending, params.clone()));
+    }
+    while sockets_tasks.join_next().await.is_some() {}
+}
+
+async fn serve_socket<H: AsyncDnsHandler>(
+    handler: Arc<H>,
+    socket: Arc<net::UdpSocket>,
+    pending: Arc<Semaphore>,
+    params: UdpParams,
+) {
+    // Loop receiving UDP messages. When a new request arrives, spawn a new
+    // task to handle it and reply to the client, unless too many are pending.
+    loop {
+        let mut buffer = [0; dns::MAX_UDP_LEN_BYTES];
+        let (n_read, src_addr) = match socket.recv_from(&mut buffer).await {
+            Ok(read_data) => read_data,
+            Err(err) => {
+                log::warn!("Cannot recv_from socket: {}", err);
+                continue;
+            }
+        };
+
+        let permit = match Arc::clone(&pending).try_acquire_owned() {
+            Ok(v) => v,
+            Err(_) => {
+                shed_request(&socket, &buffer[..n_read], src_addr, params.overload_action);
+                continue;
+            }
+        };
+
+        let handler = Arc::clone(&handler);
+        let socket = Arc::clone(&socket);
+        let write_timeout = params.write_timeout;
+        tokio::spawn(async move {
+            let _permit = permit;
+            let request = DnsReadResult::from_bytes(&buffer[..n_read]);
+            let source = RequestSource {
+                addr: src_addr,
+                transport: Transport::Udp,
+            };
+            let response = match dispatch_request(&handler, request, source).await {
+                Some(v) => v,
+                None => return,
+            };
+            let resp_bytes = response.encode_to_bytes_trunc().unwrap();
+            match time::timeout(write_timeout, socket.send_to(&resp_bytes, src_addr)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(err)) => log::warn!("Cannot send_to socket: {}", err),
+                Err(_) => log::warn!("Timeout sending response to {}", src_addr),
+            }
+        });
+    }
+}
+
+// Handle a request exceeding the pending requests, answering it with SERVFAIL
+// if configured. The response is sent only if the socket is ready to send it.
+fn shed_request(socket: &net::UdpSocket, bytes: &[u8], addr: std::net::SocketAddr, action: OverloadAction) {
+    log::debug!(
+        "Too many pending requests, shedding request from {} ({:?}).",
+        addr,
+        action
+    );
+    if action == OverloadAction::Drop {
+        return;
+    }
+    let resp_bytes = match servfail_response(bytes).and_then(|resp| resp.encode_to_bytes().ok()) {
+        Some(v) => v,
+        None => return,
+    };
+    if let Err(err) = socket.try_send_to(&resp_bytes, addr) {
+        log::warn!("Cannot send_to socket: {}", err);
+    }
+}
@@ -0,0 +1,460 @@
+use crate::shared::dns;
+use crate::shared::net::listen::*;
+use crate::shared::net::tls_server::load_tls_config;
+use crate::shared::net::traits::*;
+use crate::shared::net::utils::wait_stop;
+use base64::Engine;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::{header, Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use std::convert::Infallible;
+use std::sync::{atomic, Arc};
+use std::{io, net, time};
+use tokio::sync::{mpsc, oneshot, watch, Semaphore};
+use tokio::task::JoinSet;
+
+/// The media type of dns messages exchanged over HTTPS (RFC 8484).
+pub const DNS_MESSAGE_MIME: &str = "application/dns-message";
+
+/// Parameters to be used when starting the DNS-over-HTTPS server with
+/// [start_https_server]. The `threads` are the ones used to run the dns
+/// handler, while the HTTP connections are driven by an async runtime.
+/// The `max_connections` limit is shared among all the `listeners`.
+#[derive(Clone)]
+pub struct HttpsParams {
+    pub listeners: Vec<ListenAddr>,
+    pub path: String,
+    pub read_timeout: time::Duration,
+    pub max_connections: usize,
+    pub threads: usize,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// The request coming from DoH clients. Implements [DnsRead] by returning
+/// the dns message carried by the HTTP request (either in the `dns` query
+/// parameter of GET requests or in the body of POST requests).
+pub struct HttpsRequest {
+    request: DnsReadResult,
+    source: RequestSource,
+}
+
+impl DnsRead for HttpsRequest {
+    fn read(self) -> DnsReadResult {
+        self.request
+    }
+
+    fn source(&self) -> RequestSource {
+        self.source
+    }
+}
+
+/// The response to DoH clients. Implements [DnsWrite] by handing the dns
+/// response back to the HTTP connection task, which sends it to the client
+/// in the body of the HTTP response.
+pub struct HttpsResponse(oneshot::Sender<dns::Message>);
+
+impl DnsWrite for HttpsResponse {
+    fn reply(self, response: dns::Message) -> io::Result<()> {
+        match self.0.send(response) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "https connection gone")),
+        }
+    }
+}
+
+/// Starts a new DNS-over-HTTPS server (RFC 8484) generic over a request handler ([DnsHandler]).
+/// Both HTTP/2 and HTTP/1.1 are supported, negotiated via ALPN. Connections are served by an
+/// async runtime, while the dns handler is executed in the blocking threads of the runtime. The
+/// [HttpsParams] is used to setup the server, while the `stop` argument can be used to stop it.
+pub fn start_https_server<H>(handler: Arc<H>, params: HttpsParams, stop: &atomic::AtomicBool)
+where
+    H: DnsHandler,
+{
+    let tls_config = match load_tls_config(&params.cert_file, &params.key_file, &[b"h2", b"http/1.1"]) {
+        Ok(v) => Arc::new(v),
+        Err(err) => {
+            log::error!("Cannot setup tls: {}", err);
+            return;
+        }
+    };
+    let listeners = match bind_tcp_listeners(&params.listeners, "HTTPS") {
+        Some(v) => v,
+        None => return,
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(params.threads)
+        .thread_name("https-worker")
+        .enable_all()
+        .build();
+    let runtime = match runtime {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Cannot build the https runtime: {}", err);
+            return;
+        }
+    };
+
+    runtime.block_on(serve_https(handler, params, listeners, tls_config, stop));
+    runtime.shutdown_timeout(time::Duration::from_secs(4));
+}
+
+async fn serve_https<H: DnsHandler>(
+    handler: Arc<H>,
+    params: HttpsParams,
+    listeners: Vec<net::TcpListener>,
+    tls_config: Arc<rustls::ServerConfig>,
+    stop: &atomic::AtomicBool,
+) {
+    // Accept connections on every listener in a dedicated task,
+    // forwarding them to the main loop.
+    let mut accepting = JoinSet::new();
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(listeners.len());
+    for listener in listeners {
+        let tcp_socket = match listener
+            .set_nonblocking(true)
+            .and_then(|_| tokio::net::TcpListener::from_std(listener))
+        {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Cannot setup socket: {}", err);
+                return;
+            }
+        };
+        let accepted_tx = accepted_tx.clone();
+        accepting.spawn(async move {
+            loop {
+                let accepted = tcp_socket.accept().await;
+                if accepted_tx.send(accepted).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(accepted_tx);
+
+    let acceptor = tokio_rustls::TlsAcceptor::from(tls_config);
+    let connections = Arc::new(Semaphore::new(params.max_connections));
+    let path: Arc<str> = Arc::from(params.path.as_str());
+    let mut http = auto::Builder::new(TokioExecutor::new());
+    http.http1()
+        .timer(hyper_util::rt::TokioTimer::new())
+        .header_read_timeout(params.read_timeout);
+
+    // Loop accepting TCP connections. When a new one is accepted, spawn a
+    // task performing the TLS handshake and serving the HTTP requests.
+    let (draining_tx, draining) = watch::channel(false);
+    loop {
+        let accepted = tokio::select! {
+            Some(accepted) = accepted_rx.recv() => accepted,
+            _ = wait_stop(stop) => break,
+        };
+        let (tcp_stream, src_addr) = match accepted {
+            Ok(v) => v,
+            Err(err) => {
+                log::error!("Accepting https connection: {}", err);
+                continue;
+            }
+        };
+
+        let permit = match Arc::clone(&connections).try_acquire_owned() {
+            Ok(v) => v,
+            Err(_) => {
+                log::warn!("Max https connections reached, dropping connection from {}.", src_addr);
+                continue;
+            }
+        };
+
+        let (acceptor, http, handler, path) = (acceptor.clone(), http.clone(), Arc::clone(&handler), Arc::clone(&path));
+        let mut draining = draining.clone();
+        let read_timeout = params.read_timeout;
+        tokio::spawn(async move {
+            let tls_stream = match tokio::time::timeout(read_timeout, acceptor.accept(tcp_stream)).await {
+                Ok(Ok(v)) => v,
+                Ok(Err(err)) => {
+                    log::warn!("Tls handshake with {}: {}", src_addr, err);
+                    return;
+                }
+                Err(_) => {
+                    log::warn!("Tls handshake with {}: timeout", src_addr);
+                    return;
+                }
+            };
+            let source = RequestSource {
+                addr: src_addr,
+                transport: Transport::Https,
+            };
+            let service = hyper::service::service_fn(move |req| {
+                serve_request(Arc::clone(&handler), Arc::clone(&path), source, req)
+            });
+            let conn = http.serve_connection(TokioIo::new(tls_stream), service);
+            tokio::pin!(conn);
+
+            // When draining, requests in flight are completed
+            // before closing the connection.
+            let served = tokio::select! {
+                served = conn.as_mut() => served,
+                _ = draining.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = served {
+                log::warn!("Serving https connection from {}: {}", src_addr, err);
+            }
+            drop(permit);
+        });
+    }
+
+    // Stop accepting connections and wait for the open ones to be closed.
+    accepting.shutdown().await;
+    let _ = draining_tx.send(true);
+    let _ = connections.acquire_many(params.max_connections as u32).await;
+}
+
+// Extract the dns message from the HTTP request, serve it with the handler and
+// compose the HTTP response. Errors in the HTTP request are reported with the
+// proper HTTP status codes, while dns errors are carried by the dns response.
+async fn serve_request<H: DnsHandler>(
+    handler: Arc<H>,
+    path: Arc<str>,
+    source: RequestSource,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if req.uri().path() != &*path {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+    let msg_bytes = match *req.method() {
+        Method::GET => match decode_dns_param(req.uri().query()) {
+            Some(v) => v,
+            None => return Ok(status_response(StatusCode::BAD_REQUEST)),
+        },
+        Method::POST => {
+            let content_type = req.headers().get(header::CONTENT_TYPE);
+            if content_type.map(|v| v.as_bytes()) != Some(DNS_MESSAGE_MIME.as_bytes()) {
+                return Ok(status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            match Limited::new(req.into_body(), u16::MAX as usize).collect().await {
+                Ok(body) => body.to_bytes().to_vec(),
+                Err(_) => return Ok(status_response(StatusCode::PAYLOAD_TOO_LARGE)),
+            }
+        }
+        _ => return Ok(status_response(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    // Run the handler in the blocking threads, the request is
+    // dropped without a response if it cannot be decoded at all.
+    let request = HttpsRequest {
+        request: DnsReadResult::from_bytes(&msg_bytes),
+        source,
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = tokio::task::spawn_blocking(move || handler.handle_request(request, HttpsResponse(tx)));
+    if let Err(err) = task.await {
+        log::error!("Https handler task failed: {}", err);
+        return Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR));
+    }
+    let response = match rx.await {
+        Ok(v) => v,
+        Err(_) => return Ok(status_response(StatusCode::BAD_REQUEST)),
+    };
+
+    let resp_bytes = response.encode_to_bytes().unwrap();
+    let http_response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, DNS_MESSAGE_MIME)
+        .header(header::CACHE_CONTROL, cache_control(&response))
+        .body(Full::new(Bytes::from(resp_bytes)))
+        .unwrap();
+    Ok(http_response)
+}
+
+fn status_response(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::new()));
+    *response.status_mut() = status;
+    response
+}
+
+/// Decodes the dns message carried in the `dns` parameter of the query
+/// string, encoded with the base64url alphabet without padding.
+fn decode_dns_param(query: Option<&str>) -> Option<Vec<u8>> {
+    let value = query?.split('&').find_map(|param| param.strip_prefix("dns="))?;
+    let engine = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    engine.decode(value.trim_end_matches('=')).ok()
+}
+
+/// Returns the Cache-Control header value of the response. As suggested by
+/// RFC 8484, the freshness lifetime is the smallest TTL of the records in the
+/// response (for SOA records the minimum field is considered too). Responses
+/// with errors other than NxDomain are not cached.
+fn cache_control(response: &dns::Message) -> String {
+    if !matches!(
+        response.header.resp_code,
+        dns::RespCode::NoError | dns::RespCode::NxDomain
+    ) {
+        return "no-store".to_string();
+    }
+    let records = response
+        .answers
+        .iter()
+        .chain(&response.authorities)
+        .chain(&response.additionals);
+    let min_ttl = records
+        .map(|record| match record {
+            dns::Record::SOA { ttl, minimum, .. } => u32::min(*ttl, *minimum),
+            record => *record.ttl(),
+        })
+        .min();
+    format!("max-age={}", min_ttl.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::EchoHandler;
+    use std::io::{Read, Write};
+    use std::{env, fs, net, thread};
+
+    const QUERY: &[u8] = include_bytes!("../../../assets/messages/query_packet_bin.txt");
+
+    // Start the server on the passed port and return a TLS client configuration
+    // trusting the self-signed certificate used by the server.
+    fn start_test_server(port: u16, alpn: &[u8]) -> rustls::ClientConfig {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = env::temp_dir().join(format!("ariadne-https-{}-{}", std::process::id(), port));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_file, cert.cert.pem()).unwrap();
+        fs::write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+
+        let params = HttpsParams {
+            listeners: vec![ListenAddr {
+                address: net::SocketAddr::from(([127, 0, 0, 1], port)),
+                ipv6_only: false,
+            }],
+            path: "/dns-query".to_string(),
+            read_timeout: time::Duration::from_secs(2),
+            max_connections: 4,
+            threads: 2,
+            cert_file: cert_file.to_str().unwrap().to_string(),
+            key_file: key_file.to_str().unwrap().to_string(),
+        };
+        let handler = EchoHandler {
+            answers: vec![dns::Record::A {
+                node: dns::Name::from_string("example.com.").unwrap(),
+                class: dns::Class::IN,
+                ttl: 300,
+                data_len: 4,
+                address: [10, 0, 0, 1],
+            }],
+        };
+        thread::spawn(move || start_https_server(Arc::new(handler), params, &atomic::AtomicBool::new(false)));
+        thread::sleep(time::Duration::from_millis(300));
+        fs::remove_dir_all(dir).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        config
+    }
+
+    #[test]
+    fn test_cache_control() {
+        let mut response = dns::Message::decode_from_bytes(QUERY).unwrap();
+        assert_eq!(cache_control(&response), "max-age=0");
+        let node = dns::Name::from_string("example.com.").unwrap();
+        let record = |ttl| dns::Record::A {
+            node: node.clone(),
+            class: dns::Class::IN,
+            ttl,
+            data_len: 4,
+            address: [10, 0, 0, 1],
+        };
+        response.answers = vec![record(300), record(60)];
+        response.additionals = vec![record(120)];
+        assert_eq!(cache_control(&response), "max-age=60");
+        response.header.resp_code = dns::RespCode::ServFail;
+        assert_eq!(cache_control(&response), "no-store");
+    }
+
+    #[test]
+    fn test_decode_dns_param() {
+        assert_eq!(decode_dns_param(Some("a=1&dns=AAEC")), Some(vec![0, 1, 2]));
+        assert_eq!(decode_dns_param(Some("dns=AAE=")), Some(vec![0, 1]));
+        assert_eq!(decode_dns_param(Some("dns=A+/")), None);
+        assert_eq!(decode_dns_param(Some("other=AAEC")), None);
+        assert_eq!(decode_dns_param(None), None);
+    }
+
+    #[test]
+    fn test_https_server_http1_get() {
+        let config = start_test_server(48443, b"http/1.1");
+        let conn = rustls::ClientConnection::new(Arc::new(config), "localhost".try_into().unwrap()).unwrap();
+        let socket = net::TcpStream::connect("127.0.0.1:48443").unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, socket);
+
+        let dns_param = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(QUERY);
+        let request = format!(
+            "GET /dns-query?dns={} HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\nConnection: close\r\n\r\n",
+            dns_param, DNS_MESSAGE_MIME
+        );
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut raw_response = vec![];
+        let _ = stream.read_to_end(&mut raw_response);
+
+        let split = raw_response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8_lossy(&raw_response[..split]).to_lowercase();
+        assert!(head.starts_with("http/1.1 200"));
+        assert!(head.contains("content-type: application/dns-message"));
+        assert!(head.contains("cache-control: max-age=300"));
+        let response = dns::Message::decode_from_bytes(&raw_response[split + 4..]).unwrap();
+        assert_eq!(response.id(), 0x39f4);
+        assert_eq!(response.answers[0].a_data(), &[10, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_https_server_http2_post() {
+        let config = start_test_server(48444, b"h2");
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let connector = tokio_rustls::TlsConnector::from(Arc::new(config));
+            let socket = tokio::net::TcpStream::connect("127.0.0.1:48444").await.unwrap();
+            let tls_stream = connector
+                .connect("localhost".try_into().unwrap(), socket)
+                .await
+                .unwrap();
+            let (mut sender, conn) =
+                hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(tls_stream))
+                    .await
+                    .unwrap();
+            tokio::spawn(conn);
+
+            let request = Request::post("https://localhost/dns-query")
+                .header(header::CONTENT_TYPE, DNS_MESSAGE_MIME)
+                .body(Full::new(Bytes::from_static(QUERY)))
+                .unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.version(), hyper::Version::HTTP_2);
+            assert_eq!(response.headers()[header::CACHE_CONTROL], "max-age=300");
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            let response = dns::Message::decode_from_bytes(&body).unwrap();
+            assert_eq!(response.answers.len(), 1);
+
+            let request = Request::post("https://localhost/dns-query")
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Full::new(Bytes::from_static(QUERY)))
+                .unwrap();
+            let response = sender.send_request(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        });
+    }
+}
@@ -0,0 +1,225 @@
+use std::sync::Mutex;
+use std::{fmt, io, iter, net};
+
+// Sockets bound in advance, see [add_prebound].
+static PREBOUND: Mutex<Vec<socket2::Socket>> = Mutex::new(Vec::new());
+
+/// A local address the servers listen on. For IPv6 addresses, `ipv6_only`
+/// controls if the socket only accepts IPv6 traffic or if it also accepts
+/// IPv4 traffic (dual-stack), it is ignored for IPv4 addresses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ListenAddr {
+    pub address: net::SocketAddr,
+    pub ipv6_only: bool,
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.address.is_ipv6() && !self.ipv6_only {
+            true => write!(f, "'{}' (dual-stack)", self.address),
+            false => write!(f, "'{}'", self.address),
+        }
+    }
+}
+
+/// Binds `shards` UDP sockets for each of the passed addresses, `name` identifies
+/// the server in logs. With more than one shard the sockets of the same address
+/// are bound with SO_REUSEPORT, so that the kernel spreads the clients among them.
+/// Addresses with pre-bound sockets use all and only those, ignoring the shards:
+/// the inherited sockets may lack SO_REUSEPORT. Errors are logged and None is returned.
+pub(crate) fn bind_udp_sockets(listen_addrs: &[ListenAddr], shards: usize, name: &str) -> Option<Vec<net::UdpSocket>> {
+    let sharded: Vec<ListenAddr> = listen_addrs
+        .iter()
+        .flat_map(|listen_addr| {
+            let prebound = prebound_count(listen_addr.address, socket2::Type::DGRAM);
+            if prebound > 0 && prebound != shards {
+                log::warn!(
+                    "Using {} pre-bound sockets for {} server, address: {}, ignoring {} sockets per listener.",
+                    prebound,
+                    name,
+                    listen_addr,
+                    shards
+                );
+            }
+            iter::repeat_n(listen_addr.clone(), if prebound > 0 { prebound } else { shards })
+        })
+        .collect();
+    bind_all(&sharded, name, |listen_addr| bind_udp_socket(listen_addr, shards > 1))
+}
+
+/// Binds a TCP listener for each of the passed addresses, `name` identifies
+/// the server in logs. Errors are logged and None is returned.
+pub(crate) fn bind_tcp_listeners(listen_addrs: &[ListenAddr], name: &str) -> Option<Vec<net::TcpListener>> {
+    bind_all(listen_addrs, name, bind_tcp_listener)
+}
+
+fn bind_all<S, F>(listen_addrs: &[ListenAddr], name: &str, bind: F) -> Option<Vec<S>>
+where
+    F: Fn(&ListenAddr) -> io::Result<S>,
+{
+    let mut sockets = Vec::with_capacity(listen_addrs.len());
+    for listen_addr in listen_addrs {
+        match bind(listen_addr) {
+            Ok(v) => {
+                log::info!("Starting {} server, address: {}.", name, listen_addr);
+                sockets.push(v);
+            }
+            Err(err) => {
+                log::error!("Cannot setup socket {}: {}", listen_addr, err);
+                return None;
+            }
+        }
+    }
+    Some(sockets)
+}
+
+/// Adds a socket bound in advance (e.g. inherited from systemd, or bound before
+/// dropping privileges). When binding, servers take the sockets of the same type
+/// and address from the pre-bound ones, instead of creating new sockets.
+pub(crate) fn add_prebound(socket: socket2::Socket) {
+    PREBOUND.lock().unwrap().push(socket);
+}
+
+/// Returns the number of pre-bound sockets of the passed type and address.
+pub(crate) fn prebound_count(address: net::SocketAddr, kind: socket2::Type) -> usize {
+    let prebound = PREBOUND.lock().unwrap();
+    prebound
+        .iter()
+        .filter(|socket| is_bound_to(socket, address, kind))
+        .count()
+}
+
+fn take_prebound(address: net::SocketAddr, kind: socket2::Type) -> Option<socket2::Socket> {
+    let mut prebound = PREBOUND.lock().unwrap();
+    let i = prebound.iter().position(|socket| is_bound_to(socket, address, kind))?;
+    Some(prebound.swap_remove(i))
+}
+
+fn is_bound_to(socket: &socket2::Socket, address: net::SocketAddr, kind: socket2::Type) -> bool {
+    let local_addr = socket.local_addr().ok().and_then(|addr| addr.as_socket());
+    local_addr == Some(address) && socket.r#type().ok() == Some(kind)
+}
+
+/// Creates a UDP socket bound to the passed address. With `reuse_port`
+/// other sockets can be bound to the same address (SO_REUSEPORT).
+pub(crate) fn bind_udp_socket(listen_addr: &ListenAddr, reuse_port: bool) -> io::Result<net::UdpSocket> {
+    if let Some(socket) = take_prebound(listen_addr.address, socket2::Type::DGRAM) {
+        return Ok(socket.into());
+    }
+    let socket = new_socket(listen_addr, socket2::Type::DGRAM)?;
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&listen_addr.address.into())?;
+    Ok(socket.into())
+}
+
+/// Creates a TCP socket bound to the passed address and listening for connections.
+pub(crate) fn bind_tcp_listener(listen_addr: &ListenAddr) -> io::Result<net::TcpListener> {
+    if let Some(socket) = take_prebound(listen_addr.address, socket2::Type::STREAM) {
+        return Ok(socket.into());
+    }
+    let socket = new_socket(listen_addr, socket2::Type::STREAM)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&listen_addr.address.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into())
+}
+
+// The IPV6_V6ONLY option is always set explicitly for IPv6 sockets,
+// otherwise the default depends on the system configuration.
+fn new_socket(listen_addr: &ListenAddr, kind: socket2::Type) -> io::Result<socket2::Socket> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(listen_addr.address), kind, None)?;
+    if listen_addr.address.is_ipv6() {
+        socket.set_only_v6(listen_addr.ipv6_only)?;
+    }
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_dual_stack() {
+        let v6_only = ListenAddr {
+            address: "[::]:48153".parse().unwrap(),
+            ipv6_only: true,
+        };
+        let v4 = ListenAddr {
+            address: "0.0.0.0:48153".parse().unwrap(),
+            ipv6_only: false,
+        };
+        let dual_stack = ListenAddr {
+            address: "[::]:48153".parse().unwrap(),
+            ipv6_only: false,
+        };
+
+        // An IPv6-only socket doesn't conflict with the IPv4 one on the same port.
+        let udp_v6 = bind_udp_socket(&v6_only, false).unwrap();
+        let _udp_v4 = bind_udp_socket(&v4, false).unwrap();
+        let tcp_v6 = bind_tcp_listener(&v6_only).unwrap();
+        let _tcp_v4 = bind_tcp_listener(&v4).unwrap();
+        drop((udp_v6, tcp_v6));
+        assert!(bind_tcp_listener(&dual_stack).is_err());
+
+        // A dual-stack socket also receives IPv4 traffic.
+        drop(_udp_v4);
+        let udp_dual = bind_udp_socket(&dual_stack, false).unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(&[1, 2, 3], "127.0.0.1:48153").unwrap();
+        let mut buf = [0; 8];
+        let (n, _) = udp_dual.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_bind_reuse_port() {
+        let listen_addr = ListenAddr {
+            address: "127.0.0.1:48154".parse().unwrap(),
+            ipv6_only: false,
+        };
+        let sockets = bind_udp_sockets(std::slice::from_ref(&listen_addr), 3, "test").unwrap();
+        assert_eq!(sockets.len(), 3);
+        assert!(sockets.iter().all(|s| s.local_addr().unwrap() == listen_addr.address));
+
+        // Sockets bound without SO_REUSEPORT keep the address exclusive.
+        drop(sockets);
+        let _socket = bind_udp_socket(&listen_addr, false).unwrap();
+        assert!(bind_udp_socket(&listen_addr, true).is_err());
+    }
+
+    #[test]
+    fn test_bind_prebound() {
+        let listen_addr = ListenAddr {
+            address: "127.0.0.1:48156".parse().unwrap(),
+            ipv6_only: false,
+        };
+        let prebound = bind_udp_socket(&listen_addr, false).unwrap();
+        add_prebound(prebound.into());
+
+        // The pre-bound socket is taken only once, and only by UDP servers.
+        let tcp_listener = bind_tcp_listener(&listen_addr).unwrap();
+        let udp_socket = bind_udp_socket(&listen_addr, false).unwrap();
+        assert_eq!(udp_socket.local_addr().unwrap(), listen_addr.address);
+        assert!(bind_udp_socket(&listen_addr, false).is_err());
+        drop((tcp_listener, udp_socket));
+    }
+
+    #[test]
+    fn test_bind_prebound_shards() {
+        let listen_addr = ListenAddr {
+            address: "127.0.0.1:48158".parse().unwrap(),
+            ipv6_only: false,
+        };
+        let prebound = bind_udp_socket(&listen_addr, false).unwrap();
+        add_prebound(prebound.into());
+
+        // The pre-bound socket lacks SO_REUSEPORT, so it replaces the shards.
+        assert_eq!(prebound_count(listen_addr.address, socket2::Type::DGRAM), 1);
+        let sockets = bind_udp_sockets(std::slice::from_ref(&listen_addr), 2, "test").unwrap();
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(sockets[0].local_addr().unwrap(), listen_addr.address);
+        assert_eq!(prebound_count(listen_addr.address, socket2::Type::DGRAM), 0);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::shared::dns;
+use crate::shared::net::traits::*;
+use std::sync::mpsc;
+use std::{io, net};
+
+/// A dns request held in memory, read by handlers as if it was received from
+/// the `source` client. The raw bytes are decoded on [read](DnsRead::read), so
+/// malformed requests can be tested too.
+#[derive(Clone, Debug)]
+pub struct MemoryRequest {
+    pub bytes: Vec<u8>,
+    pub source: RequestSource,
+}
+
+impl DnsRead for MemoryRequest {
+    fn read(self) -> DnsReadResult {
+        DnsReadResult::from_bytes(&self.bytes)
+    }
+
+    fn source(&self) -> RequestSource {
+        self.source
+    }
+}
+
+/// A dns response written to memory. The response is encoded as the servers
+/// do (truncated for UDP clients) and the bytes are sent to the receiver
+/// returned by [MemoryResponse::channel], along with the reported [Resolution].
+#[derive(Debug)]
+pub struct MemoryResponse {
+    transport: Transport,
+    resolution: Option<Resolution>,
+    sender: mpsc::Sender<MemoryReply>,
+}
+
+/// The reply received from a [MemoryResponse].
+#[derive(Clone, Debug)]
+pub struct MemoryReply {
+    pub bytes: Vec<u8>,
+    pub resolution: Option<Resolution>,
+}
+
+impl MemoryResponse {
+    /// Returns a new response for a client using the passed transport and the
+    /// receiver of its reply. Nothing is received if the request is dropped.
+    pub fn channel(transport: Transport) -> (MemoryResponse, mpsc::Receiver<MemoryReply>) {
+        let (sender, receiver) = mpsc::channel();
+        let response = MemoryResponse { transport, resolution: None, sender };
+        (response, receiver)
+    }
+}
+
+impl DnsWrite for MemoryResponse {
+    fn reply(self, response: dns::Message) -> io::Result<()> {
+        let encoded = match self.transport {
+            Transport::Udp => response.encode_to_bytes_trunc(),
+            _ => response.encode_to_bytes(),
+        };
+        let bytes = encoded.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", err)))?;
+        let reply = MemoryReply { bytes, resolution: self.resolution };
+        self.sender
+            .send(reply)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+
+    fn annotate(&mut self, resolution: Resolution) {
+        self.resolution = Some(resolution);
+    }
+}
+
+/// A client sending requests straight to a [DnsHandler], through a
+/// [MemoryRequest] and a [MemoryResponse], without sockets. Queries are
+/// sent from `source`, with the `recursion_desired` flag and `edns` set.
+#[derive(Clone, Debug)]
+pub struct TestClient {
+    pub source: RequestSource,
+    pub recursion_desired: bool,
+    pub edns: Option<dns::Edns>,
+}
+
+impl Default for TestClient {
+    fn default() -> Self {
+        TestClient {
+            source: RequestSource {
+                addr: net::SocketAddr::from(([127, 0, 0, 1], 5300)),
+                transport: Transport::Udp,
+            },
+            recursion_desired: true,
+            edns: None,
+        }
+    }
+}
+
+impl TestClient {
+    /// Returns a new client sending requests from the given address and transport.
+    pub fn new(addr: net::SocketAddr, transport: Transport) -> Self {
+        TestClient {
+            source: RequestSource { addr, transport },
+            ..Default::default()
+        }
+    }
+
+    /// Builds the query for the records of the given name and type, and returns
+    /// the decoded response of the handler, or None if the request was dropped.
+    pub fn query<H: DnsHandler>(&self, handler: &H, node: &dns::Name, kind: dns::RecordType) -> Option<dns::Message> {
+        self.send(handler, &self.build_query(node, kind))
+    }
+
+    /// Builds a query message, with a random id, as sent by [TestClient::query].
+    pub fn build_query(&self, node: &dns::Name, kind: dns::RecordType) -> dns::Message {
+        let header = dns::Header {
+            recursion_desired: self.recursion_desired,
+            questions_count: 1,
+            ..Default::default()
+        };
+        let question = dns::Question {
+            node: node.clone(),
+            record_type: kind,
+            class: dns::Class::IN,
+        };
+        dns::Message {
+            header,
+            questions: vec![question],
+            answers: vec![],
+            authorities: vec![],
+            additionals: vec![],
+            edns: self.edns.clone(),
+        }
+    }
+
+    /// Encodes the request, which must be a valid message, and sends it to the
+    /// handler. Returns the decoded response, or None if it was dropped.
+    pub fn send<H: DnsHandler>(&self, handler: &H, request: &dns::Message) -> Option<dns::Message> {
+        self.send_bytes(handler, request.encode_to_bytes().unwrap())
+    }
+
+    /// Sends the raw bytes to the handler, returning its reply undecoded (see
+    /// [MemoryReply]), or None if the request was dropped.
+    pub fn send_raw<H: DnsHandler>(&self, handler: &H, bytes: Vec<u8>) -> Option<MemoryReply> {
+        let request = MemoryRequest { bytes, source: self.source };
+        let (response, receiver) = MemoryResponse::channel(self.source.transport);
+        handler.handle_request(request, response);
+        receiver.try_recv().ok()
+    }
+
+    /// Sends the raw bytes to the handler and returns the decoded response, or
+    /// None if the request was dropped. Panics if the response is not valid.
+    pub fn send_bytes<H: DnsHandler>(&self, handler: &H, bytes: Vec<u8>) -> Option<dns::Message> {
+        let reply = self.send_raw(handler, bytes)?;
+        Some(dns::Message::decode_from_bytes(&reply.bytes).expect("invalid response"))
+    }
+}
+
+/// A handler replying to the valid requests with the request itself, flagged as a
+/// response and carrying the `answers`, used to test the servers and the wrappers
+/// of the handlers. Malformed requests are dropped.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct EchoHandler {
+    pub answers: Vec<dns::Record>,
+}
+
+#[cfg(test)]
+impl DnsHandler for EchoHandler {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, mut resp: W) {
+        if let DnsReadResult::FullMessage(mut msg) = req.read() {
+            msg.header.query_resp = true;
+            msg.header.answers_count = self.answers.len() as u16;
+            msg.answers = self.answers.clone();
+            resp.annotate(Resolution::default());
+            resp.reply(msg).unwrap();
+        }
+    }
+}
+
+/// A handler echoing the requests as [EchoHandler] after sleeping for `delay`, keeping
+/// the thread busy. With the async runtime, it sleeps on a blocking thread as the
+/// resolver does.
+#[cfg(test)]
+pub(crate) struct SlowHandler {
+    pub delay: std::time::Duration,
+}
+
+#[cfg(test)]
+impl DnsHandler for SlowHandler {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        std::thread::sleep(self.delay);
+        EchoHandler::default().handle_request(req, resp);
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
+impl AsyncDnsHandler for SlowHandler {
+    async fn handle_request_async<R, W>(self: &std::sync::Arc<Self>, req: R, resp: W)
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let handler = std::sync::Arc::clone(self);
+        let _ = tokio::task::spawn_blocking(move || handler.handle_request(req, resp)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client() {
+        let client = TestClient {
+            edns: Some(dns::Edns::default()),
+            ..Default::default()
+        };
+        let node = dns::Name::from_string("example.com.").unwrap();
+        let request = client.build_query(&node, dns::RecordType::A);
+        let response = client.send(&EchoHandler::default(), &request).unwrap();
+        assert_eq!(response.id(), request.id());
+        assert!(response.header.query_resp);
+        assert!(response.header.recursion_desired);
+        assert_eq!(response.questions[0].node, node);
+        assert_eq!(response.edns, Some(dns::Edns::default()));
+
+        let reply = client
+            .send_raw(&EchoHandler::default(), request.encode_to_bytes().unwrap())
+            .unwrap();
+        assert_eq!(reply.resolution, Some(Resolution::default()));
+        assert!(client.send_bytes(&EchoHandler::default(), vec![1, 2, 3]).is_none());
+    }
+}
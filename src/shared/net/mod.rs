@@ -1,9 +1,40 @@
+mod acl;
+#[cfg(feature = "async")]
+mod async_setup;
+#[cfg(feature = "async")]
+mod async_tcp_server;
+#[cfg(feature = "async")]
+mod async_udp_server;
+#[cfg(feature = "https")]
+mod https_server;
+mod listen;
+mod memory;
+mod proxy;
+#[cfg(feature = "quic")]
+mod quic_server;
+mod reload;
 mod setup;
 mod tcp_server;
+#[cfg(feature = "tls")]
+mod tls_server;
 mod traits;
+mod udp_batch;
 mod udp_server;
+mod utils;
 
+pub use acl::*;
+#[cfg(feature = "async")]
+pub use async_setup::*;
+#[cfg(feature = "https")]
+pub use https_server::*;
+pub use listen::ListenAddr;
+pub use memory::*;
+#[cfg(feature = "quic")]
+pub use quic_server::*;
+pub use reload::*;
 pub use setup::*;
 pub use tcp_server::TcpParams;
+#[cfg(feature = "tls")]
+pub use tls_server::*;
 pub use traits::*;
-pub use udp_server::UdpParams;
+pub use udp_server::{OverloadAction, UdpParams};
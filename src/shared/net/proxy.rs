@@ -0,0 +1,123 @@
+use crate::shared::net::acl::IpPrefix;
+use std::io::{self, Read};
+use std::{net, time};
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const CMD_LOCAL: u8 = 0x0;
+const CMD_PROXY: u8 = 0x1;
+const FAMILY_INET: u8 = 0x1;
+const FAMILY_INET6: u8 = 0x2;
+const MAX_HEADER_LEN: usize = 1024;
+
+/// Returns the address of the client connected over the `stream`. Connections
+/// from the `trusted` ranges (e.g. from load balancers) must start with a PROXY
+/// protocol v2 header, which carries the address of the real client. All the
+/// other connections are served as coming from their `peer` address.
+pub(crate) fn client_addr(
+    stream: &net::TcpStream,
+    peer: net::SocketAddr,
+    trusted: &[IpPrefix],
+    read_timeout: time::Duration,
+) -> io::Result<net::SocketAddr> {
+    if !trusted.iter().any(|prefix| prefix.contains(peer.ip())) {
+        return Ok(peer);
+    }
+    stream.set_read_timeout(Some(read_timeout))?;
+    let mut reader = stream;
+    Ok(read_proxy_header(&mut reader)?.unwrap_or(peer))
+}
+
+/// Reads a PROXY protocol v2 header, returning the source address it carries.
+/// None is returned for LOCAL connections (e.g. health checks) and for address
+/// families other than TCP or UDP over IPv4 and IPv6. Only the header is read,
+/// so the request data can be read from the `reader` afterwards.
+pub(crate) fn read_proxy_header<R: Read>(reader: &mut R) -> io::Result<Option<net::SocketAddr>> {
+    let mut fixed = [0; 16];
+    reader.read_exact(&mut fixed)?;
+    if &fixed[..12] != SIGNATURE {
+        return Err(invalid_header("missing signature"));
+    }
+    if fixed[12] >> 4 != 2 {
+        return Err(invalid_header("unsupported version"));
+    }
+    let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    if len > MAX_HEADER_LEN {
+        return Err(invalid_header("too long"));
+    }
+    let mut addresses = vec![0; len];
+    reader.read_exact(&mut addresses)?;
+
+    // Trailing TLVs (after the addresses) are ignored.
+    match fixed[12] & 0x0f {
+        CMD_LOCAL => return Ok(None),
+        CMD_PROXY => {}
+        _ => return Err(invalid_header("unsupported command")),
+    }
+    let source = match fixed[13] >> 4 {
+        FAMILY_INET if len >= 12 => {
+            let ip: [u8; 4] = addresses[..4].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            net::SocketAddr::from((ip, port))
+        }
+        FAMILY_INET6 if len >= 36 => {
+            let ip: [u8; 16] = addresses[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            net::SocketAddr::from((ip, port))
+        }
+        FAMILY_INET | FAMILY_INET6 => return Err(invalid_header("addresses too short")),
+        _ => return Ok(None),
+    };
+    Ok(Some(source))
+}
+
+fn invalid_header(reason: &str) -> io::Error {
+    let err = format!("invalid proxy protocol header: {}", reason);
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn header(ver_cmd: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = SIGNATURE.to_vec();
+        header.extend_from_slice(&[ver_cmd, family]);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn test_read_proxy_header() {
+        // TCP over IPv4, followed by a TLV and the request data.
+        let mut bytes = header(0x21, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0x14, 0xb4, 0, 53, 4, 0, 1, 0]);
+        bytes.extend_from_slice(b"request");
+        let mut reader = Cursor::new(bytes);
+        let addr = read_proxy_header(&mut reader).unwrap();
+        assert_eq!(addr, Some("10.0.0.1:5300".parse().unwrap()));
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"request");
+
+        // TCP over IPv6.
+        let mut addresses = vec![0; 36];
+        addresses[15] = 1;
+        addresses[32..34].copy_from_slice(&5300_u16.to_be_bytes());
+        let addr = read_proxy_header(&mut Cursor::new(header(0x21, 0x21, &addresses))).unwrap();
+        assert_eq!(addr, Some("[::1]:5300".parse().unwrap()));
+
+        // Local connections and unix sockets carry no client address.
+        assert_eq!(
+            read_proxy_header(&mut Cursor::new(header(0x20, 0x00, &[]))).unwrap(),
+            None
+        );
+        let unix = header(0x21, 0x31, &[0; 216]);
+        assert_eq!(read_proxy_header(&mut Cursor::new(unix)).unwrap(), None);
+
+        // Invalid headers.
+        assert!(read_proxy_header(&mut Cursor::new(b"\0\x1dPROXY TCP4 10.0.0.1".to_vec())).is_err());
+        assert!(read_proxy_header(&mut Cursor::new(header(0x11, 0x11, &[0; 12]))).is_err());
+        assert!(read_proxy_header(&mut Cursor::new(header(0x21, 0x11, &[0; 8]))).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+use crate::shared::dns;
+use crate::shared::net::listen::*;
+use crate::shared::net::tls_server::load_tls_config;
+use crate::shared::net::traits::*;
+use crate::shared::net::utils::wait_stop;
+use std::sync::{atomic, Arc};
+use std::{io, net, time};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
+
+/// The ALPN token identifying DNS-over-QUIC (RFC 9250).
+pub const DOQ_ALPN: &[u8] = b"doq";
+
+// Error codes used when closing DoQ connections and resetting streams.
+const DOQ_NO_ERROR: u32 = 0x0;
+const DOQ_INTERNAL_ERROR: u32 = 0x1;
+const DOQ_PROTOCOL_ERROR: u32 = 0x2;
+
+/// Parameters to be used when starting the DNS-over-QUIC server with
+/// [start_quic_server]. The `threads` are the ones used to run the dns handler,
+/// the `max_streams` limits the concurrent queries of a single connection.
+/// The `max_connections` limit is shared among all the `listeners`.
+#[derive(Clone)]
+pub struct QuicParams {
+    pub listeners: Vec<ListenAddr>,
+    pub read_timeout: time::Duration,
+    pub idle_timeout: time::Duration,
+    pub max_connections: usize,
+    pub max_streams: u32,
+    pub threads: usize,
+    pub early_data: bool,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// The request coming from DoQ clients. Implements [DnsRead] by returning
+/// the request read from a QUIC stream and decoded by the connection task.
+pub struct QuicRequest {
+    request: DnsReadResult,
+    source: RequestSource,
+}
+
+impl DnsRead for QuicRequest {
+    fn read(self) -> DnsReadResult {
+        self.request
+    }
+
+    fn source(&self) -> RequestSource {
+        self.source
+    }
+}
+
+/// The response to DoQ clients. Implements [DnsWrite] by handing the dns
+/// response back to the stream task, which writes it to the same QUIC
+/// stream the request was read from.
+pub struct QuicResponse(oneshot::Sender<dns::Message>);
+
+impl DnsWrite for QuicResponse {
+    fn reply(self, response: dns::Message) -> io::Result<()> {
+        match self.0.send(response) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "quic stream gone")),
+        }
+    }
+}
+
+/// Starts a new DNS-over-QUIC server (RFC 9250) generic over a request handler ([DnsHandler]).
+/// Every query is sent by clients on a dedicated bidirectional stream and the dns handler is run
+/// in the blocking threads of an async runtime. If `early_data` is set, queries sent in 0-RTT are
+/// accepted, but only standard queries are served before the handshake completes, since those
+/// are safe to be replayed. The `stop` argument can be used to stop the server.
+pub fn start_quic_server<H>(handler: Arc<H>, params: QuicParams, stop: &atomic::AtomicBool)
+where
+    H: DnsHandler,
+{
+    let server_config = match build_server_config(&params) {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Cannot setup quic: {}", err);
+            return;
+        }
+    };
+    let sockets = match bind_udp_sockets(&params.listeners, 1, "QUIC") {
+        Some(v) => v,
+        None => return,
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .max_blocking_threads(params.threads)
+        .thread_name("quic-worker")
+        .enable_all()
+        .build();
+    let runtime = match runtime {
+        Ok(v) => v,
+        Err(err) => {
+            log::error!("Cannot build the quic runtime: {}", err);
+            return;
+        }
+    };
+
+    runtime.block_on(serve_quic(handler, params, sockets, server_config, stop));
+    runtime.shutdown_timeout(time::Duration::from_secs(4));
+}
+
+// Build the QUIC configuration. TLS 1.3 early data is enabled only if requested,
+// resumption tickets are single-use so a 0-RTT flight cannot be replayed against
+// this server instance (but it can still be replayed against other instances).
+fn build_server_config(params: &QuicParams) -> Result<quinn::ServerConfig, String> {
+    let mut tls_config = load_tls_config(&params.cert_file, &params.key_file, &[DOQ_ALPN])?;
+    tls_config.max_early_data_size = if params.early_data { u32::MAX } else { 0 };
+    let quic_tls_config = quinn::crypto::rustls::QuicServerConfig::try_from(tls_config)
+        .map_err(|err| format!("invalid tls config: {}", err))?;
+    let idle_timeout = quinn::IdleTimeout::try_from(params.idle_timeout).map_err(|err| err.to_string())?;
+
+    let mut transport = quinn::TransportConfig::default();
+    transport
+        .max_concurrent_bidi_streams(params.max_streams.into())
+        .max_concurrent_uni_streams(0_u32.into())
+        .max_idle_timeout(Some(idle_timeout));
+    let mut server_config = quinn::ServerConfig::with_crypto(Arc::new(quic_tls_config));
+    server_config.transport_config(Arc::new(transport));
+    Ok(server_config)
+}
+
+async fn serve_quic<H: DnsHandler>(
+    handler: Arc<H>,
+    params: QuicParams,
+    sockets: Vec<net::UdpSocket>,
+    server_config: quinn::ServerConfig,
+    stop: &atomic::AtomicBool,
+) {
+    let mut endpoints = Vec::with_capacity(sockets.len());
+    for socket in sockets {
+        let endpoint = quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            Some(server_config.clone()),
+            socket,
+            Arc::new(quinn::TokioRuntime),
+        );
+        match endpoint {
+            Ok(v) => endpoints.push(v),
+            Err(err) => {
+                log::error!("Cannot setup socket: {}", err);
+                return;
+            }
+        };
+    }
+
+    // Accept connections on every endpoint in a dedicated task,
+    // forwarding them to the main loop.
+    let mut accepting = JoinSet::new();
+    let (incoming_tx, mut incoming_rx) = mpsc::channel(endpoints.len());
+    for endpoint in &endpoints {
+        let (endpoint, incoming_tx) = (endpoint.clone(), incoming_tx.clone());
+        accepting.spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                if incoming_tx.send(incoming).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+    drop(incoming_tx);
+
+    // Loop accepting QUIC connections, checking periodically if we got a
+    // signal to exit. Incoming connections over the limit are refused.
+    let (draining_tx, draining) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    loop {
+        let incoming = tokio::select! {
+            incoming = incoming_rx.recv() => incoming,
+            _ = wait_stop(stop) => None,
+        };
+        let Some(incoming) = incoming else { break };
+        while connections.try_join_next().is_some() {}
+
+        let open_connections: usize = endpoints.iter().map(|e| e.open_connections()).sum();
+        if open_connections >= params.max_connections {
+            log::warn!(
+                "Max quic connections reached, refusing connection from {}.",
+                incoming.remote_address()
+            );
+            incoming.refuse();
+            continue;
+        }
+
+        let handler = Arc::clone(&handler);
+        let draining = draining.clone();
+        let read_timeout = params.read_timeout;
+        connections.spawn(async move {
+            let src_addr = incoming.remote_address();
+            if let Err(err) = serve_connection(handler, incoming, read_timeout, draining).await {
+                log::warn!("Serving quic connection from {}: {}", src_addr, err);
+            }
+        });
+    }
+
+    // Refuse new connections and wait for the open ones to be drained.
+    for endpoint in &endpoints {
+        endpoint.set_server_config(None);
+    }
+    accepting.shutdown().await;
+    let _ = draining_tx.send(true);
+    while connections.join_next().await.is_some() {}
+    for endpoint in &endpoints {
+        endpoint.close(DOQ_NO_ERROR.into(), b"");
+        let _ = tokio::time::timeout(time::Duration::from_secs(2), endpoint.wait_idle()).await;
+    }
+}
+
+// Complete the handshake and accept the streams opened by the client, each one
+// carrying a single query. Streams opened in 0-RTT are accepted right away, the
+// `handshake_done` channel tracks when the handshake is completed. When draining,
+// no more streams are accepted and the connection is closed once the queries in
+// flight are served.
+async fn serve_connection<H: DnsHandler>(
+    handler: Arc<H>,
+    incoming: quinn::Incoming,
+    read_timeout: time::Duration,
+    mut draining: watch::Receiver<bool>,
+) -> Result<(), quinn::ConnectionError> {
+    let (conn, handshake) = match incoming.accept()?.into_0rtt() {
+        Ok(v) => v,
+        Err(_) => unreachable!("0.5-RTT always available on incoming connections"),
+    };
+    let (handshake_tx, handshake_done) = watch::channel(false);
+    tokio::spawn(async move {
+        handshake.await;
+        let _ = handshake_tx.send(true);
+    });
+
+    let mut streams = JoinSet::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = conn.accept_bi() => accepted,
+            _ = draining.changed() => break,
+        };
+        let (send, recv) = match accepted {
+            Ok(v) => v,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(quinn::ConnectionError::LocallyClosed) => return Ok(()),
+            Err(quinn::ConnectionError::TimedOut) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        while streams.try_join_next().is_some() {}
+        let handler = Arc::clone(&handler);
+        let conn = conn.clone();
+        let handshake_done = handshake_done.clone();
+        streams.spawn(async move {
+            serve_stream(handler, conn, send, recv, read_timeout, handshake_done).await;
+        });
+    }
+
+    while streams.join_next().await.is_some() {}
+    conn.close(DOQ_NO_ERROR.into(), b"");
+    Ok(())
+}
+
+// Read the query from the stream, run the handler and write back the response.
+// Violations of the DoQ protocol close the whole connection, as per RFC 9250.
+async fn serve_stream<H: DnsHandler>(
+    handler: Arc<H>,
+    conn: quinn::Connection,
+    mut send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+    read_timeout: time::Duration,
+    mut handshake_done: watch::Receiver<bool>,
+) {
+    let msg_bytes = match tokio::time::timeout(read_timeout, recv.read_to_end(u16::MAX as usize + 2)).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(quinn::ReadToEndError::TooLong)) | Err(_) => {
+            let _ = send.reset(DOQ_PROTOCOL_ERROR.into());
+            return;
+        }
+        Ok(Err(_)) => return,
+    };
+    let request = match decode_query(&msg_bytes) {
+        Some(v) => v,
+        None => {
+            conn.close(DOQ_PROTOCOL_ERROR.into(), b"invalid query");
+            return;
+        }
+    };
+
+    // Queries received in 0-RTT could be replayed by an attacker,
+    // those not safe to replay wait for the handshake to complete.
+    if recv.is_0rtt() && !is_replay_safe(&request) && handshake_done.wait_for(|done| *done).await.is_err() {
+        return;
+    }
+
+    let request = QuicRequest {
+        request,
+        source: RequestSource {
+            addr: conn.remote_address(),
+            transport: Transport::Quic,
+        },
+    };
+    let (tx, rx) = oneshot::channel();
+    let task = tokio::task::spawn_blocking(move || handler.handle_request(request, QuicResponse(tx)));
+    if let Err(err) = task.await {
+        log::error!("Quic handler task failed: {}", err);
+        let _ = send.reset(DOQ_INTERNAL_ERROR.into());
+        return;
+    }
+    let response = match rx.await {
+        Ok(v) => v,
+        Err(_) => {
+            let _ = send.reset(DOQ_INTERNAL_ERROR.into());
+            return;
+        }
+    };
+
+    let resp_bytes = response.encode_to_bytes().unwrap();
+    let mut buf = Vec::with_capacity(resp_bytes.len() + 2);
+    buf.extend_from_slice(&(resp_bytes.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&resp_bytes);
+    if send.write_all(&buf).await.is_ok() {
+        let _ = send.finish();
+    }
+}
+
+/// Decodes a query read from a DoQ stream, made of the two bytes length and
+/// the message itself. Returns None on protocol errors: mismatching length,
+/// message id other than 0 or edns-tcp-keepalive option in the request.
+fn decode_query(bytes: &[u8]) -> Option<DnsReadResult> {
+    if bytes.len() < 2 || u16::from_be_bytes([bytes[0], bytes[1]]) as usize != bytes.len() - 2 {
+        return None;
+    }
+    let request = DnsReadResult::from_bytes(&bytes[2..]);
+    match &request {
+        DnsReadResult::FullMessage(msg) if msg.id() != 0 => None,
+        DnsReadResult::FullMessage(msg) if msg.edns.as_ref().is_some_and(|e| e.has_tcp_keepalive()) => None,
+        DnsReadResult::HeaderOnly(header, _) if header.id != 0 => None,
+        _ => Some(request),
+    }
+}
+
+/// Reports if the request can be safely served in 0-RTT, so before the client
+/// proves it is not replaying the data of another connection. Only standard
+/// queries not asking for zone transfers are considered safe.
+fn is_replay_safe(request: &DnsReadResult) -> bool {
+    let msg = match request {
+        DnsReadResult::FullMessage(msg) => msg,
+        _ => return false,
+    };
+    matches!(msg.header.op_code, dns::OpCode::STD)
+        && msg.header.is_request()
+        && msg.questions.iter().all(|q| q.record_type != dns::RecordType::AXFR)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::EchoHandler;
+    use std::{env, fs, thread};
+
+    const QUERY: &[u8] = include_bytes!("../../../assets/messages/query_packet_bin.txt");
+
+    fn framed_query(id: u16) -> Vec<u8> {
+        let mut query = dns::Message::decode_from_bytes(QUERY).unwrap();
+        query.header.id = id;
+        let bytes = query.encode_to_bytes().unwrap();
+        let mut buf = (bytes.len() as u16).to_be_bytes().to_vec();
+        buf.extend_from_slice(&bytes);
+        buf
+    }
+
+    async fn doq_query(conn: &quinn::Connection, id: u16) -> Result<dns::Message, String> {
+        let (mut send, mut recv) = conn.open_bi().await.map_err(|err| err.to_string())?;
+        send.write_all(&framed_query(id)).await.map_err(|err| err.to_string())?;
+        send.finish().unwrap();
+        let resp_bytes = recv
+            .read_to_end(u16::MAX as usize + 2)
+            .await
+            .map_err(|err| err.to_string())?;
+        assert_eq!(
+            u16::from_be_bytes([resp_bytes[0], resp_bytes[1]]) as usize,
+            resp_bytes.len() - 2
+        );
+        dns::Message::decode_from_bytes(&resp_bytes[2..]).map_err(|err| format!("{:?}", err))
+    }
+
+    #[test]
+    fn test_decode_query() {
+        assert!(matches!(
+            decode_query(&framed_query(0)),
+            Some(DnsReadResult::FullMessage(_))
+        ));
+        assert!(decode_query(&framed_query(1)).is_none());
+        assert!(decode_query(&framed_query(0)[..20]).is_none());
+        assert!(decode_query(&[0]).is_none());
+
+        let mut query = dns::Message::decode_from_bytes(QUERY).unwrap();
+        query.header.id = 0;
+        query.edns = Some(dns::Edns::default());
+        query.edns.as_mut().unwrap().set_tcp_keepalive(None);
+        let bytes = query.encode_to_bytes().unwrap();
+        let mut buf = (bytes.len() as u16).to_be_bytes().to_vec();
+        buf.extend_from_slice(&bytes);
+        assert!(decode_query(&buf).is_none());
+    }
+
+    #[test]
+    fn test_is_replay_safe() {
+        let query = || dns::Message::decode_from_bytes(QUERY).unwrap();
+        assert!(is_replay_safe(&DnsReadResult::FullMessage(query())));
+        let mut axfr = query();
+        axfr.questions[0].record_type = dns::RecordType::AXFR;
+        assert!(!is_replay_safe(&DnsReadResult::FullMessage(axfr)));
+        let mut status = query();
+        status.header.op_code = dns::OpCode::STS;
+        assert!(!is_replay_safe(&DnsReadResult::FullMessage(status)));
+    }
+
+    #[test]
+    fn test_quic_server() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = env::temp_dir().join(format!("ariadne-quic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_file, cert.cert.pem()).unwrap();
+        fs::write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+
+        let params = QuicParams {
+            listeners: vec![ListenAddr {
+                address: "127.0.0.1:48853".parse().unwrap(),
+                ipv6_only: false,
+            }],
+            read_timeout: time::Duration::from_secs(2),
+            idle_timeout: time::Duration::from_secs(5),
+            max_connections: 4,
+            max_streams: 16,
+            threads: 2,
+            early_data: true,
+            cert_file: cert_file.to_str().unwrap().to_string(),
+            key_file: key_file.to_str().unwrap().to_string(),
+        };
+        thread::spawn(move || {
+            start_quic_server(
+                Arc::new(EchoHandler::default()),
+                params,
+                &atomic::AtomicBool::new(false),
+            )
+        });
+        thread::sleep(time::Duration::from_millis(300));
+        fs::remove_dir_all(dir).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut tls_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        tls_config.alpn_protocols = vec![DOQ_ALPN.to_vec()];
+        tls_config.enable_early_data = true;
+        let quic_config = quinn::crypto::rustls::QuicClientConfig::try_from(tls_config).unwrap();
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+            endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(quic_config)));
+            let server_addr = "127.0.0.1:48853".parse().unwrap();
+
+            // Concurrent queries on the same connection, each one on its own stream.
+            let conn = endpoint.connect(server_addr, "localhost").unwrap().await.unwrap();
+            let (first, second) = tokio::join!(doq_query(&conn, 0), doq_query(&conn, 0));
+            for response in [first.unwrap(), second.unwrap()] {
+                assert!(!response.header.is_request());
+                assert_eq!(response.id(), 0);
+                assert_eq!(response.questions[0].node.as_ref(), "google.com.");
+            }
+            conn.close(DOQ_NO_ERROR.into(), b"");
+
+            // Resume the session sending the query in 0-RTT.
+            let connecting = endpoint.connect(server_addr, "localhost").unwrap();
+            let (conn, accepted) = connecting.into_0rtt().map_err(|_| "0-RTT not available").unwrap();
+            let response = doq_query(&conn, 0).await.unwrap();
+            assert!(!response.header.is_request());
+            assert!(accepted.await);
+
+            // Messages with an id other than 0 are protocol errors.
+            assert!(doq_query(&conn, 1).await.is_err());
+            match conn.closed().await {
+                quinn::ConnectionError::ApplicationClosed(close) => {
+                    assert_eq!(close.error_code, DOQ_PROTOCOL_ERROR.into())
+                }
+                err => panic!("unexpected close: {}", err),
+            }
+        });
+    }
+}
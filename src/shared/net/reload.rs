@@ -0,0 +1,120 @@
+use crate::shared::net::traits::*;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::{io, thread};
+
+/// A handler that can be replaced while the servers are running. Requests are
+/// served by the handler current when they are received, so the ones in flight
+/// complete with the previous handler. Clones share the same current handler:
+/// one is passed to the servers, while another one is kept to [replace] it.
+///
+/// [replace]: ReloadableHandler::replace
+pub struct ReloadableHandler<H> {
+    current: Arc<RwLock<Arc<H>>>,
+}
+
+impl<H> Clone for ReloadableHandler<H> {
+    fn clone(&self) -> Self {
+        ReloadableHandler { current: Arc::clone(&self.current) }
+    }
+}
+
+impl<H> ReloadableHandler<H> {
+    pub fn new(handler: H) -> Self {
+        ReloadableHandler {
+            current: Arc::new(RwLock::new(Arc::new(handler))),
+        }
+    }
+
+    /// Returns the handler serving new requests.
+    pub fn current(&self) -> Arc<H> {
+        Arc::clone(&self.current.read().unwrap())
+    }
+
+    /// Atomically replaces the handler, new requests are served by the passed one.
+    pub fn replace(&self, handler: H) {
+        *self.current.write().unwrap() = Arc::new(handler);
+    }
+}
+
+impl<H: DnsHandler> DnsHandler for ReloadableHandler<H> {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        self.current().handle_request(req, resp);
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H: AsyncDnsHandler> AsyncDnsHandler for ReloadableHandler<H> {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let current = self.current();
+        async move { current.handle_request_async(req, resp).await }
+    }
+}
+
+/// Calls `reload` from a dedicated thread every time the process receives SIGHUP.
+/// Signals received while `reload` is running are coalesced into a single call.
+#[cfg(unix)]
+pub fn register_reload_signal<F>(mut reload: F) -> io::Result<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    let mut signals = signal_hook::iterator::Signals::new([signal_hook::consts::SIGHUP])?;
+    thread::Builder::new().name("reload".to_string()).spawn(move || {
+        for _ in signals.forever() {
+            reload();
+        }
+    })?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn register_reload_signal<F>(_reload: F) -> io::Result<()>
+where
+    F: FnMut() + Send + 'static,
+{
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dns;
+    use crate::shared::net::TestClient;
+
+    // Replies with the configured response code.
+    struct CodeHandler(dns::RespCode);
+
+    impl DnsHandler for CodeHandler {
+        fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+            if let DnsReadResult::FullMessage(mut msg) = req.read() {
+                msg.header.query_resp = true;
+                msg.header.resp_code = self.0;
+                resp.reply(msg).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_replace() {
+        let handler = ReloadableHandler::new(CodeHandler(dns::RespCode::NoError));
+        let reloader = handler.clone();
+        let client = TestClient::default();
+        let node = dns::Name::from_string("example.com.").unwrap();
+
+        let response = client.query(&handler, &node, dns::RecordType::A).unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+        let previous = handler.current();
+        reloader.replace(CodeHandler(dns::RespCode::Refused));
+        let response = client.query(&handler, &node, dns::RecordType::A).unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::Refused);
+
+        // Requests already being served keep the previous handler.
+        let response = client.query(&*previous, &node, dns::RecordType::A).unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    }
+}
@@ -1,60 +1,384 @@
+#[cfg(feature = "https")]
+use crate::shared::net::https_server::*;
+use crate::shared::net::listen::*;
+#[cfg(feature = "quic")]
+use crate::shared::net::quic_server::*;
 use crate::shared::net::tcp_server::*;
+#[cfg(feature = "tls")]
+use crate::shared::net::tls_server::*;
 use crate::shared::net::traits::*;
 use crate::shared::net::udp_server::*;
-use std::io::Write;
-use std::sync::{atomic, mpsc, Arc};
-use std::{net, thread, time};
-
-/// Setup and start UDP and TCP dns servers. Every server runs in its own
-/// thread, when one of them errors or exits, the current thread is notified
-/// and also the other server is teared down.
-pub fn start_servers<H: DnsHandler>(handler: Arc<H>, udp_params: UdpParams, tcp_params: TcpParams) {
+use crate::shared::net::utils::*;
+use crate::shared::thread_pool::QueueStats;
+use std::sync::{atomic, mpsc, Arc, Mutex};
+use std::{io, thread, time};
+
+/// Parameters of all the servers started with [start_servers]. The UDP and
+/// TCP servers are always started, while the others only if configured.
+#[derive(Clone)]
+pub struct ServersParams {
+    pub udp: UdpParams,
+    pub tcp: TcpParams,
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsParams>,
+    #[cfg(feature = "https")]
+    pub https: Option<HttpsParams>,
+    #[cfg(feature = "quic")]
+    pub quic: Option<QuicParams>,
+    pub drain_timeout: time::Duration,
+}
+
+/// Setup and start the dns servers. Every server runs in its own thread, the
+/// returned [ServerHandle] can be used to stop them and wait for them to exit.
+/// When one of them errors or exits, also the other servers are teared down.
+pub fn start_servers<H: DnsHandler>(handler: Arc<H>, params: ServersParams) -> ServerHandle {
     let (tx, rx) = mpsc::channel();
     let stop = Arc::new(atomic::AtomicBool::new(false));
+    let mut queue_stats = vec![];
+    let mut servers = 0;
 
     // Setup udp parameters and spawn the udp server in a new thread.
-    let udp_params_clone = udp_params.clone();
+    let udp_params = params.udp.clone();
     let handler_clone = Arc::clone(&handler);
-    let stop_clone = Arc::clone(&stop);
-    let tx_clone = tx.clone();
-    thread::spawn(move || {
-        start_udp_server(handler_clone, udp_params_clone, &stop_clone);
-        log::warn!("UDP server shut down.");
-        tx_clone.send(()).unwrap();
+    let stats = Arc::new(QueueStats::default());
+    queue_stats.push(("udp", Arc::clone(&stats)));
+    spawn_server("UDP", &tx, &stop, move |stop| {
+        start_udp_server(handler_clone, udp_params, stats, stop)
     });
+    servers += 1;
 
     // Setup tcp parameters and spawn the tcp server in a new thread.
-    let tcp_params_clone = tcp_params.clone();
+    let tcp_params = params.tcp.clone();
     let handler_clone = Arc::clone(&handler);
-    let stop_clone = Arc::clone(&stop);
-    let tx_clone = tx.clone();
-    thread::spawn(move || {
-        start_tcp_server(handler_clone, tcp_params_clone, &stop_clone);
-        log::warn!("TCP server shut down.");
-        tx_clone.send(()).unwrap();
+    let stats = Arc::new(QueueStats::default());
+    queue_stats.push(("tcp", Arc::clone(&stats)));
+    spawn_server("TCP", &tx, &stop, move |stop| {
+        start_tcp_server(handler_clone, tcp_params, stats, stop)
     });
+    servers += 1;
 
-    // Wait for errors or teardowns. Note that in any case
-    // we have a timeout on the second recv to avoid locks.
-    rx.recv().unwrap();
-    stop.store(true, atomic::Ordering::SeqCst);
-    wake_up_servers(&udp_params, &tcp_params);
-    rx.recv_timeout(time::Duration::from_secs(4)).unwrap();
-}
-
-/// Dirty hack. The only way to interrupt the UDP 'recv' and the TCP 'accept' calls
-/// is sending them a message. Those calls are blocking and without this hack the
-/// servers cannot unblock and check the stop signal (and so exit properly).
-#[allow(unused_must_use)]
-fn wake_up_servers(udp_conf: &UdpParams, tcp_conf: &TcpParams) {
-    let udp_server_addr: (&str, u16) = (&udp_conf.address, udp_conf.port);
-    match net::UdpSocket::bind("0.0.0.0:0") {
-        Ok(udp_sock) => udp_sock.send_to(&[0], udp_server_addr),
-        Err(_) => return,
-    };
-    let tcp_server_addr: (&str, u16) = (&tcp_conf.address, tcp_conf.port);
-    match net::TcpStream::connect(tcp_server_addr) {
-        Ok(mut tcp_sock) => tcp_sock.write_all(&[0]),
-        Err(_) => return,
+    // Spawn the tls server in a new thread, if configured.
+    #[cfg(feature = "tls")]
+    if let Some(tls_params) = params.tls.clone() {
+        let handler_clone = Arc::clone(&handler);
+        let stats = Arc::new(QueueStats::default());
+        queue_stats.push(("tls", Arc::clone(&stats)));
+        spawn_server("TLS", &tx, &stop, move |stop| {
+            start_tls_server(handler_clone, tls_params, stats, stop)
+        });
+        servers += 1;
+    }
+
+    // Spawn the https server in a new thread, if configured.
+    #[cfg(feature = "https")]
+    if let Some(https_params) = params.https.clone() {
+        let handler_clone = Arc::clone(&handler);
+        spawn_server("HTTPS", &tx, &stop, move |stop| {
+            start_https_server(handler_clone, https_params, stop)
+        });
+        servers += 1;
+    }
+
+    // Spawn the quic server in a new thread, if configured.
+    #[cfg(feature = "quic")]
+    if let Some(quic_params) = params.quic.clone() {
+        let handler_clone = Arc::clone(&handler);
+        spawn_server("QUIC", &tx, &stop, move |stop| {
+            start_quic_server(handler_clone, quic_params, stop)
+        });
+        servers += 1;
+    }
+
+    ServerHandle {
+        stop,
+        exits: Mutex::new(ServerExits { rx, running: servers }),
+        drain_timeout: params.drain_timeout,
+        queue_stats,
+    }
+}
+
+/// Binds the sockets of all the servers in advance, on the calling thread. The
+/// servers started later with the same parameters, including the async ones,
+/// use these sockets instead of binding new ones. This allows to drop the
+/// privileges needed to bind reserved ports before starting the servers.
+/// UDP addresses with inherited sockets are left to those, without shards.
+pub fn bind_servers(params: &ServersParams) -> io::Result<()> {
+    let mut sockets = vec![];
+    let udp_shards = params.udp.sockets_per_listener;
+    for listen_addr in &params.udp.listeners {
+        if prebound_count(listen_addr.address, socket2::Type::DGRAM) > 0 {
+            continue;
+        }
+        for _ in 0..udp_shards {
+            sockets.push(socket2::Socket::from(bind_udp_socket(listen_addr, udp_shards > 1)?));
+        }
+    }
+    for listen_addr in &params.tcp.listeners {
+        sockets.push(socket2::Socket::from(bind_tcp_listener(listen_addr)?));
+    }
+    #[cfg(feature = "tls")]
+    if let Some(tls_params) = &params.tls {
+        for listen_addr in &tls_params.listeners {
+            sockets.push(socket2::Socket::from(bind_tcp_listener(listen_addr)?));
+        }
+    }
+    #[cfg(feature = "https")]
+    if let Some(https_params) = &params.https {
+        for listen_addr in &https_params.listeners {
+            sockets.push(socket2::Socket::from(bind_tcp_listener(listen_addr)?));
+        }
+    }
+    #[cfg(feature = "quic")]
+    if let Some(quic_params) = &params.quic {
+        for listen_addr in &quic_params.listeners {
+            sockets.push(socket2::Socket::from(bind_udp_socket(listen_addr, false)?));
+        }
+    }
+    sockets.into_iter().for_each(add_prebound);
+    Ok(())
+}
+
+/// Takes the listening sockets passed by systemd socket activation (see
+/// `sd_listen_fds(3)`), returning how many were received. The sockets are
+/// used by the servers bound to the same addresses, see [bind_servers]. The
+/// activation environment variables are removed, so the function should
+/// be called once, before other threads are spawned.
+#[cfg(unix)]
+pub fn inherit_listen_fds() -> io::Result<usize> {
+    use std::os::unix::io::FromRawFd;
+    const LISTEN_FDS_START: i32 = 3;
+
+    let listen_pid = std::env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let listen_fds = std::env::var("LISTEN_FDS").ok().and_then(|n| n.parse::<i32>().ok());
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let listen_fds = match (listen_pid, listen_fds) {
+        (Some(pid), Some(n)) if pid == std::process::id() => n,
+        _ => return Ok(0),
     };
+
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + listen_fds {
+        // Safety: the passed descriptors are owned by the process from now on.
+        if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+        match socket.local_addr().map(|addr| addr.as_socket()) {
+            Ok(Some(addr)) => {
+                log::info!("Inherited socket {} bound to '{}'.", fd, addr);
+                add_prebound(socket);
+            }
+            _ => log::warn!("Inherited socket {} is not bound to an IP address, closing it.", fd),
+        }
+    }
+    Ok(listen_fds as usize)
+}
+
+/// Handle to the servers started with [start_servers], used to stop them
+/// and to wait for their termination. It can be shared among threads.
+pub struct ServerHandle {
+    stop: Arc<atomic::AtomicBool>,
+    exits: Mutex<ServerExits>,
+    drain_timeout: time::Duration,
+    queue_stats: Vec<(&'static str, Arc<QueueStats>)>,
+}
+
+struct ServerExits {
+    rx: mpsc::Receiver<&'static str>,
+    running: usize,
+}
+
+impl ServerHandle {
+    /// Signals the servers to stop: they stop accepting new requests and
+    /// drain the ones in flight. Use [ServerHandle::wait] to wait for them.
+    pub fn stop(&self) {
+        self.stop.store(true, atomic::Ordering::SeqCst);
+    }
+
+    /// Stops the servers when the process receives SIGINT or SIGTERM.
+    pub fn stop_on_signals(&self) -> io::Result<()> {
+        register_stop_signals(&self.stop)
+    }
+
+    /// Blocks until the servers are stopped or one of them exits (e.g. on errors),
+    /// then stops all of them and waits for the requests in flight to be served.
+    /// Servers not drained within the drain timeout are abandoned.
+    pub fn wait(&self) {
+        let mut exits = self.exits.lock().unwrap();
+        let mut deadline: Option<time::Instant> = None;
+        while exits.running > 0 {
+            let timeout = match deadline {
+                Some(deadline) => deadline.saturating_duration_since(time::Instant::now()),
+                None => STOP_POLL_INTERVAL,
+            };
+            let exited = match exits.rx.recv_timeout(timeout) {
+                Ok(_) => true,
+                Err(mpsc::RecvTimeoutError::Timeout) if deadline.is_some() => {
+                    log::error!("Servers not drained in time, {} still running.", exits.running);
+                    return;
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => false,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            };
+            if exited {
+                exits.running -= 1;
+            }
+
+            // Start draining on the first exit or as soon as we got the stop
+            // signal, servers check the same signal and stop by themselves.
+            if deadline.is_none() && (exited || self.stop.load(atomic::Ordering::SeqCst)) {
+                log::warn!("Stopping servers, draining requests in flight.");
+                self.stop();
+                deadline = Some(time::Instant::now() + self.drain_timeout);
+            }
+        }
+    }
+
+    /// The counters of the thread pools queues of the servers, labelled
+    /// with the server protocol. The async servers are not included.
+    pub fn queue_stats(&self) -> &[(&'static str, Arc<QueueStats>)] {
+        &self.queue_stats
+    }
+
+    /// Stops the servers and waits for them to be drained.
+    pub fn shutdown(&self) {
+        self.stop();
+        self.wait();
+    }
+}
+
+/// Sets the `stop` flag when the process receives SIGINT or SIGTERM. A second
+/// signal received after the flag is set terminates the process immediately.
+pub fn register_stop_signals(stop: &Arc<atomic::AtomicBool>) -> io::Result<()> {
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register_conditional_shutdown(signal, 1, Arc::clone(stop))?;
+        signal_hook::flag::register(signal, Arc::clone(stop))?;
+    }
+    Ok(())
+}
+
+fn spawn_server<F>(name: &'static str, tx: &mpsc::Sender<&'static str>, stop: &Arc<atomic::AtomicBool>, server: F)
+where
+    F: FnOnce(&atomic::AtomicBool) + Send + 'static,
+{
+    let tx = tx.clone();
+    let stop = Arc::clone(stop);
+    thread::spawn(move || {
+        server(&stop);
+        log::warn!("{} server shut down.", name);
+        let _ = tx.send(name);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::dns;
+    use crate::shared::net::listen::ListenAddr;
+    use crate::shared::net::SlowHandler;
+    use crate::shared::thread_pool::QueueLimits;
+    use std::io::{Read, Write};
+    use std::net;
+
+    const QUERY: &[u8] = include_bytes!("../../../assets/messages/query_packet_bin.txt");
+
+    fn servers_params(listeners: Vec<ListenAddr>, sockets_per_listener: usize) -> ServersParams {
+        ServersParams {
+            udp: UdpParams {
+                listeners: listeners.clone(),
+                write_timeout: time::Duration::from_secs(2),
+                threads: 2,
+                sockets_per_listener,
+                batch_size: 8,
+                queue: QueueLimits::UNBOUNDED,
+                overload_action: OverloadAction::Drop,
+            },
+            tcp: TcpParams {
+                listeners,
+                write_timeout: time::Duration::from_secs(2),
+                read_timeout: time::Duration::from_secs(2),
+                idle_timeout: time::Duration::from_secs(10),
+                max_connections: 4,
+                threads: 2,
+                queue: QueueLimits::UNBOUNDED,
+                proxy_trusted: vec![],
+            },
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(feature = "https")]
+            https: None,
+            #[cfg(feature = "quic")]
+            quic: None,
+            drain_timeout: time::Duration::from_secs(3),
+        }
+    }
+
+    #[test]
+    fn test_bind_servers_prebound() {
+        let listen_addr = ListenAddr {
+            address: "127.0.0.1:48159".parse().unwrap(),
+            ipv6_only: false,
+        };
+        let inherited = bind_udp_socket(&listen_addr, false).unwrap();
+        add_prebound(inherited.into());
+
+        // The shards don't conflict with the inherited socket, which lacks SO_REUSEPORT.
+        let mut params = servers_params(vec![listen_addr.clone()], 2);
+        params.tcp.listeners.clear();
+        bind_servers(&params).unwrap();
+        let sockets = bind_udp_sockets(&params.udp.listeners, 2, "test").unwrap();
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(sockets[0].local_addr().unwrap(), listen_addr.address);
+    }
+
+    #[test]
+    fn test_shutdown_drains_requests() {
+        let listeners: Vec<_> = ["127.0.0.1:48053", "127.0.0.1:48054"]
+            .iter()
+            .map(|addr| ListenAddr {
+                address: addr.parse().unwrap(),
+                ipv6_only: false,
+            })
+            .collect();
+        let params = servers_params(listeners, 2);
+        let handle = start_servers(
+            Arc::new(SlowHandler {
+                delay: time::Duration::from_millis(500),
+            }),
+            params,
+        );
+        thread::sleep(time::Duration::from_millis(300));
+
+        // Send the requests on different listeners, then stop
+        // the servers while the requests are being served.
+        let udp_socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        udp_socket.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        udp_socket.send_to(QUERY, "127.0.0.1:48054").unwrap();
+        let mut tcp_stream = net::TcpStream::connect("127.0.0.1:48053").unwrap();
+        tcp_stream.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        tcp_stream.write_all(&(QUERY.len() as u16).to_be_bytes()).unwrap();
+        tcp_stream.write_all(QUERY).unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+
+        let start = time::Instant::now();
+        handle.shutdown();
+        assert!(start.elapsed() < time::Duration::from_secs(3));
+
+        let mut buf = [0; 512];
+        let n = udp_socket.recv(&mut buf).unwrap();
+        assert!(!dns::Message::decode_from_bytes(&buf[..n]).unwrap().header.is_request());
+        let mut len = [0; 2];
+        tcp_stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        tcp_stream.read_exact(&mut buf).unwrap();
+        assert!(!dns::Message::decode_from_bytes(&buf).unwrap().header.is_request());
+
+        // The connection is closed and the ports are released.
+        assert_eq!(tcp_stream.read(&mut len).unwrap(), 0);
+        for addr in ["127.0.0.1:48053", "127.0.0.1:48054"] {
+            net::UdpSocket::bind(addr).unwrap();
+            net::TcpListener::bind(addr).unwrap();
+        }
+    }
 }
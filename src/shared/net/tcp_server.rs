@@ -1,127 +1,454 @@
+use crate::shared::dns;
+use crate::shared::net::acl::IpPrefix;
+use crate::shared::net::listen::*;
+use crate::shared::net::proxy::*;
 use crate::shared::net::traits::*;
-use crate::shared::{dns, thread_pool};
+use crate::shared::net::utils::*;
+use crate::shared::thread_pool::*;
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::sync::{atomic, Arc};
-use std::{io, net, time};
+use std::sync::{atomic, Arc, Mutex};
+use std::{io, net, thread, time};
 
-/// The request coming from resolver TCP clients. Implements [DnsRead]
-/// by reading directly from the bytes read form the TCP request. The
-/// amount of bytes is determined by the two first bytes of the TCP
-/// message.
-pub struct TcpRequest(net::TcpStream);
+/// The request coming from resolver TCP clients. Implements [DnsRead] by
+/// returning the request already read and decoded by the connection thread.
+/// The amount of bytes read is determined by the two first bytes of the
+/// TCP message.
+pub struct TcpRequest {
+    request: DnsReadResult,
+    source: RequestSource,
+}
 
 impl DnsRead for TcpRequest {
-    fn read(mut self) -> DnsReadResult {
-        let mut buf: [u8; 2] = [0; 2];
-        if let Err(err) = self.0.read_exact(&mut buf) {
-            return DnsReadResult::IoErr(err);
-        };
-        let req_len = ((buf[0] as u16) << 8) | (buf[1] as u16);
-        let mut buf = vec![0_u8; req_len as usize];
-        if let Err(err) = self.0.read_exact(&mut buf) {
-            return DnsReadResult::IoErr(err);
-        };
+    fn read(self) -> DnsReadResult {
+        self.request
+    }
 
-        let req = dns::Message::decode_from_bytes(&buf);
-        let err = match req {
-            Ok(req) => return DnsReadResult::FullMessage(req),
-            Err(err) => err,
-        };
-        match dns::Header::decode_from_bytes(&buf) {
-            Ok(v) => DnsReadResult::HeaderOnly(v, err),
-            Err(err_h) => DnsReadResult::ParseErr(err, err_h),
-        }
+    fn source(&self) -> RequestSource {
+        self.source
     }
 }
 
 /// A wrapper around the an established TCP connection. Implements [DnsWrite],
 /// writing directly into the underlying connection. It is required to write
-/// the length of the message itself before writing the actual response.
-pub struct TcpResponse(net::TcpStream);
+/// the length of the message itself before writing the actual response. The
+/// connection is shared among all the requests pipelined on it, so responses
+/// are written as soon as they are ready, possibly out of order.
+pub struct TcpResponse {
+    stream: Arc<Mutex<dyn Write + Send>>,
+    keepalive: Option<u16>,
+}
 
 impl DnsWrite for TcpResponse {
-    fn reply(mut self, response: dns::Message) -> io::Result<()> {
+    fn reply(self, mut response: dns::Message) -> io::Result<()> {
+        set_keepalive(&mut response, self.keepalive);
         let resp_bytes = response.encode_to_bytes().unwrap();
         let resp_len = resp_bytes.len() as u16;
-        let buf = [(resp_len >> 8) as u8, (resp_len) as u8];
-        self.0.write_all(&buf)?;
-        self.0.write_all(&resp_bytes)
+        let mut buf = Vec::with_capacity(resp_bytes.len() + 2);
+        buf.extend_from_slice(&resp_len.to_be_bytes());
+        buf.extend_from_slice(&resp_bytes);
+        self.stream.lock().unwrap().write_all(&buf)
     }
 }
 
-/// Parameters to be used when starting
-/// the TCP server with [start_tcp_server].
+/// Parameters to be used when starting the TCP server with [start_tcp_server].
+/// The server accepts connections on all the `listeners`, the thread pool and
+/// the `max_connections` limit are shared among them. New connections are
+/// refused while the `queue` of the pool is full. Connections from the
+/// `proxy_trusted` ranges must start with a PROXY protocol v2 header, and
+/// their requests are served as coming from the client in the header.
 #[derive(Clone)]
 pub struct TcpParams {
-    pub address: String,
-    pub port: u16,
+    pub listeners: Vec<ListenAddr>,
     pub write_timeout: time::Duration,
     pub read_timeout: time::Duration,
+    pub idle_timeout: time::Duration,
+    pub max_connections: usize,
     pub threads: usize,
+    pub queue: QueueLimits,
+    pub proxy_trusted: Vec<IpPrefix>,
 }
 
 /// Starts a new TCP server generic over a request handler ([DnsHandler]). The function
-/// spawns a threads pool to handle requests and loops over new TCP connections messages.
-/// When a new client establish a new TCP connection, a new task for the thread pool is
-/// created. The task will use the dns handler to serve the request. The [TcpParams] is
-/// used to setup the server, while the `stop` argument can be used to stop the server.
-pub fn start_tcp_server<H>(handler: Arc<H>, params: TcpParams, stop: &atomic::AtomicBool)
+/// spawns a threads pool to handle requests and loops over new TCP connections. Every
+/// accepted connection is served by a dedicated thread, which reads the requests sent
+/// over it (RFC 7766) and creates a new task for the thread pool for each one. The task
+/// will use the dns handler to serve the request. The [TcpParams] is used to setup the
+/// server, while the `stop` argument can be used to stop the server. The counters of
+/// the queue of the pool are updated into `stats`.
+pub fn start_tcp_server<H>(handler: Arc<H>, params: TcpParams, stats: Arc<QueueStats>, stop: &atomic::AtomicBool)
 where
     H: DnsHandler,
 {
-    let threads_pool = thread_pool::ThreadPool::new(params.threads, "tcp");
-    let listen_address: (&str, u16) = (&params.address, params.port);
-    let tcp_socket = match net::TcpListener::bind(listen_address) {
-        Ok(v) => {
-            log::info!("Starting TCP server, address: '{}:{}'.", &params.address, params.port);
-            v
+    let listeners = match bind_tcp_listeners(&params.listeners, "TCP") {
+        Some(v) => v,
+        None => return,
+    };
+    let threads_pool = Arc::new(ThreadPool::with_queue(params.threads, "tcp", params.queue, stats));
+    let connections = OpenConns::default();
+
+    // Accept TCP connections. When a new one is accepted, spawn a thread reading
+    // the requests sent over it, unless too many connections are already open.
+    accept_connections(
+        listeners,
+        &connections,
+        &threads_pool,
+        &params,
+        "tcp",
+        stop,
+        |tcp_stream, src_addr, conn_guard| {
+            let handler = Arc::clone(&handler);
+            let threads_pool = Arc::clone(&threads_pool);
+            let params = params.clone();
+            thread::spawn(move || {
+                let client = client_addr(&tcp_stream, src_addr, &params.proxy_trusted, params.read_timeout);
+                let served = client.and_then(|client| {
+                    let conn = StreamConn {
+                        transport: Transport::Tcp,
+                        client,
+                        socket: &tcp_stream,
+                        reader: &tcp_stream,
+                        writer: Arc::new(Mutex::new(tcp_stream.try_clone()?)),
+                    };
+                    serve_stream(handler, &threads_pool, conn, &params)
+                });
+                if let Err(err) = served {
+                    log::warn!("Serving tcp connection from {}: {}", src_addr, err);
+                }
+                drop(threads_pool);
+                drop(conn_guard);
+            });
+        },
+    );
+    drop(threads_pool);
+}
+
+/// Accepts connections on all the `listeners` until the stop signal is received,
+/// with a thread for every listener. Accepted connections are registered in `conns`
+/// and passed to `serve` along with their guard, unless the queue of `threads_pool`
+/// is full. Before returning, the connections still open are drained. The `proto`
+/// is used in logs.
+pub(crate) fn accept_connections<F>(
+    listeners: Vec<net::TcpListener>,
+    conns: &OpenConns,
+    threads_pool: &ThreadPool,
+    params: &TcpParams,
+    proto: &str,
+    stop: &atomic::AtomicBool,
+    serve: F,
+) where
+    F: Fn(net::TcpStream, net::SocketAddr, ConnGuard) + Sync,
+{
+    thread::scope(|scope| {
+        for listener in listeners {
+            let serve = &serve;
+            scope.spawn(move || loop {
+                // Check if we got a signal to exit.
+                if stop.load(atomic::Ordering::SeqCst) {
+                    return;
+                }
+                match wait_readable(&listener, STOP_POLL_INTERVAL) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(err) => {
+                        log::error!("Cannot poll socket: {}", err);
+                        return;
+                    }
+                }
+
+                let (tcp_stream, src_addr) = match listener.accept() {
+                    Ok(v) => v,
+                    Err(err) => {
+                        log::error!("Accepting {} connection: {}", proto, err);
+                        continue;
+                    }
+                };
+                if threads_pool.is_full() {
+                    log::warn!("Queue of {} pool full, refusing connection from {}.", proto, src_addr);
+                    continue;
+                }
+                match conns.register(&tcp_stream, params.max_connections) {
+                    Ok(Some(conn_guard)) => serve(tcp_stream, src_addr, conn_guard),
+                    Ok(None) => log::warn!(
+                        "Max {} connections reached, dropping connection from {}.",
+                        proto,
+                        src_addr
+                    ),
+                    Err(err) => log::error!("Registering {} connection: {}", proto, err),
+                };
+            });
+        }
+    });
+    conns.drain();
+}
+
+/// The connections currently open on a stream-based server. Used to enforce the
+/// max connections limit and to drain the connections when the server is stopped.
+#[derive(Clone, Default)]
+pub(crate) struct OpenConns(Arc<Mutex<OpenConnsInner>>);
+
+#[derive(Default)]
+struct OpenConnsInner {
+    next_id: u64,
+    conns: HashMap<u64, net::TcpStream>,
+}
+
+/// Keeps a connection registered in [OpenConns] until dropped. Connection
+/// threads must drop it as the last thing, after releasing the thread pool.
+pub(crate) struct ConnGuard {
+    conns: OpenConns,
+    id: u64,
+}
+
+impl OpenConns {
+    /// Registers the connection, unless the open ones are already `max` (in
+    /// that case None is returned and the passed connection must be closed).
+    pub fn register(&self, stream: &net::TcpStream, max: usize) -> io::Result<Option<ConnGuard>> {
+        let mut inner = self.0.lock().unwrap();
+        if inner.conns.len() >= max {
+            return Ok(None);
         }
-        Err(err) => {
-            log::error!("Cannot setup socket: {}", err);
-            return;
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.conns.insert(id, stream.try_clone()?);
+        let conns = self.clone();
+        Ok(Some(ConnGuard { conns, id }))
+    }
+
+    /// Shuts down the reading side of the open connections, so no more requests
+    /// are read from them, and waits for their threads to exit. Responses to the
+    /// requests already read can still be written back to the clients.
+    pub fn drain(&self) {
+        for conn in self.0.lock().unwrap().conns.values() {
+            let _ = conn.shutdown(net::Shutdown::Read);
+        }
+        while !self.0.lock().unwrap().conns.is_empty() {
+            thread::sleep(time::Duration::from_millis(50));
         }
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        self.conns.0.lock().unwrap().conns.remove(&self.id);
+    }
+}
+
+/// The parts of a stream-based connection served by [serve_stream]. The `socket`
+/// is the underlying TCP connection, used to set up timeouts, while `reader` and
+/// `writer` are used to read requests and write responses (e.g. over TLS). The
+/// `client` is the peer address, or the one sent by a trusted proxy.
+pub(crate) struct StreamConn<'a, R: Read> {
+    pub transport: Transport,
+    pub client: net::SocketAddr,
+    pub socket: &'a net::TcpStream,
+    pub reader: R,
+    pub writer: Arc<Mutex<dyn Write + Send>>,
+}
+
+/// Read the requests sent over the connection until the client closes it or the
+/// idle timeout expires. Every request is handled in the thread pool, so many
+/// requests on the same connection can be processed concurrently. Requests read
+/// while the queue of the pool is full are answered with SERVFAIL.
+pub(crate) fn serve_stream<H: DnsHandler, R: Read>(
+    handler: Arc<H>,
+    threads_pool: &ThreadPool,
+    mut conn: StreamConn<R>,
+    params: &TcpParams,
+) -> io::Result<()> {
+    conn.socket.set_write_timeout(Some(params.write_timeout))?;
+    let source = RequestSource {
+        addr: conn.client,
+        transport: conn.transport,
     };
 
-    // Loop accepting TCP connections. When a new one is accepted, read
-    // it and delegate the request processing to a thread in the pool.
+    // The first request is awaited for the read timeout only,
+    // while the following ones for the idle timeout.
+    let mut wait_timeout = params.read_timeout;
     loop {
-        let (mut tcp_stream, _) = match tcp_socket.accept() {
-            Ok(v) => v,
-            Err(err) => {
-                log::error!("Accepting tcp connection: {}", err);
+        conn.socket.set_read_timeout(Some(wait_timeout))?;
+        let mut len_buf = [0_u8; 2];
+        match conn.reader.read_exact(&mut len_buf) {
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) if is_timeout(&err) => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        conn.socket.set_read_timeout(Some(params.read_timeout))?;
+        let mut buf = vec![0_u8; u16::from_be_bytes(len_buf) as usize];
+        conn.reader.read_exact(&mut buf)?;
+
+        let request = DnsReadResult::from_bytes(&buf);
+        let response = TcpResponse {
+            stream: Arc::clone(&conn.writer),
+            keepalive: keepalive_timeout(&request, params.idle_timeout),
+        };
+        wait_timeout = params.idle_timeout;
+        let queue_slot = match threads_pool.try_reserve() {
+            Some(v) => v,
+            None => {
+                log::debug!(
+                    "Queue full, answering {:?} request from {} with SERVFAIL.",
+                    source.transport,
+                    source.addr
+                );
+                if let Some(servfail) = servfail_response(&buf) {
+                    response.reply(servfail)?;
+                }
                 continue;
             }
         };
+        let handler = Arc::clone(&handler);
+        queue_slot.execute(move || {
+            handler.handle_request(TcpRequest { request, source }, response);
+        });
+    }
+}
 
-        // Check if we got a signal to exit.
-        if stop.load(atomic::Ordering::SeqCst) {
-            drop(threads_pool);
-            return;
-        }
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
 
-        // Create and send a new task to the worker pool: setup the connection
-        // parameters, read the request, compose request and response and call
-        // the handler to serve the request.
-        let handler = Arc::clone(&handler);
-        threads_pool.execute(move || {
-            let setup_ok = setup_connection(&mut tcp_stream, (params.read_timeout, params.write_timeout));
-            if let Err(err) = setup_ok {
-                log::error!("Setting the conn: {}", err);
-                return;
-            };
+/// Returns the edns-tcp-keepalive timeout (RFC 7828) to be sent back to the
+/// client, in units of 100 milliseconds. The option is sent only if the
+/// client included it in its request.
+pub(crate) fn keepalive_timeout(request: &DnsReadResult, idle_timeout: time::Duration) -> Option<u16> {
+    let edns = match request {
+        DnsReadResult::FullMessage(msg) => msg.edns.as_ref()?,
+        _ => return None,
+    };
+    if !edns.has_tcp_keepalive() {
+        return None;
+    }
+    Some((idle_timeout.as_millis() / 100).min(u16::MAX as u128) as u16)
+}
 
-            let request = TcpRequest(tcp_stream.try_clone().unwrap());
-            let response = TcpResponse(tcp_stream);
-            handler.handle_request(request, response);
-        })
+/// Adds the edns-tcp-keepalive option to the response, if the response
+/// carries EDNS data (so if the handler echoed the OPT of the request).
+pub(crate) fn set_keepalive(response: &mut dns::Message, keepalive: Option<u16>) {
+    if let (Some(timeout), Some(edns)) = (keepalive, response.edns.as_mut()) {
+        edns.set_tcp_keepalive(Some(timeout));
     }
 }
 
-fn setup_connection(
-    tcp_stream: &mut net::TcpStream,
-    (r_timeout, w_timeout): (time::Duration, time::Duration),
-) -> io::Result<()> {
-    tcp_stream.set_read_timeout(Some(r_timeout))?;
-    tcp_stream.set_write_timeout(Some(w_timeout))?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::SlowHandler;
+
+    const QUERY: &[u8] = include_bytes!("../../../assets/messages/query_packet_bin.txt");
+
+    // Reply only to requests coming from the client address sent by the proxy.
+    struct ProxiedHandler;
+
+    impl DnsHandler for ProxiedHandler {
+        fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+            let expected: net::SocketAddr = "10.0.0.1:5300".parse().unwrap();
+            if req.source().addr != expected {
+                return;
+            }
+            if let DnsReadResult::FullMessage(mut msg) = req.read() {
+                msg.header.query_resp = true;
+                resp.reply(msg).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_proxy_protocol() {
+        let params = TcpParams {
+            listeners: vec![ListenAddr {
+                address: "127.0.0.1:48155".parse().unwrap(),
+                ipv6_only: false,
+            }],
+            write_timeout: time::Duration::from_secs(2),
+            read_timeout: time::Duration::from_secs(2),
+            idle_timeout: time::Duration::from_secs(2),
+            max_connections: 4,
+            threads: 2,
+            queue: QueueLimits::UNBOUNDED,
+            proxy_trusted: vec!["127.0.0.0/8".parse().unwrap()],
+        };
+        thread::spawn(move || {
+            let stop = atomic::AtomicBool::new(false);
+            start_tcp_server(Arc::new(ProxiedHandler), params, Arc::default(), &stop)
+        });
+        thread::sleep(time::Duration::from_millis(300));
+
+        let mut stream = net::TcpStream::connect("127.0.0.1:48155").unwrap();
+        stream.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        let mut header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\0\x0c".to_vec();
+        header.extend_from_slice(&[10, 0, 0, 1, 127, 0, 0, 1, 0x14, 0xb4, 0, 53]);
+        stream.write_all(&header).unwrap();
+        stream.write_all(&(QUERY.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(QUERY).unwrap();
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).unwrap();
+        assert!(!dns::Message::decode_from_bytes(&buf).unwrap().header.is_request());
+
+        // Connections from trusted ranges without the header are closed.
+        let mut stream = net::TcpStream::connect("127.0.0.1:48155").unwrap();
+        stream.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        stream.write_all(&[0; 16]).unwrap();
+        assert_eq!(stream.read(&mut len).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_pipelined_requests_shed() {
+        let params = TcpParams {
+            listeners: vec![ListenAddr {
+                address: "127.0.0.1:48157".parse().unwrap(),
+                ipv6_only: false,
+            }],
+            write_timeout: time::Duration::from_secs(2),
+            read_timeout: time::Duration::from_secs(2),
+            idle_timeout: time::Duration::from_secs(2),
+            max_connections: 4,
+            threads: 1,
+            queue: QueueLimits { max_depth: 1, max_age: None },
+            proxy_trusted: vec![],
+        };
+        let stats = Arc::new(QueueStats::default());
+        let stats_clone = Arc::clone(&stats);
+        thread::spawn(move || {
+            let stop = atomic::AtomicBool::new(false);
+            start_tcp_server(
+                Arc::new(SlowHandler {
+                    delay: time::Duration::from_millis(300),
+                }),
+                params,
+                stats_clone,
+                &stop,
+            )
+        });
+        thread::sleep(time::Duration::from_millis(300));
+
+        // The first request keeps the only thread busy and the second one fills
+        // the queue, so the third one is answered right away with SERVFAIL.
+        let mut stream = net::TcpStream::connect("127.0.0.1:48157").unwrap();
+        stream.set_read_timeout(Some(time::Duration::from_secs(2))).unwrap();
+        let mut request = (QUERY.len() as u16).to_be_bytes().to_vec();
+        request.extend_from_slice(QUERY);
+        stream.write_all(&request).unwrap();
+        thread::sleep(time::Duration::from_millis(100));
+        stream.write_all(&request.repeat(2)).unwrap();
+
+        let mut resp_codes = vec![];
+        for _ in 0..3 {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            resp_codes.push(dns::Message::decode_from_bytes(&buf).unwrap().header.resp_code);
+        }
+        assert_eq!(
+            resp_codes,
+            [dns::RespCode::ServFail, dns::RespCode::NoError, dns::RespCode::NoError]
+        );
+        assert_eq!(stats.shed_full(), 1);
+    }
 }
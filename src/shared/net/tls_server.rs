@@ -0,0 +1,300 @@
+use crate::shared::net::acl::IpPrefix;
+use crate::shared::net::listen::*;
+use crate::shared::net::proxy::*;
+use crate::shared::net::tcp_server::*;
+use crate::shared::net::traits::*;
+use crate::shared::thread_pool::*;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::io::{BufReader, Read, Write};
+use std::sync::{atomic, Arc, Mutex};
+use std::{fs, io, net, thread, time};
+
+/// Parameters to be used when starting the DNS-over-TLS server with
+/// [start_tls_server]. The certificate chain and the private key are
+/// read from PEM-encoded files. PROXY protocol headers are expected
+/// from the `proxy_trusted` ranges, as for the TCP server.
+#[derive(Clone)]
+pub struct TlsParams {
+    pub listeners: Vec<ListenAddr>,
+    pub write_timeout: time::Duration,
+    pub read_timeout: time::Duration,
+    pub idle_timeout: time::Duration,
+    pub max_connections: usize,
+    pub threads: usize,
+    pub queue: QueueLimits,
+    pub proxy_trusted: Vec<IpPrefix>,
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+/// Starts a new DNS-over-TLS server (RFC 7858) generic over a request handler ([DnsHandler]).
+/// The server works like the TCP one (see [start_tcp_server]): every accepted connection is
+/// served by a dedicated thread, which reads the requests sent over it and creates a new task
+/// for the thread pool for each one. Messages are exchanged over TLS, with the same framing
+/// used over TCP. The `stop` argument can be used to stop the server, while the counters
+/// of the queue of the pool are updated into `stats`.
+pub fn start_tls_server<H>(handler: Arc<H>, params: TlsParams, stats: Arc<QueueStats>, stop: &atomic::AtomicBool)
+where
+    H: DnsHandler,
+{
+    let tls_config = match load_tls_config(&params.cert_file, &params.key_file, &[]) {
+        Ok(v) => Arc::new(v),
+        Err(err) => {
+            log::error!("Cannot setup tls: {}", err);
+            return;
+        }
+    };
+    let listeners = match bind_tcp_listeners(&params.listeners, "TLS") {
+        Some(v) => v,
+        None => return,
+    };
+    let threads_pool = Arc::new(ThreadPool::with_queue(params.threads, "tls", params.queue, stats));
+    let connections = OpenConns::default();
+
+    let tcp_params = TcpParams {
+        listeners: params.listeners.clone(),
+        write_timeout: params.write_timeout,
+        read_timeout: params.read_timeout,
+        idle_timeout: params.idle_timeout,
+        max_connections: params.max_connections,
+        threads: params.threads,
+        queue: params.queue,
+        proxy_trusted: params.proxy_trusted.clone(),
+    };
+
+    // Accept TCP connections. When a new one is accepted, spawn a thread
+    // performing the TLS handshake and reading the requests, unless too
+    // many connections are already open.
+    accept_connections(
+        listeners,
+        &connections,
+        &threads_pool,
+        &tcp_params,
+        "tls",
+        stop,
+        |tcp_stream, src_addr, conn_guard| {
+            let tls_conn = match rustls::ServerConnection::new(Arc::clone(&tls_config)) {
+                Ok(v) => v,
+                Err(err) => {
+                    log::error!("Creating tls connection: {}", err);
+                    return;
+                }
+            };
+
+            let handler = Arc::clone(&handler);
+            let threads_pool = Arc::clone(&threads_pool);
+            let tcp_params = tcp_params.clone();
+            thread::spawn(move || {
+                let trusted = &tcp_params.proxy_trusted;
+                let client = client_addr(&tcp_stream, src_addr, trusted, tcp_params.read_timeout);
+                let served = client.and_then(|client| {
+                    let tls_stream = Arc::new(TlsStream {
+                        conn: Mutex::new(tls_conn),
+                        socket: tcp_stream,
+                    });
+                    let conn = StreamConn {
+                        transport: Transport::Tls,
+                        client,
+                        socket: &tls_stream.socket,
+                        reader: TlsReader(Arc::clone(&tls_stream), vec![0; 4096]),
+                        writer: Arc::new(Mutex::new(TlsWriter(Arc::clone(&tls_stream)))),
+                    };
+                    serve_stream(handler, &threads_pool, conn, &tcp_params)
+                });
+                if let Err(err) = served {
+                    log::warn!("Serving tls connection from {}: {}", src_addr, err);
+                }
+                drop(threads_pool);
+                drop(conn_guard);
+            });
+        },
+    );
+    drop(threads_pool);
+}
+
+/// Builds the TLS server configuration, reading the PEM-encoded certificate
+/// chain and private key from the passed files. The passed ALPN protocols are
+/// advertised to clients, if empty no protocol negotiation is performed.
+pub fn load_tls_config(cert_file: &str, key_file: &str, alpn: &[&[u8]]) -> Result<rustls::ServerConfig, String> {
+    let certs = load_certs(cert_file)?;
+    let key = load_private_key(key_file)?;
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| err.to_string())?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| format!("invalid certificate or key: {}", err))?;
+    config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+/// Reads a chain of PEM-encoded certificates from the passed file.
+pub fn load_certs(cert_file: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = fs::File::open(cert_file).map_err(|err| format!("opening '{}': {}", cert_file, err))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("reading '{}': {}", cert_file, err))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in '{}'", cert_file));
+    }
+    Ok(certs)
+}
+
+/// Reads the first PEM-encoded private key from the passed file.
+pub fn load_private_key(key_file: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = fs::File::open(key_file).map_err(|err| format!("opening '{}': {}", key_file, err))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("no private key found in '{}'", key_file)),
+        Err(err) => Err(format!("reading '{}': {}", key_file, err)),
+    }
+}
+
+/// A TLS session over a TCP connection, shared between the connection thread
+/// reading the requests and the pool threads writing the responses. The lock
+/// on the session is held only while encrypting or decrypting data, never
+/// while blocking on the socket reads.
+struct TlsStream {
+    conn: Mutex<rustls::ServerConnection>,
+    socket: net::TcpStream,
+}
+
+/// The reading half of a [TlsStream], implementing [Read] over plaintext.
+struct TlsReader(Arc<TlsStream>, Vec<u8>);
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let TlsReader(stream, raw_buf) = self;
+        loop {
+            match stream.conn.lock().unwrap().reader().read(buf) {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                res => return res,
+            };
+
+            // No plaintext available, read more encrypted data from the socket and
+            // feed the session with it. Handshake messages are written back here.
+            let n = (&stream.socket).read(raw_buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut conn = stream.conn.lock().unwrap();
+            let mut data = &raw_buf[..n];
+            while !data.is_empty() {
+                conn.read_tls(&mut data)?;
+                if let Err(err) = conn.process_new_packets() {
+                    let _ = conn.write_tls(&mut &stream.socket);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+                }
+            }
+            while conn.wants_write() {
+                conn.write_tls(&mut &stream.socket)?;
+            }
+        }
+    }
+}
+
+/// The writing half of a [TlsStream], implementing [Write] over plaintext.
+struct TlsWriter(Arc<TlsStream>);
+
+impl Write for TlsWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut conn = self.0.conn.lock().unwrap();
+        conn.writer().write_all(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut &self.0.socket)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::shared::dns;
+    use crate::shared::net::*;
+    use crate::shared::thread_pool::QueueLimits;
+    use std::io::{Read, Write};
+    use std::sync::{atomic, Arc};
+    use std::{env, fs, net, thread, time};
+
+    #[test]
+    fn test_tls_server() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = env::temp_dir().join(format!("ariadne-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert_file, key_file) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_file, cert.cert.pem()).unwrap();
+        fs::write(&key_file, cert.key_pair.serialize_pem()).unwrap();
+
+        let params = TlsParams {
+            listeners: vec![ListenAddr {
+                address: "127.0.0.1:48553".parse().unwrap(),
+                ipv6_only: false,
+            }],
+            write_timeout: time::Duration::from_secs(2),
+            read_timeout: time::Duration::from_secs(2),
+            idle_timeout: time::Duration::from_secs(2),
+            max_connections: 4,
+            threads: 2,
+            queue: QueueLimits::UNBOUNDED,
+            proxy_trusted: vec![],
+            cert_file: cert_file.to_str().unwrap().to_string(),
+            key_file: key_file.to_str().unwrap().to_string(),
+        };
+        thread::spawn(move || {
+            start_tls_server(
+                Arc::new(EchoHandler::default()),
+                params,
+                Arc::default(),
+                &atomic::AtomicBool::new(false),
+            )
+        });
+        thread::sleep(time::Duration::from_millis(300));
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(cert.cert.der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_name = "localhost".try_into().unwrap();
+        let conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let socket = net::TcpStream::connect("127.0.0.1:48553").unwrap();
+        let mut stream = rustls::StreamOwned::new(conn, socket);
+
+        // Pipeline two queries over the same connection.
+        let query = dns::Message::decode_from_bytes(include_bytes!("../../../assets/messages/query_packet_bin.txt"));
+        let mut query = query.unwrap();
+        for id in [1, 2] {
+            query.header.id = id;
+            let bytes = query.encode_to_bytes().unwrap();
+            stream.write_all(&(bytes.len() as u16).to_be_bytes()).unwrap();
+            stream.write_all(&bytes).unwrap();
+        }
+        let mut ids = vec![];
+        for _ in 0..2 {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+            stream.read_exact(&mut buf).unwrap();
+            let response = dns::Message::decode_from_bytes(&buf).unwrap();
+            assert!(!response.header.is_request());
+            assert_eq!(response.questions[0].node.as_ref(), "google.com.");
+            ids.push(response.id());
+        }
+        ids.sort();
+        assert_eq!(ids, vec![1, 2]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
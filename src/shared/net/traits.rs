@@ -1,12 +1,46 @@
 use crate::shared::dns;
-use std::io;
+#[cfg(feature = "async")]
+use std::{future::Future, sync::Arc};
+use std::{io, net};
 
 /// A type implementing the [DnsRead] trait is able to read and parse a dns
 /// response form an underlying source, usually a OS socket. **The trait decouples
 /// the request handling from the server communication mechanism**. Note that
 /// the method takes self, this is intentional: only one request should be read.
+/// The [source](DnsRead::source) of the request can be inspected before reading it.
 pub trait DnsRead {
     fn read(self) -> DnsReadResult;
+    fn source(&self) -> RequestSource;
+}
+
+/// The client that sent a dns request and the transport used to send it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestSource {
+    pub addr: net::SocketAddr,
+    pub transport: Transport,
+}
+
+/// The transports over which dns requests are received.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+    Quic,
+}
+
+impl Transport {
+    /// The lowercase name of the transport, as used in metrics and logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Https => "https",
+            Transport::Quic => "quic",
+        }
+    }
 }
 
 /// Results of reading and parsing a DNS request with a [DnsRead] implementor.
@@ -17,12 +51,40 @@ pub enum DnsReadResult {
     IoErr(io::Error),
 }
 
+impl DnsReadResult {
+    /// Decode a dns request from raw bytes. If the full message cannot be
+    /// decoded, try to decode at least the header, so that handlers are
+    /// still able to reply to the client with a proper error code.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let err = match dns::Message::decode_from_bytes(bytes) {
+            Ok(req) => return DnsReadResult::FullMessage(req),
+            Err(err) => err,
+        };
+        match dns::Header::decode_from_bytes(bytes) {
+            Ok(v) => DnsReadResult::HeaderOnly(v, err),
+            Err(err_h) => DnsReadResult::ParseErr(err, err_h),
+        }
+    }
+}
+
 /// A type implementing the [DnsWrite] trait is able to write a dns response
 /// to an underlying destination, usually a OS socket. **The trait decouples
 /// the request handling from the server communication mechanism**. Note that
 /// the method takes self, this is intentional: only one response should be sent.
 pub trait DnsWrite {
     fn reply(self, response: dns::Message) -> io::Result<()>;
+
+    /// Reports how the response was obtained, before replying. The wrappers of
+    /// the writers (e.g. the query log) record it, the other ones ignore it.
+    fn annotate(&mut self, _resolution: Resolution) {}
+}
+
+/// How a response was obtained by the handler, see [DnsWrite::annotate]: if
+/// it was served from cache and how many queries were sent to other nameservers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resolution {
+    pub cache_hit: bool,
+    pub upstream_queries: u32,
 }
 
 /// A type implementing the [DnsHandler] is able to handle dns requests. The
@@ -34,3 +96,16 @@ pub trait DnsHandler: Send + Sync + 'static {
         R: DnsRead,
         W: DnsWrite;
 }
+
+/// The async counterpart of [DnsHandler], used by the async servers (available
+/// with the `async` feature). Requests and responses are still handled through
+/// [DnsRead] and [DnsWrite] implementors, so handlers can share the same logic
+/// between the sync and the async servers. Implementors that perform blocking
+/// operations must move them out of the async runtime (e.g. `spawn_blocking`).
+#[cfg(feature = "async")]
+pub trait AsyncDnsHandler: Send + Sync + 'static {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static;
+}
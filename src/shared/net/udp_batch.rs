@@ -0,0 +1,199 @@
+use crate::shared::dns;
+use std::{io, net, ops};
+#[cfg(target_os = "linux")]
+use {socket2::SockAddr, socket2::SockAddrStorage, std::mem, std::os::unix::io::AsRawFd, std::ptr};
+
+/// Upper bound of the datagrams received or sent with a single system
+/// call, the kernel doesn't accept more than this (UIO_MAXIOV).
+pub(crate) const MAX_UDP_BATCH: usize = 1024;
+
+/// A batch of datagrams received with a single [recv_batch] call. The
+/// datagrams are stored back to back in a single buffer, each one taking
+/// at most [dns::MAX_UDP_LEN_BYTES] bytes.
+pub(crate) struct RecvBatch {
+    buffer: Vec<u8>,
+    messages: Vec<(ops::Range<usize>, net::SocketAddr)>,
+}
+
+impl RecvBatch {
+    /// Creates a batch able to receive up to `capacity` datagrams.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.clamp(1, MAX_UDP_BATCH);
+        RecvBatch {
+            buffer: vec![0; capacity * dns::MAX_UDP_LEN_BYTES],
+            messages: Vec::with_capacity(capacity),
+        }
+    }
+
+    /// Iterates over the received datagrams and their source addresses.
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], net::SocketAddr)> {
+        self.messages
+            .iter()
+            .map(|(range, addr)| (&self.buffer[range.clone()], *addr))
+    }
+}
+
+/// Receives the datagrams available on the socket, up to the batch capacity,
+/// without blocking. Returns the number of datagrams received, 0 if none was
+/// available. On Linux a single `recvmmsg` call is used, while on the other
+/// systems only one datagram is received.
+#[cfg(target_os = "linux")]
+pub(crate) fn recv_batch(socket: &net::UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+    batch.messages.clear();
+    let capacity = batch.buffer.len() / dns::MAX_UDP_LEN_BYTES;
+    let mut names: Vec<SockAddrStorage> = (0..capacity).map(|_| SockAddrStorage::zeroed()).collect();
+    let mut iovecs: Vec<libc::iovec> = batch
+        .buffer
+        .chunks_exact_mut(dns::MAX_UDP_LEN_BYTES)
+        .take(capacity)
+        .map(|chunk| libc::iovec {
+            iov_base: chunk.as_mut_ptr() as *mut libc::c_void,
+            iov_len: chunk.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(names.iter_mut())
+        .map(|(iovec, name)| {
+            let name_len = name.size_of();
+            mmsg_header(iovec, name as *mut SockAddrStorage as *mut libc::c_void, name_len)
+        })
+        .collect();
+
+    // SAFETY: every header points to a valid buffer and address storage, both
+    // outliving the call. The kernel writes at most the lengths reported.
+    let flags = libc::MSG_DONTWAIT as _;
+    let received = unsafe {
+        libc::recvmmsg(
+            socket.as_raw_fd(),
+            headers.as_mut_ptr(),
+            capacity as _,
+            flags,
+            ptr::null_mut(),
+        )
+    };
+    if received < 0 {
+        return match io::Error::last_os_error() {
+            err if err.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            err => Err(err),
+        };
+    }
+
+    let received = received as usize;
+    for (i, (header, name)) in headers.iter().zip(names).take(received).enumerate() {
+        // SAFETY: the kernel initialized the address with the reported length.
+        let addr = unsafe { SockAddr::new(name, header.msg_hdr.msg_namelen) };
+        if let Some(addr) = addr.as_socket() {
+            let start = i * dns::MAX_UDP_LEN_BYTES;
+            let len = (header.msg_len as usize).min(dns::MAX_UDP_LEN_BYTES);
+            batch.messages.push((start..start + len, addr));
+        }
+    }
+    Ok(received)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn recv_batch(socket: &net::UdpSocket, batch: &mut RecvBatch) -> io::Result<usize> {
+    batch.messages.clear();
+    let (n_read, addr) = socket.recv_from(&mut batch.buffer[..dns::MAX_UDP_LEN_BYTES])?;
+    batch.messages.push((0..n_read, addr));
+    Ok(1)
+}
+
+/// Sends the datagrams to the paired addresses. On Linux the datagrams are
+/// sent with as few `sendmmsg` calls as possible. Datagrams that cannot be
+/// sent are logged and skipped.
+pub(crate) fn send_batch(socket: &net::UdpSocket, datagrams: &[(Vec<u8>, net::SocketAddr)]) {
+    let mut sent = 0;
+    while sent < datagrams.len() {
+        match send_datagrams(socket, &datagrams[sent..]) {
+            Ok(n) => sent += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                log::warn!("Cannot send UDP response to {}: {}", datagrams[sent].1, err);
+                sent += 1;
+            }
+        }
+    }
+}
+
+// Send a prefix of the datagrams, returning how many were sent. An
+// error is reported only if the first datagram couldn't be sent.
+#[cfg(target_os = "linux")]
+fn send_datagrams(socket: &net::UdpSocket, datagrams: &[(Vec<u8>, net::SocketAddr)]) -> io::Result<usize> {
+    let datagrams = &datagrams[..datagrams.len().min(MAX_UDP_BATCH)];
+    let names: Vec<SockAddr> = datagrams.iter().map(|(_, addr)| SockAddr::from(*addr)).collect();
+    let mut iovecs: Vec<libc::iovec> = datagrams
+        .iter()
+        .map(|(bytes, _)| libc::iovec {
+            iov_base: bytes.as_ptr() as *mut libc::c_void,
+            iov_len: bytes.len(),
+        })
+        .collect();
+    let mut headers: Vec<libc::mmsghdr> = iovecs
+        .iter_mut()
+        .zip(&names)
+        .map(|(iovec, name)| mmsg_header(iovec, name.as_ptr() as *mut libc::c_void, name.len()))
+        .collect();
+
+    // SAFETY: every header points to a valid buffer and address, both outliving
+    // the call. The kernel only reads from them when sending.
+    match unsafe { libc::sendmmsg(socket.as_raw_fd(), headers.as_mut_ptr(), headers.len() as _, 0) } {
+        n if n < 0 => Err(io::Error::last_os_error()),
+        n => Ok(n as usize),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_datagrams(socket: &net::UdpSocket, datagrams: &[(Vec<u8>, net::SocketAddr)]) -> io::Result<usize> {
+    let (bytes, addr) = &datagrams[0];
+    socket.send_to(bytes, addr)?;
+    Ok(1)
+}
+
+// Build the header of a single datagram, with one buffer and an address.
+#[cfg(target_os = "linux")]
+fn mmsg_header(iovec: &mut libc::iovec, name: *mut libc::c_void, name_len: libc::socklen_t) -> libc::mmsghdr {
+    // SAFETY: the all-zero value is valid for the C struct (null pointers and lengths).
+    let mut header: libc::mmsghdr = unsafe { mem::zeroed() };
+    header.msg_hdr.msg_name = name;
+    header.msg_hdr.msg_namelen = name_len;
+    header.msg_hdr.msg_iov = iovec;
+    header.msg_hdr.msg_iovlen = 1;
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::utils::wait_readable;
+    use std::time;
+
+    #[test]
+    fn test_udp_batch() {
+        let server = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = server.local_addr().unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let datagrams: Vec<_> = (1..=3_u8).map(|i| (vec![i; i as usize], server_addr)).collect();
+        send_batch(&client, &datagrams);
+
+        let mut received = vec![];
+        while received.len() < 3 {
+            assert!(wait_readable(&server, time::Duration::from_secs(2)).unwrap());
+            let mut batch = RecvBatch::new(8);
+            recv_batch(&server, &mut batch).unwrap();
+            assert!(batch.iter().count() <= 3);
+            received.extend(batch.iter().map(|(bytes, addr)| (bytes.to_vec(), addr)));
+        }
+        let expected: Vec<_> = (1..=3_u8).map(|i| (vec![i; i as usize], client_addr)).collect();
+        assert_eq!(received, expected);
+
+        // Nothing else is available, the call doesn't block.
+        let mut batch = RecvBatch::new(8);
+        if cfg!(target_os = "linux") {
+            assert_eq!(recv_batch(&server, &mut batch).unwrap(), 0);
+        }
+    }
+}
@@ -1,120 +1,180 @@
+use crate::shared::dns;
+use crate::shared::net::listen::*;
 use crate::shared::net::traits::*;
-use crate::shared::{dns, thread_pool};
+use crate::shared::net::udp_batch::*;
+use crate::shared::net::utils::*;
+use crate::shared::thread_pool::*;
 use std::sync::{atomic, Arc};
-use std::{io, net, time};
+use std::{io, net, thread, time};
 
 /// The request coming from resolver UDP clients. Implements [DnsRead]
 /// by reading directly from the bytes read form the UDP request.
-pub struct UdpRequest<'a>(&'a [u8]);
+pub struct UdpRequest<'a> {
+    bytes: &'a [u8],
+    addr: net::SocketAddr,
+}
 
 impl<'a> DnsRead for UdpRequest<'a> {
     fn read(self) -> DnsReadResult {
-        let req = dns::Message::decode_from_bytes(self.0);
-        let err = match req {
-            Ok(req) => return DnsReadResult::FullMessage(req),
-            Err(err) => err,
-        };
-        match dns::Header::decode_from_bytes(self.0) {
-            Ok(v) => DnsReadResult::HeaderOnly(v, err),
-            Err(err_h) => DnsReadResult::ParseErr(err, err_h),
+        DnsReadResult::from_bytes(self.bytes)
+    }
+
+    fn source(&self) -> RequestSource {
+        RequestSource {
+            addr: self.addr,
+            transport: Transport::Udp,
         }
     }
 }
 
 /// A wrapper around the socket and the address to be used to respond
 /// to a resolver UDP request. Implements [DnsWrite], writing directly
-/// into the underlying OS socket.
-pub struct UdpResponse {
-    socket: net::UdpSocket,
+/// into the underlying OS socket as soon as the response is ready.
+pub struct UdpResponse<'a> {
+    socket: &'a net::UdpSocket,
     addr: net::SocketAddr,
 }
 
-impl DnsWrite for UdpResponse {
+impl DnsWrite for UdpResponse<'_> {
     fn reply(self, response: dns::Message) -> io::Result<()> {
         let resp_bytes = response.encode_to_bytes_trunc().unwrap();
-        let mut written = 0;
-        while written < resp_bytes.len() {
-            let n = self.socket.send_to(&resp_bytes[written..], self.addr)?;
-            written += n;
-        }
+        self.socket.send_to(&resp_bytes, self.addr)?;
         Ok(())
     }
 }
 
-/// Parameters to be used when starting
-/// the UDP server with [start_udp_server].
+/// Parameters to be used when starting the UDP server with
+/// [start_udp_server]. The server receives requests on all the
+/// `listeners`, the thread pool is shared among them. Each listener is
+/// served by `sockets_per_listener` sockets bound with SO_REUSEPORT, and
+/// up to `batch_size` requests are received at once. When
+/// the `queue` of the pool is full, requests are handled as `overload_action`.
 #[derive(Clone)]
 pub struct UdpParams {
-    pub address: String,
-    pub port: u16,
+    pub listeners: Vec<ListenAddr>,
     pub write_timeout: time::Duration,
     pub threads: usize,
+    pub sockets_per_listener: usize,
+    pub batch_size: usize,
+    pub queue: QueueLimits,
+    pub overload_action: OverloadAction,
+}
+
+/// How the UDP server handles requests received while the queue of its
+/// thread pool is full: either silently dropped or answered with SERVFAIL.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverloadAction {
+    #[default]
+    Drop,
+    ServFail,
 }
 
 /// Starts a new UDP server generic over a request handler ([DnsHandler]). The function
-/// spawns a threads pool to handle requests and loops over new UDP messages. When a new
-/// one arrives a new task for the thread pool is created. The task will use the dns handler
-/// to serve the request. The [UdpParams] is used to setup the server properly, while the
-/// `stop` argument can be used to stop the server.
-pub fn start_udp_server<H>(handler: Arc<H>, params: UdpParams, stop: &atomic::AtomicBool)
+/// spawns a threads pool to handle requests and loops over new UDP messages, one thread
+/// for every listening socket. When a new batch arrives a new task for the thread pool is
+/// created for every request. The task will use the dns handler to serve it. The [UdpParams] is
+/// used to setup the server properly, while the `stop` argument can be used to stop it.
+/// The counters of the queue of the pool are updated into `stats`.
+pub fn start_udp_server<H>(handler: Arc<H>, params: UdpParams, stats: Arc<QueueStats>, stop: &atomic::AtomicBool)
 where
     H: DnsHandler,
 {
-    let threads_pool = thread_pool::ThreadPool::new(params.threads, "udp");
-    let socket = match setup_listening_socket(&params) {
-        Ok(v) => {
-            log::info!("Starting UDP server, address: '{}:{}'.", &params.address, params.port);
-            v
-        }
-        Err(err) => {
+    let sockets = match bind_udp_sockets(&params.listeners, params.sockets_per_listener, "UDP") {
+        Some(v) => v,
+        None => return,
+    };
+    for socket in &sockets {
+        if let Err(err) = socket.set_write_timeout(Some(params.write_timeout)) {
             log::error!("Cannot setup socket: {}", err);
             return;
         }
-    };
+    }
 
-    // Loop receiving UDP messages. When a new request arrives, read
-    // it and delegate request handling to a thread in the pool.
-    loop {
-        let mut buffer = [0; dns::MAX_UDP_LEN_BYTES];
-        let (n_read, src_addr) = match socket.recv_from(&mut buffer) {
-            Ok(read_data) => read_data,
-            Err(err) => {
-                log::warn!("Cannot recv_from socket: {}", err);
-                continue;
-            }
-        };
+    // Dropping the pool after all the loops exited waits
+    // for the requests already queued to be served.
+    let threads_pool = ThreadPool::with_queue(params.threads, "udp", params.queue, stats);
+    thread::scope(|scope| {
+        for socket in sockets {
+            let (handler, threads_pool, params) = (&handler, &threads_pool, &params);
+            scope.spawn(move || serve_socket(handler, threads_pool, socket, params, stop));
+        }
+    });
+    drop(threads_pool);
+}
 
+// Loop receiving UDP messages. When new requests arrive, read them in batch
+// and delegate the handling of every request to a thread in the pool. The
+// socket is polled so that the stop signal is checked periodically.
+fn serve_socket<H: DnsHandler>(
+    handler: &Arc<H>,
+    threads_pool: &ThreadPool,
+    socket: net::UdpSocket,
+    params: &UdpParams,
+    stop: &atomic::AtomicBool,
+) {
+    let socket = Arc::new(socket);
+    let mut batch = RecvBatch::new(params.batch_size);
+    loop {
         // Check if we got a signal to exit.
         if stop.load(atomic::Ordering::SeqCst) {
-            drop(threads_pool);
             return;
         }
+        match wait_readable(socket.as_ref(), STOP_POLL_INTERVAL) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(err) => {
+                log::error!("Cannot poll socket: {}", err);
+                return;
+            }
+        }
 
-        let socket_clone = match socket.try_clone() {
-            Ok(socket) => socket,
+        match recv_batch(&socket, &mut batch) {
+            Ok(0) => continue,
+            Ok(_) => {}
             Err(err) => {
-                log::warn!("Cannot clone socket: {}", err);
+                log::warn!("Cannot receive from socket: {}", err);
                 continue;
             }
         };
 
-        // Create and send a new task to the worker pool: compose request and
+        // Shed the requests if the pool is overloaded. Otherwise, create and send
+        // a new task to the worker pool for every request: compose request and
         // response objects and call the handler function to serve the request.
-        let handler = Arc::clone(&handler);
-        threads_pool.execute(move || {
-            let request = UdpRequest(&buffer[0..n_read]);
-            let response = UdpResponse {
-                socket: socket_clone,
-                addr: src_addr,
+        // Only the shed requests are answered together.
+        let mut shed = vec![];
+        for (bytes, addr) in batch.iter() {
+            let queue_slot = match threads_pool.try_reserve() {
+                Some(v) => v,
+                None => {
+                    shed.push((bytes, addr));
+                    continue;
+                }
             };
-            handler.handle_request(request, response);
-        });
+            let handler = Arc::clone(handler);
+            let socket = Arc::clone(&socket);
+            let bytes = bytes.to_vec();
+            queue_slot.execute(move || {
+                let request = UdpRequest { bytes: &bytes, addr };
+                let response = UdpResponse { socket: &socket, addr };
+                handler.handle_request(request, response);
+            });
+        }
+        if !shed.is_empty() {
+            shed_requests(&socket, &shed, params.overload_action);
+        }
     }
 }
 
-fn setup_listening_socket(server_conf: &UdpParams) -> Result<net::UdpSocket, io::Error> {
-    let listen_address: (&str, u16) = (&server_conf.address, server_conf.port);
-    let socket = net::UdpSocket::bind(listen_address)?;
-    socket.set_write_timeout(Some(server_conf.write_timeout))?;
-    Ok(socket)
+// Handle the requests that cannot be queued, answering them with SERVFAIL if
+// configured. Requests whose header cannot be decoded are always dropped.
+fn shed_requests(socket: &net::UdpSocket, requests: &[(&[u8], net::SocketAddr)], action: OverloadAction) {
+    log::debug!("UDP queue full, shedding {} requests ({:?}).", requests.len(), action);
+    if action == OverloadAction::Drop {
+        return;
+    }
+    let responses: Vec<_> = requests
+        .iter()
+        .filter_map(|&(bytes, addr)| Some((servfail_response(bytes)?.encode_to_bytes().ok()?, addr)))
+        .collect();
+    send_batch(socket, &responses);
 }
@@ -0,0 +1,69 @@
+use crate::shared::dns;
+use std::os::unix::io::AsRawFd;
+#[cfg(any(feature = "https", feature = "quic", feature = "async"))]
+use std::sync::atomic;
+use std::{io, time};
+
+/// How often the servers blocked waiting for new requests
+/// or connections wake up to check if they must stop.
+pub(crate) const STOP_POLL_INTERVAL: time::Duration = time::Duration::from_millis(200);
+
+/// Waits until the socket is readable (new data or connections are available)
+/// or the timeout expires, returning false in the latter case. Interrupted
+/// waits are reported as expired timeouts.
+pub(crate) fn wait_readable<S: AsRawFd>(socket: &S, timeout: time::Duration) -> io::Result<bool> {
+    let mut poll_fd = libc::pollfd {
+        fd: socket.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    // SAFETY: the pointer refers to a single valid pollfd for the whole call.
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } {
+        -1 => match io::Error::last_os_error() {
+            err if err.kind() == io::ErrorKind::Interrupted => Ok(false),
+            err => Err(err),
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Builds the SERVFAIL response to a request that cannot be served (e.g. when
+/// the server is overloaded), from its header only. None is returned if the
+/// header cannot be decoded or the message is not a request.
+pub(crate) fn servfail_response(req_bytes: &[u8]) -> Option<dns::Message> {
+    let req_header = dns::Header::decode_from_bytes(req_bytes).ok()?;
+    if !req_header.is_request() {
+        return None;
+    }
+    Some(dns::Message {
+        header: dns::Header {
+            query_resp: true,
+            auth_answer: false,
+            truncated: false,
+            recursion_available: false,
+            z: 0,
+            resp_code: dns::RespCode::ServFail,
+            questions_count: 0,
+            answers_count: 0,
+            authorities_count: 0,
+            additionals_count: 0,
+            ..req_header
+        },
+        questions: vec![],
+        answers: vec![],
+        authorities: vec![],
+        additionals: vec![],
+        edns: None,
+    })
+}
+
+/// Resolves when the `stop` flag is set, used by the servers
+/// running on an async runtime to stop accepting connections.
+#[cfg(any(feature = "https", feature = "quic", feature = "async"))]
+pub(crate) async fn wait_stop(stop: &atomic::AtomicBool) {
+    while !stop.load(atomic::Ordering::SeqCst) {
+        tokio::time::sleep(STOP_POLL_INTERVAL).await;
+    }
+}
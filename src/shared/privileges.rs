@@ -0,0 +1,376 @@
+use std::ffi::CString;
+use std::{io, path};
+
+/// The parameters passed to [drop_privileges]. The process switches to the
+/// `user` (and to its primary group, unless `group` is set), after changing
+/// the root directory to `chroot`. With `seccomp`, the system calls of the
+/// process are restricted to the ones needed to serve requests.
+#[derive(Clone, Debug, Default)]
+pub struct PrivilegesParams {
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub chroot: Option<path::PathBuf>,
+    pub seccomp: bool,
+}
+
+/// Drops the privileges of the process, once the sockets are bound (see
+/// [bind_servers](crate::shared::net::bind_servers)) and the files are read.
+/// Users and groups are looked up before changing the root directory, since
+/// the system databases are not reachable afterwards. Changing the root
+/// directory and the user requires the process to be started as root (or
+/// with the CAP_SYS_CHROOT, CAP_SETUID and CAP_SETGID capabilities).
+#[cfg(unix)]
+pub fn drop_privileges(params: &PrivilegesParams) -> io::Result<()> {
+    let user = params.user.as_deref().map(lookup_user).transpose()?;
+    let group = params.group.as_deref().map(lookup_group).transpose()?;
+
+    if let Some(chroot) = &params.chroot {
+        std::os::unix::fs::chroot(chroot)?;
+        std::env::set_current_dir("/")?;
+        log::info!("Changed root directory to '{}'.", chroot.display());
+    }
+
+    // The group is changed first, as the user may not be allowed to afterwards.
+    let gid = group.or(user.map(|(_, gid)| gid));
+    if let Some(gid) = gid {
+        // Safety: the functions are called with valid arguments.
+        if unsafe { libc::setgroups(1, &gid) } < 0 || unsafe { libc::setgid(gid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        log::info!("Changed group to {}.", gid);
+    }
+    if let Some((uid, _)) = user {
+        if unsafe { libc::setuid(uid) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        log::info!("Changed user to {}.", uid);
+    }
+
+    if params.seccomp {
+        seccomp::install_filter()?;
+        log::info!("Installed seccomp filter.");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_params: &PrivilegesParams) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+// Return the user id and the primary group id of the user.
+#[cfg(unix)]
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = c_string(name)?;
+    let mut buf = vec![0; 16384];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // Safety: the buffers outlive the call, the result points into them.
+    let code = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    match (code, result.is_null()) {
+        (0, false) => Ok((passwd.pw_uid, passwd.pw_gid)),
+        (0, true) => Err(not_found("user", name)),
+        (code, _) => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = c_string(name)?;
+    let mut buf = vec![0; 16384];
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // Safety: the buffers outlive the call, the result points into them.
+    let code = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut group, buf.as_mut_ptr(), buf.len(), &mut result) };
+    match (code, result.is_null()) {
+        (0, false) => Ok(group.gr_gid),
+        (0, true) => Err(not_found("group", name)),
+        (code, _) => Err(io::Error::from_raw_os_error(code)),
+    }
+}
+
+#[cfg(unix)]
+fn c_string(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+#[cfg(unix)]
+fn not_found(kind: &str, name: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} '{}' not found", kind, name))
+}
+
+/// Reports if the seccomp filter is supported on the current platform.
+pub fn seccomp_supported() -> bool {
+    cfg!(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))
+}
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod seccomp {
+    use libc::*;
+    use std::io;
+
+    // The architectures of the audit subsystem, not exported by libc.
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    // Offsets of the fields of `struct seccomp_data`.
+    const NR_OFFSET: u32 = 0;
+    const ARCH_OFFSET: u32 = 4;
+
+    // The system calls used by the servers after the startup: threads and memory
+    // management, sockets, timers, and the files opened and rotated by the writer threads.
+    pub(super) const ALLOWED: &[c_long] = &[
+        SYS_read,
+        SYS_write,
+        SYS_readv,
+        SYS_writev,
+        SYS_pread64,
+        SYS_pwrite64,
+        SYS_close,
+        SYS_openat,
+        SYS_fstat,
+        SYS_newfstatat,
+        SYS_statx,
+        SYS_lseek,
+        SYS_renameat,
+        SYS_renameat2,
+        SYS_fcntl,
+        SYS_ioctl,
+        SYS_fsync,
+        SYS_dup,
+        SYS_dup3,
+        SYS_pipe2,
+        SYS_socket,
+        SYS_socketpair,
+        SYS_bind,
+        SYS_listen,
+        SYS_connect,
+        SYS_accept,
+        SYS_accept4,
+        SYS_shutdown,
+        SYS_getsockname,
+        SYS_getpeername,
+        SYS_getsockopt,
+        SYS_setsockopt,
+        SYS_sendto,
+        SYS_recvfrom,
+        SYS_sendmsg,
+        SYS_recvmsg,
+        SYS_sendmmsg,
+        SYS_recvmmsg,
+        SYS_ppoll,
+        SYS_pselect6,
+        SYS_epoll_create1,
+        SYS_epoll_ctl,
+        SYS_epoll_pwait,
+        SYS_eventfd2,
+        SYS_clock_gettime,
+        SYS_clock_nanosleep,
+        SYS_nanosleep,
+        SYS_gettimeofday,
+        SYS_getrandom,
+        SYS_futex,
+        SYS_clone,
+        SYS_clone3,
+        SYS_set_robust_list,
+        SYS_rseq,
+        SYS_sched_yield,
+        SYS_sched_getaffinity,
+        SYS_mmap,
+        SYS_munmap,
+        SYS_mremap,
+        SYS_mprotect,
+        SYS_madvise,
+        SYS_brk,
+        SYS_rt_sigaction,
+        SYS_rt_sigprocmask,
+        SYS_rt_sigreturn,
+        SYS_sigaltstack,
+        SYS_tgkill,
+        SYS_getpid,
+        SYS_gettid,
+        SYS_getuid,
+        SYS_geteuid,
+        SYS_getgid,
+        SYS_getegid,
+        SYS_prctl,
+        SYS_uname,
+        SYS_exit,
+        SYS_exit_group,
+        #[cfg(target_arch = "x86_64")]
+        SYS_poll,
+        #[cfg(target_arch = "x86_64")]
+        SYS_epoll_wait,
+        #[cfg(target_arch = "x86_64")]
+        SYS_open,
+        #[cfg(target_arch = "x86_64")]
+        SYS_stat,
+        #[cfg(target_arch = "x86_64")]
+        SYS_rename,
+        #[cfg(target_arch = "x86_64")]
+        SYS_arch_prctl,
+    ];
+
+    /// Installs the filter on all the threads of the process. Calls to system
+    /// calls not allowed fail with EPERM, rather than killing the process, so
+    /// that unexpected calls (e.g. from a new libc) surface as errors in logs.
+    pub(super) fn install_filter() -> io::Result<()> {
+        let mut filter = build_filter(ALLOWED);
+        let prog = sock_fprog {
+            len: filter.len() as c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        // Safety: the program points to the filter, which outlives the calls.
+        if unsafe { prctl(PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = SECCOMP_FILTER_FLAG_TSYNC;
+        match unsafe { syscall(SYS_seccomp, SECCOMP_SET_MODE_FILTER, flags, &prog as *const sock_fprog) } {
+            0 => Ok(()),
+            // With TSYNC, a positive result is the id of a thread which cannot be synced.
+            tid if tid > 0 => Err(io::Error::other(format!("cannot apply filter to thread {}", tid))),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    // Kill the process on other architectures, whose syscall numbers differ,
+    // then compare the syscall number with each of the allowed ones.
+    pub(super) fn build_filter(allowed: &[c_long]) -> Vec<sock_filter> {
+        let mut filter = vec![
+            stmt(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET | BPF_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        ];
+        for nr in allowed {
+            filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, *nr as u32, 0, 1));
+            filter.push(stmt(BPF_RET | BPF_K, SECCOMP_RET_ALLOW));
+        }
+        filter.push(stmt(
+            BPF_RET | BPF_K,
+            SECCOMP_RET_ERRNO | (EPERM as u32 & SECCOMP_RET_DATA),
+        ));
+        filter
+    }
+
+    fn stmt(code: u32, k: u32) -> sock_filter {
+        jump(code, k, 0, 0)
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+        sock_filter { code: code as u16, jt, jf, k }
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))
+))]
+mod seccomp {
+    pub(super) fn install_filter() -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn test_lookup() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("root").unwrap(), 0);
+        let err = lookup_user("ariadne-no-such-user").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(lookup_group("bad\0name").is_err());
+    }
+
+    // Install the filter on the calling thread only, not on the whole process.
+    // Threads spawned afterwards by the calling thread inherit it.
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn install_thread_filter() {
+        let mut filter = seccomp::build_filter(seccomp::ALLOWED);
+        let prog = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        unsafe {
+            assert_eq!(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0), 0);
+            assert_eq!(libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &prog), 0);
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn test_seccomp_filter() {
+        use std::{fs, net, thread};
+
+        let result = thread::spawn(|| {
+            install_thread_filter();
+            let socket = net::UdpSocket::bind("127.0.0.1:0").map(|_| ());
+            let dir = fs::create_dir(std::env::temp_dir().join("ariadne-seccomp-test"));
+            (socket, dir)
+        })
+        .join()
+        .unwrap();
+        assert!(result.0.is_ok());
+        assert_eq!(result.1.unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn test_seccomp_query_log_rotation() {
+        use crate::shared::net::{RequestSource, Transport};
+        use crate::shared::query_log::*;
+        use std::{fs, thread, time};
+
+        let dir = std::env::temp_dir().join(format!("ariadne-seccomp-rotation-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+        let entry = QueryEntry {
+            time: time::UNIX_EPOCH,
+            source: RequestSource {
+                addr: "127.0.0.1:5300".parse().unwrap(),
+                transport: Transport::Udp,
+            },
+            id: 1,
+            question: None,
+            response: None,
+            duration: time::Duration::ZERO,
+            resolution: None,
+        };
+        let params = QueryLogParams {
+            output: QueryLogOutput::File {
+                path: path.clone(),
+                chroot_path: None,
+                max_size: entry.to_json().len() as u64 + 1,
+                max_files: 2,
+            },
+            sample_rate: 1.0,
+            filter: QueryFilter::default(),
+            queue_size: 16,
+        };
+
+        // The writer thread is spawned after the filter is installed, and rotates the
+        // file twice: every file has a line only if the renames are allowed.
+        thread::spawn(move || {
+            install_thread_filter();
+            let query_log = QueryLog::start(params).unwrap();
+            for _ in 0..3 {
+                query_log.log(&entry);
+            }
+        })
+        .join()
+        .unwrap();
+        for name in ["queries.log", "queries.log.1", "queries.log.2"] {
+            assert_eq!(fs::read_to_string(dir.join(name)).unwrap().lines().count(), 1);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use crate::shared::dns;
+use crate::shared::net::{RequestSource, Resolution};
+use serde::Serialize;
+use std::time;
+
+/// A request served by a server, logged as a line of the query log. The
+/// response header is missing if the request was dropped, the question if
+/// the request could not be decoded. The resolution is reported by handlers
+/// serving requests from cache or querying other nameservers (the resolver).
+#[derive(Clone, Debug)]
+pub struct QueryEntry {
+    pub time: time::SystemTime,
+    pub source: RequestSource,
+    pub id: u16,
+    pub question: Option<(dns::Name, dns::RecordType)>,
+    pub response: Option<dns::Header>,
+    pub duration: time::Duration,
+    pub resolution: Option<Resolution>,
+}
+
+/// Filters on the logged entries: only the responses with the listed codes and
+/// the queries of the listed types are logged (all if the lists are empty),
+/// taking at least `min_duration`. Dropped requests have no response code.
+#[derive(Clone, Debug, Default)]
+pub struct QueryFilter {
+    pub rcodes: Vec<dns::RespCode>,
+    pub qtypes: Vec<dns::RecordType>,
+    pub min_duration: time::Duration,
+}
+
+impl QueryFilter {
+    pub fn matches(&self, entry: &QueryEntry) -> bool {
+        let rcode = entry.response.as_ref().map(|header| header.resp_code);
+        let qtype = entry.question.as_ref().map(|(_, qtype)| *qtype);
+        (self.rcodes.is_empty() || rcode.is_some_and(|rcode| self.rcodes.contains(&rcode)))
+            && (self.qtypes.is_empty() || qtype.is_some_and(|qtype| self.qtypes.contains(&qtype)))
+            && entry.duration >= self.min_duration
+    }
+}
+
+// The JSON object written for every entry.
+#[derive(Serialize)]
+struct Line {
+    timestamp: String,
+    client: String,
+    transport: &'static str,
+    id: u16,
+    qname: Option<String>,
+    qtype: Option<String>,
+    rcode: String,
+    flags: Vec<&'static str>,
+    answers: u16,
+    duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cache_hit: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_queries: Option<u32>,
+}
+
+impl QueryEntry {
+    /// Encodes the entry as a single-line JSON object, without the newline.
+    pub fn to_json(&self) -> String {
+        let header = self.response.as_ref();
+        let flags = header.map_or(vec![], |header| {
+            let flags = [
+                ("qr", header.query_resp),
+                ("aa", header.auth_answer),
+                ("tc", header.truncated),
+                ("rd", header.recursion_desired),
+                ("ra", header.recursion_available),
+            ];
+            flags.iter().filter(|(_, set)| *set).map(|(flag, _)| *flag).collect()
+        });
+        let line = Line {
+            timestamp: format_timestamp(self.time),
+            client: self.source.addr.to_string(),
+            transport: self.source.transport.name(),
+            id: self.id,
+            qname: self.question.as_ref().map(|(qname, _)| qname.to_string()),
+            qtype: self.question.as_ref().map(|(_, qtype)| format!("{:?}", qtype)),
+            rcode: header.map_or("DROPPED".to_string(), |header| {
+                format!("{:?}", header.resp_code).to_uppercase()
+            }),
+            flags,
+            answers: header.map_or(0, |header| header.answers_count),
+            duration_ms: (self.duration.as_micros() as f64) / 1000.0,
+            cache_hit: self.resolution.map(|resolution| resolution.cache_hit),
+            upstream_queries: self.resolution.map(|resolution| resolution.upstream_queries),
+        };
+        serde_json::to_string(&line).unwrap()
+    }
+}
+
+// Format the time as RFC 3339 in UTC with millisecond precision, converting
+// the days since the epoch to a civil date (see http://howardhinnant.github.io/date_algorithms.html).
+fn format_timestamp(t: time::SystemTime) -> String {
+    let since_epoch = t.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::Transport;
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(format_timestamp(time::UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let t = time::UNIX_EPOCH + time::Duration::from_millis(1_709_210_096_789);
+        assert_eq!(format_timestamp(t), "2024-02-29T12:34:56.789Z");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let header = dns::Header {
+            id: 7,
+            query_resp: true,
+            recursion_desired: true,
+            resp_code: dns::RespCode::NxDomain,
+            ..Default::default()
+        };
+        let mut entry = QueryEntry {
+            time: time::UNIX_EPOCH,
+            source: RequestSource {
+                addr: "10.0.0.1:5300".parse().unwrap(),
+                transport: Transport::Udp,
+            },
+            id: 7,
+            question: Some((dns::Name::from_string("example.com.").unwrap(), dns::RecordType::MX)),
+            response: Some(header),
+            duration: time::Duration::from_micros(1500),
+            resolution: Some(Resolution {
+                cache_hit: false,
+                upstream_queries: 3,
+            }),
+        };
+        assert_eq!(
+            entry.to_json(),
+            r#"{"timestamp":"1970-01-01T00:00:00.000Z","client":"10.0.0.1:5300","transport":"udp","id":7,"qname":"example.com.","qtype":"MX","rcode":"NXDOMAIN","flags":["qr","rd"],"answers":0,"duration_ms":1.5,"cache_hit":false,"upstream_queries":3}"#
+        );
+
+        // Dropped requests, without resolution info.
+        entry.response = None;
+        entry.resolution = None;
+        let json = entry.to_json();
+        assert!(json.contains(r#""rcode":"DROPPED","flags":[],"answers":0,"duration_ms":1.5}"#));
+    }
+
+    #[test]
+    fn test_filter() {
+        let entry = QueryEntry {
+            time: time::UNIX_EPOCH,
+            source: RequestSource {
+                addr: "10.0.0.1:5300".parse().unwrap(),
+                transport: Transport::Tcp,
+            },
+            id: 7,
+            question: Some((dns::Name::from_string("example.com.").unwrap(), dns::RecordType::A)),
+            response: Some(dns::Header {
+                resp_code: dns::RespCode::ServFail,
+                ..Default::default()
+            }),
+            duration: time::Duration::from_millis(20),
+            resolution: None,
+        };
+        assert!(QueryFilter::default().matches(&entry));
+        let filter = QueryFilter {
+            rcodes: vec![dns::RespCode::ServFail, dns::RespCode::NxDomain],
+            qtypes: vec![dns::RecordType::A],
+            min_duration: time::Duration::from_millis(10),
+        };
+        assert!(filter.matches(&entry));
+        let slow = QueryFilter {
+            min_duration: time::Duration::from_millis(50),
+            ..filter.clone()
+        };
+        assert!(!slow.matches(&entry));
+        let errors = QueryFilter {
+            rcodes: vec![dns::RespCode::Refused],
+            ..filter
+        };
+        assert!(!errors.matches(&entry));
+    }
+}
@@ -0,0 +1,143 @@
+use crate::shared::dns;
+use crate::shared::net::*;
+use crate::shared::query_log::entry::QueryEntry;
+use crate::shared::query_log::writer::QueryLog;
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::{io, time};
+
+/// A [DnsHandler] wrapping another one, logging the requests it serves to the
+/// [QueryLog]. Requests are passed through if `query_log` is None or if they
+/// are not sampled.
+pub struct QueryLogHandler<H> {
+    pub inner: Arc<H>,
+    pub query_log: Option<Arc<QueryLog>>,
+}
+
+impl<H: DnsHandler> DnsHandler for QueryLogHandler<H> {
+    fn handle_request<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) {
+        let (req, resp) = self.wrap(req, resp);
+        self.inner.handle_request(req, resp);
+    }
+}
+
+#[cfg(feature = "async")]
+impl<H: AsyncDnsHandler> AsyncDnsHandler for QueryLogHandler<H> {
+    fn handle_request_async<R, W>(self: &Arc<Self>, req: R, resp: W) -> impl Future<Output = ()> + Send
+    where
+        R: DnsRead + Send + 'static,
+        W: DnsWrite + Send + 'static,
+    {
+        let (req, resp) = self.wrap(req, resp);
+        let inner = Arc::clone(&self.inner);
+        async move { inner.handle_request_async(req, resp).await }
+    }
+}
+
+impl<H> QueryLogHandler<H> {
+    pub fn new(inner: H, query_log: Option<Arc<QueryLog>>) -> Self {
+        QueryLogHandler { inner: Arc::new(inner), query_log }
+    }
+
+    fn wrap<R: DnsRead, W: DnsWrite>(&self, req: R, resp: W) -> (LoggedRead<R>, LoggedWrite<W>) {
+        let query_log = self.query_log.as_ref().filter(|query_log| query_log.sample());
+        let entry = query_log.map(|_| {
+            Arc::new(Mutex::new(QueryEntry {
+                time: time::SystemTime::now(),
+                source: req.source(),
+                id: 0,
+                question: None,
+                response: None,
+                duration: time::Duration::ZERO,
+                resolution: None,
+            }))
+        });
+        let req = LoggedRead { inner: req, entry: entry.clone() };
+        let resp = LoggedWrite {
+            inner: Some(resp),
+            query_log: query_log.cloned(),
+            entry,
+            start: time::Instant::now(),
+        };
+        (req, resp)
+    }
+}
+
+// Record the id and the question when the request is read.
+struct LoggedRead<R> {
+    inner: R,
+    entry: Option<Arc<Mutex<QueryEntry>>>,
+}
+
+impl<R: DnsRead> DnsRead for LoggedRead<R> {
+    fn read(self) -> DnsReadResult {
+        let request = self.inner.read();
+        if let Some(entry) = &self.entry {
+            let mut entry = entry.lock().unwrap();
+            match &request {
+                DnsReadResult::FullMessage(msg) => {
+                    entry.id = msg.id();
+                    entry.question = msg.questions.first().map(|q| (q.node.clone(), q.record_type));
+                }
+                DnsReadResult::HeaderOnly(header, _) => entry.id = header.id,
+                _ => {}
+            }
+        }
+        request
+    }
+
+    fn source(&self) -> RequestSource {
+        self.inner.source()
+    }
+}
+
+// Log the entry when the response is sent. Requests left unanswered
+// by the handler are logged as dropped when the writer is dropped.
+struct LoggedWrite<W> {
+    inner: Option<W>,
+    query_log: Option<Arc<QueryLog>>,
+    entry: Option<Arc<Mutex<QueryEntry>>>,
+    start: time::Instant,
+}
+
+impl<W: DnsWrite> DnsWrite for LoggedWrite<W> {
+    fn reply(mut self, response: dns::Message) -> io::Result<()> {
+        self.log(Some(&response));
+        self.inner.take().unwrap().reply(response)
+    }
+
+    fn annotate(&mut self, resolution: Resolution) {
+        if let Some(entry) = &self.entry {
+            entry.lock().unwrap().resolution = Some(resolution);
+        }
+        if let Some(inner) = self.inner.as_mut() {
+            inner.annotate(resolution);
+        }
+    }
+}
+
+impl<W> LoggedWrite<W> {
+    fn log(&mut self, response: Option<&dns::Message>) {
+        let (Some(query_log), Some(entry)) = (&self.query_log, self.entry.take()) else {
+            return;
+        };
+        let mut entry = entry.lock().unwrap();
+        entry.duration = self.start.elapsed();
+        if let Some(response) = response {
+            entry.response = Some(response.header.clone());
+            if entry.question.is_none() {
+                entry.question = response.questions.first().map(|q| (q.node.clone(), q.record_type));
+            }
+        }
+        query_log.log(&entry);
+    }
+}
+
+impl<W> Drop for LoggedWrite<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            self.log(None);
+        }
+    }
+}
@@ -0,0 +1,7 @@
+mod entry;
+mod handler;
+mod writer;
+
+pub use entry::*;
+pub use handler::*;
+pub use writer::*;
@@ -0,0 +1,286 @@
+use crate::shared::query_log::entry::*;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::{fmt, fs, io, path, thread};
+
+/// Where the query log is written: the standard output or a file. Files are
+/// rotated when they grow over `max_size` bytes, keeping `max_files` rotated
+/// files, named after the file with the `.1` (the newest) to `.N` suffixes.
+/// If the root directory of the process is changed after starting the query
+/// log (see [drop_privileges](crate::shared::privileges::drop_privileges)),
+/// `chroot_path` is the path of the file inside the new root directory,
+/// used to rotate it.
+#[derive(Clone, Debug)]
+pub enum QueryLogOutput {
+    Stdout,
+    File {
+        path: path::PathBuf,
+        chroot_path: Option<path::PathBuf>,
+        max_size: u64,
+        max_files: usize,
+    },
+}
+
+/// The parameters passed to [QueryLog::start]. Requests are sampled with the
+/// `sample_rate` probability (1 logs all of them) and filtered by `filter`.
+/// At most `queue_size` lines wait for the writer thread, further ones are dropped.
+#[derive(Clone, Debug)]
+pub struct QueryLogParams {
+    pub output: QueryLogOutput,
+    pub sample_rate: f64,
+    pub filter: QueryFilter,
+    pub queue_size: usize,
+}
+
+/// Logs the served requests, one JSON object per line. The query log is
+/// independent of the diagnostic log and of its level. Lines are written by a
+/// background thread, so logging never blocks: if the writer falls behind
+/// lines are dropped. The writer thread terminates when the [QueryLog] is dropped.
+pub struct QueryLog {
+    sample_rate: f64,
+    filter: QueryFilter,
+    sender: Option<mpsc::SyncSender<String>>,
+    writer: Option<thread::JoinHandle<()>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl QueryLog {
+    /// Starts the writer thread. Files are opened (in append mode) before
+    /// returning, so that errors are reported immediately.
+    pub fn start(params: QueryLogParams) -> io::Result<QueryLog> {
+        let sink = match params.output {
+            QueryLogOutput::Stdout => Sink::Stdout(BufWriter::new(io::stdout())),
+            QueryLogOutput::File {
+                path,
+                chroot_path,
+                max_size,
+                max_files,
+            } => Sink::File(RotatingFile::open(path, chroot_path, max_size, max_files)?),
+        };
+        let (sender, receiver) = mpsc::sync_channel(params.queue_size);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let writer_dropped = Arc::clone(&dropped);
+        let writer = thread::spawn(move || write_lines(receiver, sink, &writer_dropped));
+        Ok(QueryLog {
+            sample_rate: params.sample_rate,
+            filter: params.filter,
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped,
+        })
+    }
+
+    /// Decides if a new request is logged, according to the sample rate.
+    /// Callers can skip collecting the entries of the requests not sampled.
+    pub fn sample(&self) -> bool {
+        self.sample_rate >= 1.0 || rand::random::<f64>() < self.sample_rate
+    }
+
+    /// Queues the entry for the writer thread, if it matches the filter.
+    pub fn log(&self, entry: &QueryEntry) {
+        if !self.filter.matches(entry) {
+            return;
+        }
+        let sender = self.sender.as_ref().unwrap();
+        if sender.try_send(entry.to_json()).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The lines dropped because the queue was full or the output failed.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for QueryLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueryLog")
+            .field("sample_rate", &self.sample_rate)
+            .field("filter", &self.filter)
+            .field("dropped", &self.dropped())
+            .finish()
+    }
+}
+
+impl Drop for QueryLog {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+enum Sink {
+    Stdout(BufWriter<io::Stdout>),
+    File(RotatingFile),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stdout(out) => writeln!(out, "{}", line),
+            Sink::File(file) => file.write_line(line),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Stdout(out) => out.flush(),
+            Sink::File(file) => file.out.flush(),
+        }
+    }
+}
+
+// A file rotated when its size would exceed the maximum one. The file is
+// opened with its path, and rotated with the path inside the root directory.
+struct RotatingFile {
+    path: path::PathBuf,
+    max_size: u64,
+    max_files: usize,
+    out: BufWriter<fs::File>,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(
+        path: path::PathBuf,
+        chroot_path: Option<path::PathBuf>,
+        max_size: u64,
+        max_files: usize,
+    ) -> io::Result<Self> {
+        let file = fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: chroot_path.unwrap_or(path),
+            max_size,
+            max_files,
+            out: BufWriter::new(file),
+            size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.out, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    // Shift the rotated files by one, discarding the oldest one,
+    // then move the current file to `.1` and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            path::PathBuf::from(name)
+        };
+        for n in (1..self.max_files).rev() {
+            match fs::rename(rotated(n), rotated(n + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated(1))?;
+        let file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.out = BufWriter::new(file);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+// Write the queued lines, flushing the output when the queue is empty. On
+// errors the output is abandoned and all the following lines are dropped.
+fn write_lines(receiver: mpsc::Receiver<String>, sink: Sink, dropped: &AtomicU64) {
+    let mut sink = Some(sink);
+    loop {
+        let line = match receiver.try_recv() {
+            Ok(line) => line,
+            Err(mpsc::TryRecvError::Empty) => {
+                if let Some(Err(err)) = sink.as_mut().map(Sink::flush) {
+                    log::error!("Writing query log: {}", err);
+                    sink = None;
+                }
+                match receiver.recv() {
+                    Ok(line) => line,
+                    Err(_) => break,
+                }
+            }
+            Err(mpsc::TryRecvError::Disconnected) => break,
+        };
+
+        let written = match sink.as_mut() {
+            Some(sink) => sink.write_line(&line),
+            None => Err(io::ErrorKind::NotConnected.into()),
+        };
+        if let Err(err) = written {
+            dropped.fetch_add(1, Ordering::Relaxed);
+            if sink.take().is_some() {
+                log::error!("Writing query log: {}", err);
+            }
+        }
+    }
+
+    if let Some(Err(err)) = sink.as_mut().map(Sink::flush) {
+        log::error!("Writing query log: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::net::{RequestSource, Transport};
+    use std::time;
+
+    #[test]
+    fn test_rotate_file() {
+        let dir = std::env::temp_dir().join(format!("ariadne-query-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+        let entry = QueryEntry {
+            time: time::UNIX_EPOCH,
+            source: RequestSource {
+                addr: "127.0.0.1:5300".parse().unwrap(),
+                transport: Transport::Udp,
+            },
+            id: 1,
+            question: None,
+            response: None,
+            duration: time::Duration::ZERO,
+            resolution: None,
+        };
+        let line_len = entry.to_json().len() as u64 + 1;
+        let params = QueryLogParams {
+            output: QueryLogOutput::File {
+                path: path.clone(),
+                chroot_path: None,
+                max_size: 2 * line_len,
+                max_files: 2,
+            },
+            sample_rate: 1.0,
+            filter: QueryFilter::default(),
+            queue_size: 16,
+        };
+
+        // Seven lines: two in every rotated file, the oldest two are discarded.
+        let query_log = QueryLog::start(params).unwrap();
+        assert!(query_log.sample());
+        for _ in 0..7 {
+            query_log.log(&entry);
+        }
+        drop(query_log);
+
+        let lines = |path: path::PathBuf| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(path.clone()), 1);
+        assert_eq!(lines(dir.join("queries.log.1")), 2);
+        assert_eq!(lines(dir.join("queries.log.2")), 2);
+        assert!(!dir.join("queries.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,48 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
-use std::thread;
+use std::{mem, thread, time};
+
+/// Limits of the queue of jobs waiting for a free thread of a [ThreadPool]. At
+/// most `max_depth` jobs can be queued with [ThreadPool::try_reserve], while jobs
+/// waiting for longer than `max_age` (if set) are discarded without running them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub max_depth: usize,
+    pub max_age: Option<time::Duration>,
+}
+
+impl QueueLimits {
+    pub const UNBOUNDED: QueueLimits = QueueLimits {
+        max_depth: usize::MAX,
+        max_age: None,
+    };
+}
+
+/// Counters of the queue of a [ThreadPool]. They are shared, so
+/// they can be read while the pool is running (e.g. for alerting).
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    depth: AtomicUsize,
+    shed_full: AtomicU64,
+    shed_expired: AtomicU64,
+}
+
+impl QueueStats {
+    /// The jobs currently queued, waiting for a free thread.
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// The jobs rejected because the queue was full.
+    pub fn shed_full(&self) -> u64 {
+        self.shed_full.load(Ordering::Relaxed)
+    }
+
+    /// The jobs discarded because they were queued for too long.
+    pub fn shed_expired(&self) -> u64 {
+        self.shed_expired.load(Ordering::Relaxed)
+    }
+}
 
 /// Represents and controls a pool of OS threads, which can receive jobs (`FnOnce`
 /// pointers) to be executed. Threads are spawned when the pool is created via the
@@ -8,6 +51,8 @@ pub struct ThreadPool {
     label: String,
     workers: Vec<Worker>,
     sender: mpsc::Sender<WorkerMessage>,
+    limits: QueueLimits,
+    stats: Arc<QueueStats>,
 }
 
 impl ThreadPool {
@@ -15,26 +60,69 @@ impl ThreadPool {
     /// before returning from this functions. The `size` parameters controls
     /// how many threads are spawned and must be > 0.
     pub fn new(size: usize, label: &str) -> ThreadPool {
+        ThreadPool::with_queue(size, label, QueueLimits::UNBOUNDED, Arc::default())
+    }
+
+    /// Creates a new [`ThreadPool`] as [ThreadPool::new], with the passed
+    /// queue limits. The queue counters are updated into `stats`.
+    pub fn with_queue(size: usize, label: &str, limits: QueueLimits, stats: Arc<QueueStats>) -> ThreadPool {
         assert!(size > 0);
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
         let mut workers = Vec::with_capacity(size);
         for _ in 0..size {
             let receiver_clone = Arc::clone(&receiver);
-            let worker = Worker::new(receiver_clone);
+            let worker = Worker::new(receiver_clone, limits.max_age, Arc::clone(&stats));
             workers.push(worker);
         }
         ThreadPool {
             label: label.to_string(),
             workers,
             sender,
+            limits,
+            stats,
         }
     }
 
     /// Provide a job to be sent to one of any threads of the [`ThreadPool`].
     /// Jobs are scheduled in a queue and executed as soon a thread is free.
+    /// The job is queued even if the queue is full, see [ThreadPool::try_reserve].
     pub fn execute<F: FnOnce() + Send + 'static>(&self, function: F) {
-        let job = WorkerMessage::Job(Box::new(function));
+        self.stats.depth.fetch_add(1, Ordering::Relaxed);
+        self.send(function);
+    }
+
+    /// Reserves a place in the queue for a job, unless the queue is full. In
+    /// that case None is returned and the rejected job is accounted as shed.
+    pub fn try_reserve(&self) -> Option<QueueSlot<'_>> {
+        let max_depth = self.limits.max_depth;
+        let reserved = self
+            .stats
+            .depth
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |depth| {
+                (depth < max_depth).then_some(depth + 1)
+            });
+        match reserved {
+            Ok(_) => Some(QueueSlot { pool: self }),
+            Err(_) => {
+                self.stats.shed_full.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    /// Reports if the queue is full, so new jobs would be rejected.
+    pub fn is_full(&self) -> bool {
+        self.stats.depth() >= self.limits.max_depth
+    }
+
+    /// The counters of the queue of the pool.
+    pub fn stats(&self) -> &Arc<QueueStats> {
+        &self.stats
+    }
+
+    fn send<F: FnOnce() + Send + 'static>(&self, function: F) {
+        let job = WorkerMessage::Job(Box::new(function), time::Instant::now());
         self.sender.send(job).unwrap();
     }
 }
@@ -56,6 +144,27 @@ impl Drop for ThreadPool {
     }
 }
 
+/// A place in the queue of a [ThreadPool] reserved with [ThreadPool::try_reserve].
+/// The place is released if the slot is dropped without executing a job.
+pub struct QueueSlot<'a> {
+    pool: &'a ThreadPool,
+}
+
+impl QueueSlot<'_> {
+    /// Sends the job to the pool, using the reserved place.
+    pub fn execute<F: FnOnce() + Send + 'static>(self, function: F) {
+        let pool = self.pool;
+        mem::forget(self);
+        pool.send(function);
+    }
+}
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.pool.stats.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 /// Represents a thread of a [`ThreadPool`]. It dequeue new jobs from
 /// the receiving end of the dedicated channel. The spawned thread can
 /// be stopped sending the [`WorkerMessage::Stop`] message to it.
@@ -64,24 +173,89 @@ struct Worker {
 }
 
 enum WorkerMessage {
-    Job(Box<dyn FnOnce() + Send + 'static>),
+    Job(Box<dyn FnOnce() + Send + 'static>, time::Instant),
     Stop,
 }
 
 impl Worker {
-    /// Spawn an OS thread and returns a [`Worker`] containing the
-    /// thread handle. The thread loops receiving and executing jobs.
-    fn new(receiver: Arc<Mutex<mpsc::Receiver<WorkerMessage>>>) -> Worker {
+    /// Spawn an OS thread and returns a [`Worker`] containing the thread
+    /// handle. The thread loops receiving and executing jobs, discarding
+    /// the ones queued for longer than `max_age`.
+    fn new(
+        receiver: Arc<Mutex<mpsc::Receiver<WorkerMessage>>>,
+        max_age: Option<time::Duration>,
+        stats: Arc<QueueStats>,
+    ) -> Worker {
         let thread = thread::spawn(move || loop {
             let receiver_guard = receiver.lock().unwrap();
             let worker_message = receiver_guard.recv().unwrap();
             drop(receiver_guard);
             match worker_message {
                 WorkerMessage::Stop => return,
-                WorkerMessage::Job(job_fn) => job_fn(),
+                WorkerMessage::Job(job_fn, queued_at) => {
+                    stats.depth.fetch_sub(1, Ordering::Relaxed);
+                    if max_age.is_some_and(|max_age| queued_at.elapsed() > max_age) {
+                        stats.shed_expired.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    job_fn()
+                }
             }
         });
 
         Worker { thread: Some(thread) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+
+    #[test]
+    fn test_bounded_queue() {
+        let limits = QueueLimits {
+            max_depth: 2,
+            max_age: Some(time::Duration::from_millis(100)),
+        };
+        let pool = ThreadPool::with_queue(1, "test", limits, Arc::default());
+
+        // Keep the only thread busy, then fill the queue.
+        let (started_tx, started_rx) = mpsc::channel();
+        pool.execute(move || {
+            started_tx.send(()).unwrap();
+            thread::sleep(time::Duration::from_millis(300));
+        });
+        started_rx.recv().unwrap();
+        let ran = Arc::new(AtomicBool::new(false));
+        for _ in 0..2 {
+            let ran = Arc::clone(&ran);
+            pool.try_reserve()
+                .unwrap()
+                .execute(move || ran.store(true, Ordering::SeqCst));
+        }
+        assert!(pool.is_full());
+        assert!(pool.try_reserve().is_none());
+        assert_eq!(pool.stats().depth(), 2);
+        assert_eq!(pool.stats().shed_full(), 1);
+
+        // The queued jobs expire while the thread is busy.
+        let stats = Arc::clone(pool.stats());
+        drop(pool);
+        assert!(!ran.load(Ordering::SeqCst));
+        assert_eq!(stats.depth(), 0);
+        assert_eq!(stats.shed_expired(), 2);
+    }
+
+    #[test]
+    fn test_queue_slot_released() {
+        let limits = QueueLimits { max_depth: 1, max_age: None };
+        let pool = ThreadPool::with_queue(1, "test", limits, Arc::default());
+        drop(pool.try_reserve().unwrap());
+        assert_eq!(pool.stats().depth(), 0);
+
+        let (tx, rx) = mpsc::channel();
+        pool.try_reserve().unwrap().execute(move || tx.send(()).unwrap());
+        rx.recv_timeout(time::Duration::from_secs(1)).unwrap();
+    }
+}
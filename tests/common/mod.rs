@@ -0,0 +1,67 @@
+//! Helpers shared by the tests running the binaries of the crate.
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::{env, fs, net, path, thread, time};
+
+/// The `nameserver` binary, serving the configuration in `conf_path`. The
+/// process is killed when dropped, while [Nameserver::stop] returns its logs.
+pub struct Nameserver {
+    pub child: Child,
+    pub conf_path: path::PathBuf,
+}
+
+impl Nameserver {
+    /// Writes the configuration to a temporary file named after `name`, starts the
+    /// nameserver and waits for it to listen on the first TCP listener of `conf`.
+    pub fn start(name: &str, conf: &Value) -> Nameserver {
+        let conf_path = env::temp_dir().join(format!("ariadne-{}-{}.conf.json", name, std::process::id()));
+        fs::write(&conf_path, conf.to_string()).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_nameserver"))
+            .arg(&conf_path)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let nameserver = Nameserver { child, conf_path };
+
+        let listener = &conf["tcp_server"]["listeners"][0];
+        let address = listener["address"].as_str().unwrap();
+        let port = listener["port"].as_u64().unwrap() as u16;
+        for _ in 0..50 {
+            if net::TcpStream::connect((address, port)).is_ok() {
+                return nameserver;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        panic!("nameserver not listening on {}:{}", address, port);
+    }
+
+    /// Kills the nameserver and returns its logs.
+    pub fn stop(mut self) -> String {
+        let _ = self.child.kill();
+        let mut logs = String::new();
+        self.child.stdout.take().unwrap().read_to_string(&mut logs).unwrap();
+        logs
+    }
+}
+
+impl Drop for Nameserver {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.conf_path);
+    }
+}
+
+/// The default nameserver configuration, serving the example zone in `assets/zones`,
+/// with the UDP and TCP servers listening on the passed local port.
+pub fn nameserver_conf(port: u16) -> Value {
+    let conf = fs::read_to_string("conf/nameserver.conf.json").unwrap();
+    let mut conf: Value = serde_json::from_str(&conf).unwrap();
+    conf["udp_server"]["listeners"][0]["port"] = port.into();
+    conf["tcp_server"]["listeners"][0]["port"] = port.into();
+    conf
+}
@@ -0,0 +1,71 @@
+//! End to end tests of the control server of the `nameserver` binary, driven by `ariadne-ctl`.
+
+mod common;
+
+use common::*;
+use std::process::Command;
+use std::{env, fs};
+
+const PORT: u16 = 48753;
+
+// Run the command through ariadne-ctl, returning its output or error.
+fn ctl(socket: &str, args: &[&str]) -> Result<String, String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_ariadne-ctl"))
+        .args(["-s", socket])
+        .args(args)
+        .output()
+        .unwrap();
+    match output.status.success() {
+        true => Ok(String::from_utf8(output.stdout).unwrap()),
+        false => Err(String::from_utf8(output.stderr).unwrap()),
+    }
+}
+
+#[test]
+fn test_control() {
+    let dir = env::temp_dir().join(format!("ariadne-control-e2e-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("nameserver.sock").to_string_lossy().into_owned();
+
+    // Serve a copy of the example zone, to change it.
+    let zone_file = dir.join("example.com.");
+    fs::copy("assets/zones/example.com./example.com.", &zone_file).unwrap();
+    let mut conf = nameserver_conf(PORT);
+    conf["zone"]["file"] = zone_file.to_string_lossy().into();
+    conf["control"] = serde_json::json!({ "path": socket });
+    let nameserver = Nameserver::start("control", &conf);
+
+    let status = ctl(&socket, &["status"]).unwrap();
+    assert!(status.contains("log level: INFO\n"));
+    assert!(status.contains("zone example.com.: serial 20, "));
+    assert!(status.contains("sub zone a.example.com.: "));
+
+    assert_eq!(
+        ctl(&socket, &["log-level", "debug"]).unwrap(),
+        "Log level set to DEBUG.\n"
+    );
+    assert_eq!(ctl(&socket, &["log-level"]).unwrap(), "DEBUG\n");
+    assert!(ctl(&socket, &["log-level", "loud"])
+        .unwrap_err()
+        .contains("invalid log level 'loud'"));
+    assert!(ctl(&socket, &["flush", "example.com"])
+        .unwrap_err()
+        .contains("invalid command"));
+
+    // Zones are reloaded from the files of the current configuration,
+    // the previous ones are kept if the files are not valid.
+    let zone = fs::read_to_string(&zone_file).unwrap();
+    fs::write(&zone_file, zone.replace("20       ;SERIAL", "21       ;SERIAL")).unwrap();
+    assert!(ctl(&socket, &["reload-zone"])
+        .unwrap()
+        .contains("zone example.com.: serial 21, "));
+    fs::write(&zone_file, "invalid").unwrap();
+    assert!(ctl(&socket, &["reload-zone"]).is_err());
+    assert!(ctl(&socket, &["status"])
+        .unwrap()
+        .contains("zone example.com.: serial 21, "));
+
+    let logs = nameserver.stop();
+    assert!(logs.contains("Control command from user"));
+    fs::remove_dir_all(&dir).unwrap();
+}
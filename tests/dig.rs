@@ -0,0 +1,75 @@
+//! End to end tests of `ariadne-dig` querying the `nameserver` binary, which
+//! serves the example zone of the default configuration on a local port.
+
+mod common;
+
+use common::*;
+use serde_json::Value;
+use std::process::{Command, Output};
+
+const PORT: u16 = 48453;
+
+fn dig(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ariadne-dig"))
+        .args(["@127.0.0.1", "-p", &PORT.to_string(), "+tries=1", "+timeout=2"])
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn dig_json(args: &[&str]) -> Value {
+    let output = dig(&[args, &["+json"]].concat());
+    assert!(output.status.success());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+#[test]
+fn test_dig() {
+    let _nameserver = Nameserver::start("dig", &nameserver_conf(PORT));
+
+    // Answers, over both transports.
+    for (option, transport) in [("+notcp", "udp"), ("+tcp", "tcp")] {
+        let output = dig_json(&["portal.example.com", "A", option]);
+        assert_eq!(output["transport"], transport);
+        let message = &output["message"];
+        assert_eq!(message["status"], "NOERROR");
+        assert_eq!(message["flags"], serde_json::json!(["qr", "aa", "rd"]));
+        assert_eq!(message["questions"][0]["name"], "portal.example.com.");
+        let answers: Vec<&str> = message["answers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|record| record["data"].as_str().unwrap())
+            .collect();
+        assert_eq!(answers, ["194.45.65.31", "194.45.65.32"]);
+        assert_eq!(message["edns"]["udp_payload_size"], 1232);
+    }
+
+    // Negative answers, without EDNS and recursion.
+    let output = dig_json(&["example.com", "+noedns", "+norecurse"]);
+    let message = &output["message"];
+    assert_eq!(message["status"], "NXDOMAIN");
+    assert_eq!(message["flags"], serde_json::json!(["qr", "aa"]));
+    assert_eq!(message["authorities"][0]["type"], "SOA");
+    assert!(message["edns"].is_null());
+
+    // Text output.
+    let output = dig(&["metrics.example.com"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("status: NOERROR"));
+    assert!(stdout.contains("metrics.example.com.\t10000\tIN\tA\t140.0.0.1"));
+
+    // Usage errors.
+    let output = dig(&["-t", "FOO"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_dig_no_reply() {
+    let output = Command::new(env!("CARGO_BIN_EXE_ariadne-dig"))
+        .args(["@127.0.0.1", "-p", "48454", "+tries=1", "+timeout=1", "example.com"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(9));
+}
@@ -0,0 +1,336 @@
+//! Tests of the nameserver handler serving the example zone in `assets/zones`,
+//! through the in-memory transport. The zone files are read relative to the
+//! root of the crate, as done by the default configuration.
+
+use ariadne_dns::nameserver::*;
+use ariadne_dns::shared::dns;
+use ariadne_dns::shared::layers::*;
+use ariadne_dns::shared::net::*;
+
+fn name(name: &str) -> dns::Name {
+    dns::Name::from_string(name).unwrap()
+}
+
+fn example_zone() -> ManagedZone {
+    let sub_zone = |zone: &str, starting_ttl, min_ttl| SubParsingParams {
+        file_path: format!("assets/zones/example.com./{}", zone),
+        zone: name(zone),
+        starting_ttl,
+        min_ttl,
+    };
+    parse_zone_files(ParsingParams {
+        file_path: "assets/zones/example.com./example.com.".to_string(),
+        zone: name("example.com."),
+        starting_ttl: 1000,
+        sub_zones: vec![
+            sub_zone("a.example.com.", 500, 60),
+            sub_zone("b.example.com.", 1000, 150),
+        ],
+    })
+    .unwrap()
+}
+
+fn handler(acls: NameserverAcls) -> NameserverHandler {
+    NameserverHandler {
+        zones: example_zone(),
+        rrl: None,
+        acls,
+        layers: LayerStack::default(),
+        metrics: NameserverMetrics::default(),
+    }
+}
+
+fn addresses(records: &[dns::Record]) -> Vec<[u8; 4]> {
+    let mut addresses: Vec<_> = records.iter().map(|record| *record.a_data()).collect();
+    addresses.sort();
+    addresses
+}
+
+#[test]
+fn test_answers() {
+    let handler = handler(NameserverAcls::default());
+    let client = TestClient::default();
+    let request = client.build_query(&name("portal.example.com."), dns::RecordType::A);
+    let response = client.send(&handler, &request).unwrap();
+    assert_eq!(response.id(), request.id());
+    assert!(response.header.query_resp);
+    assert!(response.header.auth_answer);
+    assert!(response.header.recursion_desired);
+    assert!(!response.header.recursion_available);
+    assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    assert_eq!(response.questions, request.questions);
+    assert_eq!(addresses(&response.answers), [[194, 45, 65, 31], [194, 45, 65, 32]]);
+    assert!(response.authorities.is_empty() && response.additionals.is_empty());
+
+    // Records of the included files, with their own origin and ttl.
+    let response = client.query(&handler, &name("metrics.example.com."), dns::RecordType::A);
+    let answers = response.unwrap().answers;
+    assert_eq!(addresses(&answers), [[140, 0, 0, 1], [140, 0, 0, 2]]);
+    let mut ttls: Vec<_> = answers.iter().map(|record| *record.ttl()).collect();
+    ttls.sort();
+    assert_eq!(ttls, [10000, 15000]);
+    let response = client.query(&handler, &name("db3.example.com."), dns::RecordType::A);
+    assert_eq!(addresses(&response.unwrap().answers), [[130, 0, 0, 5], [130, 0, 0, 6]]);
+
+    // Other record types.
+    let response = client.query(&handler, &name("example.com."), dns::RecordType::NS);
+    let ns_names: Vec<_> = response.unwrap().answers.iter().map(|r| r.ns_data().clone()).collect();
+    assert_eq!(ns_names.len(), 2);
+    assert!(ns_names.contains(&name("ns1.example.it.")));
+    for (node, kind) in [
+        ("mx-test.example.com.", dns::RecordType::MX),
+        ("txt-test.example.com.", dns::RecordType::TXT),
+        ("ptr-test.example.com.", dns::RecordType::PTR),
+        ("hinfo-test.example.com.", dns::RecordType::HINFO),
+        ("example.com.", dns::RecordType::SOA),
+    ] {
+        let response = client.query(&handler, &name(node), kind).unwrap();
+        assert_eq!(response.answers.len(), 1, "{} {:?}", node, kind);
+        assert_eq!(response.answers[0].record_type(), kind);
+    }
+}
+
+#[test]
+fn test_cname_answers() {
+    let handler = handler(NameserverAcls::default());
+    let client = TestClient::default();
+    for node in ["dashboard.example.com.", "www.api.example.com.", "db.example.com."] {
+        let response = client.query(&handler, &name(node), dns::RecordType::A).unwrap();
+        assert!(response.header.auth_answer);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].record_type(), dns::RecordType::CNAME);
+    }
+    let response = client.query(&handler, &name("db.example.com."), dns::RecordType::A);
+    assert_eq!(response.unwrap().answers[0].cname_data(), &name("db1.example.com."));
+}
+
+#[test]
+fn test_not_found() {
+    let handler = handler(NameserverAcls::default());
+    let client = TestClient::default();
+    for node in ["missing.example.com.", "example.com."] {
+        let response = client.query(&handler, &name(node), dns::RecordType::A).unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::NxDomain);
+        assert!(response.header.auth_answer);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.authorities.len(), 1);
+        assert_eq!(response.authorities[0].record_type(), dns::RecordType::SOA);
+        assert_eq!(response.authorities[0].node(), &name("example.com."));
+    }
+
+    // Names outside of the zone are refused.
+    let response = client.query(&handler, &name("example.org."), dns::RecordType::A);
+    let response = response.unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::Refused);
+    assert!(response.answers.is_empty() && response.authorities.is_empty());
+}
+
+#[test]
+fn test_referrals() {
+    let handler = handler(NameserverAcls::default());
+    let client = TestClient::default();
+    let response = client.query(&handler, &name("www.a.example.com."), dns::RecordType::A);
+    let response = response.unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    assert!(!response.header.auth_answer);
+    assert!(response.answers.is_empty());
+    let ns_names: Vec<_> = response.authorities.iter().map(|r| r.ns_data().clone()).collect();
+    assert_eq!(ns_names.len(), 4);
+    for ns_name in [
+        "ns.external.com.",
+        "ns3.example.com.",
+        "ns.a.example.com.",
+        "ns-a.b.example.com.",
+    ] {
+        assert!(ns_names.contains(&name(ns_name)), "{}", ns_name);
+    }
+
+    // Glue records only for the nameservers in the sub zones.
+    let mut glue: Vec<_> = response
+        .additionals
+        .iter()
+        .map(|record| (record.node().clone(), *record.a_data()))
+        .collect();
+    glue.sort_by_key(|(_, address)| *address);
+    assert_eq!(
+        glue,
+        [
+            (name("ns.a.example.com."), [145, 0, 0, 1]),
+            (name("ns-a.b.example.com."), [145, 6, 7, 8]),
+        ]
+    );
+
+    let response = client.query(&handler, &name("b.example.com."), dns::RecordType::NS);
+    let response = response.unwrap();
+    assert!(!response.header.auth_answer);
+    assert_eq!(response.authorities.len(), 1);
+    assert_eq!(addresses(&response.additionals), [[145, 0, 0, 2]]);
+}
+
+#[test]
+fn test_edns() {
+    let handler = handler(NameserverAcls::default());
+    let client = TestClient {
+        edns: Some(dns::Edns {
+            udp_payload_size: 4096,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let response = client.query(&handler, &name("portal.example.com."), dns::RecordType::A);
+    let edns = response.unwrap().edns.unwrap();
+    assert_eq!(edns.version, 0);
+    let response = client.query(&handler, &name("missing.example.com."), dns::RecordType::A);
+    assert!(response.unwrap().edns.is_some());
+
+    let response = TestClient::default().query(&handler, &name("portal.example.com."), dns::RecordType::A);
+    assert!(response.unwrap().edns.is_none());
+}
+
+#[test]
+fn test_malformed_requests() {
+    let handler = handler(NameserverAcls::default());
+    let client = TestClient::default();
+    let mut request = client.build_query(&name("portal.example.com."), dns::RecordType::A);
+    request.header.query_resp = true;
+    let response = client.send(&handler, &request).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::FormErr);
+    assert_eq!(response.questions, request.questions);
+
+    // Only the header can be decoded.
+    let mut bytes = client
+        .build_query(&name("portal.example.com."), dns::RecordType::A)
+        .encode_to_bytes()
+        .unwrap();
+    bytes.truncate(bytes.len() - 2);
+    let response = client.send_bytes(&handler, bytes).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::FormErr);
+    assert!(response.questions.is_empty());
+
+    // Nothing can be decoded, the request is dropped.
+    assert!(client.send_bytes(&handler, vec![0, 1, 2]).is_none());
+}
+
+// Replace the type of the question (the last four bytes are its type and class).
+fn with_type(request: &dns::Message, kind: u16) -> Vec<u8> {
+    let mut bytes = request.encode_to_bytes().unwrap();
+    let at = bytes.len() - 4;
+    bytes[at..at + 2].copy_from_slice(&kind.to_be_bytes());
+    bytes
+}
+
+#[test]
+fn test_unsupported_requests() {
+    let client = TestClient::default();
+    let request = client.build_query(&name("example.com."), dns::RecordType::A);
+    let handler = handler(NameserverAcls::default());
+    let response = client.send_bytes(&handler, with_type(&request, 252)).unwrap();
+    assert_eq!(response.id(), request.id());
+    assert_eq!(response.header.resp_code, dns::RespCode::NotImp);
+
+    // Transfers are checked against their own acl.
+    let handler = self::handler(NameserverAcls {
+        transfer: Some(Acl::default()),
+        ..Default::default()
+    });
+    let response = client.send_bytes(&handler, with_type(&request, 252)).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::Refused);
+    let response = client.send(&handler, &request).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NxDomain);
+}
+
+#[test]
+fn test_query_acl() {
+    let acl = Acl {
+        allow: vec!["10.0.0.0/8".parse().unwrap()],
+        deny: vec!["10.0.0.1/32".parse().unwrap()],
+    };
+    let handler = handler(NameserverAcls {
+        query: Some(acl.clone()),
+        ..Default::default()
+    });
+    let node = name("portal.example.com.");
+    let allowed = TestClient::new("10.0.0.2:5300".parse().unwrap(), Transport::Tcp);
+    let response = allowed.query(&handler, &node, dns::RecordType::A).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    for addr in ["10.0.0.1:5300", "127.0.0.1:5300"] {
+        let denied = TestClient::new(addr.parse().unwrap(), Transport::Udp);
+        let response = denied.query(&handler, &node, dns::RecordType::A).unwrap();
+        assert_eq!(response.header.resp_code, dns::RespCode::Refused);
+        assert!(response.answers.is_empty());
+    }
+
+    let handler = self::handler(NameserverAcls {
+        query: Some(acl),
+        action: AclAction::Drop,
+        ..Default::default()
+    });
+    assert!(TestClient::default()
+        .query(&handler, &node, dns::RecordType::A)
+        .is_none());
+}
+
+#[test]
+fn test_layers() {
+    let layers: Vec<Box<dyn Layer>> = vec![
+        Box::new(BlockLayer {
+            names: vec![name("private.example.com.")],
+            action: BlockAction::NxDomain,
+        }),
+        Box::new(RewriteLayer {
+            from: name("example.net."),
+            to: name("example.com."),
+        }),
+        Box::new(TtlLayer { min: 0, max: 100 }),
+    ];
+    let handler = NameserverHandler {
+        layers: LayerStack::new(layers),
+        ..handler(NameserverAcls::default())
+    };
+    let client = TestClient::default();
+
+    // The rewritten query is answered with the records of the
+    // queried name, with the TTLs clamped by the last layer.
+    let node = name("portal.example.net.");
+    let response = client.query(&handler, &node, dns::RecordType::A).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    assert!(response.header.auth_answer);
+    assert_eq!(response.questions[0].node, node);
+    assert_eq!(addresses(&response.answers), [[194, 45, 65, 31], [194, 45, 65, 32]]);
+    assert!(response
+        .answers
+        .iter()
+        .all(|record| *record.node() == node && *record.ttl() <= 100));
+
+    let node = name("www.private.example.com.");
+    let response = client.query(&handler, &node, dns::RecordType::A).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NxDomain);
+    assert!(response.authorities.is_empty());
+}
+
+#[test]
+fn test_layers_acl() {
+    let layers: Vec<Box<dyn Layer>> = vec![Box::new(BlockLayer {
+        names: vec![name("private.example.com.")],
+        action: BlockAction::NxDomain,
+    })];
+    let handler = NameserverHandler {
+        layers: LayerStack::new(layers),
+        ..handler(NameserverAcls {
+            query: Some(Acl {
+                allow: vec!["10.0.0.0/8".parse().unwrap()],
+                deny: vec![],
+            }),
+            ..Default::default()
+        })
+    };
+
+    // The layers see only the requests allowed by the acls.
+    let node = name("www.private.example.com.");
+    let allowed = TestClient::new("10.0.0.2:5300".parse().unwrap(), Transport::Udp);
+    let response = allowed.query(&handler, &node, dns::RecordType::A).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NxDomain);
+    let denied = TestClient::new("127.0.0.1:5300".parse().unwrap(), Transport::Udp);
+    let response = denied.query(&handler, &node, dns::RecordType::A).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::Refused);
+}
@@ -0,0 +1,98 @@
+//! End to end tests of the configuration reload of the `nameserver` binary on SIGHUP.
+
+mod common;
+
+use ariadne_dns::shared::client::*;
+use ariadne_dns::shared::dns;
+use common::*;
+use std::process::{Command, Stdio};
+use std::{env, fs, thread, time};
+
+const PORT: u16 = 48653;
+
+fn query(node: &str) -> dns::RespCode {
+    let client = Client::new(ClientParams {
+        server: ([127, 0, 0, 1], PORT).into(),
+        attempts: 1,
+        ..Default::default()
+    });
+    let node = dns::Name::from_string(node).unwrap();
+    let reply = client.query(&node, dns::RecordType::A).unwrap();
+    reply.message.header.resp_code
+}
+
+fn reload(nameserver: &Nameserver, conf: &str) {
+    fs::write(&nameserver.conf_path, conf).unwrap();
+    // Safety: the pid is the one of the running child process.
+    assert_eq!(
+        unsafe { libc::kill(nameserver.child.id() as libc::pid_t, libc::SIGHUP) },
+        0
+    );
+}
+
+// Wait for the response code of the query to change, as the reload is asynchronous.
+fn wait_for(node: &str, expected: dns::RespCode) {
+    for _ in 0..50 {
+        if query(node) == expected {
+            return;
+        }
+        thread::sleep(time::Duration::from_millis(100));
+    }
+    panic!("query of {} not answered with {:?}", node, expected);
+}
+
+#[test]
+fn test_reload() {
+    let mut conf = nameserver_conf(PORT);
+    let nameserver = Nameserver::start("reload", &conf);
+    assert_eq!(query("portal.example.com."), dns::RespCode::NoError);
+
+    // The access control lists are applied live, while the drain timeout needs a restart.
+    conf["acl"] = serde_json::json!({ "query": { "deny": ["127.0.0.0/8"] } });
+    conf["drain_timeout"] = 10.into();
+    reload(&nameserver, &conf.to_string());
+    wait_for("portal.example.com.", dns::RespCode::Refused);
+
+    // Invalid configurations and zone files keep the previous configuration.
+    reload(&nameserver, "{");
+    thread::sleep(time::Duration::from_millis(300));
+    conf["acl"] = serde_json::Value::Null;
+    conf["zone"]["file"] = "assets/zones/missing".into();
+    reload(&nameserver, &conf.to_string());
+    thread::sleep(time::Duration::from_millis(300));
+    assert_eq!(query("portal.example.com."), dns::RespCode::Refused);
+
+    conf["zone"]["file"] = "assets/zones/example.com./example.com.".into();
+    reload(&nameserver, &conf.to_string());
+    wait_for("portal.example.com.", dns::RespCode::NoError);
+
+    let logs = nameserver.stop();
+    assert!(logs.contains("Reloading configuration file: "));
+    assert!(logs.contains("Reloading zone files: "));
+    assert_eq!(
+        logs.matches("Changes not applied until restart: drain_timeout.")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn test_chroot_outside_conf() {
+    // The configuration file is outside the directory of the zone file,
+    // the root directory, so it couldn't be reloaded after changing it.
+    let mut conf = nameserver_conf(PORT + 1);
+    conf["privileges"] = serde_json::json!({ "chroot": true });
+    let conf_path = env::temp_dir().join(format!("ariadne-chroot-{}.conf.json", std::process::id()));
+    fs::write(&conf_path, conf.to_string()).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_nameserver"))
+        .arg(&conf_path)
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    fs::remove_file(&conf_path).unwrap();
+
+    assert!(!output.status.success());
+    let logs = String::from_utf8_lossy(&output.stdout);
+    assert!(logs.contains("Changing root directory: "));
+    assert!(logs.contains("outside of the root directory"));
+}
@@ -0,0 +1,184 @@
+//! Tests of the resolver handler through the in-memory transport. Only
+//! requests not reaching external nameservers are tested: answers
+//! found in cache, access control lists and malformed requests. The
+//! cache operations run through the control server are tested too.
+
+use ariadne_dns::resolver::*;
+use ariadne_dns::shared::dns;
+use ariadne_dns::shared::layers::LayerStack;
+use ariadne_dns::shared::net::*;
+use std::sync::Arc;
+use std::time;
+
+fn name(name: &str) -> dns::Name {
+    dns::Name::from_string(name).unwrap()
+}
+
+// A resolver whose cache holds the A records of 'portal.example.com.'.
+fn handler(acls: ResolverAcls) -> ResolverHandler {
+    let cache = Arc::new(RecordsCache::new(CacheConf::default()));
+    let node = name("portal.example.com.");
+    let records = [[194, 45, 65, 31], [194, 45, 65, 32]]
+        .into_iter()
+        .map(|address| dns::Record::A {
+            node: node.clone(),
+            class: dns::Class::IN,
+            ttl: 300,
+            data_len: 4,
+            address,
+        })
+        .collect();
+    cache.set((node, dns::RecordType::A), time::Duration::from_secs(300), records);
+    let trace = TraceParams { silent: true, ..Default::default() };
+    ResolverHandler {
+        resolver: Resolver::new(&cache, ResolverParams::default(), trace),
+        acls,
+        layers: LayerStack::default(),
+    }
+}
+
+#[test]
+fn test_cached_answers() {
+    let handler = handler(ResolverAcls::default());
+    let client = TestClient::default();
+    let request = client.build_query(&name("portal.example.com."), dns::RecordType::A);
+    let reply = client.send_raw(&handler, request.encode_to_bytes().unwrap()).unwrap();
+    let resolution = reply.resolution.unwrap();
+    assert!(resolution.cache_hit);
+    assert_eq!(resolution.upstream_queries, 0);
+
+    let response = dns::Message::decode_from_bytes(&reply.bytes).unwrap();
+    assert_eq!(response.id(), request.id());
+    assert!(response.header.query_resp);
+    assert!(response.header.recursion_available);
+    assert!(!response.header.auth_answer);
+    assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    assert_eq!(response.questions, request.questions);
+    let mut addresses: Vec<_> = response.answers.iter().map(|record| *record.a_data()).collect();
+    addresses.sort();
+    assert_eq!(addresses, [[194, 45, 65, 31], [194, 45, 65, 32]]);
+    assert!(*response.answers[0].ttl() <= 300);
+}
+
+#[test]
+fn test_recursion_acl() {
+    let handler = handler(ResolverAcls {
+        recursion: Some(Acl {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec![],
+        }),
+        ..Default::default()
+    });
+
+    // Clients not allowed to recurse are answered from cache only.
+    let client = TestClient::default();
+    let response = client.query(&handler, &name("portal.example.com."), dns::RecordType::A);
+    let response = response.unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    assert!(!response.header.recursion_available);
+    assert_eq!(response.answers.len(), 2);
+
+    let response = client.query(&handler, &name("www.example.com."), dns::RecordType::A);
+    let response = response.unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::Refused);
+    assert!(response.answers.is_empty());
+}
+
+#[test]
+fn test_query_acl() {
+    let acl = Acl {
+        allow: vec!["10.0.0.0/8".parse().unwrap()],
+        deny: vec![],
+    };
+    let handler = handler(ResolverAcls {
+        query: Some(acl.clone()),
+        ..Default::default()
+    });
+    let node = name("portal.example.com.");
+    let allowed = TestClient::new("10.1.2.3:5300".parse().unwrap(), Transport::Tls);
+    let response = allowed.query(&handler, &node, dns::RecordType::A).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::NoError);
+    let response = TestClient::default().query(&handler, &node, dns::RecordType::A);
+    assert_eq!(response.unwrap().header.resp_code, dns::RespCode::Refused);
+
+    let handler = self::handler(ResolverAcls {
+        query: Some(acl),
+        action: AclAction::Drop,
+        ..Default::default()
+    });
+    assert!(TestClient::default()
+        .query(&handler, &node, dns::RecordType::A)
+        .is_none());
+}
+
+#[test]
+fn test_malformed_requests() {
+    let handler = handler(ResolverAcls::default());
+    let client = TestClient::default();
+    let mut request = client.build_query(&name("portal.example.com."), dns::RecordType::A);
+    request.header.query_resp = true;
+    let response = client.send(&handler, &request).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::FormErr);
+    assert_eq!(response.id(), request.id());
+
+    let mut bytes = request.encode_to_bytes().unwrap();
+    bytes.truncate(bytes.len() - 2);
+    let response = client.send_bytes(&handler, bytes).unwrap();
+    assert_eq!(response.header.resp_code, dns::RespCode::FormErr);
+    assert!(response.questions.is_empty());
+    assert!(client.send_bytes(&handler, vec![0, 1, 2]).is_none());
+}
+
+#[test]
+fn test_cache_operations() {
+    let cache = RecordsCache::new(CacheConf::default());
+    let ttl = time::Duration::from_secs(300);
+    let a_record = |node: &str| dns::Record::A {
+        node: name(node),
+        class: dns::Class::IN,
+        ttl: 300,
+        data_len: 4,
+        address: [10, 0, 0, 1],
+    };
+    for node in [
+        "www.portal.example.com.",
+        "example.org.",
+        "portal.example.com.",
+        "example.com.",
+    ] {
+        cache.set((name(node), dns::RecordType::A), ttl, vec![a_record(node)]);
+    }
+    let ns_record = dns::Record::NS {
+        node: name("portal.example.com."),
+        class: dns::Class::IN,
+        ttl: 300,
+        data_len: 0,
+        name: name("ns.example.com."),
+    };
+    cache.set((name("portal.example.com."), dns::RecordType::NS), ttl, vec![ns_record]);
+
+    // Records are dumped sorted, with the remaining ttl.
+    let dump = cache.dump();
+    let lines: Vec<Vec<&str>> = dump.lines().map(|line| line.split('\t').collect()).collect();
+    let nodes: Vec<&str> = lines.iter().map(|line| line[0]).collect();
+    assert_eq!(
+        nodes,
+        [
+            "example.com.",
+            "example.org.",
+            "portal.example.com.",
+            "portal.example.com.",
+            "www.portal.example.com."
+        ]
+    );
+    assert!(lines[0][1].parse::<u32>().unwrap() <= 300);
+    assert_eq!(lines[0][2..], ["IN", "A", "10.0.0.1"]);
+    assert_eq!(lines[3][2..], ["IN", "NS", "ns.example.com."]);
+
+    assert_eq!(cache.flush(&name("portal.example.com."), Some(dns::RecordType::NS)), 1);
+    assert_eq!(cache.flush(&name("portal.example.com."), None), 1);
+    assert_eq!(cache.flush(&name("portal.example.com."), None), 0);
+    assert_eq!(cache.flush_tree(&name("example.com.")), 2);
+    assert_eq!(cache.len(), 1);
+    assert!(cache.dump().starts_with("example.org.\t"));
+}
@@ -0,0 +1,171 @@
+//! End to end tests of the resolver lookups against local `nameserver` processes,
+//! whose responses are too large for UDP: the delegation of `big.example.net.`
+//! lists many nameservers and `txt.big.example.net.` has a large TXT record set.
+//! Nameservers never answering are sockets not reading the queries.
+
+mod common;
+
+use ariadne_dns::resolver::*;
+use ariadne_dns::shared::dns;
+use common::*;
+use serde_json::json;
+use std::sync::Arc;
+use std::{env, fs, net, path, time};
+
+const PORT: u16 = 48953;
+const RACE_PORT: u16 = 48954;
+const NAMESERVERS: usize = 20;
+
+// The parent zone, on 127.0.0.1, delegates `big.example.net.` to the child
+// zone, on 127.0.0.2, through nameservers that need glue records.
+fn start_nameservers(dir: &path::Path, port: u16) -> (Nameserver, Nameserver) {
+    let soa = |zone: &str| {
+        format!(
+            "{} IN 3600 SOA ns.{} admin.example.net. ( 1 7200 600 3600000 60 )\n",
+            zone, zone
+        )
+    };
+    let mut parent = soa("example.net.");
+    parent.push_str("example.net. NS ns.example.net.\nns.example.net. A 127.0.0.1\n");
+    let mut delegation = String::new();
+    let mut child = soa("big.example.net.");
+    for i in 0..NAMESERVERS {
+        delegation.push_str(&format!("big.example.net. NS ns{:02}.big.example.net.\n", i));
+        delegation.push_str(&format!("ns{:02}.big.example.net. A 127.0.0.2\n", i));
+    }
+    child.push_str("big.example.net. NS ns.big.example.net.\nns.big.example.net. A 127.0.0.2\n");
+    for i in 0..10 {
+        child.push_str(&format!(
+            "txt.big.example.net. TXT \"record-{:02}-{}\"\n",
+            i,
+            "x".repeat(80)
+        ));
+    }
+    fs::write(dir.join("example.net."), parent).unwrap();
+    fs::write(dir.join("delegation.big.example.net."), delegation).unwrap();
+    fs::write(dir.join("big.example.net."), child).unwrap();
+
+    let zone_file = |name: &str| dir.join(name).to_string_lossy().into_owned();
+    let mut parent_conf = nameserver_conf(port);
+    parent_conf["zone"] = json!({
+        "zone": "example.net.",
+        "file": zone_file("example.net."),
+        "starting_ttl": 3600,
+        "sub_zones": [{
+            "zone": "big.example.net.",
+            "file": zone_file("delegation.big.example.net."),
+            "starting_ttl": 3600,
+            "min_ttl": 60
+        }]
+    });
+    let mut child_conf = nameserver_conf(port);
+    child_conf["udp_server"]["listeners"][0]["address"] = "127.0.0.2".into();
+    child_conf["tcp_server"]["listeners"][0]["address"] = "127.0.0.2".into();
+    child_conf["zone"] = json!({
+        "zone": "big.example.net.",
+        "file": zone_file("big.example.net."),
+        "starting_ttl": 3600,
+        "sub_zones": []
+    });
+    let parent = Nameserver::start(&format!("upstream-parent-{}", port), &parent_conf);
+    let child = Nameserver::start(&format!("upstream-child-{}", port), &child_conf);
+    (parent, child)
+}
+
+// A resolver querying the local nameservers, starting from the
+// passed nameservers of the zone, seeded in its cache.
+fn start_resolver(params: ResolverParams, zone: &str, nameservers: &[(&str, [u8; 4])]) -> Resolver {
+    let cache = Arc::new(RecordsCache::new(CacheConf::default()));
+    let ttl = time::Duration::from_secs(3600);
+    let zone = dns::Name::from_string(zone).unwrap();
+    let mut ns_records = vec![];
+    for (name, address) in nameservers {
+        let name = dns::Name::from_string(name).unwrap();
+        ns_records.push(dns::Record::NS {
+            node: zone.clone(),
+            class: dns::Class::IN,
+            ttl: 3600,
+            data_len: 0,
+            name: name.clone(),
+        });
+        let a_record = dns::Record::A {
+            node: name.clone(),
+            class: dns::Class::IN,
+            ttl: 3600,
+            data_len: 4,
+            address: *address,
+        };
+        cache.set((name, dns::RecordType::A), ttl, vec![a_record]);
+    }
+    cache.set((zone, dns::RecordType::NS), ttl, ns_records);
+
+    let trace = TraceParams { silent: true, ..Default::default() };
+    Resolver::new(&cache, params, trace)
+}
+
+fn lookup_txt(resolver: &Resolver) -> Vec<dns::Record> {
+    let node = dns::Name::from_string("txt.big.example.net.").unwrap();
+    let (result, _) = resolver.new_lookup(&node, dns::RecordType::TXT).perform();
+    let LookupResponse(answers, _, _, no_domain) = result.unwrap();
+    assert!(!no_domain);
+    answers
+}
+
+#[test]
+fn test_truncated_responses() {
+    let dir = env::temp_dir().join(format!("ariadne-upstream-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let _nameservers = start_nameservers(&dir, PORT);
+
+    // Both the delegation, whose glue records don't fit in a UDP
+    // response, and the answer are retried over TCP when truncated.
+    for tcp_only in [false, true] {
+        let params = ResolverParams {
+            tcp_only,
+            upstream_port: PORT,
+            ..Default::default()
+        };
+        let metrics = Arc::new(ResolverMetrics::default());
+        let resolver = start_resolver(params, "example.net.", &[("ns.example.net.", [127, 0, 0, 1])])
+            .with_metrics(Arc::clone(&metrics));
+        let answers = lookup_txt(&resolver);
+        assert_eq!(answers.len(), 10);
+        assert_eq!(metrics.upstream_queries.get(), 2);
+        assert_eq!(metrics.upstream_tcp_queries.get(), 2);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_racing_nameservers() {
+    let dir = env::temp_dir().join(format!("ariadne-upstream-race-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let _nameservers = start_nameservers(&dir, RACE_PORT);
+    let _dead = net::UdpSocket::bind(("127.0.0.3", RACE_PORT)).unwrap();
+
+    // The nameserver never answering is queried first while its round trip time is
+    // unknown, the other one answers in its place well before the read timeout.
+    let params = ResolverParams {
+        upstream_port: RACE_PORT,
+        race_nameservers: true,
+        ..Default::default()
+    };
+    let nameservers = [
+        ("dead.big.example.net.", [127, 0, 0, 3]),
+        ("ns.big.example.net.", [127, 0, 0, 2]),
+    ];
+    let servers = Arc::new(ServerStats::default());
+    let metrics = Arc::new(ResolverMetrics::default());
+    for _ in 0..3 {
+        let resolver = start_resolver(params.clone(), "big.example.net.", &nameservers)
+            .with_metrics(Arc::clone(&metrics))
+            .with_server_stats(Arc::clone(&servers));
+        let started = time::Instant::now();
+        assert_eq!(lookup_txt(&resolver).len(), 10);
+        assert!(started.elapsed() < time::Duration::from_secs(1));
+    }
+    assert!(metrics.upstream_races.get() > 0);
+    assert!(servers.srtt([127, 0, 0, 2].into()).is_some());
+    assert!(servers.srtt([127, 0, 0, 3].into()).is_none());
+    fs::remove_dir_all(&dir).unwrap();
+}